@@ -0,0 +1,170 @@
+//! Compares uploading the changed instances of each entity with its own `write_buffer`
+//! to packing them in a single staging buffer like `update_instance_buffer` does.
+//! Every entity has a single instance, like the waving cubes of the main app scaled up.
+//!
+//! The uploads need a gpu, only the packing is measured when no adapter is available.
+//!
+//! `instance_update` measures the cpu time saved by [`StaticInstances`] when every transform
+//! of the scene is marked as changed, the static entities are skipped by the update query.
+
+use bevy::{ecs::prelude::*, math::Vec3, transform::components::Transform};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_lite::future;
+use glace::instances::{
+    pack_instances, CompactInstances, InstanceAnimation, InstanceStagingBuffer, Instances,
+    StaticInstances,
+};
+
+const ENTITY_COUNTS: [usize; 3] = [100, 1_000, 10_000];
+
+fn transforms(count: usize) -> Vec<Transform> {
+    (0..count)
+        .map(|i| Transform::from_translation(Vec3::new(i as f32, (i as f32).sin(), 0.0)))
+        .collect()
+}
+
+fn request_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::default();
+    let adapter =
+        future::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+    future::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None)).ok()
+}
+
+fn pack(c: &mut Criterion) {
+    let mut group = c.benchmark_group("instance_pack");
+    for count in ENTITY_COUNTS {
+        let transforms = transforms(count);
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(
+            BenchmarkId::new("per_entity", count),
+            &transforms,
+            |b, t| {
+                b.iter(|| {
+                    t.chunks(1)
+                        .map(|transform| pack_instances([(transform, false)]).0)
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("batched", count), &transforms, |b, t| {
+            b.iter(|| pack_instances(t.chunks(1).map(|transform| (transform, false))))
+        });
+    }
+    group.finish();
+}
+
+fn upload(c: &mut Criterion) {
+    let (device, queue) = if let Some(device) = request_device() {
+        device
+    } else {
+        eprintln!("No gpu adapter available, skipping the instance_upload benchmarks");
+        return;
+    };
+
+    let mut group = c.benchmark_group("instance_upload");
+    for count in ENTITY_COUNTS {
+        let transforms = transforms(count);
+        let buffers: Vec<_> = (0..count)
+            .map(|_| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Instance Buffer"),
+                    size: pack_instances([(&transforms[..1], false)]).0.len() as u64,
+                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+        // The writes only happen on submit, waiting for the gpu measures the whole upload
+        let flush = || {
+            queue.submit(None);
+            device.poll(wgpu::Maintain::Wait);
+        };
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_function(BenchmarkId::new("per_entity", count), |b| {
+            b.iter(|| {
+                for (buffer, transform) in buffers.iter().zip(transforms.chunks(1)) {
+                    let (data, _) = pack_instances([(transform, false)]);
+                    queue.write_buffer(buffer, 0, &data);
+                }
+                flush();
+            })
+        });
+
+        let mut staging = InstanceStagingBuffer::default();
+        group.bench_function(BenchmarkId::new("batched", count), |b| {
+            b.iter(|| {
+                let (data, ranges) =
+                    pack_instances(transforms.chunks(1).map(|transform| (transform, false)));
+                let copies: Vec<_> = buffers.iter().zip(ranges).collect();
+                staging.upload(&device, &queue, &data, &copies);
+                flush();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Something like a physics step touching every transform
+fn touch_transforms(mut query: Query<&mut Transform>) {
+    for mut transform in &mut query {
+        transform.set_changed();
+    }
+}
+
+/// The cpu side of `update_instance_buffer`, it uses the same query and packs the changed instances
+#[allow(clippy::type_complexity)]
+fn pack_changed_instances(
+    query: Query<
+        (
+            Option<&Transform>,
+            Option<&Instances>,
+            Option<&CompactInstances>,
+        ),
+        (
+            Or<(Changed<Transform>, Changed<Instances>)>,
+            Without<InstanceAnimation>,
+            Without<StaticInstances>,
+        ),
+    >,
+) {
+    let (data, _) = pack_instances(query.iter().map(|(transform, instances, compact)| {
+        let transforms = if let Some(t) = transform {
+            std::slice::from_ref(t)
+        } else {
+            &instances.unwrap().0[..]
+        };
+        (transforms, compact.is_some())
+    }));
+    black_box(data);
+}
+
+fn update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("instance_update");
+    for count in ENTITY_COUNTS {
+        group.throughput(Throughput::Elements(count as u64));
+        for static_instances in [false, true] {
+            let mut world = World::new();
+            for transform in transforms(count) {
+                let mut entity = world.spawn(transform);
+                if static_instances {
+                    entity.insert(StaticInstances);
+                }
+            }
+            let mut schedule = Schedule::new();
+            schedule.add_systems((touch_transforms, pack_changed_instances).chain());
+            let name = if static_instances {
+                "static"
+            } else {
+                "dynamic"
+            };
+            group.bench_function(BenchmarkId::new(name, count), |b| {
+                b.iter(|| schedule.run(&mut world))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pack, upload, update);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+use bevy::{
+    a11y::AccessibilityPlugin, input::InputPlugin, prelude::*, window::WindowPlugin,
+    winit::WinitPlugin,
+};
+
+use glace::{model, prelude::*, shapes};
+
+const LIGHT_POSITION: Vec3 = Vec3::from_array([2.0, 2.0, 2.0]);
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .filter_module("wgpu_hal", log::LevelFilter::Error)
+        .filter_module("wgpu_core", log::LevelFilter::Error)
+        .init();
+
+    App::new()
+        .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
+        .add_plugins((
+            MinimalPlugins,
+            WindowPlugin::default(),
+            AccessibilityPlugin,
+            WinitPlugin,
+            InputPlugin,
+            WgpuRendererPlugin,
+            EguiPlugin,
+        ))
+        .add_systems(Startup, (spawn_light, spawn_arrows))
+        .run();
+}
+
+fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let cube = shapes::cube::Cube::new(1.0, 1.0, 1.0);
+    let mesh = cube.mesh(&renderer.device);
+    let model = Model {
+        meshes: vec![mesh],
+        materials: vec![],
+    };
+
+    let light = Light {
+        position: LIGHT_POSITION,
+        color: Color::WHITE.as_rgba_f32().into(),
+    };
+
+    commands.spawn((light, model));
+}
+
+/// Spawns an arrow for each axis, the arrow points along +Y so it's rotated for X and Z
+fn spawn_arrows(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let arrow = Model {
+        meshes: vec![shapes::arrow::Arrow::default().mesh(&renderer.device)],
+        materials: vec![],
+    };
+
+    for (color, rotation) in [
+        (
+            Color::RED,
+            Quat::from_rotation_z(-std::f32::consts::FRAC_PI_2),
+        ),
+        (Color::GREEN, Quat::IDENTITY),
+        (
+            Color::BLUE,
+            Quat::from_rotation_x(std::f32::consts::FRAC_PI_2),
+        ),
+    ] {
+        commands.spawn((
+            Model {
+                materials: vec![model::Material::from_color(color)],
+                ..arrow.clone_gpu()
+            },
+            Transform {
+                rotation,
+                scale: Vec3::splat(2.0),
+                ..default()
+            },
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+use bevy::{
+    a11y::AccessibilityPlugin, input::InputPlugin, prelude::*, window::WindowPlugin,
+    winit::WinitPlugin,
+};
+
+use glace::{
+    model::{self, BlendMode},
+    prelude::*,
+    shapes,
+};
+
+const LIGHT_POSITION: Vec3 = Vec3::from_array([2.0, 2.0, 2.0]);
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .filter_module("wgpu_hal", log::LevelFilter::Error)
+        .filter_module("wgpu_core", log::LevelFilter::Error)
+        .init();
+
+    App::new()
+        .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
+        .add_plugins((
+            MinimalPlugins,
+            WindowPlugin::default(),
+            AccessibilityPlugin,
+            WinitPlugin,
+            InputPlugin,
+            WgpuRendererPlugin,
+            EguiPlugin,
+        ))
+        .add_systems(Startup, (spawn_light, spawn_quads))
+        .run();
+}
+
+fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let cube = shapes::cube::Cube::new(1.0, 1.0, 1.0);
+    let mesh = cube.mesh(&renderer.device);
+    let model = Model {
+        meshes: vec![mesh],
+        materials: vec![],
+    };
+
+    let light = Light {
+        position: LIGHT_POSITION,
+        color: Color::WHITE.as_rgba_f32().into(),
+    };
+
+    commands.spawn((light, model));
+}
+
+fn spawn_quads(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let mut spawn_quad = |color: Color, blend_mode: BlendMode, translation: Vec3| {
+        let quad = Model {
+            meshes: vec![shapes::quad::Quad.mesh(&renderer.device)],
+            materials: vec![model::Material {
+                blend_mode,
+                ..model::Material::from_color(color)
+            }],
+        };
+        commands.spawn((
+            quad,
+            Transform {
+                translation,
+                scale: Vec3::splat(2.0),
+                ..default()
+            },
+        ));
+    };
+
+    // An opaque quad in the back to show the blending
+    spawn_quad(Color::WHITE, BlendMode::Opaque, Vec3::new(-1.0, -1.0, -2.0));
+
+    // Additive particles, overlapping each other
+    spawn_quad(
+        Color::rgba(1.0, 0.0, 0.0, 0.75),
+        BlendMode::Additive,
+        Vec3::new(-2.0, -0.5, -1.0),
+    );
+    spawn_quad(
+        Color::rgba(0.0, 1.0, 0.0, 0.75),
+        BlendMode::Additive,
+        Vec3::new(-1.5, -1.0, -0.5),
+    );
+    spawn_quad(
+        Color::rgba(0.0, 0.0, 1.0, 0.75),
+        BlendMode::Additive,
+        Vec3::new(-1.0, -0.5, -0.75),
+    );
+
+    // Alpha blended glass in front of the additive quads
+    spawn_quad(
+        Color::rgba(0.5, 0.8, 1.0, 0.3),
+        BlendMode::Alpha,
+        Vec3::new(-1.25, -1.25, 0.5),
+    );
+    // A second pane overlapping the first one, both should be visible through each other
+    // from any side since transparent meshes don't write depth
+    spawn_quad(
+        Color::rgba(1.0, 0.5, 0.8, 0.3),
+        BlendMode::Alpha,
+        Vec3::new(-0.5, -0.75, 0.75),
+    );
+
+    // Multiply tints everything behind it
+    spawn_quad(
+        Color::rgba(1.0, 0.8, 0.2, 1.0),
+        BlendMode::Multiply,
+        Vec3::new(0.0, -1.5, 1.0),
+    );
+
+    // Green tinted glass, it lets most of the green of the background through
+    commands.spawn((
+        Model {
+            meshes: vec![shapes::quad::Quad.mesh(&renderer.device)],
+            materials: vec![model::Material {
+                blend_mode: BlendMode::Constant,
+                blend_constant: Vec3::new(0.2, 0.8, 0.2),
+                ..model::Material::from_color(Color::rgb(0.1, 0.3, 0.1))
+            }],
+        },
+        Transform {
+            translation: Vec3::new(0.5, -0.5, 1.25),
+            scale: Vec3::splat(2.0),
+            ..default()
+        },
+    ));
+}
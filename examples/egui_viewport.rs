@@ -0,0 +1,107 @@
+use bevy::{
+    a11y::AccessibilityPlugin, input::InputPlugin, prelude::*, window::WindowPlugin,
+    winit::WinitPlugin,
+};
+
+use glace::{egui_plugin::EguiCtxRes, model, prelude::*, shapes};
+
+const LIGHT_POSITION: Vec3 = Vec3::from_array([2.0, 2.0, 2.0]);
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .filter_module("wgpu_hal", log::LevelFilter::Error)
+        .filter_module("wgpu_core", log::LevelFilter::Error)
+        .init();
+
+    App::new()
+        .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
+        .init_resource::<EguiViewport>()
+        // Ignores the scale factor of the window so the slider controls the size of the ui
+        .insert_resource(UiScale(1.0))
+        .add_plugins((
+            MinimalPlugins,
+            WindowPlugin::default(),
+            AccessibilityPlugin,
+            WinitPlugin,
+            InputPlugin,
+            WgpuRendererPlugin,
+            EguiPlugin,
+        ))
+        .add_systems(Startup, (spawn_light, spawn_cube))
+        .add_systems(Update, (ui, rotate_cube))
+        .run();
+}
+
+#[derive(Component)]
+struct Rotate;
+
+fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let cube = shapes::cube::Cube::new(1.0, 1.0, 1.0);
+    let mesh = cube.mesh(&renderer.device);
+    let model = Model {
+        meshes: vec![mesh],
+        materials: vec![],
+    };
+
+    let light = Light {
+        position: LIGHT_POSITION,
+        color: Color::WHITE.as_rgba_f32().into(),
+    };
+
+    commands.spawn((light, model));
+}
+
+fn spawn_cube(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let cube = Model {
+        meshes: vec![shapes::cube::Cube::new(1.0, 1.0, 1.0).mesh(&renderer.device)],
+        materials: vec![model::Material::from_color(Color::ORANGE)],
+    };
+    commands.spawn((
+        cube,
+        Transform::default(),
+        ModelVisibility::default(),
+        Rotate,
+    ));
+}
+
+fn rotate_cube(mut query: Query<&mut Transform, With<Rotate>>, time: Res<Time>) {
+    for mut transform in &mut query {
+        transform.rotate_y(time.delta_seconds());
+    }
+}
+
+/// An editor style layout, the 3d scene is drawn in the central panel
+fn ui(
+    ctx: Res<EguiCtxRes>,
+    mut viewport: ResMut<EguiViewport>,
+    mut cubes: Query<&mut ModelVisibility, With<Rotate>>,
+    mut ui_scale: ResMut<UiScale>,
+) {
+    egui::SidePanel::left("Inspector").show(&ctx.0, |ui| {
+        ui.heading("Inspector");
+        if let Some(rect) = viewport.rect {
+            ui.label(format!(
+                "Viewport: {:.0}x{:.0}",
+                rect.width(),
+                rect.height()
+            ));
+        }
+        ui.label(format!("Hovered: {}", viewport.hovered));
+        for mut visibility in &mut cubes {
+            ui.checkbox(&mut visibility.visible, "Cube visible");
+        }
+        ui.label("Ui scale");
+        ui.add(egui::Slider::new(&mut ui_scale.0, 0.5..=3.0).step_by(0.25));
+    });
+
+    egui::CentralPanel::default()
+        .frame(egui::Frame::none())
+        .show(&ctx.0, |ui| {
+            viewport.show(ui);
+        });
+}
@@ -3,15 +3,7 @@ use bevy::{
     window::WindowPlugin, winit::WinitPlugin,
 };
 
-use glace::{
-    camera::CameraSettings,
-    egui_plugin::EguiPlugin,
-    gltf_loader::{GltfBundle, GltfLoaderPlugin},
-    light::Light,
-    model::Model,
-    renderer::{GlaceClearColor, WgpuRenderer, WgpuRendererPlugin},
-    shapes,
-};
+use glace::{prelude::*, shapes};
 
 const LIGHT_POSITION: Vec3 = Vec3::from_array([2.0, 2.0, 2.0]);
 
@@ -24,7 +16,10 @@ fn main() {
 
     App::new()
         .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
-        .insert_resource(CameraSettings { speed: 10.0 })
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
         .add_plugins((
             MinimalPlugins,
             WindowPlugin::default(),
@@ -3,26 +3,19 @@ use bevy::{
     window::WindowPlugin, winit::WinitPlugin,
 };
 
-use glace::{
-    camera::CameraSettings,
-    egui_plugin::{EguiCtxRes, EguiPlugin},
-    instances::Instances,
-    light::Light,
-    model::Model,
-    obj_loader::{ObjBundle, ObjLoaderPlugin},
-    renderer::GlaceClearColor,
-    renderer::{WgpuRenderer, WgpuRendererPlugin},
-    shapes,
-};
+use glace::{egui_plugin::EguiCtxRes, prelude::*, shapes};
 
 const LIGHT_POSITION: Vec3 = Vec3::from_array([4.0, 4.0, 2.0]);
 
-const NUM_INSTANCES_PER_ROW: u32 = 6;
+const NUM_INSTANCES_PER_ROW: u32 = 7;
 const SPACE_BETWEEN: f32 = 3.0;
 
+// Scenes this big z-fight far from the camera with the regular depth range,
+// set REVERSE_Z to true to spread the depth precision more evenly.
 // const MODEL_NAME: &str = "models/obj/large_obj/sponza_obj/sponza.obj";
 // const MODEL_NAME: &str = "models/obj/large_obj/bistro/Exterior/exterior.obj";
 // const SCALE: Vec3 = Vec3::from_array([0.05, 0.05, 0.05]);
+const REVERSE_Z: bool = false;
 
 const MODEL_NAME: &str = "models/obj/teapot/teapot.obj";
 const SCALE: Vec3 = Vec3::from_array([0.025, 0.025, 0.025]);
@@ -40,6 +33,10 @@ const INSTANCED_SCALE: Vec3 = Vec3::from_array([1.0, 1.0, 1.0]);
 #[derive(Resource)]
 struct InstanceSettings {
     move_instances: bool,
+    /// Animates the instances with a compute shader instead of updating them on the cpu
+    gpu_animation: bool,
+    /// Culls the instances on the gpu and draws them with indirect draws
+    indirect: bool,
 }
 
 fn main() {
@@ -51,9 +48,15 @@ fn main() {
 
     App::new()
         .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
-        .insert_resource(CameraSettings { speed: 10.0 })
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
+        .insert_resource(ReverseZ(REVERSE_Z))
         .insert_resource(InstanceSettings {
             move_instances: false,
+            gpu_animation: false,
+            indirect: false,
         })
         .add_plugins((
             MinimalPlugins,
@@ -67,37 +70,37 @@ fn main() {
             ObjLoaderPlugin,
         ))
         .add_systems(Startup, (spawn_obj, spawn_light))
-        .add_systems(Update, (update_light, settings_ui, move_instances))
+        .add_systems(
+            Update,
+            (
+                update_light,
+                settings_ui,
+                move_instances,
+                toggle_gpu_animation,
+                toggle_indirect,
+            ),
+        )
         .run();
 }
 
 fn spawn_obj(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let mut instances = Vec::new();
-    for z in 0..=NUM_INSTANCES_PER_ROW {
-        for x in 0..=NUM_INSTANCES_PER_ROW {
-            let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-            let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-
-            let translation = Vec3::new(x, 0.0, z);
-            let rotation = if translation == Vec3::ZERO {
-                Quat::from_axis_angle(Vec3::Y, 0.0)
-            } else {
-                Quat::from_axis_angle(translation.normalize(), std::f32::consts::FRAC_PI_4)
-            };
-
-            instances.push(Transform {
-                rotation,
-                translation,
-                scale: INSTANCED_SCALE,
-            });
+    let mut instances =
+        Instances::grid(NUM_INSTANCES_PER_ROW, NUM_INSTANCES_PER_ROW, SPACE_BETWEEN);
+    for instance in instances.0.iter_mut() {
+        if instance.translation != Vec3::ZERO {
+            instance.rotation = Quat::from_axis_angle(
+                instance.translation.normalize(),
+                std::f32::consts::FRAC_PI_4,
+            );
         }
+        instance.scale = INSTANCED_SCALE;
     }
 
     commands
         .spawn(ObjBundle {
             obj: asset_server.load(INSTANCED_MODEL_NAME),
         })
-        .insert(Instances(instances))
+        .insert(instances)
         .insert(Wave::default());
 
     commands
@@ -116,7 +119,7 @@ fn move_instances(
     mut query: Query<(&mut Instances, &mut Wave)>,
     settings: Res<InstanceSettings>,
 ) {
-    if !settings.move_instances {
+    if !settings.move_instances || settings.gpu_animation {
         return;
     }
     for (mut instances, mut wave) in query.iter_mut() {
@@ -128,6 +131,50 @@ fn move_instances(
     }
 }
 
+fn toggle_gpu_animation(
+    mut commands: Commands,
+    query: Query<(Entity, &Wave)>,
+    settings: Res<InstanceSettings>,
+    mut enabled: Local<bool>,
+) {
+    // The settings are mutably borrowed by the ui every frame so is_changed() can't be used
+    let enable = settings.move_instances && settings.gpu_animation;
+    if *enabled == enable {
+        return;
+    }
+    *enabled = enable;
+    for (entity, wave) in query.iter() {
+        if enable {
+            commands.entity(entity).insert(InstanceAnimation {
+                amplitude: wave.amplitude,
+                wavelength: wave.wavelength,
+                speed: wave.frequency,
+            });
+        } else {
+            commands.entity(entity).remove::<InstanceAnimation>();
+        }
+    }
+}
+
+fn toggle_indirect(
+    mut commands: Commands,
+    query: Query<Entity, With<Wave>>,
+    settings: Res<InstanceSettings>,
+    mut enabled: Local<bool>,
+) {
+    if *enabled == settings.indirect {
+        return;
+    }
+    *enabled = settings.indirect;
+    for entity in query.iter() {
+        if settings.indirect {
+            commands.entity(entity).insert(IndirectInstances);
+        } else {
+            commands.entity(entity).remove::<IndirectInstances>();
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct Wave {
     pub amplitude: f32,
@@ -203,5 +250,7 @@ fn settings_ui(
             ui.heading("Instances");
 
             ui.checkbox(&mut instance_settings.move_instances, "Move");
+            ui.checkbox(&mut instance_settings.gpu_animation, "Animate on the gpu");
+            ui.checkbox(&mut instance_settings.indirect, "Cull on the gpu");
         });
 }
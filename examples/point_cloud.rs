@@ -0,0 +1,58 @@
+use bevy::{
+    a11y::AccessibilityPlugin, asset::AssetPlugin, input::InputPlugin, prelude::*,
+    window::WindowPlugin, winit::WinitPlugin,
+};
+
+use glace::prelude::*;
+
+const MODEL_NAME: &str = "models/obj/bunny.obj";
+const SCALE: Vec3 = Vec3::from_array([1.5, 1.5, 1.5]);
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .filter_module("wgpu_hal", log::LevelFilter::Error)
+        .filter_module("wgpu_core", log::LevelFilter::Error)
+        .init();
+
+    App::new()
+        .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
+        .add_plugins((
+            MinimalPlugins,
+            WindowPlugin::default(),
+            AccessibilityPlugin,
+            WinitPlugin,
+            InputPlugin,
+            AssetPlugin::default(),
+            WgpuRendererPlugin,
+            EguiPlugin,
+            ObjLoaderPlugin,
+        ))
+        .add_systems(Startup, spawn_obj)
+        .add_systems(Update, render_as_points)
+        .run();
+}
+
+fn spawn_obj(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(ObjBundle {
+            obj: asset_server.load(MODEL_NAME),
+        })
+        .insert(Transform {
+            scale: SCALE,
+            ..default()
+        });
+}
+
+/// Draws every vertex of the loaded model as a point
+fn render_as_points(mut query: Query<&mut Model, Added<Model>>) {
+    for mut model in &mut query {
+        for mesh in &mut model.meshes {
+            mesh.topology = wgpu::PrimitiveTopology::PointList;
+        }
+    }
+}
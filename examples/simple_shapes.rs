@@ -3,14 +3,7 @@ use bevy::{
     winit::WinitPlugin,
 };
 
-use glace::{
-    camera::CameraSettings,
-    egui_plugin::EguiPlugin,
-    light::Light,
-    model::{self, Model},
-    renderer::{wireframe::Wireframe, GlaceClearColor, WgpuRenderer, WgpuRendererPlugin},
-    shapes,
-};
+use glace::{camera::Camera, model, prelude::*, shapes};
 
 const LIGHT_POSITION: Vec3 = Vec3::from_array([2.0, 2.0, 2.0]);
 
@@ -23,7 +16,10 @@ fn main() {
 
     App::new()
         .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
-        .insert_resource(CameraSettings { speed: 10.0 })
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
         .add_plugins((
             MinimalPlugins,
             WindowPlugin::default(),
@@ -34,9 +30,18 @@ fn main() {
             EguiPlugin,
         ))
         .add_systems(Startup, (spawn_light, spawn_shapes))
+        .add_systems(Update, (spawn_light_at_camera, despawn_light, shake))
         .run();
 }
 
+/// The model used to draw every light, the lights share the same gpu buffers
+#[derive(Resource)]
+struct LightModel(Model);
+
+/// Lights spawned with the keyboard, only those can be removed
+#[derive(Component)]
+struct SpawnedLight;
+
 fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
     let cube = shapes::cube::Cube::new(1.0, 1.0, 1.0);
     let mesh = cube.mesh(&renderer.device);
@@ -50,33 +55,84 @@ fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
         color: Color::WHITE.as_rgba_f32().into(),
     };
 
-    commands.spawn((light, model));
+    // The spawned lights don't cast shadows to show the difference
+    commands.spawn((light, model.clone_gpu(), PointLightShadow));
+    commands.insert_resource(LightModel(model));
 }
 
-fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
-    let diffuse_texture_bytes = include_bytes!("../assets/rock_plane/Rock-Albedo.png");
-    let diffuse_texture = image::load_from_memory(diffuse_texture_bytes)
-        .unwrap()
-        .to_rgba8();
+/// Press L to spawn a light with a random color at the camera position
+fn spawn_light_at_camera(
+    mut commands: Commands,
+    key_input: Res<Input<KeyCode>>,
+    camera: Res<Camera>,
+    light_model: Res<LightModel>,
+    time: Res<Time>,
+) {
+    if !key_input.just_pressed(KeyCode::L) {
+        return;
+    }
 
-    let normal_texture_bytes = include_bytes!("../assets/rock_plane/Rock-Normal.png");
-    let normal_texture = image::load_from_memory(normal_texture_bytes)
-        .unwrap()
-        .to_rgba8();
+    // Good enough randomness for an example
+    let hue = (time.elapsed_seconds() * 1000.0) % 360.0;
+    commands.spawn((
+        Light {
+            position: camera.eye,
+            color: Color::hsl(hue, 1.0, 0.5),
+        },
+        light_model.0.clone_gpu(),
+        SpawnedLight,
+    ));
+}
 
+/// Press K to remove one of the spawned lights
+fn despawn_light(
+    mut commands: Commands,
+    key_input: Res<Input<KeyCode>>,
+    lights: Query<Entity, With<SpawnedLight>>,
+) {
+    if !key_input.just_pressed(KeyCode::K) {
+        return;
+    }
+
+    if let Some(entity) = lights.iter().min() {
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Press X to shake the camera, pressing it again while shaking adds to the shake
+fn shake(key_input: Res<Input<KeyCode>>, mut impulses: EventWriter<CameraShakeImpulse>) {
+    if key_input.just_pressed(KeyCode::X) {
+        impulses.send(CameraShakeImpulse(0.5));
+    }
+}
+
+fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
     let plane = Model {
         meshes: vec![shapes::plane::Plane {
             resolution: 5,
             size: 5.0,
+            // One tile per unit
+            uv_scale: Vec2::splat(5.0),
+            ..default()
         }
         .mesh(&renderer.device)],
         materials: vec![model::Material {
             name: "rock_material".to_string(),
-            diffuse_texture,
-            normal_texture: Some(normal_texture),
             ..Default::default()
         }],
-    };
+    }
+    .with_texture_bytes(
+        0,
+        include_bytes!("../assets/rock_plane/Rock-Albedo.png"),
+        Some(image::ImageFormat::Png),
+    )
+    .unwrap()
+    .with_normal_texture_bytes(
+        0,
+        include_bytes!("../assets/rock_plane/Rock-Normal.png"),
+        Some(image::ImageFormat::Png),
+    )
+    .unwrap();
     commands.spawn((
         plane,
         Transform {
@@ -0,0 +1,99 @@
+use bevy::{
+    a11y::AccessibilityPlugin, input::InputPlugin, prelude::*, window::WindowPlugin,
+    winit::WinitPlugin,
+};
+
+use glace::{model, prelude::*, shapes};
+
+const LIGHT_POSITION: Vec3 = Vec3::from_array([2.0, 2.0, 2.0]);
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .filter_module("wgpu_hal", log::LevelFilter::Error)
+        .filter_module("wgpu_core", log::LevelFilter::Error)
+        .init();
+
+    App::new()
+        .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            initial_eye: Vec3::new(0.0, 0.5, 4.0),
+            ..default()
+        })
+        // The default depth format has no stencil
+        .insert_resource(DepthFormat::Depth24PlusStencil8)
+        .add_plugins((
+            MinimalPlugins,
+            WindowPlugin::default(),
+            AccessibilityPlugin,
+            WinitPlugin,
+            InputPlugin,
+            WgpuRendererPlugin,
+            EguiPlugin,
+        ))
+        .add_systems(Startup, (spawn_light, spawn_shapes))
+        .run();
+}
+
+fn spawn_light(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let cube = shapes::cube::Cube::new(1.0, 1.0, 1.0);
+    let mesh = cube.mesh(&renderer.device);
+    let model = Model {
+        meshes: vec![mesh],
+        materials: vec![],
+    };
+
+    let light = Light {
+        position: LIGHT_POSITION,
+        color: Color::WHITE.as_rgba_f32().into(),
+    };
+
+    commands.spawn((light, model));
+}
+
+fn spawn_shapes(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    // The window, only its shape is written to the stencil buffer
+    commands.spawn((
+        Model {
+            meshes: vec![shapes::quad::Quad.mesh(&renderer.device)],
+            materials: vec![model::Material::from_color(Color::WHITE)],
+        },
+        Transform {
+            translation: Vec3::new(-1.0, -1.0, 0.0),
+            scale: Vec3::splat(2.0),
+            ..default()
+        },
+        StencilWrite(1),
+    ));
+
+    // Only visible through the window
+    for (i, color) in [Color::RED, Color::GREEN, Color::BLUE]
+        .into_iter()
+        .enumerate()
+    {
+        commands.spawn((
+            Model {
+                meshes: vec![shapes::sphere::UVSphere::default().mesh(&renderer.device)],
+                materials: vec![model::Material::from_color(color)],
+            },
+            Transform {
+                translation: Vec3::new(i as f32 - 1.0, 0.0, -2.0),
+                ..default()
+            },
+            StencilTest(1),
+        ));
+    }
+
+    // A regular cube in front of the window still hides what's behind it
+    commands.spawn((
+        Model {
+            meshes: vec![shapes::cube::Cube::new(0.5, 0.5, 0.5).mesh(&renderer.device)],
+            materials: vec![model::Material::from_color(Color::WHITE)],
+        },
+        Transform {
+            translation: Vec3::new(0.75, -0.75, 1.0),
+            ..default()
+        },
+    ));
+}
@@ -5,24 +5,128 @@ use bevy::{
     math::prelude::*,
     time::prelude::*,
     window::prelude::*,
+    winit::WinitWindows,
 };
+use winit::window::CursorGrabMode;
 
-use crate::renderer::bind_groups::mesh_view::CameraUniform;
+use crate::{
+    egui_plugin::viewport::EguiViewport,
+    math::Ray,
+    renderer::{
+        bind_groups::mesh_view::{update_camera_buffer, CameraUniform},
+        ReverseZ,
+    },
+};
 
 const FRICTION: f32 = 0.5;
+/// Radians per second
+const ROLL_SPEED: f32 = std::f32::consts::FRAC_PI_2;
 
 const CAMERRA_EYE: Vec3 = Vec3::from_array([0.0, 5.0, 8.0]);
 
 #[derive(Resource)]
 pub struct CameraSettings {
     pub speed: f32,
+    /// Grabs and hides the cursor while the fly camera is rotating
+    pub grab_cursor: bool,
+    /// The camera yaws around this axis and Space/Shift move along it
+    pub up: Vec3,
+    /// Enables rolling around the local Z axis with Q and E.
+    /// The camera then yaws around its own up axis so it can be freely oriented, like in a flight sim
+    pub roll: bool,
+    /// Maximum angle in radians between the view direction and the horizon.
+    /// Prevents flipping when looking straight up or down, it's ignored when rolling is enabled.
+    pub pitch_limit: Option<f32>,
+    /// Where the camera is placed when it's created, the first frame is already rendered from there
+    pub initial_eye: Vec3,
+    /// The point the camera looks at when it's created
+    pub initial_target: Vec3,
+    /// Eases the position and the rotation of the camera toward where the input moved them.
+    /// It's the time in seconds to cover about 63% of the remaining distance, 0 moves the camera instantly.
+    pub smoothing: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            speed: 10.0,
+            grab_cursor: true,
+            up: Vec3::Y,
+            roll: false,
+            pitch_limit: Some(89f32.to_radians()),
+            initial_eye: CAMERRA_EYE,
+            initial_target: Vec3::ZERO,
+            smoothing: 0.0,
+        }
+    }
+}
+
+/// Whether the fly camera is currently being rotated with the right mouse button
+#[derive(Resource, Default)]
+pub struct FlyCameraRotating(pub bool);
+
+/// Where the input moved the camera and where the smoothing left it on the previous frame
+#[derive(Default)]
+pub struct FlyCameraSmoothing {
+    target: (Vec3, Quat),
+    current: (Vec3, Quat),
+}
+
+/// Shakes the view of the [`Camera`] with smooth noise, raise the trauma with a [`CameraShakeImpulse`].
+/// The shake is stored separately from the eye and rotation of the camera so the controllers
+/// never see it and the camera goes back to where it was once the trauma decayed.
+#[derive(Resource, Debug, Clone)]
+pub struct CameraShake {
+    /// From 0 to 1, the shake grows with its square so small impulses stay subtle
+    pub trauma: f32,
+    /// The trauma removed every second
+    pub decay: f32,
+    /// The yaw, pitch and roll in radians at full trauma
+    pub max_angle: f32,
+    /// The offset in world units at full trauma, 0 only rotates the camera
+    pub max_offset: f32,
+    /// How many times per second the noise changes direction
+    pub frequency: f32,
+    /// Selects the noise so multiple apps don't shake the same way
+    pub seed: u32,
+    /// Moves along the noise, it only advances while shaking
+    time: f32,
+}
+
+impl Default for CameraShake {
+    fn default() -> Self {
+        Self {
+            trauma: 0.0,
+            decay: 1.0,
+            max_angle: 5f32.to_radians(),
+            max_offset: 0.1,
+            frequency: 15.0,
+            seed: 0,
+            time: 0.0,
+        }
+    }
 }
 
+/// Adds to the trauma of the [`CameraShake`], for example when something hits the player
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CameraShakeImpulse(pub f32);
+
 pub struct CameraPlugin;
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PreStartup, setup_camera)
-            .add_systems(Update, fly_camera);
+        app.init_resource::<FlyCameraRotating>()
+            .init_resource::<CameraShake>()
+            .add_event::<CameraShakeImpulse>()
+            .add_systems(PreStartup, setup_camera)
+            .add_systems(
+                Update,
+                (
+                    fly_camera,
+                    shake_camera.after(fly_camera).before(update_camera_buffer),
+                ),
+            )
+            // egui updates the cursor visibility when rendering so this needs to run after it
+            .add_systems(PostUpdate, grab_cursor);
     }
 }
 
@@ -31,6 +135,8 @@ pub struct Projection {
     pub fov_y: f32,
     pub z_near: f32,
     pub z_far: f32,
+    /// Swaps the near and far planes in the depth range, set from [`ReverseZ`]
+    pub reverse_z: bool,
 }
 
 impl Projection {
@@ -39,16 +145,75 @@ impl Projection {
     }
 
     pub fn compute_matrix(&self) -> Mat4 {
-        Mat4::perspective_rh(self.fov_y, self.aspect, self.z_near, self.z_far)
+        if self.reverse_z {
+            Mat4::perspective_rh(self.fov_y, self.aspect, self.z_far, self.z_near)
+        } else {
+            Mat4::perspective_rh(self.fov_y, self.aspect, self.z_near, self.z_far)
+        }
+    }
+}
+
+/// A bit mask of the layers an entity is on, entities without it are only on layer 0.
+/// They're only rendered when they share a layer with the [`Camera::render_layers`].
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderLayers(pub u32);
+
+impl Default for RenderLayers {
+    fn default() -> Self {
+        Self::layer(0)
+    }
+}
+
+impl RenderLayers {
+    #[allow(unused)]
+    pub const ALL: Self = Self(u32::MAX);
+
+    /// Only on the given layer, it must be smaller than 32
+    pub const fn layer(layer: u32) -> Self {
+        Self(1 << layer)
     }
+
+    /// Also adds the given layer, it must be smaller than 32
+    #[allow(unused)]
+    pub const fn with(self, layer: u32) -> Self {
+        Self(self.0 | 1 << layer)
+    }
+
+    pub fn intersects(&self, other: &RenderLayers) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+/// Hides an entity without despawning it, entities without it are visible
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelVisibility {
+    pub visible: bool,
 }
 
+impl Default for ModelVisibility {
+    fn default() -> Self {
+        Self { visible: true }
+    }
+}
+
+/// The components checked by [`Camera::is_visible`], add it to the queries of the render passes
+pub type VisibilityQuery = (
+    Option<&'static RenderLayers>,
+    Option<&'static ModelVisibility>,
+);
+
 #[derive(Resource)]
 pub struct Camera {
     pub eye: Vec3,
     pub target: Vec3,
     pub rotation: Quat,
     pub projection: Projection,
+    /// The layers of the entities rendered by this camera
+    pub render_layers: RenderLayers,
+    /// Applied on top of the eye and rotation in the view matrix, set by [`CameraShake`].
+    /// The offset is in the local space of the camera.
+    pub shake_offset: Vec3,
+    pub shake_rotation: Quat,
 }
 
 impl Camera {
@@ -61,16 +226,56 @@ impl Camera {
                 fov_y: 45.0,
                 z_near: 0.1,
                 z_far: 1000.0,
+                reverse_z: false,
             },
             rotation: Quat::from_mat4(&Mat4::look_at_rh(CAMERRA_EYE, Vec3::ZERO, Vec3::Y))
                 .inverse(),
+            render_layers: RenderLayers::default(),
+            shake_offset: Vec3::ZERO,
+            shake_rotation: Quat::IDENTITY,
         }
     }
 
+    /// Moves the camera to `eye` and rotates it to look at `target`
+    pub fn look_at(&mut self, eye: Vec3, target: Vec3, up: Vec3) {
+        self.eye = eye;
+        self.target = target;
+        self.rotation = Quat::from_mat4(&Mat4::look_at_rh(eye, target, up)).inverse();
+    }
+
+    /// Whether an entity is rendered, it needs to be visible and share a layer with the camera.
+    /// Entities without the components are visible on the default layer.
+    pub fn is_visible(
+        &self,
+        (layers, visibility): (Option<&RenderLayers>, Option<&ModelVisibility>),
+    ) -> bool {
+        visibility.is_none_or(|visibility| visibility.visible)
+            && self
+                .render_layers
+                .intersects(layers.unwrap_or(&RenderLayers::default()))
+    }
+
+    /// Transforms from world space to view space
+    pub fn build_view_matrix(&self) -> Mat4 {
+        Mat4::from_rotation_translation(
+            self.rotation * self.shake_rotation,
+            self.eye + self.rotation * self.shake_offset,
+        )
+        .inverse()
+    }
+
     pub fn build_view_projection_matrix(&self) -> Mat4 {
-        let view = Mat4::from_rotation_translation(self.rotation, self.eye);
         let proj = self.projection.compute_matrix();
-        proj * view.inverse()
+        proj * self.build_view_matrix()
+    }
+
+    /// The world space ray going through a pixel, it starts on the near plane.
+    /// The cursor is in physical pixels with the origin at the top left, like winit's cursor
+    /// position, and the viewport size is the size of what the camera renders to.
+    /// When the scene is in an [`EguiViewport`] the cursor needs to be relative to the viewport.
+    #[allow(unused)]
+    pub fn screen_to_world_ray(&self, cursor: Vec2, viewport_size: Vec2) -> Ray {
+        Ray::unproject(cursor, viewport_size, self.build_view_projection_matrix())
     }
 
     #[inline]
@@ -82,7 +287,6 @@ impl Camera {
     pub fn right(&self) -> Vec3 {
         self.local_x()
     }
-    #[allow(unused)]
     #[inline]
     pub fn up(&self) -> Vec3 {
         self.local_y()
@@ -93,7 +297,6 @@ impl Camera {
         self.rotation * Vec3::X
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn local_y(&self) -> Vec3 {
         self.rotation * Vec3::Y
@@ -105,9 +308,16 @@ impl Camera {
     }
 }
 
-fn setup_camera(mut commands: Commands, windows: Query<&Window>) {
+fn setup_camera(
+    mut commands: Commands,
+    windows: Query<&Window>,
+    settings: Res<CameraSettings>,
+    reverse_z: Option<Res<ReverseZ>>,
+) {
     let window = windows.single();
-    let camera = Camera::new(window.width(), window.height());
+    let mut camera = Camera::new(window.width(), window.height());
+    camera.projection.reverse_z = reverse_z.is_some_and(|reverse_z| reverse_z.0);
+    camera.look_at(settings.initial_eye, settings.initial_target, settings.up);
 
     let mut camera_uniform = CameraUniform::new();
     camera_uniform.update_view_proj(&camera);
@@ -116,7 +326,7 @@ fn setup_camera(mut commands: Commands, windows: Query<&Window>) {
     commands.insert_resource(camera_uniform);
 }
 
-fn fly_camera(
+pub fn fly_camera(
     time: Res<Time>,
     windows: Query<&Window>,
     mouse_input: Res<Input<MouseButton>>,
@@ -125,34 +335,164 @@ fn fly_camera(
     mut mouse_motion: EventReader<MouseMotion>,
     mut velocity: Local<Vec3>,
     settings: Res<CameraSettings>,
+    mut rotating: ResMut<FlyCameraRotating>,
+    viewport: Option<Res<EguiViewport>>,
+    mut smoothing: Local<FlyCameraSmoothing>,
 ) {
-    if !mouse_input.pressed(MouseButton::Right) {
-        return;
+    let is_rotating = if mouse_input.just_pressed(MouseButton::Right) {
+        // When the scene is drawn in an egui viewport only drags started inside of it move the camera
+        viewport.is_none_or(|viewport| viewport.hovered)
+    } else {
+        rotating.0 && mouse_input.pressed(MouseButton::Right)
+    };
+    if rotating.0 != is_rotating {
+        rotating.0 = is_rotating;
     }
 
     let dt = time.delta_seconds();
+    let current = (camera.eye, camera.rotation);
+    // Something else moved the camera so it becomes the new target
+    if current != smoothing.current {
+        smoothing.target = current;
+    }
 
-    // Rotate
+    // The input moves the target, change detection is bypassed so the camera
+    // is only marked as changed if it actually moves
+    let camera_mut = camera.bypass_change_detection();
+    (camera_mut.eye, camera_mut.rotation) = smoothing.target;
+    if is_rotating {
+        let mouse_delta = mouse_motion.iter().map(|motion| motion.delta).sum();
+        let window = windows
+            .get_single()
+            .map(|window| Vec2::new(window.width(), window.height()))
+            .unwrap_or(Vec2::ZERO);
+        fly(
+            camera_mut,
+            &settings,
+            &key_input,
+            mouse_delta,
+            window,
+            &mut velocity,
+            dt,
+        );
+    } else {
+        mouse_motion.clear();
+    }
+    smoothing.target = (camera_mut.eye, camera_mut.rotation);
+
+    let (target_eye, target_rotation) = smoothing.target;
+    // Snaps once it's close enough so the camera doesn't keep changing by tiny amounts
+    let arrived = current.0.distance_squared(target_eye) < 1e-8
+        && current.1.angle_between(target_rotation) < 1e-4;
+    if settings.smoothing > 0.0 && !arrived {
+        let t = 1.0 - (-dt / settings.smoothing).exp();
+        camera_mut.eye = current.0.lerp(target_eye, t);
+        camera_mut.rotation = current.1.slerp(target_rotation, t);
+    }
+    smoothing.current = (camera_mut.eye, camera_mut.rotation);
+    if smoothing.current != current {
+        camera.set_changed();
+    }
+}
+
+/// Smooth 1D gradient noise from -1 to 1, each seed gives a different curve
+fn gradient_noise(seed: u32, t: f32) -> f32 {
+    let gradient = |i: i32| {
+        let mut hash = (i as u32).wrapping_mul(0x9e37_79b1) ^ seed.wrapping_mul(0x85eb_ca77);
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x2c1b_3c6d);
+        hash ^= hash >> 12;
+        hash as f32 / u32::MAX as f32 * 2.0 - 1.0
+    };
+    let i = t.floor();
+    let f = t - i;
+    let v0 = gradient(i as i32) * f;
+    let v1 = gradient(i as i32 + 1) * (f - 1.0);
+    let s = f * f * (3.0 - 2.0 * f);
+    // 1D gradient noise peaks at 0.5
+    (v0 + (v1 - v0) * s) * 2.0
+}
+
+/// Applies the [`CameraShakeImpulse`] and decays the trauma
+pub fn shake_camera(
+    time: Res<Time>,
+    mut shake: ResMut<CameraShake>,
+    mut impulses: EventReader<CameraShakeImpulse>,
+    mut camera: ResMut<Camera>,
+) {
+    for impulse in impulses.iter() {
+        shake.trauma += impulse.0;
+    }
+    shake.trauma = shake.trauma.clamp(0.0, 1.0);
 
-    let mut mouse_delta = Vec2::ZERO;
-    for mouse_motion in mouse_motion.iter() {
-        mouse_delta += mouse_motion.delta;
+    let (offset, rotation) = if shake.trauma > 0.0 {
+        shake.time += time.delta_seconds() * shake.frequency;
+        let amount = shake.trauma * shake.trauma;
+        let noise = |channel: u32| gradient_noise(shake.seed.wrapping_add(channel), shake.time);
+        let angles = Vec3::new(noise(0), noise(1), noise(2)) * shake.max_angle * amount;
+        let offset = Vec3::new(noise(3), noise(4), noise(5)) * shake.max_offset * amount;
+        (
+            offset,
+            Quat::from_euler(EulerRot::YXZ, angles.x, angles.y, angles.z),
+        )
+    } else {
+        (Vec3::ZERO, Quat::IDENTITY)
+    };
+    shake.trauma = (shake.trauma - shake.decay * time.delta_seconds()).max(0.0);
+
+    // Only mark the camera as changed while it's shaking
+    if camera.shake_offset != offset || camera.shake_rotation != rotation {
+        camera.shake_offset = offset;
+        camera.shake_rotation = rotation;
     }
+}
+
+/// Moves the camera based on the mouse and keyboard input
+fn fly(
+    camera: &mut Camera,
+    settings: &CameraSettings,
+    key_input: &Input<KeyCode>,
+    mouse_delta: Vec2,
+    window: Vec2,
+    velocity: &mut Vec3,
+    dt: f32,
+) {
+    // Rotate
+
+    let up = if settings.roll {
+        camera.up()
+    } else {
+        settings.up.normalize()
+    };
 
     if mouse_delta != Vec2::ZERO {
-        let window = if let Ok(window) = windows.get_single() {
-            Vec2::new(window.width(), window.height())
-        } else {
-            Vec2::ZERO
-        };
         let delta_x = mouse_delta.x / window.x * std::f32::consts::TAU;
-        let delta_y = mouse_delta.y / window.y * std::f32::consts::PI;
-        let yaw = Quat::from_rotation_y(-delta_x);
+        let mut delta_y = mouse_delta.y / window.y * std::f32::consts::PI;
+        if let Some(pitch_limit) = settings.pitch_limit.filter(|_| !settings.roll) {
+            // Angle between the view direction and the horizon
+            let current_pitch = camera.forward().dot(up).clamp(-1.0, 1.0).asin();
+            delta_y = current_pitch - (current_pitch - delta_y).clamp(-pitch_limit, pitch_limit);
+        }
+        let yaw = Quat::from_axis_angle(up, -delta_x);
         let pitch = Quat::from_rotation_x(-delta_y);
-        camera.rotation = yaw * camera.rotation; // rotate around global y axis
+        camera.rotation = yaw * camera.rotation; // rotate around the up axis
         camera.rotation *= pitch; // rotate around local x axis
     }
 
+    if settings.roll {
+        let mut roll_input = 0.0;
+        if key_input.pressed(KeyCode::Q) {
+            roll_input += 1.0;
+        }
+        if key_input.pressed(KeyCode::E) {
+            roll_input -= 1.0;
+        }
+        if roll_input != 0.0 {
+            camera.rotation *= Quat::from_rotation_z(roll_input * ROLL_SPEED * dt);
+            // rotate around local z axis
+        }
+    }
+
     // Translate
 
     let mut axis_input = Vec3::ZERO;
@@ -186,5 +526,91 @@ fn fly_camera(
 
     let forward = camera.forward();
     let right = camera.right();
-    camera.eye += velocity.x * dt * right + velocity.y * dt * Vec3::Y + velocity.z * dt * forward;
+    camera.eye += velocity.x * dt * right + velocity.y * dt * up + velocity.z * dt * forward;
+}
+
+/// Grabs the cursor while the camera is rotating so fast turns don't make it leave the window
+fn grab_cursor(
+    settings: Res<CameraSettings>,
+    rotating: Res<FlyCameraRotating>,
+    windows: Query<Entity, With<Window>>,
+    winit_windows: NonSend<WinitWindows>,
+) {
+    if !settings.grab_cursor {
+        return;
+    }
+
+    let window = if let Some(window) = windows
+        .get_single()
+        .ok()
+        .and_then(|window| winit_windows.get_window(window))
+    {
+        window
+    } else {
+        return;
+    };
+
+    if rotating.is_changed() && rotating.0 {
+        // Locked isn't supported on Windows and Confined isn't supported on macOS
+        // so try both before giving up
+        if let Err(err) = window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+        {
+            log::warn!("Failed to grab cursor: {err}");
+        }
+    }
+
+    if rotating.0 {
+        // egui can make the cursor visible again so hide it every frame
+        window.set_cursor_visible(false);
+    }
+
+    if rotating.is_changed() && !rotating.0 {
+        if let Err(err) = window.set_cursor_grab(CursorGrabMode::None) {
+            log::warn!("Failed to release cursor: {err}");
+        }
+        window.set_cursor_visible(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new(800.0, 600.0);
+        camera.look_at(Vec3::new(1.0, 2.0, 5.0), Vec3::ZERO, Vec3::Y);
+        camera
+    }
+
+    #[test]
+    fn center_ray() {
+        let size = Vec2::new(800.0, 600.0);
+        for reverse_z in [false, true] {
+            let mut camera = camera();
+            camera.projection.reverse_z = reverse_z;
+            let ray = camera.screen_to_world_ray(size / 2.0, size);
+            assert!(
+                ray.direction.abs_diff_eq(camera.forward(), 1e-4),
+                "{} {}",
+                ray.direction,
+                camera.forward()
+            );
+            // The ray starts on the near plane
+            let near = camera.eye + camera.forward() * camera.projection.z_near;
+            assert!(ray.origin.abs_diff_eq(near, 1e-4), "{}", ray.origin);
+        }
+    }
+
+    #[test]
+    fn corner_ray() {
+        let size = Vec2::new(800.0, 600.0);
+        let camera = camera();
+        // The cursor origin is at the top left so the y axis is flipped
+        let ray = camera.screen_to_world_ray(Vec2::ZERO, size);
+        assert!(ray.direction.dot(camera.forward()) > 0.0);
+        assert!(ray.direction.dot(camera.right()) < 0.0);
+        assert!(ray.direction.dot(camera.up()) > 0.0);
+    }
 }
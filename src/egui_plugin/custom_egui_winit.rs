@@ -9,6 +9,8 @@ pub struct EguiWinitState {
     start_time: std::time::Instant,
     egui_input: egui::RawInput,
     pointer_pos_in_points: Option<egui::Pos2>,
+    /// The touch currently used to emulate the mouse pointer
+    pointer_touch_id: Option<u64>,
     current_cursor_icon: egui::CursorIcon,
     /// What egui uses.
     pixels_per_point: f32,
@@ -23,6 +25,7 @@ impl Default for EguiWinitState {
                 ..Default::default()
             },
             pointer_pos_in_points: None,
+            pointer_touch_id: None,
             current_cursor_icon: egui::CursorIcon::Default,
             pixels_per_point: 1.0,
         }
@@ -55,6 +58,14 @@ impl EguiWinitState {
         self.egui_input.take()
     }
 
+    /// Sets the `pixels_per_point` used by the next frame and to convert the pointer positions.
+    /// Unlike a `ScaleFactorChanged` event it doesn't have to be the scale factor of the window,
+    /// see [`UiScale`](super::UiScale).
+    pub fn set_pixels_per_point(&mut self, pixels_per_point: f32) {
+        self.egui_input.pixels_per_point = Some(pixels_per_point);
+        self.pixels_per_point = pixels_per_point;
+    }
+
     /// Call this when there is a new event.
     ///
     /// The result can be found in [`Self::egui_input`] and be extracted with [`Self::take_egui_input`].
@@ -96,6 +107,15 @@ impl EguiWinitState {
                 self.egui_input.events.push(egui::Event::PointerGone);
                 false
             }
+            WindowEvent::Touch(touch) => {
+                self.on_touch(touch);
+                match touch.phase {
+                    winit::event::TouchPhase::Started
+                    | winit::event::TouchPhase::Ended
+                    | winit::event::TouchPhase::Cancelled => egui_ctx.wants_pointer_input(),
+                    winit::event::TouchPhase::Moved => egui_ctx.is_using_pointer(),
+                }
+            }
             WindowEvent::ReceivedCharacter(ch) => {
                 // On Mac we get here when the user presses Cmd-C (copy), ctrl-W, etc.
                 // We need to ignore these characters that are side-effects of commands.
@@ -228,6 +248,63 @@ impl EguiWinitState {
             .push(egui::Event::PointerMoved(pos_in_points));
     }
 
+    fn on_touch(&mut self, touch: &winit::event::Touch) {
+        // Emit touch event
+        self.egui_input.events.push(egui::Event::Touch {
+            device_id: egui::TouchDeviceId(egui::epaint::util::hash(touch.device_id)),
+            id: egui::TouchId::from(touch.id),
+            phase: match touch.phase {
+                winit::event::TouchPhase::Started => egui::TouchPhase::Start,
+                winit::event::TouchPhase::Moved => egui::TouchPhase::Move,
+                winit::event::TouchPhase::Ended => egui::TouchPhase::End,
+                winit::event::TouchPhase::Cancelled => egui::TouchPhase::Cancel,
+            },
+            pos: egui::pos2(
+                touch.location.x as f32 / self.pixels_per_point,
+                touch.location.y as f32 / self.pixels_per_point,
+            ),
+            force: match touch.force {
+                Some(winit::event::Force::Normalized(force)) => force as f32,
+                Some(winit::event::Force::Calibrated {
+                    force,
+                    max_possible_force,
+                    ..
+                }) => (force / max_possible_force) as f32,
+                None => 0_f32,
+            },
+        });
+
+        // If we're not yet translating a touch or we're translating this very
+        // touch, emit PointerButton resp. PointerMoved events to emulate mouse
+        if self.pointer_touch_id.is_none() || self.pointer_touch_id == Some(touch.id) {
+            match touch.phase {
+                winit::event::TouchPhase::Started => {
+                    self.pointer_touch_id = Some(touch.id);
+                    // First move the pointer to the right location
+                    self.on_cursor_moved(touch.location);
+                    self.on_mouse_button_input(
+                        winit::event::ElementState::Pressed,
+                        winit::event::MouseButton::Left,
+                    );
+                }
+                winit::event::TouchPhase::Moved => {
+                    self.on_cursor_moved(touch.location);
+                }
+                winit::event::TouchPhase::Ended | winit::event::TouchPhase::Cancelled => {
+                    self.pointer_touch_id = None;
+                    self.on_mouse_button_input(
+                        winit::event::ElementState::Released,
+                        winit::event::MouseButton::Left,
+                    );
+                    // The pointer should vanish completely to not get any
+                    // hover effects
+                    self.pointer_pos_in_points = None;
+                    self.egui_input.events.push(egui::Event::PointerGone);
+                }
+            }
+        }
+    }
+
     fn on_mouse_wheel(&mut self, delta: winit::event::MouseScrollDelta) {
         let delta = match delta {
             winit::event::MouseScrollDelta::LineDelta(x, y) => {
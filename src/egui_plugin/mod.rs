@@ -1,18 +1,24 @@
+use std::path::PathBuf;
+
 use bevy::{
     app::{prelude::*, AppExit},
     ecs::prelude::*,
     input::{
+        keyboard::KeyboardInput,
         mouse::{MouseButtonInput, MouseWheel},
         prelude::*,
+        touch::{ForceTouch, TouchPhase},
     },
-    window::{prelude::*, WindowCloseRequested},
+    window::{prelude::*, WindowCloseRequested, WindowFocused, WindowScaleFactorChanged},
     winit::WinitWindows,
 };
 
-use self::custom_egui_winit::EguiWinitState;
-use crate::renderer::{Msaa, WgpuEncoder, WgpuRenderer, WgpuView};
+use self::{custom_egui_winit::EguiWinitState, viewport::EguiViewport};
+use crate::renderer::{WgpuEncoder, WgpuRenderer, WgpuView};
 
 mod custom_egui_winit;
+pub mod texture_viewer;
+pub mod viewport;
 
 #[derive(Resource)]
 pub struct EguiCtxRes(pub egui::Context);
@@ -20,17 +26,66 @@ pub struct EguiCtxRes(pub egui::Context);
 #[derive(Resource)]
 pub struct EguiScreenDesciptorRes(pub egui_wgpu::renderer::ScreenDescriptor);
 
+/// Overrides the `pixels_per_point` of egui, which uses the scale factor of the window by default.
+/// The scale factor can be wrong on mixed DPI or remote desktop setups, it can also be used
+/// to scale the ui independently of the OS setting. It can be changed or removed at runtime.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct UiScale(pub f32);
+
+impl UiScale {
+    /// The `pixels_per_point` used by egui for this window
+    pub fn pixels_per_point(ui_scale: Option<&UiScale>, window: &Window) -> f32 {
+        ui_scale.map_or(window.scale_factor() as f32, |scale| scale.0.max(0.1))
+    }
+}
+
+/// Where the egui memory, like the position of the windows, is saved on exit and loaded on startup.
+/// Insert it before the plugin to change it, the memory isn't persisted when the path is None.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct EguiSettings {
+    pub persistence_path: Option<PathBuf>,
+}
+
+impl Default for EguiSettings {
+    fn default() -> Self {
+        Self {
+            persistence_path: Some(PathBuf::from("egui.ron")),
+        }
+    }
+}
+
+/// The modifier keys currently pressed, sent with every fake winit event given to egui
+#[derive(Resource, Default, PartialEq, Eq)]
+pub struct EguiModifiers(pub winit::event::ModifiersState);
+
 #[derive(Resource)]
 pub struct PaintJobs(Vec<egui::ClippedPrimitive>);
 
 pub struct EguiPlugin;
 impl Plugin for EguiPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, (setup, setup_render_pass))
+        app.init_resource::<EguiModifiers>()
+            .init_resource::<EguiSettings>()
+            .init_resource::<texture_viewer::TextureViewer>()
+            .add_systems(Startup, (setup, setup_render_pass))
             .add_systems(PreUpdate, begin_frame)
-            // .add_system(update_render_pass)
             // .add_system(render)
-            .add_systems(Update, (handle_mouse_events, on_exit));
+            .add_systems(
+                Update,
+                (
+                    (
+                        handle_window_events,
+                        update_modifiers,
+                        (
+                            handle_mouse_events,
+                            handle_keyboard_events,
+                            handle_touch_events,
+                        ),
+                    )
+                        .chain(),
+                    on_exit,
+                ),
+            );
     }
 }
 
@@ -38,35 +93,59 @@ fn on_exit(
     exit: EventReader<AppExit>,
     window_close: EventReader<WindowCloseRequested>,
     egui_ctx: Res<EguiCtxRes>,
+    settings: Res<EguiSettings>,
 ) {
-    if !exit.is_empty() || !window_close.is_empty() {
-        egui_ctx.0.memory(|mem| {
-            std::fs::write(
-                "egui.ron",
-                ron::ser::to_string_pretty(&mem, ron::ser::PrettyConfig::new())
-                    .expect("failed to serialize egui memory"),
-            )
-            .expect("Failed to write egui memory");
-        })
+    if exit.is_empty() && window_close.is_empty() {
+        return;
+    }
+    let path = if let Some(path) = &settings.persistence_path {
+        path
+    } else {
+        return;
+    };
+    let result = egui_ctx.0.memory(|mem| {
+        let mem = ron::ser::to_string_pretty(&mem, ron::ser::PrettyConfig::new())?;
+        std::fs::write(path, mem)?;
+        anyhow::Ok(())
+    });
+    if let Err(err) = result {
+        log::error!("Failed to save egui memory to {}: {err}", path.display());
     }
 }
 
-fn setup(mut commands: Commands, windows: Query<&Window>) {
+fn setup(
+    mut commands: Commands,
+    windows: Query<&Window>,
+    ui_scale: Option<Res<UiScale>>,
+    settings: Res<EguiSettings>,
+) {
     let window = windows.single();
+    let pixels_per_point = UiScale::pixels_per_point(ui_scale.as_deref(), window);
     let screen_descriptor = egui_wgpu::renderer::ScreenDescriptor {
         size_in_pixels: [window.width() as u32, window.height() as u32],
-        pixels_per_point: window.scale_factor() as f32,
+        pixels_per_point,
     };
     commands.insert_resource(EguiScreenDesciptorRes(screen_descriptor));
-    commands.init_resource::<EguiWinitState>();
+    let mut winit_state = EguiWinitState::default();
+    winit_state.set_pixels_per_point(pixels_per_point);
+    commands.insert_resource(winit_state);
 
     let ctx = egui::Context::default();
-    if let Ok(mem) = std::fs::read_to_string("egui.ron") {
-        let mem: egui::Memory = ron::de::from_str(&mem).expect("Failed to deserialize egui.ron");
-        ctx.memory_mut(|memory| {
-            memory.clone_from(&mem);
-        })
+    // A missing file is expected on the first run
+    let saved_memory = settings
+        .persistence_path
+        .as_ref()
+        .and_then(|path| Some((path, std::fs::read_to_string(path).ok()?)));
+    if let Some((path, mem)) = saved_memory {
+        match ron::de::from_str::<egui::Memory>(&mem) {
+            Ok(mem) => ctx.memory_mut(|memory| {
+                memory.clone_from(&mem);
+            }),
+            Err(err) => log::warn!("Failed to load egui memory from {}: {err}", path.display()),
+        }
     }
+    // The context needs to agree with the screen descriptor used to render it
+    ctx.set_pixels_per_point(pixels_per_point);
 
     log::info!("inserting egui ctx");
     commands.insert_resource(EguiCtxRes(ctx));
@@ -76,49 +155,17 @@ fn setup(mut commands: Commands, windows: Query<&Window>) {
 #[derive(Resource)]
 pub struct EguiRenderer(egui_wgpu::renderer::Renderer);
 
+/// egui is rendered after the msaa resolve so its renderer is always single sampled
+/// and doesn't need to be recreated when the [`Msaa`](crate::renderer::Msaa) changes.
+/// Recreating it would also lose the textures uploaded by egui, like the font atlas.
 fn setup_render_pass(world: &mut World) {
-    let msaa = world.resource::<Msaa>();
     let renderer = world.resource::<WgpuRenderer>();
-    let egui_renderer = egui_wgpu::renderer::Renderer::new(
-        &renderer.device,
-        wgpu::TextureFormat::Bgra8UnormSrgb,
-        None,
-        msaa.samples,
-    );
-    // let pass = egui_wgpu::renderer::RenderPass::new(
-    //     &renderer.device,
-    //     wgpu::TextureFormat::Bgra8UnormSrgb,
-    //     msaa.samples,
-    // );
+    let egui_renderer =
+        egui_wgpu::renderer::Renderer::new(&renderer.device, renderer.config.format, None, 1);
     world.insert_non_send_resource(EguiRenderer(egui_renderer));
 }
 
-pub fn update_render_pass(
-    // mut rpass: NonSendMut<EguiRenderPassRes>,
-    // renderer: Res<WgpuRenderer>,
-    // msaa: Res<Msaa>,
-    world: &mut World,
-) {
-    if world.is_resource_changed::<Msaa>() {
-        let msaa = world.resource::<Msaa>();
-        let renderer = world.resource::<WgpuRenderer>();
-        log::info!("updating egui render pass");
-        let egui_renderer = egui_wgpu::renderer::Renderer::new(
-            &renderer.device,
-            wgpu::TextureFormat::Bgra8UnormSrgb,
-            None,
-            msaa.samples,
-        );
-        // let pass = egui_wgpu::renderer::RenderPass::new(
-        //     &renderer.device,
-        //     wgpu::TextureFormat::Bgra8UnormSrgb,
-        //     msaa.samples,
-        // );
-        world.insert_non_send_resource(EguiRenderer(egui_renderer));
-    }
-}
-
-fn begin_frame(
+pub(crate) fn begin_frame(
     ctx: Res<EguiCtxRes>,
     mut winit_state: ResMut<EguiWinitState>,
     windows: Query<Entity, With<Window>>,
@@ -143,6 +190,7 @@ pub fn render(
     mut state: ResMut<EguiWinitState>,
     windows: Query<Entity, With<Window>>,
     winit_windows: NonSend<WinitWindows>,
+    viewport: Option<Res<EguiViewport>>,
 ) {
     let window = if let Ok(window) = windows.get_single() {
         winit_windows
@@ -186,10 +234,20 @@ pub fn render(
     );
 
     let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-        color_attachments: &[Some(view.get_color_attachment(wgpu::Operations {
-            load: wgpu::LoadOp::Load,
-            store: true,
-        }))],
+        // Drawn on top of the resolved image, the msaa target isn't used by egui
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: &view.view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                // Nothing else was rendered to the window when the 3d scene is in a viewport
+                load: if viewport.is_some_and(|viewport| viewport.target().is_some()) {
+                    wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+                } else {
+                    wgpu::LoadOp::Load
+                },
+                store: true,
+            },
+        })],
         depth_stencil_attachment: None,
         label: Some("egui main render pass"),
     });
@@ -202,11 +260,51 @@ pub fn render(
     rpass.pop_debug_group();
 }
 
+/// Forwards the focus and scale factor changes of the window to egui.
+/// Losing the focus also removes the pointer, otherwise egui keeps hovering what was under it
+/// until the cursor moves again. The scale factor is ignored when there's a [`UiScale`].
+fn handle_window_events(
+    mut focused_events: EventReader<WindowFocused>,
+    mut scale_factor_events: EventReader<WindowScaleFactorChanged>,
+    mut platform: ResMut<EguiWinitState>,
+    ctx: Res<EguiCtxRes>,
+    mut screen_descriptor: ResMut<EguiScreenDesciptorRes>,
+    ui_scale: Option<Res<UiScale>>,
+    windows: Query<&Window>,
+) {
+    for ev in focused_events.iter() {
+        platform.on_event(&ctx.0, &winit::event::WindowEvent::Focused(ev.focused));
+        if !ev.focused {
+            platform.on_event(
+                &ctx.0,
+                &winit::event::WindowEvent::CursorLeft {
+                    device_id: unsafe { winit::event::DeviceId::dummy() },
+                },
+            );
+        }
+    }
+
+    for ev in scale_factor_events.iter() {
+        let window = if let Ok(window) = windows.get(ev.window) {
+            window
+        } else {
+            continue;
+        };
+        let pixels_per_point = UiScale::pixels_per_point(ui_scale.as_deref(), window);
+        platform.set_pixels_per_point(pixels_per_point);
+        if screen_descriptor.0.pixels_per_point != pixels_per_point {
+            screen_descriptor.0.pixels_per_point = pixels_per_point;
+            ctx.0.set_pixels_per_point(pixels_per_point);
+        }
+    }
+}
+
 /// Wraps bevy mouse events and convert them back to fake winit events to send to the egui winit platform support
 fn handle_mouse_events(
     mut mouse_button_input_events: EventReader<MouseButtonInput>,
     mut cursor_moved_events: EventReader<CursorMoved>,
     mut mouse_wheel_events: EventReader<MouseWheel>,
+    modifiers: Res<EguiModifiers>,
     mut platform: ResMut<EguiWinitState>,
     ctx: ResMut<EguiCtxRes>,
     windows: Query<&Window>,
@@ -222,7 +320,7 @@ fn handle_mouse_events(
             &ctx.0,
             &winit::event::WindowEvent::CursorMoved {
                 device_id: unsafe { winit::event::DeviceId::dummy() },
-                modifiers: winit::event::ModifiersState::empty(),
+                modifiers: modifiers.0,
                 position: winit::dpi::PhysicalPosition {
                     x: ev.position.x as f64,
                     y: if ev.position.y as u32 > window_height {
@@ -240,7 +338,7 @@ fn handle_mouse_events(
             &ctx.0,
             &winit::event::WindowEvent::MouseInput {
                 device_id: unsafe { winit::event::DeviceId::dummy() },
-                modifiers: winit::event::ModifiersState::empty(),
+                modifiers: modifiers.0,
                 state: match ev.state {
                     bevy::input::ButtonState::Pressed => winit::event::ElementState::Pressed,
                     bevy::input::ButtonState::Released => winit::event::ElementState::Released,
@@ -260,7 +358,7 @@ fn handle_mouse_events(
             &ctx.0,
             &winit::event::WindowEvent::MouseWheel {
                 device_id: unsafe { winit::event::DeviceId::dummy() },
-                modifiers: winit::event::ModifiersState::empty(),
+                modifiers: modifiers.0,
                 phase: winit::event::TouchPhase::Moved,
                 delta: match ev.unit {
                     bevy::input::mouse::MouseScrollUnit::Line => {
@@ -277,3 +375,222 @@ fn handle_mouse_events(
         );
     }
 }
+
+/// Wraps bevy touch events and convert them back to fake winit events to send to the egui winit platform support.
+/// The egui winit platform emulates the mouse with the first finger.
+fn handle_touch_events(
+    mut touch_input_events: EventReader<TouchInput>,
+    mut platform: ResMut<EguiWinitState>,
+    ctx: ResMut<EguiCtxRes>,
+    windows: Query<&Window>,
+) {
+    let window_height = if let Ok(window) = windows.get_single() {
+        window.physical_height()
+    } else {
+        return;
+    };
+
+    for ev in touch_input_events.iter() {
+        platform.on_event(
+            &ctx.0,
+            &winit::event::WindowEvent::Touch(winit::event::Touch {
+                device_id: unsafe { winit::event::DeviceId::dummy() },
+                phase: match ev.phase {
+                    TouchPhase::Started => winit::event::TouchPhase::Started,
+                    TouchPhase::Moved => winit::event::TouchPhase::Moved,
+                    TouchPhase::Ended => winit::event::TouchPhase::Ended,
+                    TouchPhase::Canceled => winit::event::TouchPhase::Cancelled,
+                },
+                location: winit::dpi::PhysicalPosition {
+                    x: ev.position.x as f64,
+                    y: if ev.position.y as u32 > window_height {
+                        0.0
+                    } else {
+                        (ev.position.y as u32) as f64
+                    },
+                },
+                force: ev.force.map(|force| match force {
+                    ForceTouch::Calibrated {
+                        force,
+                        max_possible_force,
+                        altitude_angle,
+                    } => winit::event::Force::Calibrated {
+                        force,
+                        max_possible_force,
+                        altitude_angle,
+                    },
+                    ForceTouch::Normalized(force) => winit::event::Force::Normalized(force),
+                }),
+                id: ev.id,
+            }),
+        );
+    }
+}
+
+/// Updates the modifiers from the bevy keyboard state and notifies egui when they change.
+/// This needs to run before the other events are sent so clicks and shortcuts see the right modifiers.
+fn update_modifiers(
+    key_input: Res<Input<KeyCode>>,
+    mut modifiers: ResMut<EguiModifiers>,
+    mut platform: ResMut<EguiWinitState>,
+    ctx: ResMut<EguiCtxRes>,
+) {
+    let mut current_modifiers = winit::event::ModifiersState::empty();
+    current_modifiers.set(
+        winit::event::ModifiersState::SHIFT,
+        key_input.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]),
+    );
+    current_modifiers.set(
+        winit::event::ModifiersState::CTRL,
+        key_input.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]),
+    );
+    current_modifiers.set(
+        winit::event::ModifiersState::ALT,
+        key_input.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]),
+    );
+    current_modifiers.set(
+        winit::event::ModifiersState::LOGO,
+        key_input.any_pressed([KeyCode::SuperLeft, KeyCode::SuperRight]),
+    );
+    if modifiers.0 != current_modifiers {
+        modifiers.0 = current_modifiers;
+        platform.on_event(
+            &ctx.0,
+            &winit::event::WindowEvent::ModifiersChanged(current_modifiers),
+        );
+    }
+}
+
+/// Wraps bevy keyboard events and convert them back to fake winit events to send to the egui winit platform support
+fn handle_keyboard_events(
+    mut keyboard_input_events: EventReader<KeyboardInput>,
+    mut received_character_events: EventReader<ReceivedCharacter>,
+    modifiers: Res<EguiModifiers>,
+    mut platform: ResMut<EguiWinitState>,
+    ctx: ResMut<EguiCtxRes>,
+) {
+    for ev in keyboard_input_events.iter() {
+        #[allow(deprecated)]
+        platform.on_event(
+            &ctx.0,
+            &winit::event::WindowEvent::KeyboardInput {
+                device_id: unsafe { winit::event::DeviceId::dummy() },
+                input: winit::event::KeyboardInput {
+                    scancode: ev.scan_code,
+                    state: match ev.state {
+                        bevy::input::ButtonState::Pressed => winit::event::ElementState::Pressed,
+                        bevy::input::ButtonState::Released => winit::event::ElementState::Released,
+                    },
+                    virtual_keycode: ev.key_code.and_then(translate_key_code),
+                    modifiers: modifiers.0,
+                },
+                is_synthetic: false,
+            },
+        );
+    }
+
+    for ev in received_character_events.iter() {
+        platform.on_event(
+            &ctx.0,
+            &winit::event::WindowEvent::ReceivedCharacter(ev.char),
+        );
+    }
+}
+
+/// Converts the bevy key codes used by egui back to winit key codes
+fn translate_key_code(key_code: KeyCode) -> Option<winit::event::VirtualKeyCode> {
+    use winit::event::VirtualKeyCode;
+
+    Some(match key_code {
+        KeyCode::Down => VirtualKeyCode::Down,
+        KeyCode::Left => VirtualKeyCode::Left,
+        KeyCode::Right => VirtualKeyCode::Right,
+        KeyCode::Up => VirtualKeyCode::Up,
+
+        KeyCode::Escape => VirtualKeyCode::Escape,
+        KeyCode::Tab => VirtualKeyCode::Tab,
+        KeyCode::Back => VirtualKeyCode::Back,
+        KeyCode::Return => VirtualKeyCode::Return,
+        KeyCode::Space => VirtualKeyCode::Space,
+
+        KeyCode::Insert => VirtualKeyCode::Insert,
+        KeyCode::Delete => VirtualKeyCode::Delete,
+        KeyCode::Home => VirtualKeyCode::Home,
+        KeyCode::End => VirtualKeyCode::End,
+        KeyCode::PageUp => VirtualKeyCode::PageUp,
+        KeyCode::PageDown => VirtualKeyCode::PageDown,
+
+        KeyCode::Key0 => VirtualKeyCode::Key0,
+        KeyCode::Key1 => VirtualKeyCode::Key1,
+        KeyCode::Key2 => VirtualKeyCode::Key2,
+        KeyCode::Key3 => VirtualKeyCode::Key3,
+        KeyCode::Key4 => VirtualKeyCode::Key4,
+        KeyCode::Key5 => VirtualKeyCode::Key5,
+        KeyCode::Key6 => VirtualKeyCode::Key6,
+        KeyCode::Key7 => VirtualKeyCode::Key7,
+        KeyCode::Key8 => VirtualKeyCode::Key8,
+        KeyCode::Key9 => VirtualKeyCode::Key9,
+        KeyCode::Numpad0 => VirtualKeyCode::Numpad0,
+        KeyCode::Numpad1 => VirtualKeyCode::Numpad1,
+        KeyCode::Numpad2 => VirtualKeyCode::Numpad2,
+        KeyCode::Numpad3 => VirtualKeyCode::Numpad3,
+        KeyCode::Numpad4 => VirtualKeyCode::Numpad4,
+        KeyCode::Numpad5 => VirtualKeyCode::Numpad5,
+        KeyCode::Numpad6 => VirtualKeyCode::Numpad6,
+        KeyCode::Numpad7 => VirtualKeyCode::Numpad7,
+        KeyCode::Numpad8 => VirtualKeyCode::Numpad8,
+        KeyCode::Numpad9 => VirtualKeyCode::Numpad9,
+
+        KeyCode::A => VirtualKeyCode::A,
+        KeyCode::B => VirtualKeyCode::B,
+        KeyCode::C => VirtualKeyCode::C,
+        KeyCode::D => VirtualKeyCode::D,
+        KeyCode::E => VirtualKeyCode::E,
+        KeyCode::F => VirtualKeyCode::F,
+        KeyCode::G => VirtualKeyCode::G,
+        KeyCode::H => VirtualKeyCode::H,
+        KeyCode::I => VirtualKeyCode::I,
+        KeyCode::J => VirtualKeyCode::J,
+        KeyCode::K => VirtualKeyCode::K,
+        KeyCode::L => VirtualKeyCode::L,
+        KeyCode::M => VirtualKeyCode::M,
+        KeyCode::N => VirtualKeyCode::N,
+        KeyCode::O => VirtualKeyCode::O,
+        KeyCode::P => VirtualKeyCode::P,
+        KeyCode::Q => VirtualKeyCode::Q,
+        KeyCode::R => VirtualKeyCode::R,
+        KeyCode::S => VirtualKeyCode::S,
+        KeyCode::T => VirtualKeyCode::T,
+        KeyCode::U => VirtualKeyCode::U,
+        KeyCode::V => VirtualKeyCode::V,
+        KeyCode::W => VirtualKeyCode::W,
+        KeyCode::X => VirtualKeyCode::X,
+        KeyCode::Y => VirtualKeyCode::Y,
+        KeyCode::Z => VirtualKeyCode::Z,
+
+        KeyCode::F1 => VirtualKeyCode::F1,
+        KeyCode::F2 => VirtualKeyCode::F2,
+        KeyCode::F3 => VirtualKeyCode::F3,
+        KeyCode::F4 => VirtualKeyCode::F4,
+        KeyCode::F5 => VirtualKeyCode::F5,
+        KeyCode::F6 => VirtualKeyCode::F6,
+        KeyCode::F7 => VirtualKeyCode::F7,
+        KeyCode::F8 => VirtualKeyCode::F8,
+        KeyCode::F9 => VirtualKeyCode::F9,
+        KeyCode::F10 => VirtualKeyCode::F10,
+        KeyCode::F11 => VirtualKeyCode::F11,
+        KeyCode::F12 => VirtualKeyCode::F12,
+        KeyCode::F13 => VirtualKeyCode::F13,
+        KeyCode::F14 => VirtualKeyCode::F14,
+        KeyCode::F15 => VirtualKeyCode::F15,
+        KeyCode::F16 => VirtualKeyCode::F16,
+        KeyCode::F17 => VirtualKeyCode::F17,
+        KeyCode::F18 => VirtualKeyCode::F18,
+        KeyCode::F19 => VirtualKeyCode::F19,
+        KeyCode::F20 => VirtualKeyCode::F20,
+
+        _ => {
+            return None;
+        }
+    })
+}
@@ -0,0 +1,380 @@
+use bevy::ecs::prelude::*;
+
+use super::{viewport::EguiViewport, EguiRenderer};
+use crate::renderer::{
+    base_3d::GBuffer, shadow::ShadowPass, ssao::SsaoPass, DepthTexture, WgpuEncoder, WgpuRenderer,
+};
+
+/// A debug window listing the render targets, like the depth, the g-buffer and the shadow maps.
+/// While the window is open every target is copied to a texture registered with egui,
+/// the copies are recreated when the targets are resized.
+///
+/// Call [`TextureViewer::show`] every frame to draw it. The depth is shown as is,
+/// so it's mostly white without [`ReverseZ`](crate::renderer::ReverseZ).
+#[derive(Resource, Default)]
+pub struct TextureViewer {
+    pub open: bool,
+    /// The name of the texture shown in its own window
+    selected: Option<String>,
+    entries: Vec<ViewerEntry>,
+    /// Created the first time the viewer is opened
+    pipelines: Option<ViewerPipelines>,
+}
+
+struct ViewerEntry {
+    name: String,
+    size: [u32; 2],
+    texture_id: egui::TextureId,
+    view: wgpu::TextureView,
+}
+
+impl TextureViewer {
+    const THUMBNAIL_SIZE: f32 = 128.0;
+
+    /// Draws the thumbnails of the targets, clicking one shows it in a bigger window
+    #[allow(unused)]
+    pub fn show(&mut self, ctx: &egui::Context) {
+        let mut open = self.open;
+        egui::Window::new("Textures")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                if self.entries.is_empty() {
+                    ui.label("No render targets");
+                }
+                ui.horizontal_wrapped(|ui| {
+                    for entry in &self.entries {
+                        ui.vertical(|ui| {
+                            let selected = self.selected.as_ref() == Some(&entry.name);
+                            let size =
+                                fit_size(entry.size, egui::Vec2::splat(Self::THUMBNAIL_SIZE));
+                            let button =
+                                egui::ImageButton::new(entry.texture_id, size).selected(selected);
+                            if ui.add(button).clicked() {
+                                self.selected = if selected {
+                                    None
+                                } else {
+                                    Some(entry.name.clone())
+                                };
+                            }
+                            ui.label(format!(
+                                "{} {}x{}",
+                                entry.name, entry.size[0], entry.size[1]
+                            ));
+                        });
+                    }
+                });
+            });
+        self.open = open;
+
+        if !self.open {
+            return;
+        }
+        let selected = self
+            .selected
+            .as_ref()
+            .and_then(|name| self.entries.iter().find(|entry| &entry.name == name));
+        if let Some(entry) = selected {
+            let mut selected_open = true;
+            egui::Window::new(entry.name.as_str())
+                .id(egui::Id::new("texture_viewer_selected"))
+                .open(&mut selected_open)
+                .default_size([512.0, 512.0])
+                .show(ctx, |ui| {
+                    ui.image(entry.texture_id, fit_size(entry.size, ui.available_size()));
+                });
+            if !selected_open {
+                self.selected = None;
+            }
+        }
+    }
+}
+
+/// The biggest size with the aspect ratio of the texture that fits in `max`
+fn fit_size(size: [u32; 2], max: egui::Vec2) -> egui::Vec2 {
+    let size = egui::vec2(size[0] as f32, size[1] as f32);
+    size * (max.x / size.x).min(max.y / size.y)
+}
+
+/// How a target is converted to a color
+#[derive(Clone, Copy)]
+enum SourceKind {
+    Color,
+    /// Single channel textures, shown in grayscale
+    Red,
+    Depth,
+    /// Only the first sample is shown
+    DepthMultisampled,
+}
+
+struct Source<'a> {
+    name: String,
+    size: [u32; 2],
+    kind: SourceKind,
+    view: &'a wgpu::TextureView,
+}
+
+struct ViewerPipelines {
+    color_layout: wgpu::BindGroupLayout,
+    depth_layout: wgpu::BindGroupLayout,
+    depth_multisampled_layout: wgpu::BindGroupLayout,
+    color: wgpu::RenderPipeline,
+    red: wgpu::RenderPipeline,
+    depth: wgpu::RenderPipeline,
+    depth_multisampled: wgpu::RenderPipeline,
+}
+
+impl ViewerPipelines {
+    fn new(renderer: &WgpuRenderer) -> Self {
+        let device = &renderer.device;
+
+        // Every target is read with textureLoad, so the formats that can't be filtered work too
+        let texture_layout = |label, binding, sample_type, multisampled| {
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some(label),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type,
+                    },
+                    count: None,
+                }],
+            })
+        };
+        let color_layout = texture_layout(
+            "texture_viewer_color_bind_group_layout",
+            0,
+            wgpu::TextureSampleType::Float { filterable: false },
+            false,
+        );
+        let depth_layout = texture_layout(
+            "texture_viewer_depth_bind_group_layout",
+            1,
+            wgpu::TextureSampleType::Depth,
+            false,
+        );
+        let depth_multisampled_layout = texture_layout(
+            "texture_viewer_depth_multisampled_bind_group_layout",
+            2,
+            wgpu::TextureSampleType::Depth,
+            true,
+        );
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Texture Viewer Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                include_str!("../renderer/shaders/texture_viewer.wgsl").into(),
+            ),
+        });
+        let pipeline = |label: &str, entry_point: &str, layout: &wgpu::BindGroupLayout| {
+            renderer.errors.scope(device, label, || {
+                let pipeline_layout =
+                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some(&format!("{label} Layout")),
+                        bind_group_layouts: &[layout],
+                        push_constant_ranges: &[],
+                    });
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point,
+                        targets: &[Some(renderer.config.format.into())],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            })
+        };
+
+        Self {
+            color: pipeline("Texture Viewer Color Pipeline", "color", &color_layout),
+            red: pipeline("Texture Viewer Red Pipeline", "red", &color_layout),
+            depth: pipeline("Texture Viewer Depth Pipeline", "depth", &depth_layout),
+            depth_multisampled: pipeline(
+                "Texture Viewer Depth Multisampled Pipeline",
+                "depth_multisampled",
+                &depth_multisampled_layout,
+            ),
+            color_layout,
+            depth_layout,
+            depth_multisampled_layout,
+        }
+    }
+
+    fn get(&self, kind: SourceKind) -> (&wgpu::RenderPipeline, &wgpu::BindGroupLayout, u32) {
+        match kind {
+            SourceKind::Color => (&self.color, &self.color_layout, 0),
+            SourceKind::Red => (&self.red, &self.color_layout, 0),
+            SourceKind::Depth => (&self.depth, &self.depth_layout, 1),
+            SourceKind::DepthMultisampled => {
+                (&self.depth_multisampled, &self.depth_multisampled_layout, 2)
+            }
+        }
+    }
+}
+
+/// Copies the render targets to the textures shown by the viewer, only while it's open.
+/// This needs to run after the post processes and before egui is rendered.
+pub fn update_texture_viewer(
+    mut viewer: ResMut<TextureViewer>,
+    renderer: Res<WgpuRenderer>,
+    mut encoder: ResMut<WgpuEncoder>,
+    mut egui_renderer: NonSendMut<EguiRenderer>,
+    depth_texture: Res<DepthTexture>,
+    viewport: Option<Res<EguiViewport>>,
+    gbuffer: Res<GBuffer>,
+    (ssao, shadows): (Option<Res<SsaoPass>>, Option<Res<ShadowPass>>),
+) {
+    if !viewer.open {
+        return;
+    }
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+    let device = &renderer.device;
+    let viewer = &mut *viewer;
+    let pipelines = viewer
+        .pipelines
+        .get_or_insert_with(|| ViewerPipelines::new(&renderer));
+
+    let depth = viewport
+        .as_ref()
+        .and_then(|viewport| viewport.target())
+        .map_or(&depth_texture.0, |target| &target.depth_texture);
+    // Only the depth aspect can be sampled when the format has a stencil
+    let depth_view = depth.texture.create_view(&wgpu::TextureViewDescriptor {
+        aspect: wgpu::TextureAspect::DepthOnly,
+        ..Default::default()
+    });
+
+    let mut sources = vec![Source {
+        name: "depth".to_string(),
+        size: [depth.texture.width(), depth.texture.height()],
+        kind: if depth.texture.sample_count() > 1 {
+            SourceKind::DepthMultisampled
+        } else {
+            SourceKind::Depth
+        },
+        view: &depth_view,
+    }];
+    if let Some(target) = gbuffer.target() {
+        sources.push(Source {
+            name: "g-buffer normal".to_string(),
+            size: target.size,
+            kind: SourceKind::Color,
+            view: &target.normal.view,
+        });
+    }
+    if let Some((view, size)) = ssao.as_ref().and_then(|ssao| ssao.occlusion()) {
+        sources.push(Source {
+            name: "ssao".to_string(),
+            size,
+            kind: SourceKind::Red,
+            view,
+        });
+    }
+    if let Some(shadows) = &shadows {
+        let (faces, size) = shadows.faces();
+        for (i, face) in faces.iter().enumerate() {
+            sources.push(Source {
+                name: format!("point shadow {} face {}", i / 6, i % 6),
+                size: [size, size],
+                kind: SourceKind::Depth,
+                view: face,
+            });
+        }
+        let (cascades, size) = shadows.cascades();
+        for (i, cascade) in cascades.iter().enumerate() {
+            sources.push(Source {
+                name: format!("shadow cascade {i}"),
+                size: [size, size],
+                kind: SourceKind::Depth,
+                view: cascade,
+            });
+        }
+    }
+
+    // The copies keep the order of the sources, the ones that were resized or removed are freed
+    let mut previous_entries = std::mem::take(&mut viewer.entries);
+    for source in &sources {
+        let entry = match previous_entries
+            .iter()
+            .position(|entry| entry.name == source.name && entry.size == source.size)
+        {
+            Some(index) => previous_entries.swap_remove(index),
+            None => {
+                let texture = device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("texture_viewer_texture"),
+                    size: wgpu::Extent3d {
+                        width: source.size[0].max(1),
+                        height: source.size[1].max(1),
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    // Same as the egui viewport, egui already knows how to display it
+                    format: renderer.config.format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                });
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                ViewerEntry {
+                    name: source.name.clone(),
+                    size: source.size,
+                    texture_id: egui_renderer.0.register_native_texture(
+                        device,
+                        &view,
+                        wgpu::FilterMode::Linear,
+                    ),
+                    view,
+                }
+            }
+        };
+
+        let (pipeline, layout, binding) = pipelines.get(source.kind);
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("texture_viewer_bind_group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(source.view),
+            }],
+        });
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Texture Viewer Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &entry.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+        drop(render_pass);
+
+        viewer.entries.push(entry);
+    }
+    for entry in previous_entries {
+        egui_renderer.0.free_texture(&entry.texture_id);
+    }
+}
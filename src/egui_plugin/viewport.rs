@@ -0,0 +1,156 @@
+use bevy::ecs::prelude::*;
+
+use super::{EguiRenderer, EguiScreenDesciptorRes};
+use crate::{
+    camera::Camera,
+    renderer::{create_multisampled_framebuffer, Msaa, WgpuRenderer, WgpuView},
+    texture::Texture,
+};
+
+/// Renders the 3d scene to an offscreen texture that can be drawn inside an egui ui.
+/// When this resource exists the 3d passes render to it instead of the window.
+///
+/// Call [`EguiViewport::show`] inside a panel to draw the scene, the texture is resized to match
+/// the space it was given.
+#[derive(Resource, Default)]
+pub struct EguiViewport {
+    /// The texture containing the 3d scene, None until the viewport has been shown once
+    pub texture_id: Option<egui::TextureId>,
+    /// Where the viewport was drawn in egui points
+    pub rect: Option<egui::Rect>,
+    /// Whether the cursor is over the viewport, the camera only reacts to inputs started inside it
+    pub hovered: bool,
+    target: Option<ViewportTarget>,
+}
+
+pub struct ViewportTarget {
+    pub size: [u32; 2],
+    /// Copied by the post processes that need to sample the scene
+    pub texture: wgpu::Texture,
+    pub sample_count: u32,
+    pub view: WgpuView,
+    pub depth_texture: Texture,
+}
+
+impl EguiViewport {
+    /// Draws the 3d scene using all the available space of the ui
+    #[allow(unused)]
+    pub fn show(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        let size = ui.available_size();
+        let response = if let Some(texture_id) = self.texture_id {
+            ui.image(texture_id, size)
+        } else {
+            ui.allocate_response(size, egui::Sense::hover())
+        };
+        self.rect = Some(response.rect);
+        self.hovered = response.hovered();
+        response
+    }
+
+    pub fn target(&self) -> Option<&ViewportTarget> {
+        self.target.as_ref()
+    }
+}
+
+/// Recreates the offscreen texture when the size of the viewport or the msaa changes
+/// and keeps the camera aspect ratio in sync with the viewport.
+pub fn update_egui_viewport(
+    viewport: Option<ResMut<EguiViewport>>,
+    renderer: Res<WgpuRenderer>,
+    msaa: Res<Msaa>,
+    screen_descriptor: Res<EguiScreenDesciptorRes>,
+    mut egui_renderer: NonSendMut<EguiRenderer>,
+    mut camera: ResMut<Camera>,
+) {
+    let mut viewport = if let Some(viewport) = viewport {
+        viewport
+    } else {
+        return;
+    };
+    let rect = if let Some(rect) = viewport.rect {
+        rect
+    } else {
+        return;
+    };
+
+    let pixels_per_point = screen_descriptor.0.pixels_per_point;
+    let size = [
+        ((rect.width() * pixels_per_point).round() as u32).max(1),
+        ((rect.height() * pixels_per_point).round() as u32).max(1),
+    ];
+
+    let aspect = size[0] as f32 / size[1] as f32;
+    if camera.projection.aspect != aspect {
+        camera.projection.resize(size[0], size[1]);
+    }
+
+    if viewport
+        .target
+        .as_ref()
+        .map(|target| target.size == size && target.sample_count == msaa.sample_count())
+        .unwrap_or(false)
+    {
+        return;
+    }
+
+    log::info!("Resizing egui viewport to {}x{}", size[0], size[1]);
+
+    let config = wgpu::SurfaceConfiguration {
+        width: size[0],
+        height: size[1],
+        ..renderer.config.clone()
+    };
+
+    // Uses the surface format so the 3d pipelines can render to it
+    let texture = renderer.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("egui viewport texture"),
+        size: wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    // The texture is recreated when the msaa changes so it always needs to be registered again
+    if let Some(texture_id) = viewport.texture_id.take() {
+        egui_renderer.0.free_texture(&texture_id);
+    }
+    viewport.texture_id = Some(egui_renderer.0.register_native_texture(
+        &renderer.device,
+        &view,
+        wgpu::FilterMode::Linear,
+    ));
+
+    viewport.target = Some(ViewportTarget {
+        size,
+        texture,
+        sample_count: msaa.sample_count(),
+        view: WgpuView {
+            view,
+            sampled_view: if msaa.sample_count() > 1 {
+                Some(create_multisampled_framebuffer(
+                    &renderer.device,
+                    &config,
+                    msaa.sample_count(),
+                ))
+            } else {
+                None
+            },
+        },
+        depth_texture: Texture::create_depth_texture(
+            &renderer.device,
+            &config,
+            msaa.sample_count(),
+            renderer.depth_format,
+        ),
+    });
+}
@@ -6,21 +6,27 @@ use bevy::{
 };
 use image::RgbaImage;
 
-use crate::{image_utils::image_from_color, mesh::Vertex, model::Material};
+use crate::{
+    image_utils::image_from_color,
+    mesh::Vertex,
+    model::{BlendMode, ChannelMapping, LoaderSettings, Material},
+};
 
-use super::LoadedGltf;
+use super::{GltfSceneMeshes, LoadedGltf};
 
 pub async fn load_gltf<'a, 'b>(
     bytes: &'a [u8],
     load_context: &'a mut LoadContext<'b>,
+    settings: LoaderSettings,
 ) -> anyhow::Result<LoadedGltf> {
     let gltf = gltf::Gltf::from_slice(bytes)?;
 
     let start = Instant::now();
-    let textures = load_textures(&gltf, load_context);
+    let textures = load_textures(&gltf, load_context, settings.parallel_textures).await;
     log::info!(
-        "Loaded all textures in {}ms",
-        (Instant::now() - start).as_millis()
+        "Loaded all textures in {}ms ({})",
+        (Instant::now() - start).as_millis(),
+        LoaderSettings::mode(settings.parallel_textures)
     );
 
     let start = Instant::now();
@@ -32,19 +38,82 @@ pub async fn load_gltf<'a, 'b>(
 
     let buffer_data = load_buffers(&gltf, load_context).await?;
 
-    let mut meshes = vec![];
+    let mut primitives = vec![];
+    // The range of meshes generated from the primitives of each gltf mesh
+    let mut primitive_ranges = vec![];
     for mesh in gltf.meshes() {
-        for primitive in mesh.primitives() {
-            meshes.push(generate_mesh(primitive, &buffer_data)?);
-        }
+        let start = primitives.len();
+        primitives.extend(mesh.primitives());
+        primitive_ranges.push(start..primitives.len());
     }
 
-    Ok(LoadedGltf { materials, meshes })
+    let start = Instant::now();
+    let meshes = if settings.parallel_meshes {
+        IoTaskPool::get().scope(|scope| {
+            for primitive in &primitives {
+                let buffer_data = &buffer_data;
+                scope.spawn(async move { generate_mesh(primitive.clone(), buffer_data) });
+            }
+        })
+    } else {
+        primitives
+            .iter()
+            .map(|primitive| generate_mesh(primitive.clone(), &buffer_data))
+            .collect()
+    };
+    let meshes = meshes.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+    log::info!(
+        "Generated {} meshes in {}ms ({})",
+        meshes.len(),
+        (Instant::now() - start).as_millis(),
+        LoaderSettings::mode(settings.parallel_meshes)
+    );
+
+    let scenes = gltf
+        .scenes()
+        .map(|scene| {
+            let mut mesh_indices = vec![];
+            for node in scene.nodes() {
+                collect_node_meshes(&node, &primitive_ranges, &mut mesh_indices);
+            }
+            // Node transforms aren't applied so a mesh used by multiple nodes would be drawn at the same place
+            mesh_indices.sort_unstable();
+            mesh_indices.dedup();
+            GltfSceneMeshes {
+                name: scene.name().map(|name| name.to_string()),
+                mesh_indices,
+            }
+        })
+        .collect();
+
+    Ok(LoadedGltf {
+        materials,
+        meshes,
+        scenes,
+        default_scene: gltf.default_scene().map(|scene| scene.index()),
+    })
 }
 
-fn load_textures(gltf: &gltf::Gltf, load_context: &LoadContext) -> HashMap<usize, RgbaImage> {
-    IoTaskPool::get()
-        .scope(|scope| {
+fn collect_node_meshes(
+    node: &gltf::Node,
+    primitive_ranges: &[std::ops::Range<usize>],
+    mesh_indices: &mut Vec<usize>,
+) {
+    if let Some(mesh) = node.mesh() {
+        mesh_indices.extend(primitive_ranges[mesh.index()].clone());
+    }
+    for child in node.children() {
+        collect_node_meshes(&child, primitive_ranges, mesh_indices);
+    }
+}
+
+async fn load_textures(
+    gltf: &gltf::Gltf,
+    load_context: &LoadContext<'_>,
+    parallel: bool,
+) -> HashMap<usize, RgbaImage> {
+    let results = if parallel {
+        IoTaskPool::get().scope(|scope| {
             gltf.textures().for_each(|gltf_texture| {
                 let load_context: &LoadContext = load_context;
                 scope.spawn(async move {
@@ -53,6 +122,15 @@ fn load_textures(gltf: &gltf::Gltf, load_context: &LoadContext) -> HashMap<usize
                 });
             });
         })
+    } else {
+        let mut results = vec![];
+        for gltf_texture in gltf.textures() {
+            let texture_image = load_texture(&gltf_texture, load_context).await;
+            results.push((gltf_texture.index(), texture_image));
+        }
+        results
+    };
+    results
         .into_iter()
         .filter_map(|(index, res)| {
             if let Err(err) = res.as_ref() {
@@ -103,9 +181,30 @@ fn load_materials(gltf: &gltf::Gltf, textures: HashMap<usize, RgbaImage>) -> Vec
         let metallic_roughness_texture = pbr_metallic_roughness
             .metallic_roughness_texture()
             .map(|info| textures[&info.texture().index()].clone());
+        // The occlusion values are sampled from the R channel of the occlusion texture.
+        // Only the common case of an occlusion packed in the metallic-roughness texture is supported.
+        let packed_occlusion = match (
+            material.occlusion_texture(),
+            pbr_metallic_roughness.metallic_roughness_texture(),
+        ) {
+            (Some(occlusion), Some(metallic_roughness)) => {
+                occlusion.texture().index() == metallic_roughness.texture().index()
+            }
+            _ => false,
+        };
+        let channel_mapping = if packed_occlusion {
+            ChannelMapping::ORM
+        } else {
+            ChannelMapping::GLTF
+        };
         let normal_texture = material
             .normal_texture()
             .map(|texture| textures[&texture.texture().index()].clone());
+        // The scalar parameter applied to each normal vector of the normal texture.
+        let normal_scale = material
+            .normal_texture()
+            .map(|texture| texture.scale())
+            .unwrap_or(1.0);
 
         materials.push(Material {
             name: material
@@ -118,10 +217,29 @@ fn load_materials(gltf: &gltf::Gltf, textures: HashMap<usize, RgbaImage>) -> Vec
                 gltf::material::AlphaMode::Opaque => 1.0,
                 gltf::material::AlphaMode::Mask | gltf::material::AlphaMode::Blend => 0.5,
             },
+            blend_mode: match material.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => BlendMode::Opaque,
+                gltf::material::AlphaMode::Mask => BlendMode::Mask,
+                gltf::material::AlphaMode::Blend => BlendMode::Alpha,
+            },
+            // The spec requires straight alpha
+            premultiplied_alpha: false,
+            alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+            blend_constant: Vec3::ZERO,
             gloss: metallic,
             specular_texture: metallic_roughness_texture,
             specular: Vec3::new(1.0, 1.0, 1.0),
             normal_texture,
+            normal_scale,
+            channel_mapping,
+            double_sided: material.double_sided(),
+            // The factor multiplies the emissive texture which isn't supported,
+            // ignore it instead of making the whole surface glow
+            emissive: if material.emissive_texture().is_none() {
+                Vec3::from(material.emissive_factor())
+            } else {
+                Vec3::ZERO
+            },
         });
     }
     materials
@@ -131,10 +249,8 @@ fn generate_mesh(
     primitive: gltf::Primitive,
     buffer_data: &[Vec<u8>],
 ) -> anyhow::Result<crate::mesh::Mesh> {
-    let _primitive_topology = match primitive.mode() {
-        gltf::mesh::Mode::Triangles => wgpu::PrimitiveTopology::TriangleList,
-        _ => anyhow::bail!("Only triangle list are currently supported"),
-    };
+    let mode = primitive.mode();
+    let topology = topology(mode)?;
 
     let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
 
@@ -154,9 +270,13 @@ fn generate_mesh(
         .map(|uvs| uvs.into_f32().map(Vec2::from).collect::<Vec<_>>())
         .unwrap_or_default();
 
-    let indices: Option<Vec<_>> = reader
-        .read_indices()
-        .map(|indices| indices.into_u32().collect());
+    let indices = triangle_list_indices(
+        mode,
+        reader
+            .read_indices()
+            .map(|indices| indices.into_u32().collect()),
+        positions.len(),
+    );
 
     let vertices: Vec<_> = (0..positions.len())
         .map(|i| Vertex {
@@ -169,6 +289,9 @@ fn generate_mesh(
             uv: if uvs.is_empty() { Vec2::ZERO } else { uvs[i] },
             tangent: Vec3::ZERO,
             bitangent: Vec3::ZERO,
+            // TODO read the gltf skins
+            joints: Vertex::DEFAULT_JOINTS,
+            weights: Vertex::DEFAULT_WEIGHTS,
         })
         .collect();
 
@@ -176,8 +299,14 @@ fn generate_mesh(
         vertices,
         indices,
         material_id: primitive.material().index(),
+        topology,
     };
 
+    // Lines and points are unlit so they don't need normals or tangents
+    if topology != wgpu::PrimitiveTopology::TriangleList {
+        return Ok(mesh);
+    }
+
     if normals.is_empty() {
         mesh.compute_normals();
     }
@@ -190,6 +319,66 @@ fn generate_mesh(
     Ok(mesh)
 }
 
+/// The topology of the loaded mesh, strips and fans are converted to a list by [`triangle_list_indices`]
+fn topology(mode: gltf::mesh::Mode) -> anyhow::Result<wgpu::PrimitiveTopology> {
+    Ok(match mode {
+        gltf::mesh::Mode::Triangles
+        | gltf::mesh::Mode::TriangleStrip
+        | gltf::mesh::Mode::TriangleFan => wgpu::PrimitiveTopology::TriangleList,
+        gltf::mesh::Mode::Lines => wgpu::PrimitiveTopology::LineList,
+        gltf::mesh::Mode::Points => wgpu::PrimitiveTopology::PointList,
+        _ => anyhow::bail!(
+            "Only triangle list, triangle strip, triangle fan, line list and point list are currently supported"
+        ),
+    })
+}
+
+/// Converts the indices of strips and fans to a triangle list, the other modes are left unchanged.
+/// Non indexed strips and fans use the vertices in order.
+fn triangle_list_indices(
+    mode: gltf::mesh::Mode,
+    indices: Option<Vec<u32>>,
+    vertex_count: usize,
+) -> Option<Vec<u32>> {
+    let convert = match mode {
+        gltf::mesh::Mode::TriangleStrip => triangle_strip_to_list,
+        gltf::mesh::Mode::TriangleFan => triangle_fan_to_list,
+        _ => return indices,
+    };
+    let stream = indices.unwrap_or_else(|| (0..vertex_count as u32).collect());
+    Some(convert(&stream))
+}
+
+/// Converts the indices of a triangle strip to a triangle list with the same winding.
+/// Degenerate triangles, often used to join strips, are skipped.
+fn triangle_strip_to_list(strip: &[u32]) -> Vec<u32> {
+    let mut list = Vec::with_capacity(strip.len().saturating_sub(2) * 3);
+    for (i, triangle) in strip.windows(3).enumerate() {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]];
+        if a == b || b == c || a == c {
+            continue;
+        }
+        // Every other triangle is flipped to keep the same winding
+        if i % 2 == 0 {
+            list.extend([a, b, c]);
+        } else {
+            list.extend([a, c, b]);
+        }
+    }
+    list
+}
+
+/// Converts the indices of a triangle fan to a triangle list, every triangle uses the first vertex
+fn triangle_fan_to_list(fan: &[u32]) -> Vec<u32> {
+    let mut list = Vec::with_capacity(fan.len().saturating_sub(2) * 3);
+    if let Some(&first) = fan.first() {
+        for edge in fan[1..].windows(2) {
+            list.extend([edge[0], edge[1], first]);
+        }
+    }
+    list
+}
+
 /// Loads raw glTF buffers data
 async fn load_buffers<'a>(
     gltf: &gltf::Gltf,
@@ -238,3 +427,67 @@ async fn load_texture<'a>(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use gltf::mesh::Mode;
+
+    use super::*;
+
+    #[test]
+    fn strip_to_list() {
+        // A quad made of 2 triangles, the second one is flipped to keep the winding
+        assert_eq!(
+            triangle_list_indices(Mode::TriangleStrip, Some(vec![0, 1, 2, 3]), 4),
+            Some(vec![0, 1, 2, 1, 3, 2])
+        );
+        // Non indexed strips use the vertices in order
+        assert_eq!(
+            triangle_list_indices(Mode::TriangleStrip, None, 5),
+            Some(vec![0, 1, 2, 1, 3, 2, 2, 3, 4])
+        );
+        // The degenerate triangles joining 2 strips are skipped
+        assert_eq!(
+            triangle_strip_to_list(&[0, 1, 2, 3, 3, 4, 4, 5, 6, 7]),
+            vec![0, 1, 2, 1, 3, 2, 4, 5, 6, 5, 7, 6]
+        );
+        assert!(triangle_strip_to_list(&[0, 1]).is_empty());
+    }
+
+    #[test]
+    fn fan_to_list() {
+        assert_eq!(
+            triangle_list_indices(Mode::TriangleFan, Some(vec![0, 1, 2, 3]), 4),
+            Some(vec![1, 2, 0, 2, 3, 0])
+        );
+        assert_eq!(
+            triangle_list_indices(Mode::TriangleFan, None, 3),
+            Some(vec![1, 2, 0])
+        );
+    }
+
+    #[test]
+    fn other_modes_are_unchanged() {
+        assert_eq!(
+            triangle_list_indices(Mode::Triangles, Some(vec![0, 1, 2]), 3),
+            Some(vec![0, 1, 2])
+        );
+        assert_eq!(triangle_list_indices(Mode::Triangles, None, 3), None);
+        assert_eq!(triangle_list_indices(Mode::Lines, None, 2), None);
+    }
+
+    #[test]
+    fn strips_and_fans_are_loaded_as_lists() {
+        for mode in [Mode::Triangles, Mode::TriangleStrip, Mode::TriangleFan] {
+            assert_eq!(
+                topology(mode).unwrap(),
+                wgpu::PrimitiveTopology::TriangleList
+            );
+        }
+        assert_eq!(
+            topology(Mode::Lines).unwrap(),
+            wgpu::PrimitiveTopology::LineList
+        );
+        assert!(topology(Mode::LineLoop).is_err());
+    }
+}
@@ -1,13 +1,13 @@
 use crate::{
     gltf_loader::loader::load_gltf,
-    model::{Material, Model, ModelMesh},
+    model::{LoaderSettings, Material, Model, ModelMesh, PendingModel},
     renderer::WgpuRenderer,
 };
 use bevy::{
-    asset::{AssetLoader, LoadContext, LoadedAsset},
+    asset::{AssetLoader, HandleId, LoadContext, LoadedAsset},
     prelude::*,
     reflect::{TypePath, TypeUuid},
-    utils::Instant,
+    utils::{HashMap, HashSet, Instant},
 };
 
 mod loader;
@@ -15,7 +15,8 @@ mod loader;
 pub struct GltfLoaderPlugin;
 impl Plugin for GltfLoaderPlugin {
     fn build(&self, app: &mut App) {
-        app.add_asset::<LoadedGltf>()
+        app.init_resource::<LoaderSettings>()
+            .add_asset::<LoadedGltf>()
             .init_asset_loader::<GltfLoader>()
             .add_systems(Update, gltf_spawner);
         // TODO improve loaded detection
@@ -29,9 +30,82 @@ impl Plugin for GltfLoaderPlugin {
 pub struct LoadedGltf {
     materials: Vec<Material>,
     meshes: Vec<crate::mesh::Mesh>,
+    scenes: Vec<GltfSceneMeshes>,
+    /// The scene the gltf file says should be displayed when it's loaded
+    default_scene: Option<usize>,
 }
-#[derive(Default)]
-pub struct GltfLoader;
+
+/// The meshes used by the nodes of a scene, or any of their children
+#[derive(Debug)]
+struct GltfSceneMeshes {
+    name: Option<String>,
+    mesh_indices: Vec<usize>,
+}
+
+impl LoadedGltf {
+    /// The name of each scene in the file, in the order of their index
+    #[allow(unused)]
+    pub fn scene_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.scenes.iter().map(|scene| scene.name.as_deref())
+    }
+
+    /// The scene spawned when there's no [`GltfScene`], None if the file has no scene
+    #[allow(unused)]
+    pub fn default_scene(&self) -> Option<usize> {
+        self.default_scene
+            .or_else(|| (!self.scenes.is_empty()).then_some(0))
+    }
+
+    /// Finds the index of the selected scene.
+    /// None means every mesh of the file should be spawned, like when it has no scene.
+    fn scene_index(&self, scene: Option<&GltfScene>) -> Option<usize> {
+        match scene {
+            None => self.default_scene(),
+            Some(GltfScene::Index(index)) if *index < self.scenes.len() => Some(*index),
+            Some(GltfScene::Name(name)) => {
+                let index = self
+                    .scenes
+                    .iter()
+                    .position(|scene| scene.name.as_ref() == Some(name));
+                if index.is_none() {
+                    log::error!("There's no gltf scene named {name:?}, using the default scene");
+                }
+                index.or_else(|| self.default_scene())
+            }
+            Some(GltfScene::Index(index)) => {
+                log::error!("There's no gltf scene {index}, using the default scene");
+                self.default_scene()
+            }
+        }
+    }
+
+    fn scene_meshes(&self, scene_index: Option<usize>) -> Vec<crate::mesh::Mesh> {
+        match scene_index {
+            Some(index) => self.scenes[index]
+                .mesh_indices
+                .iter()
+                .map(|mesh_index| self.meshes[*mesh_index].clone())
+                .collect(),
+            None => self.meshes.clone(),
+        }
+    }
+}
+
+pub struct GltfLoader {
+    settings: LoaderSettings,
+}
+
+impl FromWorld for GltfLoader {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            settings: world
+                .get_resource::<LoaderSettings>()
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+}
+
 impl AssetLoader for GltfLoader {
     fn extensions(&self) -> &[&str] {
         &["gltf"]
@@ -47,7 +121,7 @@ impl AssetLoader for GltfLoader {
 
             log::info!("Loading {:?}", load_context.path());
 
-            let loaded_gltf = load_gltf(bytes, load_context).await?;
+            let loaded_gltf = load_gltf(bytes, load_context, self.settings).await?;
             load_context.set_default_asset(LoadedAsset::new(loaded_gltf));
 
             log::info!(
@@ -61,31 +135,114 @@ impl AssetLoader for GltfLoader {
     }
 }
 
+/// The [`Model`] is uploaded again when the asset is modified, for example by bevy's asset watcher.
 #[derive(Default, Bundle)]
 pub struct GltfBundle {
     pub gltf: Handle<LoadedGltf>,
 }
 
+/// Add this next to a [`GltfBundle`] to only spawn the meshes of one scene of the gltf file.
+/// Without it the default scene of the file is used.
+///
+/// The node transforms aren't applied, the meshes are spawned as a single [`Model`].
+#[allow(unused)]
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub enum GltfScene {
+    Index(usize),
+    Name(String),
+}
+
 fn gltf_spawner(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
-    query: Query<(Entity, &Handle<LoadedGltf>), Without<Model>>,
+    query: Query<
+        (Entity, &Handle<LoadedGltf>, Option<&GltfScene>),
+        (Without<Model>, Without<PendingModel>),
+    >,
+    loaded_query: Query<
+        (Entity, &Handle<LoadedGltf>, Option<&GltfScene>),
+        (With<Model>, Without<PendingModel>),
+    >,
+    mut pending_query: Query<(
+        Entity,
+        &Handle<LoadedGltf>,
+        Option<&GltfScene>,
+        &mut PendingModel,
+    )>,
     gltf_assets: Res<Assets<LoadedGltf>>,
+    mut gltf_events: EventReader<AssetEvent<LoadedGltf>>,
+    loader_settings: Res<LoaderSettings>,
+    // Entities spawned from the same scene of an asset share the same gpu buffers
+    mut mesh_cache: Local<HashMap<(HandleId, Option<usize>), Vec<ModelMesh>>>,
 ) {
-    for (entity, gltf_handle) in query.iter() {
+    let mut modified = HashSet::new();
+    for event in gltf_events.iter() {
+        match event {
+            AssetEvent::Removed { handle } => {
+                mesh_cache.retain(|(id, _), _| *id != handle.id());
+            }
+            AssetEvent::Modified { handle } => {
+                mesh_cache.retain(|(id, _), _| *id != handle.id());
+                modified.insert(handle.id());
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+
+    // The current model is kept until the new one is uploaded
+    for (entity, gltf_handle, scene) in loaded_query.iter() {
+        if !modified.contains(&gltf_handle.id()) {
+            continue;
+        }
         if let Some(gltf) = gltf_assets.get(gltf_handle) {
-            let LoadedGltf { materials, meshes } = gltf;
+            log::info!("Reloading gltf model");
+            commands.entity(entity).insert(PendingModel::spawn(
+                &renderer,
+                "gltf",
+                None,
+                gltf.scene_meshes(gltf.scene_index(scene)),
+                gltf.materials.clone(),
+                loader_settings.position_buffers,
+            ));
+        }
+    }
 
-            // TODO mesh label for gltf
-            let model_meshes = meshes
-                .iter()
-                .map(|mesh| ModelMesh::from_mesh("", &renderer.device, mesh))
-                .collect();
+    for (entity, gltf_handle, scene) in query.iter() {
+        if let Some(gltf) = gltf_assets.get(gltf_handle) {
+            let scene_index = gltf.scene_index(scene);
+
+            let cached_meshes = mesh_cache.get(&(gltf_handle.id(), scene_index)).cloned();
+            let meshes = if cached_meshes.is_some() {
+                vec![]
+            } else {
+                gltf.scene_meshes(scene_index)
+            };
+            commands.entity(entity).insert(PendingModel::spawn(
+                &renderer,
+                "gltf",
+                cached_meshes,
+                meshes,
+                gltf.materials.clone(),
+                loader_settings.position_buffers,
+            ));
+        }
+    }
+
+    for (entity, gltf_handle, scene, mut pending_model) in pending_query.iter_mut() {
+        if let Some((mut model, gpu_materials)) = pending_model.poll() {
+            let scene_index = gltf_assets
+                .get(gltf_handle)
+                .and_then(|gltf| gltf.scene_index(scene));
+            // Another entity using the same asset might have finished uploading first
+            model.meshes = mesh_cache
+                .entry((gltf_handle.id(), scene_index))
+                .or_insert_with(|| model.meshes.clone())
+                .clone();
 
-            commands.entity(entity).insert(Model {
-                materials: materials.clone(),
-                meshes: model_meshes,
-            });
+            commands
+                .entity(entity)
+                .remove::<PendingModel>()
+                .insert((model, gpu_materials));
 
             log::info!("Gltf Model spawned");
         }
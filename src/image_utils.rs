@@ -15,3 +15,16 @@ pub fn image_from_color(color: Color) -> RgbaImage {
     );
     DynamicImage::ImageRgba8(rgba).to_rgba8()
 }
+
+/// Decodes an encoded image, like the bytes of a png embedded with `include_bytes!`.
+/// The format is guessed from the content when it's None.
+pub fn image_from_bytes(
+    bytes: &[u8],
+    format: Option<image::ImageFormat>,
+) -> anyhow::Result<RgbaImage> {
+    let image = match format {
+        Some(format) => image::load_from_memory_with_format(bytes, format)?,
+        None => image::load_from_memory(bytes)?,
+    };
+    Ok(image.to_rgba8())
+}
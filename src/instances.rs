@@ -1,7 +1,13 @@
-use bevy::{ecs::prelude::*, transform::prelude::*};
+use bevy::{ecs::prelude::*, math::prelude::*, time::prelude::*, transform::prelude::*};
 use wgpu::util::DeviceExt;
 
-use crate::{model::Model, renderer::WgpuRenderer, transform::to_raw};
+use crate::{
+    camera::Camera,
+    math::Frustum,
+    model::Model,
+    renderer::WgpuRenderer,
+    transform::{to_compact_raw, to_raw, TransformRaw},
+};
 
 #[derive(Component)]
 pub struct InstanceBuffer(pub wgpu::Buffer);
@@ -12,12 +18,92 @@ pub struct InstanceBuffer(pub wgpu::Buffer);
 #[derive(Component)]
 pub struct Instances(pub Vec<Transform>);
 
+/// Marks the Transform or Instances of an entity as static.
+/// The instance buffer can't be written after its creation so any change is ignored,
+/// it also skips the entity in [`update_instance_buffer`].
+/// Insert a new [`InstanceBuffer`] to upload new transforms.
+/// It can't be used with [`InstanceAnimation`] since the compute shader writes the buffer.
+#[derive(Component)]
+pub struct StaticInstances;
+
+/// Uploads only the model matrix of each instance, the normal matrix is rebuilt in the vertex shader.
+/// This uses 64 bytes per instance instead of 100.
+///
+/// The normals are rotated by the normalized axes of the model matrix. This is the same rotation
+/// used by the regular format, so neither of them is exact for non uniformly scaled instances.
+/// The cost is a few more instructions per vertex.
+///
+/// Only opaque triangle meshes of the main pass support it. Compact entities are skipped by the
+/// depth prepass, ssao, the outline, the wireframe and the transparent, masked, stencil, line and point draws.
+/// It can't be used with [`InstanceAnimation`] since the compute shader writes the regular format.
+#[derive(Component)]
+pub struct CompactInstances;
+
+/// The content of the instance buffer using the format selected by [`CompactInstances`]
+fn instance_data<'a>(transforms: impl Iterator<Item = &'a Transform>, compact: bool) -> Vec<u8> {
+    if compact {
+        let data: Vec<_> = transforms.map(to_compact_raw).collect();
+        bytemuck::cast_slice(&data).to_vec()
+    } else {
+        let data: Vec<_> = transforms.map(to_raw).collect();
+        bytemuck::cast_slice(&data).to_vec()
+    }
+}
+
+impl Instances {
+    /// A grid of `rows` by `cols` instances on the XZ plane centered on the origin
+    #[allow(unused)]
+    pub fn grid(rows: u32, cols: u32, spacing: f32) -> Self {
+        let offset = Vec3::new(cols as f32 - 1.0, 0.0, rows as f32 - 1.0) * spacing / 2.0;
+        Self(
+            (0..rows)
+                .flat_map(|z| (0..cols).map(move |x| (x, z)))
+                .map(|(x, z)| {
+                    Transform::from_translation(
+                        Vec3::new(x as f32, 0.0, z as f32) * spacing - offset,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// `count` instances evenly spaced on a circle on the XZ plane centered on the origin
+    #[allow(unused)]
+    pub fn circle(count: u32, radius: f32) -> Self {
+        Self(
+            (0..count)
+                .map(|i| {
+                    let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+                    Transform::from_xyz(angle.cos() * radius, 0.0, angle.sin() * radius)
+                })
+                .collect(),
+        )
+    }
+
+    /// One instance at each point
+    #[allow(unused)]
+    pub fn from_points(points: &[Vec3]) -> Self {
+        Self(
+            points
+                .iter()
+                .map(|point| Transform::from_translation(*point))
+                .collect(),
+        )
+    }
+}
+
 /// Creates the necessary IntanceBuffer on any Model created with a Model and a Transform or Instances
 pub fn create_instance_buffer(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
     query: Query<
-        (Entity, Option<&Transform>, Option<&Instances>),
+        (
+            Entity,
+            Option<&Transform>,
+            Option<&Instances>,
+            Option<&StaticInstances>,
+            Option<&CompactInstances>,
+        ),
         (
             Or<(
                 (Added<Model>, With<Transform>),
@@ -29,11 +115,11 @@ pub fn create_instance_buffer(
         ),
     >,
 ) {
-    for (entity, transform, instances) in query.iter() {
+    for (entity, transform, instances, static_instances, compact) in query.iter() {
         let instance_data = if let Some(transform) = transform {
-            vec![to_raw(transform)]
+            instance_data(std::iter::once(transform), compact.is_some())
         } else if let Some(instances) = instances {
-            instances.0.iter().map(to_raw).collect()
+            instance_data(instances.0.iter(), compact.is_some())
         } else {
             log::warn!("Trying to create instance buffer without Transform or Instances");
             continue;
@@ -41,13 +127,19 @@ pub fn create_instance_buffer(
 
         log::info!("creating instance buffer");
 
+        let usage = if static_instances.is_some() {
+            wgpu::BufferUsages::VERTEX
+        } else {
+            // STORAGE is needed for the InstanceAnimation compute shader
+            wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE
+        };
         let instance_buffer =
             renderer
                 .device
                 .create_buffer_init(&wgpu::util::BufferInitDescriptor {
                     label: Some("Instance Buffer"),
-                    contents: bytemuck::cast_slice(&instance_data),
-                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                    contents: &instance_data,
+                    usage,
                 });
 
         commands
@@ -56,25 +148,778 @@ pub fn create_instance_buffer(
     }
 }
 
+/// Packs the instance data of several entities in a single buffer.
+/// Each entity has its transforms and whether it uses [`CompactInstances`],
+/// the range of its bytes is returned in the same order.
+pub fn pack_instances<'a>(
+    entities: impl IntoIterator<Item = (&'a [Transform], bool)>,
+) -> (Vec<u8>, Vec<std::ops::Range<usize>>) {
+    let mut data = vec![];
+    let mut ranges = vec![];
+    for (transforms, compact) in entities {
+        let start = data.len();
+        data.extend(instance_data(transforms.iter(), compact));
+        ranges.push(start..data.len());
+    }
+    (data, ranges)
+}
+
+/// Every changed instance is written to this buffer with a single upload
+/// and then copied to the [`InstanceBuffer`] of each entity.
+#[derive(Resource, Default)]
+pub struct InstanceStagingBuffer {
+    buffer: Option<wgpu::Buffer>,
+    /// The number of bytes the buffer can hold before it needs to be reallocated
+    capacity: usize,
+}
+
+impl InstanceStagingBuffer {
+    /// Writes the ranges of `data` packed by [`pack_instances`] to the start of their buffer.
+    /// The copies are submitted right away so they happen before anything submitted after.
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[u8],
+        copies: &[(&wgpu::Buffer, std::ops::Range<usize>)],
+    ) {
+        match copies {
+            [] => {}
+            // A single write doesn't need the extra copy
+            [(buffer, range)] => {
+                queue.write_buffer(buffer, 0, &data[range.clone()]);
+            }
+            _ => {
+                if data.len() > self.capacity || self.buffer.is_none() {
+                    let capacity = data.len().next_power_of_two();
+                    log::info!(
+                        "Reallocating instance staging buffer with a capacity of {capacity} bytes"
+                    );
+                    self.buffer = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some("Instance Staging Buffer"),
+                        size: capacity as u64,
+                        usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                        mapped_at_creation: false,
+                    }));
+                    self.capacity = capacity;
+                }
+                let staging_buffer = self.buffer.as_ref().unwrap();
+                queue.write_buffer(staging_buffer, 0, data);
+
+                let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Instance Upload Encoder"),
+                });
+                for (buffer, range) in copies {
+                    encoder.copy_buffer_to_buffer(
+                        staging_buffer,
+                        range.start as u64,
+                        buffer,
+                        0,
+                        range.len() as u64,
+                    );
+                }
+                queue.submit(std::iter::once(encoder.finish()));
+            }
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub fn update_instance_buffer(
     renderer: Res<WgpuRenderer>,
+    mut staging: ResMut<InstanceStagingBuffer>,
     query: Query<
-        (&InstanceBuffer, Option<&Transform>, Option<&Instances>),
-        Or<(Changed<Transform>, Changed<Instances>)>,
+        (
+            &InstanceBuffer,
+            Option<&Transform>,
+            Option<&Instances>,
+            Option<&CompactInstances>,
+        ),
+        (
+            Or<(Changed<Transform>, Changed<Instances>)>,
+            // The compute shader writes the buffer of animated instances
+            Without<InstanceAnimation>,
+            Without<StaticInstances>,
+        ),
     >,
 ) {
-    for (buffer, transform, instances) in query.iter() {
-        let data: Vec<_> = if let Some(t) = transform {
-            vec![to_raw(t)]
-        } else if let Some(instances) = instances {
-            instances.0.iter().map(to_raw).collect()
+    let entities: Vec<_> = query.iter().collect();
+    let (data, ranges) =
+        pack_instances(entities.iter().map(|(_, transform, instances, compact)| {
+            let transforms = if let Some(t) = transform {
+                std::slice::from_ref(*t)
+            } else if let Some(instances) = instances {
+                &instances.0[..]
+            } else {
+                unreachable!();
+            };
+            (transforms, compact.is_some())
+        }));
+    // The destination buffer and the range of its bytes in the staging buffer
+    let copies: Vec<_> = entities
+        .iter()
+        .map(|(buffer, ..)| &buffer.0)
+        .zip(ranges)
+        .collect();
+    staging.upload(&renderer.device, &renderer.queue, &data, &copies);
+}
+
+/// Animates the instances with a radial wave centered on the origin, added to their translation.
+/// The instance buffer is written by a compute shader every frame instead of uploading
+/// every transform from the cpu, this scales to a lot more instances than updating [`Instances`].
+#[derive(Component, Clone, Copy)]
+pub struct InstanceAnimation {
+    pub amplitude: f32,
+    pub wavelength: f32,
+    /// Distance traveled by the wave per second
+    pub speed: f32,
+}
+
+/// The compact data used by the compute shader to build the transform of each instance
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct InstanceParams {
+    position: [f32; 3],
+    phase: f32,
+    rotation: [f32; 4],
+    scale: [f32; 3],
+    _padding: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct AnimationUniform {
+    time: f32,
+    amplitude: f32,
+    speed: f32,
+    count: u32,
+}
+
+const WORKGROUP_SIZE: u32 = 64;
+
+#[derive(Resource)]
+pub struct InstanceAnimationPipeline {
+    pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+}
+
+#[derive(Component)]
+pub struct GpuInstanceAnimation {
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    count: u32,
+}
+
+pub fn setup_instance_animation_pipeline(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let layout = renderer
+        .device
+        .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("instance_animation_bind_group_layout"),
+            entries: &[
+                // animation
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // params
+                storage_entry(1, true),
+                // transforms
+                storage_entry(2, false),
+            ],
+        });
+
+    let shader = renderer
+        .errors
+        .scope(&renderer.device, "Instance Animation Shader", || {
+            renderer
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Instance Animation Shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        include_str!("renderer/shaders/instance_animation.wgsl").into(),
+                    ),
+                })
+        });
+
+    let pipeline_layout = renderer
+        .device
+        .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Instance Animation Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+    let pipeline = renderer
+        .errors
+        .scope(&renderer.device, "Instance Animation Pipeline", || {
+            renderer
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Instance Animation Pipeline"),
+                    layout: Some(&pipeline_layout),
+                    module: &shader,
+                    entry_point: "main",
+                })
+        });
+
+    commands.insert_resource(InstanceAnimationPipeline { pipeline, layout });
+}
+
+/// Uploads the params of each instance, they only change when the Instances or the animation change
+#[allow(clippy::type_complexity)]
+pub fn prepare_instance_animation(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    pipeline: Res<InstanceAnimationPipeline>,
+    query: Query<
+        (Entity, &InstanceAnimation, &Instances, &InstanceBuffer),
+        (
+            Or<(
+                Changed<InstanceAnimation>,
+                Changed<Instances>,
+                Added<InstanceBuffer>,
+            )>,
+            Without<CompactInstances>,
+        ),
+    >,
+) {
+    for (entity, animation, instances, instance_buffer) in query.iter() {
+        if instances.0.is_empty() {
+            continue;
+        }
+
+        // Wave number
+        let k = std::f32::consts::TAU / animation.wavelength;
+        let params: Vec<_> = instances
+            .0
+            .iter()
+            .map(|transform| InstanceParams {
+                position: transform.translation.into(),
+                phase: k * Vec2::new(transform.translation.x, transform.translation.z).length(),
+                rotation: transform.rotation.into(),
+                scale: transform.scale.into(),
+                _padding: 0.0,
+            })
+            .collect();
+
+        let params_buffer = renderer
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Instance Animation Params Buffer"),
+                contents: bytemuck::cast_slice(&params),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let uniform_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Animation Uniform Buffer"),
+            size: std::mem::size_of::<AnimationUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group =
+            renderer
+                .errors
+                .scope(&renderer.device, "instance animation bind group", || {
+                    renderer
+                        .device
+                        .create_bind_group(&wgpu::BindGroupDescriptor {
+                            label: Some("instance_animation_bind_group"),
+                            layout: &pipeline.layout,
+                            entries: &[
+                                wgpu::BindGroupEntry {
+                                    binding: 0,
+                                    resource: uniform_buffer.as_entire_binding(),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 1,
+                                    resource: params_buffer.as_entire_binding(),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 2,
+                                    resource: instance_buffer.0.as_entire_binding(),
+                                },
+                            ],
+                        })
+                });
+
+        commands.entity(entity).insert(GpuInstanceAnimation {
+            uniform_buffer,
+            bind_group,
+            count: params.len() as u32,
+        });
+    }
+}
+
+/// Runs the compute shader that writes the instance buffer of every animated entity
+pub fn animate_instances(
+    renderer: Res<WgpuRenderer>,
+    pipeline: Res<InstanceAnimationPipeline>,
+    time: Res<Time>,
+    query: Query<(&InstanceAnimation, &GpuInstanceAnimation)>,
+) {
+    if query.is_empty() {
+        return;
+    }
+
+    let mut encoder = renderer
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Instance Animation Encoder"),
+        });
+    {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Instance Animation Pass"),
+        });
+        compute_pass.set_pipeline(&pipeline.pipeline);
+        for (animation, gpu_animation) in query.iter() {
+            let uniform = AnimationUniform {
+                time: time.elapsed_seconds(),
+                amplitude: animation.amplitude,
+                speed: std::f32::consts::TAU / animation.wavelength * animation.speed,
+                count: gpu_animation.count,
+            };
+            renderer.queue.write_buffer(
+                &gpu_animation.uniform_buffer,
+                0,
+                bytemuck::cast_slice(&[uniform]),
+            );
+            compute_pass.set_bind_group(0, &gpu_animation.bind_group, &[]);
+            compute_pass.dispatch_workgroups(gpu_animation.count.div_ceil(WORKGROUP_SIZE), 1, 1);
+        }
+    }
+    renderer.queue.submit(std::iter::once(encoder.finish()));
+}
+
+/// Goes back to the cpu transforms when the animation is removed
+pub fn remove_instance_animation(
+    mut commands: Commands,
+    mut removed: RemovedComponents<InstanceAnimation>,
+    mut query: Query<&mut Instances>,
+) {
+    for entity in removed.iter() {
+        if let Some(mut entity_commands) = commands.get_entity(entity) {
+            entity_commands.remove::<GpuInstanceAnimation>();
+        }
+        if let Ok(mut instances) = query.get_mut(entity) {
+            instances.set_changed();
+        }
+    }
+}
+
+/// Culls the instances against the camera frustum in a compute shader and draws the visible ones
+/// with an indirect draw, the number of visible instances never goes back to the cpu.
+///
+/// It's only used when the device supports [`wgpu::Features::MULTI_DRAW_INDIRECT`], see
+/// [`WgpuRenderer::supports_indirect_draw`], every instance is drawn with the regular loop otherwise.
+/// Only the opaque and masked meshes of the main pass use it, the other passes still draw every instance.
+/// It can't be used with [`CompactInstances`] since the compute shader copies the regular format
+/// or with [`StaticInstances`] since their buffer can't be read by the compute shader.
+#[derive(Component)]
+pub struct IndirectInstances;
+
+/// The buffers written by the cull pass of an entity with [`IndirectInstances`]
+#[derive(Component)]
+pub struct GpuIndirectInstances {
+    /// The visible instances, packed at the start of the buffer
+    pub instance_buffer: wgpu::Buffer,
+    /// One [`wgpu::util::DrawIndexedIndirect`] per mesh of the model
+    pub args_buffer: wgpu::Buffer,
+    /// The args with an instance count of 0, uploaded before every cull
+    reset_args: Vec<u8>,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// The bounding sphere of the model in local space
+    center: Vec3,
+    radius: f32,
+    count: u32,
+    mesh_count: u32,
+}
+
+impl GpuIndirectInstances {
+    /// The offset of the draw args of a mesh in the args buffer
+    pub fn args_offset(mesh_index: usize) -> wgpu::BufferAddress {
+        (mesh_index * std::mem::size_of::<wgpu::util::DrawIndexedIndirect>()) as wgpu::BufferAddress
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullUniform {
+    /// The frustum planes with the normal in xyz and the distance in w
+    planes: [[f32; 4]; 6],
+    center: [f32; 3],
+    radius: f32,
+    count: u32,
+    mesh_count: u32,
+    _padding: [u32; 2],
+}
+
+#[derive(Resource)]
+pub struct InstanceCullPipeline {
+    pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+}
+
+pub fn setup_instance_cull_pipeline(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let layout = renderer
+        .device
+        .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("instance_cull_bind_group_layout"),
+            entries: &[
+                // cull
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // instances
+                storage_entry(1, true),
+                // visible instances
+                storage_entry(2, false),
+                // draw args
+                storage_entry(3, false),
+            ],
+        });
+
+    let shader = renderer
+        .errors
+        .scope(&renderer.device, "Instance Cull Shader", || {
+            renderer
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Instance Cull Shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        include_str!("renderer/shaders/instance_cull.wgsl").into(),
+                    ),
+                })
+        });
+
+    let pipeline_layout = renderer
+        .device
+        .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Instance Cull Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+    let pipeline = renderer
+        .errors
+        .scope(&renderer.device, "Instance Cull Pipeline", || {
+            renderer
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Instance Cull Pipeline"),
+                    layout: Some(&pipeline_layout),
+                    module: &shader,
+                    entry_point: "main",
+                })
+        });
+
+    commands.insert_resource(InstanceCullPipeline { pipeline, layout });
+}
+
+/// Creates the buffers of the cull pass, they need to be recreated when the number of instances changes
+#[allow(clippy::type_complexity)]
+pub fn prepare_indirect_instances(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    pipeline: Res<InstanceCullPipeline>,
+    query: Query<
+        (
+            Entity,
+            &Model,
+            &Instances,
+            // The bind group needs to use the new buffer when it's replaced
+            Ref<InstanceBuffer>,
+            Option<&GpuIndirectInstances>,
+        ),
+        (
+            With<IndirectInstances>,
+            Or<(
+                Added<IndirectInstances>,
+                Changed<Instances>,
+                Added<InstanceBuffer>,
+            )>,
+            Without<CompactInstances>,
+            Without<StaticInstances>,
+        ),
+    >,
+) {
+    if !renderer.supports_indirect_draw() {
+        return;
+    }
+
+    for (entity, model, instances, instance_buffer, current) in query.iter() {
+        // Moving the instances doesn't change the size of the buffers
+        if let Some(current) = current {
+            if current.count == instances.0.len() as u32
+                && !instance_buffer.is_added()
+                && current.mesh_count == model.meshes.len() as u32
+            {
+                continue;
+            }
+        }
+        let bounds = model
+            .meshes
+            .iter()
+            .filter_map(|mesh| mesh.stats.bounds)
+            .reduce(|a, b| a.union(&b));
+        let bounds = if let Some(bounds) = bounds {
+            bounds
         } else {
-            unreachable!();
+            continue;
         };
+        if instances.0.is_empty() {
+            continue;
+        }
+
+        let reset_args: Vec<u8> = model
+            .meshes
+            .iter()
+            .flat_map(|mesh| {
+                wgpu::util::DrawIndexedIndirect {
+                    vertex_count: mesh.num_elements,
+                    instance_count: 0,
+                    base_index: 0,
+                    vertex_offset: 0,
+                    base_instance: 0,
+                }
+                .as_bytes()
+                .to_vec()
+            })
+            .collect();
+        let args_buffer = renderer
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Indirect Args Buffer"),
+                contents: &reset_args,
+                usage: wgpu::BufferUsages::INDIRECT
+                    | wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_DST,
+            });
+        let visible_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Visible Instance Buffer"),
+            size: (instances.0.len() * std::mem::size_of::<TransformRaw>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let uniform_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Cull Uniform Buffer"),
+            size: std::mem::size_of::<CullUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group =
+            renderer
+                .errors
+                .scope(&renderer.device, "instance cull bind group", || {
+                    renderer
+                        .device
+                        .create_bind_group(&wgpu::BindGroupDescriptor {
+                            label: Some("instance_cull_bind_group"),
+                            layout: &pipeline.layout,
+                            entries: &[
+                                wgpu::BindGroupEntry {
+                                    binding: 0,
+                                    resource: uniform_buffer.as_entire_binding(),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 1,
+                                    resource: instance_buffer.0.as_entire_binding(),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 2,
+                                    resource: visible_buffer.as_entire_binding(),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 3,
+                                    resource: args_buffer.as_entire_binding(),
+                                },
+                            ],
+                        })
+                });
+
+        commands.entity(entity).insert(GpuIndirectInstances {
+            instance_buffer: visible_buffer,
+            args_buffer,
+            reset_args,
+            uniform_buffer,
+            bind_group,
+            center: bounds.center(),
+            radius: bounds.half_extents().length(),
+            count: instances.0.len() as u32,
+            mesh_count: model.meshes.len() as u32,
+        });
+    }
+}
+
+/// Runs the compute shader that fills the visible instances and the draw args of every indirect entity
+pub fn cull_instances(
+    renderer: Res<WgpuRenderer>,
+    pipeline: Res<InstanceCullPipeline>,
+    camera: Res<Camera>,
+    query: Query<&GpuIndirectInstances, With<IndirectInstances>>,
+) {
+    if query.is_empty() {
+        return;
+    }
+
+    let frustum = Frustum::from_view_projection(camera.build_view_projection_matrix());
+    let planes = frustum
+        .planes
+        .map(|plane| plane.normal.extend(plane.d).to_array());
+
+    let mut encoder = renderer
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Instance Cull Encoder"),
+        });
+    {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Instance Cull Pass"),
+        });
+        compute_pass.set_pipeline(&pipeline.pipeline);
+        for indirect in query.iter() {
+            let uniform = CullUniform {
+                planes,
+                center: indirect.center.into(),
+                radius: indirect.radius,
+                count: indirect.count,
+                mesh_count: indirect.mesh_count,
+                _padding: [0; 2],
+            };
+            renderer.queue.write_buffer(
+                &indirect.uniform_buffer,
+                0,
+                bytemuck::cast_slice(&[uniform]),
+            );
+            // The shader counts the visible instances from 0
+            renderer
+                .queue
+                .write_buffer(&indirect.args_buffer, 0, &indirect.reset_args);
+            compute_pass.set_bind_group(0, &indirect.bind_group, &[]);
+            compute_pass.dispatch_workgroups(indirect.count.div_ceil(WORKGROUP_SIZE), 1, 1);
+        }
+    }
+    renderer.queue.submit(std::iter::once(encoder.finish()));
+}
+
+/// Goes back to drawing every instance when [`IndirectInstances`] is removed
+pub fn remove_indirect_instances(
+    mut commands: Commands,
+    mut removed: RemovedComponents<IndirectInstances>,
+) {
+    for entity in removed.iter() {
+        if let Some(mut entity_commands) = commands.get_entity(entity) {
+            entity_commands.remove::<GpuIndirectInstances>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_instances_ranges() {
+        use crate::transform::CompactTransformRaw;
+
+        let transforms = [Transform::IDENTITY; 3];
+        let (data, ranges) = pack_instances([
+            (&transforms[..1], false),
+            (&transforms[..], true),
+            (&transforms[..2], false),
+        ]);
+        let (size, compact_size) = (
+            std::mem::size_of::<TransformRaw>(),
+            std::mem::size_of::<CompactTransformRaw>(),
+        );
+        assert_eq!(
+            ranges,
+            vec![
+                0..size,
+                size..size + 3 * compact_size,
+                size + 3 * compact_size..3 * size + 3 * compact_size,
+            ]
+        );
+        assert_eq!(data.len(), ranges[2].end);
+        assert_eq!(
+            &data[ranges[0].clone()],
+            &data[ranges[2].start..ranges[2].start + size]
+        );
+    }
+
+    fn translations(instances: &Instances) -> Vec<Vec3> {
+        instances.0.iter().map(|t| t.translation).collect()
+    }
+
+    #[test]
+    fn grid() {
+        assert_eq!(
+            translations(&Instances::grid(2, 2, 1.0)),
+            vec![
+                Vec3::new(-0.5, 0.0, -0.5),
+                Vec3::new(0.5, 0.0, -0.5),
+                Vec3::new(-0.5, 0.0, 0.5),
+                Vec3::new(0.5, 0.0, 0.5),
+            ]
+        );
+
+        let grid = Instances::grid(3, 4, 2.0);
+        assert_eq!(grid.0.len(), 12);
+        // The corners of a 3 by 4 grid spaced by 2
+        let translations = translations(&grid);
+        assert_eq!(translations[0], Vec3::new(-3.0, 0.0, -2.0));
+        assert_eq!(translations[3], Vec3::new(3.0, 0.0, -2.0));
+        assert_eq!(translations[8], Vec3::new(-3.0, 0.0, 2.0));
+        assert_eq!(translations[11], Vec3::new(3.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn circle() {
+        let circle = Instances::circle(4, 2.0);
+        let expected = [Vec3::X, Vec3::Z, Vec3::NEG_X, Vec3::NEG_Z].map(|v| v * 2.0);
+        for (translation, expected) in translations(&circle).into_iter().zip(expected) {
+            assert!(translation.abs_diff_eq(expected, 1e-5), "{translation}");
+        }
+        assert!(Instances::circle(0, 1.0).0.is_empty());
+    }
 
-        renderer
-            .queue
-            .write_buffer(&buffer.0, 0, bytemuck::cast_slice(&data[..]));
+    #[test]
+    fn from_points() {
+        let points = [Vec3::ZERO, Vec3::new(1.0, 2.0, 3.0)];
+        assert_eq!(translations(&Instances::from_points(&points)), points);
     }
 }
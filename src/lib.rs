@@ -7,10 +7,55 @@ pub mod gltf_loader;
 pub mod image_utils;
 pub mod instances;
 pub mod light;
+pub mod math;
 pub mod mesh;
 pub mod model;
 pub mod obj_loader;
 pub mod renderer;
 pub mod shapes;
+pub mod skinning;
 pub mod texture;
 pub mod transform;
+
+/// The most commonly used types, use it with `use glace::prelude::*;`
+pub mod prelude {
+    pub use crate::{
+        camera::{CameraSettings, CameraShake, CameraShakeImpulse, ModelVisibility, RenderLayers},
+        egui_plugin::{
+            texture_viewer::TextureViewer, viewport::EguiViewport, EguiPlugin, EguiSettings,
+            UiScale,
+        },
+        gltf_loader::{GltfBundle, GltfLoaderPlugin, GltfScene},
+        instances::{
+            CompactInstances, IndirectInstances, InstanceAnimation, Instances, StaticInstances,
+        },
+        light::{DirectionalLight, Light, LightFollowCamera},
+        model::{ChannelMapping, LoaderSettings, Model, TextureChannel},
+        obj_loader::{ObjBundle, ObjLoaderPlugin, ObjObjects, SplitObjObjects},
+        renderer::{
+            auto_exposure::AutoExposure,
+            base_3d::{
+                ClearFlags, GBuffer, GBufferEnabled, StencilTest, StencilWrite, Transparent,
+            },
+            bind_groups::material::{
+                ColorOverride, MaterialOverride, MaterialOverrides, MaterialTextureArray,
+                SetDiffuseTexture,
+            },
+            frame_stats::FrameStats,
+            outline::{OutlineSettings, Selection},
+            picking::{PickRequest, PickResult},
+            render_phase::{RenderPhase, RenderPhaseAppExt},
+            screenshot::TakeScreenshot,
+            shadow::{
+                CascadeSettings, DirectionalLightShadow, PointLightShadow, PointShadowSettings,
+            },
+            sky::GradientSky,
+            ssao::SsaoSettings,
+            validation::RendererErrorEvent,
+            wireframe::{FaceSelection, Wireframe, WireframeConfig},
+            AntiAliasing, DebugView, DepthFormat, Exposure, FrameLatency, FullscreenRequest,
+            GlaceClearColor, Msaa, OutputColorSpace, ReverseZ, WgpuRenderer, WgpuRendererPlugin,
+            WindowConfig,
+        },
+    };
+}
@@ -1,7 +1,10 @@
-use bevy::{ecs::prelude::*, math::prelude::*, render::color::Color};
+use bevy::{ecs::prelude::*, math::prelude::*, render::color::Color, utils::default};
 use std::ops::Range;
 
-use crate::model::{Model, ModelMesh};
+use crate::{
+    camera::Camera,
+    model::{Model, ModelMesh},
+};
 
 #[derive(Component)]
 pub struct Light {
@@ -9,6 +12,92 @@ pub struct Light {
     pub color: Color,
 }
 
+impl Light {
+    /// A light at the origin with the color of a black body at the given temperature,
+    /// like 6500K for daylight or 3200K for tungsten bulbs.
+    /// The color is multiplied by the intensity since lights don't have a separate intensity.
+    #[allow(unused)]
+    pub fn from_kelvin(temperature: f32, intensity: f32) -> Self {
+        Self {
+            position: Vec3::ZERO,
+            color: kelvin_to_color(temperature) * intensity,
+        }
+    }
+}
+
+/// A light infinitely far away that lights every model from the same direction, like the sun.
+/// It doesn't have a position so it doesn't fade with the distance and it isn't drawn.
+#[derive(Component)]
+pub struct DirectionalLight {
+    /// The direction the light travels in, it doesn't need to be normalized
+    pub direction: Vec3,
+    pub color: Color,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::NEG_Y,
+            color: Color::WHITE,
+        }
+    }
+}
+
+impl DirectionalLight {
+    /// A light pointing down with the color of a black body at the given temperature,
+    /// see [`Light::from_kelvin`]
+    #[allow(unused)]
+    pub fn from_kelvin(temperature: f32, intensity: f32) -> Self {
+        Self {
+            color: kelvin_to_color(temperature) * intensity,
+            ..default()
+        }
+    }
+}
+
+/// Approximates the color of a black body at the given temperature, from 1000K to 40000K.
+/// The returned color is linear so it can be used directly by the lights.
+pub fn kelvin_to_color(temperature: f32) -> Color {
+    // Tanner Helland's curve fit of the blackbody data, it works in sRGB from 0 to 255
+    let t = temperature.clamp(1000.0, 40000.0) / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698_73 * (t - 60.0).powf(-0.133_204_76)
+    };
+    let g = if t <= 66.0 {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_17 * (t - 60.0).powf(-0.075_514_85)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (t - 10.0).ln() - 305.044_8
+    };
+    Color::rgb(
+        r.clamp(0.0, 255.0) / 255.0,
+        g.clamp(0.0, 255.0) / 255.0,
+        b.clamp(0.0, 255.0) / 255.0,
+    )
+    .as_rgba_linear()
+}
+
+/// Keeps the light at the camera position, useful as a headlamp when inspecting dark models.
+/// The offset is relative to the camera orientation.
+#[derive(Component, Default)]
+pub struct LightFollowCamera {
+    pub offset: Vec3,
+}
+
+pub fn follow_camera(camera: Res<Camera>, mut query: Query<(&mut Light, &LightFollowCamera)>) {
+    for (mut light, follow) in &mut query {
+        light.position = camera.eye + camera.rotation * follow.offset;
+    }
+}
+
 #[allow(unused)]
 fn draw_light_mesh<'a>(
     render_pass: &mut wgpu::RenderPass<'a>,
@@ -25,17 +114,25 @@ fn draw_light_mesh_instanced<'a>(
     mesh_view_bind_group: &'a wgpu::BindGroup,
 ) {
     render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-    render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    render_pass.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
     render_pass.set_bind_group(0, mesh_view_bind_group, &[]);
     render_pass.draw_indexed(0..mesh.num_elements, 0, instances);
 }
 
+/// Draws the model of the light stored at `light_index` in the light buffer.
+/// The index is passed to the shader as the instance index.
 pub fn draw_light_model<'a>(
     render_pass: &mut wgpu::RenderPass<'a>,
     model: &'a Model,
+    light_index: u32,
     mesh_view_bind_group: &'a wgpu::BindGroup,
 ) {
-    draw_light_model_instanced(render_pass, model, 0..1, mesh_view_bind_group);
+    draw_light_model_instanced(
+        render_pass,
+        model,
+        light_index..light_index + 1,
+        mesh_view_bind_group,
+    );
 }
 
 fn draw_light_model_instanced<'a>(
@@ -48,3 +145,39 @@ fn draw_light_model_instanced<'a>(
         draw_light_mesh_instanced(render_pass, mesh, instances.clone(), mesh_view_bind_group);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daylight_is_white() {
+        let [r, g, b, _] = kelvin_to_color(6500.0).as_rgba_f32();
+        for channel in [r, g, b] {
+            assert!((channel - 1.0).abs() < 0.05, "{r} {g} {b}");
+        }
+    }
+
+    #[test]
+    fn tungsten_is_orange() {
+        let [r, g, b, _] = kelvin_to_color(3200.0).as_rgba_f32();
+        assert!(r > 0.99, "{r}");
+        assert!(r > g && g > b, "{r} {g} {b}");
+    }
+
+    #[test]
+    fn from_kelvin_intensity() {
+        let light = Light::from_kelvin(3200.0, 2.0);
+        let directional = DirectionalLight::from_kelvin(3200.0, 2.0);
+        let expected = kelvin_to_color(3200.0) * 2.0;
+        assert_eq!(light.color, expected);
+        assert_eq!(directional.color, expected);
+        assert_eq!(directional.direction, Vec3::NEG_Y);
+    }
+
+    #[test]
+    fn temperature_is_clamped() {
+        assert_eq!(kelvin_to_color(0.0), kelvin_to_color(1000.0));
+        assert_eq!(kelvin_to_color(100000.0), kelvin_to_color(40000.0));
+    }
+}
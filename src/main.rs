@@ -5,7 +5,6 @@ use bevy::{
     a11y::AccessibilityPlugin,
     app::{prelude::*, AppExit},
     asset::{prelude::*, AssetPlugin},
-    diagnostic::{Diagnostic, DiagnosticsStore, FrameTimeDiagnosticsPlugin},
     ecs::prelude::*,
     hierarchy::prelude::*,
     input::prelude::*,
@@ -22,12 +21,22 @@ use bevy::{
 
 use crate::{
     camera::CameraSettings,
-    egui_plugin::{EguiCtxRes, EguiPlugin},
+    egui_plugin::{texture_viewer::TextureViewer, EguiCtxRes, EguiPlugin},
     gltf_loader::{GltfBundle, GltfLoaderPlugin},
     light::Light,
-    model::Model,
+    model::{Material, Model},
     obj_loader::{ObjBundle, ObjLoaderPlugin},
-    renderer::{wireframe::Wireframe, GlaceClearColor, Msaa, WgpuRenderer, WgpuRendererPlugin},
+    renderer::{
+        base_3d::{DepthPrepass, GBufferEnabled},
+        bind_groups::material::{ColorOverride, MaterialOverride, MaterialOverrides},
+        frame_stats::FrameStats,
+        outline::Selection,
+        screenshot::TakeScreenshot,
+        ssao::SsaoSettings,
+        wireframe::{FaceSelection, Wireframe, WireframeConfig},
+        AntiAliasing, DebugView, Fog, FogMode, FullscreenRequest, GlaceClearColor, Msaa,
+        WgpuRenderer, WgpuRendererPlugin, WindowConfig,
+    },
 };
 
 mod camera;
@@ -36,11 +45,13 @@ mod gltf_loader;
 mod image_utils;
 mod instances;
 mod light;
+mod math;
 mod mesh;
 mod model;
 mod obj_loader;
 mod renderer;
 mod shapes;
+mod skinning;
 mod texture;
 mod transform;
 
@@ -56,6 +67,16 @@ struct LightSettings {
 #[derive(Resource)]
 struct GlobalMaterialSettings {
     gloss: f32,
+    normal_scale: f32,
+}
+
+#[derive(Resource)]
+struct FogSettings {
+    enabled: bool,
+    color: [f32; 3],
+    start: f32,
+    end: f32,
+    mode: FogMode,
 }
 
 #[derive(Resource)]
@@ -77,22 +98,37 @@ fn main() {
     App::new()
         .add_plugins(WindowPlugin {
             primary_window: Some(Window {
-                // width: 800.0,
-                // height: 600.0,
                 // mode: WindowMode::Fullscreen,
-                title: "glace".into(),
                 ..default()
             }),
             ..default()
         })
+        .insert_resource(WindowConfig {
+            title: Some("glace".into()),
+            // inner_size: Some([800, 600]),
+            ..default()
+        })
         .insert_resource(GlaceClearColor(Color::rgba(0.1, 0.1, 0.1, 1.0)))
-        .insert_resource(CameraSettings { speed: 10.0 })
+        .insert_resource(CameraSettings {
+            speed: 10.0,
+            ..default()
+        })
         .insert_resource(LightSettings {
             rotate: true,
             color: [1.0, 1.0, 1.0],
             speed: 0.35,
         })
-        .insert_resource(GlobalMaterialSettings { gloss: 0.5 })
+        .insert_resource(GlobalMaterialSettings {
+            gloss: 0.5,
+            normal_scale: 1.0,
+        })
+        .insert_resource(FogSettings {
+            enabled: false,
+            color: [0.5, 0.5, 0.5],
+            start: 5.0,
+            end: 50.0,
+            mode: FogMode::Linear,
+        })
         .insert_resource(ModelSettings {
             scale: 1.0,
             wireframe: false,
@@ -108,7 +144,6 @@ fn main() {
             EguiPlugin,
             ObjLoaderPlugin,
             GltfLoaderPlugin,
-            FrameTimeDiagnosticsPlugin,
         ))
         .add_systems(Startup, (spawn_light, spawn_grid))
         .add_systems(
@@ -116,9 +151,12 @@ fn main() {
             (
                 update_light,
                 exit_on_esc,
+                screenshot_on_f12,
+                fullscreen_on_f11,
                 settings_ui,
                 update_materials,
                 update_model,
+                update_fog,
             ),
         )
         .run();
@@ -130,6 +168,7 @@ fn spawn_grid(mut commands: Commands, renderer: Res<WgpuRenderer>) {
         meshes: vec![shapes::plane::Plane {
             resolution: size as usize,
             size,
+            ..default()
         }
         .mesh(&renderer.device)],
         materials: vec![model::Material {
@@ -170,6 +209,24 @@ fn exit_on_esc(key_input: Res<Input<KeyCode>>, mut exit_events: EventWriter<AppE
     }
 }
 
+fn screenshot_on_f12(
+    key_input: Res<Input<KeyCode>>,
+    mut screenshot_events: EventWriter<TakeScreenshot>,
+) {
+    if key_input.just_pressed(KeyCode::F12) {
+        screenshot_events.send(TakeScreenshot("screenshot.png".into()));
+    }
+}
+
+fn fullscreen_on_f11(
+    key_input: Res<Input<KeyCode>>,
+    mut fullscreen_events: EventWriter<FullscreenRequest>,
+) {
+    if key_input.just_pressed(KeyCode::F11) {
+        fullscreen_events.send(FullscreenRequest::Toggle);
+    }
+}
+
 fn update_light(mut query: Query<&mut Light>, time: Res<Time>, settings: Res<LightSettings>) {
     if !settings.rotate {
         return;
@@ -196,10 +253,28 @@ fn update_materials(
     for mut model in query.iter_mut() {
         for material in model.materials.iter_mut() {
             material.gloss = settings.gloss;
+            material.normal_scale = settings.normal_scale;
         }
     }
 }
 
+fn update_fog(mut fog: ResMut<Fog>, settings: Res<FogSettings>) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    *fog = if settings.enabled {
+        Fog {
+            color: settings.color.into(),
+            start: settings.start,
+            end: settings.end,
+            mode: settings.mode,
+        }
+    } else {
+        Fog::default()
+    };
+}
+
 fn update_model(
     mut commands: Commands,
     mut query: Query<(Entity, &mut Transform), (With<Model>, With<SpawnedModel>)>,
@@ -225,10 +300,25 @@ fn settings_ui(
     mut camera_settings: ResMut<CameraSettings>,
     mut light_settings: ResMut<LightSettings>,
     mut global_material_settings: ResMut<GlobalMaterialSettings>,
-    mut model_settings: ResMut<ModelSettings>,
-    diagnostics: ResMut<DiagnosticsStore>,
+    mut fog_settings: ResMut<FogSettings>,
+    (mut model_settings, spawned_models, color_overrides, material_overrides): (
+        ResMut<ModelSettings>,
+        Query<&Model, With<SpawnedModel>>,
+        Query<(), (With<SpawnedModel>, With<ColorOverride>)>,
+        Query<(), (With<SpawnedModel>, With<MaterialOverrides>)>,
+    ),
+    frame_stats: Res<FrameStats>,
     mut spawned_entity: Local<Option<Entity>>,
-    mut msaa: ResMut<Msaa>,
+    (mut msaa, mut anti_aliasing): (ResMut<Msaa>, ResMut<AntiAliasing>),
+    mut depth_prepass: ResMut<DepthPrepass>,
+    mut gbuffer: ResMut<GBufferEnabled>,
+    mut selection: ResMut<Selection>,
+    mut ssao_settings: ResMut<SsaoSettings>,
+    (mut wireframe_config, mut debug_view, mut texture_viewer): (
+        ResMut<WireframeConfig>,
+        ResMut<DebugView>,
+        ResMut<TextureViewer>,
+    ),
 ) {
     egui::TopBottomPanel::top("my_panel").show(&ctx.0, |ui| {
         egui::menu::bar(ui, |ui| {
@@ -335,6 +425,9 @@ fn settings_ui(
         ui.heading("Camera");
         ui.label("Speed");
         ui.add(egui::Slider::new(&mut camera_settings.speed, 1.0..=20.0).step_by(0.5));
+        ui.checkbox(&mut camera_settings.roll, "Roll with Q/E");
+        ui.label("Smoothing");
+        ui.add(egui::Slider::new(&mut camera_settings.smoothing, 0.0..=1.0));
 
         ui.separator();
 
@@ -353,6 +446,37 @@ fn settings_ui(
             &mut global_material_settings.gloss,
             0.0..=1.0,
         ));
+        ui.label("Normal scale");
+        ui.add(egui::Slider::new(
+            &mut global_material_settings.normal_scale,
+            0.0..=2.0,
+        ));
+
+        ui.separator();
+
+        ui.heading("Fog");
+        ui.checkbox(&mut fog_settings.enabled, "Enabled");
+        ui.label("Color");
+        ui.color_edit_button_rgb(&mut fog_settings.color);
+        ui.label("Start");
+        ui.add(egui::Slider::new(&mut fog_settings.start, 0.0..=100.0));
+        ui.label("End");
+        ui.add(egui::Slider::new(&mut fog_settings.end, 0.0..=200.0));
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut fog_settings.mode, FogMode::Linear, "Linear");
+            ui.radio_value(&mut fog_settings.mode, FogMode::Exponential, "Exponential");
+        });
+
+        ui.separator();
+
+        ui.heading("SSAO");
+        ui.checkbox(&mut ssao_settings.enabled, "Enabled");
+        ui.label("Radius");
+        ui.add(egui::Slider::new(&mut ssao_settings.radius, 0.05..=2.0));
+        ui.label("Intensity");
+        ui.add(egui::Slider::new(&mut ssao_settings.intensity, 0.0..=2.0));
+        ui.label("Samples");
+        ui.add(egui::Slider::new(&mut ssao_settings.sample_count, 1..=64));
 
         ui.separator();
 
@@ -360,6 +484,55 @@ fn settings_ui(
         ui.label("scale");
         ui.add(egui::Slider::new(&mut model_settings.scale, 0.025..=5.0));
         ui.checkbox(&mut model_settings.wireframe, "wireframe");
+        let mut xray = *wireframe_config
+            == WireframeConfig {
+                cull: FaceSelection::Back,
+                depth_test: false,
+            };
+        ui.checkbox(&mut xray, "x-ray wireframe");
+        wireframe_config.set_if_neq(if xray {
+            WireframeConfig {
+                cull: FaceSelection::Back,
+                depth_test: false,
+            }
+        } else {
+            WireframeConfig::default()
+        });
+
+        let mut selected_debug_view = *debug_view;
+        egui::ComboBox::from_label("Debug view")
+            .selected_text(format!("{selected_debug_view:?}"))
+            .show_ui(ui, |ui| {
+                for mode in DebugView::ALL {
+                    ui.selectable_value(&mut selected_debug_view, mode, format!("{mode:?}"));
+                }
+            });
+        debug_view.set_if_neq(selected_debug_view);
+
+        let mut texture_viewer_open = texture_viewer.open;
+        ui.checkbox(&mut texture_viewer_open, "Texture viewer");
+        texture_viewer.open = texture_viewer_open;
+
+        ui.collapsing("Mesh stats", |ui| {
+            for model in &spawned_models {
+                for (i, mesh) in model.meshes.iter().enumerate() {
+                    let stats = &mesh.stats;
+                    ui.label(format!(
+                        "{i}: {} vertices, {} triangles",
+                        stats.vertex_count, stats.triangle_count
+                    ));
+                    if stats.degenerate_triangles > 0 {
+                        ui.label(format!("  {} degenerate", stats.degenerate_triangles));
+                    }
+                    if !stats.normals_normalized {
+                        ui.label("  normals missing or not normalized");
+                    }
+                    if !stats.has_uvs {
+                        ui.label("  uvs missing");
+                    }
+                }
+            }
+        });
 
         ui.separator();
 
@@ -372,34 +545,67 @@ fn settings_ui(
                 msaa.samples = 4;
             }
         });
-    });
-
-    egui::Area::new("Performance area")
-        .interactable(false)
-        .anchor(egui::Align2::LEFT_TOP, [0., 0.])
-        .show(&ctx.0, |ui| {
-            egui::Frame::none()
-                .fill(egui::Color32::from_rgba_premultiplied(
-                    0,
-                    0,
-                    0,
-                    (0.75 * 256.0) as u8,
-                ))
-                .show(ui, |ui| {
-                    let fps = diagnostics
-                        .get(FrameTimeDiagnosticsPlugin::FPS)
-                        .and_then(Diagnostic::average);
-                    let frame_time = diagnostics
-                        .get(FrameTimeDiagnosticsPlugin::FRAME_TIME)
-                        .and_then(Diagnostic::average);
-                    let (fps, frame_time) = match (fps, frame_time) {
-                        (Some(fps), Some(frame_time)) => (fps, frame_time),
-                        _ => return,
-                    };
 
-                    ui.visuals_mut().override_text_color = Some(egui::Color32::WHITE);
-                    ui.label(format!("fps: {:.2}", fps));
-                    ui.label(format!("dt: {:.2}ms", frame_time));
-                });
+        let mut smaa = *anti_aliasing == AntiAliasing::Smaa;
+        ui.checkbox(&mut smaa, "SMAA, only without msaa");
+        anti_aliasing.set_if_neq(if smaa {
+            AntiAliasing::Smaa
+        } else {
+            AntiAliasing::None
         });
+
+        let mut depth_prepass_enabled = depth_prepass.0;
+        ui.checkbox(&mut depth_prepass_enabled, "Depth prepass");
+        // Avoid rebuilding the pipelines every frame
+        depth_prepass.set_if_neq(DepthPrepass(depth_prepass_enabled));
+
+        let mut gbuffer_enabled = gbuffer.0;
+        ui.checkbox(&mut gbuffer_enabled, "G-buffer normals");
+        gbuffer.set_if_neq(GBufferEnabled(gbuffer_enabled));
+
+        let mut outline_enabled = selection.0.is_some();
+        ui.checkbox(&mut outline_enabled, "Outline spawned model");
+        selection.set_if_neq(Selection(if outline_enabled {
+            *spawned_entity
+        } else {
+            None
+        }));
+
+        if let Some(entity) = *spawned_entity {
+            let mut color_override = color_overrides.contains(entity);
+            if ui
+                .checkbox(&mut color_override, "Flat color on spawned model")
+                .changed()
+            {
+                if color_override {
+                    commands.entity(entity).insert(ColorOverride(Color::ORANGE));
+                } else {
+                    commands.entity(entity).remove::<ColorOverride>();
+                }
+            }
+
+            let mut material_override = material_overrides.contains(entity);
+            if ui
+                .checkbox(
+                    &mut material_override,
+                    "Highlight first mesh of spawned model",
+                )
+                .changed()
+            {
+                if material_override {
+                    commands
+                        .entity(entity)
+                        .insert(MaterialOverrides(vec![MaterialOverride {
+                            mesh_index: 0,
+                            material: Material::from_color(Color::ORANGE),
+                        }]));
+                } else {
+                    commands.entity(entity).remove::<MaterialOverrides>();
+                }
+            }
+        }
+    });
+
+    frame_stats.show_overlay(&ctx.0);
+    texture_viewer.show(&ctx.0);
 }
@@ -0,0 +1,346 @@
+use bevy::math::prelude::*;
+
+/// An axis aligned bounding box
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    #[allow(unused)]
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    /// The smallest box containing every point, None if there are no points
+    #[allow(unused)]
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self::new(first, first), |aabb, point| Self {
+            min: aabb.min.min(point),
+            max: aabb.max.max(point),
+        }))
+    }
+
+    #[allow(unused)]
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    #[allow(unused)]
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    #[allow(unused)]
+    pub fn contains(&self, point: Vec3) -> bool {
+        point.cmpge(self.min).all() && point.cmple(self.max).all()
+    }
+
+    #[allow(unused)]
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// The box containing the 8 transformed corners, it's usually bigger than the original box
+    #[allow(unused)]
+    pub fn transformed(&self, transform: Mat4) -> Aabb {
+        let center = transform.transform_point3(self.center());
+        let half_extents = self.half_extents();
+        // Each axis of the new box is the sum of the projected extents on that axis
+        let extents = transform.x_axis.truncate().abs() * half_extents.x
+            + transform.y_axis.truncate().abs() * half_extents.y
+            + transform.z_axis.truncate().abs() * half_extents.z;
+        Self {
+            min: center - extents,
+            max: center + extents,
+        }
+    }
+}
+
+/// A plane defined by `normal.dot(point) + d = 0`, the normal points toward the positive half space
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: Vec3,
+    pub d: f32,
+}
+
+impl Plane {
+    /// Normalizes the plane so [`Plane::signed_distance`] returns a real distance
+    #[allow(unused)]
+    pub fn from_vec4(plane: Vec4) -> Self {
+        let length = plane.truncate().length();
+        Self {
+            normal: plane.truncate() / length,
+            d: plane.w / length,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn from_point_normal(point: Vec3, normal: Vec3) -> Self {
+        let normal = normal.normalize();
+        Self {
+            normal,
+            d: -normal.dot(point),
+        }
+    }
+
+    /// Positive in front of the plane, negative behind it
+    #[allow(unused)]
+    pub fn signed_distance(&self, point: Vec3) -> f32 {
+        self.normal.dot(point) + self.d
+    }
+}
+
+/// The volume visible by a camera, the normals of the planes point inside
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    /// Left, right, bottom, top, near and far
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes of a view projection matrix using wgpu's 0 to 1 depth range
+    #[allow(unused)]
+    pub fn from_view_projection(view_proj: Mat4) -> Self {
+        let row0 = view_proj.row(0);
+        let row1 = view_proj.row(1);
+        let row2 = view_proj.row(2);
+        let row3 = view_proj.row(3);
+        Self {
+            planes: [
+                Plane::from_vec4(row3 + row0),
+                Plane::from_vec4(row3 - row0),
+                Plane::from_vec4(row3 + row1),
+                Plane::from_vec4(row3 - row1),
+                Plane::from_vec4(row2),
+                Plane::from_vec4(row3 - row2),
+            ],
+        }
+    }
+
+    #[allow(unused)]
+    pub fn contains_point(&self, point: Vec3) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.signed_distance(point) >= 0.0)
+    }
+
+    /// Conservative test, some boxes close to the corners of the frustum are reported as intersecting
+    #[allow(unused)]
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        let center = aabb.center();
+        let half_extents = aabb.half_extents();
+        self.planes.iter().all(|plane| {
+            // The distance from the center to the corner the furthest along the normal
+            let radius = half_extents.dot(plane.normal.abs());
+            plane.signed_distance(center) >= -radius
+        })
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// Normalized
+    pub direction: Vec3,
+}
+
+impl Ray {
+    #[allow(unused)]
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    /// The ray going from the near plane through the given pixel.
+    /// The screen position is in pixels with the origin at the top left, like winit's cursor position.
+    /// It works with both the regular and the reversed depth range, see [`ReverseZ`](crate::renderer::ReverseZ).
+    #[allow(unused)]
+    pub fn unproject(screen_position: Vec2, screen_size: Vec2, view_proj: Mat4) -> Self {
+        let ndc = Vec2::new(
+            screen_position.x / screen_size.x * 2.0 - 1.0,
+            1.0 - screen_position.y / screen_size.y * 2.0,
+        );
+        let inverse_view_proj = view_proj.inverse();
+        let a = inverse_view_proj.project_point3(ndc.extend(0.0));
+        let b = inverse_view_proj.project_point3(ndc.extend(1.0));
+        // The camera is the point projected to infinity, the near plane is the closest to it
+        let eye = inverse_view_proj * Vec4::Z;
+        let eye = eye.truncate() / eye.w;
+        let (near, far) = if a.distance_squared(eye) <= b.distance_squared(eye) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Self::new(near, far - near)
+    }
+
+    #[allow(unused)]
+    pub fn point_at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    /// The distance to the first intersection with the box, 0 if the ray starts inside it
+    #[allow(unused)]
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        // Slab test, the divisions by 0 give infinities which compare correctly
+        let inverse_direction = self.direction.recip();
+        let t1 = (aabb.min - self.origin) * inverse_direction;
+        let t2 = (aabb.max - self.origin) * inverse_direction;
+        let t_min = t1.min(t2).max_element();
+        let t_max = t1.max(t2).min_element();
+        if t_max < 0.0 || t_min > t_max {
+            None
+        } else {
+            Some(t_min.max(0.0))
+        }
+    }
+
+    /// The distance to the intersection with the plane, None if the ray is parallel or points away from it
+    #[allow(unused)]
+    pub fn intersect_plane(&self, plane: &Plane) -> Option<f32> {
+        let denominator = plane.normal.dot(self.direction);
+        if denominator.abs() < f32::EPSILON {
+            return None;
+        }
+        let distance = -plane.signed_distance(self.origin) / denominator;
+        (distance >= 0.0).then_some(distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A camera at the origin looking down -Z with a 90 degrees field of view
+    fn view_projection() -> Mat4 {
+        Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 100.0)
+            * Mat4::look_at_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y)
+    }
+
+    fn cube(center: Vec3) -> Aabb {
+        Aabb::new(center - 0.5, center + 0.5)
+    }
+
+    #[test]
+    fn aabb() {
+        let aabb =
+            Aabb::from_points([Vec3::new(1.0, -2.0, 0.0), Vec3::new(-1.0, 2.0, 3.0)]).unwrap();
+        assert_eq!(
+            aabb,
+            Aabb::new(Vec3::new(-1.0, -2.0, 0.0), Vec3::new(1.0, 2.0, 3.0))
+        );
+        assert_eq!(aabb.center(), Vec3::new(0.0, 0.0, 1.5));
+        assert_eq!(aabb.half_extents(), Vec3::new(1.0, 2.0, 1.5));
+        assert!(aabb.contains(Vec3::new(1.0, 0.0, 1.0)));
+        assert!(!aabb.contains(Vec3::new(1.1, 0.0, 1.0)));
+        assert_eq!(Aabb::from_points([]), None);
+
+        let union = cube(Vec3::ZERO).union(&cube(Vec3::X * 2.0));
+        assert_eq!(
+            union,
+            Aabb::new(Vec3::splat(-0.5), Vec3::new(2.5, 0.5, 0.5))
+        );
+    }
+
+    #[test]
+    fn aabb_transformed() {
+        let transform = Mat4::from_rotation_translation(
+            Quat::from_rotation_z(std::f32::consts::FRAC_PI_4),
+            Vec3::X * 10.0,
+        );
+        let aabb = cube(Vec3::ZERO).transformed(transform);
+        // The diagonal of the rotated square is aligned with the axes
+        let half_diagonal = 0.5 * 2f32.sqrt();
+        assert!(aabb.center().abs_diff_eq(Vec3::X * 10.0, 1e-5));
+        assert!(aabb
+            .half_extents()
+            .abs_diff_eq(Vec3::new(half_diagonal, half_diagonal, 0.5), 1e-5));
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Plane::from_point_normal(Vec3::Y, Vec3::Y * 2.0);
+        assert_eq!(plane.signed_distance(Vec3::new(5.0, 3.0, 5.0)), 2.0);
+        assert_eq!(plane.signed_distance(Vec3::ZERO), -1.0);
+        assert_eq!(Plane::from_vec4(Vec4::new(0.0, 2.0, 0.0, -2.0)), plane);
+    }
+
+    #[test]
+    fn frustum_contains_point() {
+        let frustum = Frustum::from_view_projection(view_projection());
+        assert!(frustum.contains_point(Vec3::NEG_Z * 10.0));
+        assert!(frustum.contains_point(Vec3::new(9.0, -9.0, -10.0)));
+        // Behind the camera, past the far plane and outside of the field of view
+        assert!(!frustum.contains_point(Vec3::Z * 10.0));
+        assert!(!frustum.contains_point(Vec3::NEG_Z * 200.0));
+        assert!(!frustum.contains_point(Vec3::new(11.0, 0.0, -10.0)));
+        assert!(!frustum.contains_point(Vec3::NEG_Z * 0.05));
+    }
+
+    #[test]
+    fn frustum_intersects_aabb() {
+        let frustum = Frustum::from_view_projection(view_projection());
+        assert!(frustum.intersects_aabb(&cube(Vec3::NEG_Z * 10.0)));
+        // Only partially inside of the right plane
+        assert!(frustum.intersects_aabb(&cube(Vec3::new(10.4, 0.0, -10.0))));
+        assert!(!frustum.intersects_aabb(&cube(Vec3::new(20.0, 0.0, -10.0))));
+        assert!(!frustum.intersects_aabb(&cube(Vec3::Z * 10.0)));
+    }
+
+    #[test]
+    fn ray_unproject() {
+        let size = Vec2::new(800.0, 600.0);
+        let view_proj = Mat4::perspective_rh(90f32.to_radians(), size.x / size.y, 0.1, 100.0)
+            * Mat4::look_at_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y);
+
+        let ray = Ray::unproject(size / 2.0, size, view_proj);
+        assert!(
+            ray.origin.abs_diff_eq(Vec3::NEG_Z * 0.1, 1e-5),
+            "{}",
+            ray.origin
+        );
+        assert!(
+            ray.direction.abs_diff_eq(Vec3::NEG_Z, 1e-5),
+            "{}",
+            ray.direction
+        );
+
+        // The top left corner of the screen is up and to the left of the camera
+        let ray = Ray::unproject(Vec2::ZERO, size, view_proj);
+        assert!(ray.direction.x < 0.0 && ray.direction.y > 0.0 && ray.direction.z < 0.0);
+        assert!(ray.direction.is_normalized());
+    }
+
+    #[test]
+    fn ray_intersections() {
+        let ray = Ray::new(Vec3::Z * 5.0, Vec3::NEG_Z * 2.0);
+        assert_eq!(ray.direction, Vec3::NEG_Z);
+        assert_eq!(ray.point_at(2.0), Vec3::Z * 3.0);
+
+        assert_eq!(ray.intersect_aabb(&cube(Vec3::ZERO)), Some(4.5));
+        assert_eq!(ray.intersect_aabb(&cube(Vec3::X * 2.0)), None);
+        assert_eq!(ray.intersect_aabb(&cube(Vec3::Z * 10.0)), None);
+        // Starting inside of the box
+        assert_eq!(ray.intersect_aabb(&cube(Vec3::Z * 5.0)), Some(0.0));
+
+        let ground = Plane::from_point_normal(Vec3::ZERO, Vec3::Z);
+        assert_eq!(ray.intersect_plane(&ground), Some(5.0));
+        let behind = Plane::from_point_normal(Vec3::Z * 10.0, Vec3::Z);
+        assert_eq!(ray.intersect_plane(&behind), None);
+        let parallel = Plane::from_point_normal(Vec3::ZERO, Vec3::X);
+        assert_eq!(ray.intersect_plane(&parallel), None);
+    }
+}
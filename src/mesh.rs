@@ -1,4 +1,11 @@
-use bevy::math::{Vec2, Vec3};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{
+    math::{Mat3, Mat4, Vec2, Vec3},
+    utils::{HashMap, HashSet},
+};
+
+use crate::math::Aabb;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -8,9 +15,18 @@ pub struct Vertex {
     pub uv: Vec2,
     pub tangent: Vec3,
     pub bitangent: Vec3,
+    /// Indices in the JointMatrices of the joints influencing this vertex
+    pub joints: [u16; 4],
+    /// How much each joint influences this vertex, they should add up to 1
+    pub weights: [f32; 4],
 }
 
 impl Vertex {
+    /// The default skinning data only uses joint 0 which is the identity,
+    /// this leaves static meshes unchanged
+    pub const DEFAULT_JOINTS: [u16; 4] = [0; 4];
+    pub const DEFAULT_WEIGHTS: [f32; 4] = [1.0, 0.0, 0.0, 0.0];
+
     pub fn new(position: Vec3, normal: Vec3, uv: Vec2) -> Self {
         Self {
             position,
@@ -18,6 +34,8 @@ impl Vertex {
             uv,
             tangent: Vec3::ZERO,
             bitangent: Vec3::ZERO,
+            joints: Self::DEFAULT_JOINTS,
+            weights: Self::DEFAULT_WEIGHTS,
         }
     }
 
@@ -28,16 +46,21 @@ impl Vertex {
             uv: Vec2::from(uv),
             tangent: Vec3::ZERO,
             bitangent: Vec3::ZERO,
+            joints: Self::DEFAULT_JOINTS,
+            weights: Self::DEFAULT_WEIGHTS,
         }
     }
 
     pub fn layout<'a>() -> wgpu::VertexBufferLayout<'a> {
-        const ATTRIBUTESS: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+        const ATTRIBUTESS: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
             0 => Float32x3,
             1 => Float32x3,
             2 => Float32x2,
             3 => Float32x3,
-            4 => Float32x3
+            4 => Float32x3,
+            // 5 to 11 are used by the instance transform
+            12 => Uint16x4,
+            13 => Float32x4
         ];
 
         wgpu::VertexBufferLayout {
@@ -48,15 +71,132 @@ impl Vertex {
     }
 }
 
+/// The attributes of a [`Vertex`] needed by the passes that only write depth,
+/// like the depth prepass and the shadows. They can be uploaded to their own buffer so those passes
+/// read 36 bytes per vertex instead of the 80 bytes of the full vertex,
+/// see [`ModelMesh::with_position_buffer`](crate::model::ModelMesh::with_position_buffer).
+/// The joints and weights are kept so skinned meshes still write the right depth.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PositionVertex {
+    pub position: Vec3,
+    pub joints: [u16; 4],
+    pub weights: [f32; 4],
+}
+
+impl From<&Vertex> for PositionVertex {
+    fn from(vertex: &Vertex) -> Self {
+        Self {
+            position: vertex.position,
+            joints: vertex.joints,
+            weights: vertex.weights,
+        }
+    }
+}
+
+impl PositionVertex {
+    /// Uses the same locations as [`Vertex::layout`] so the shaders don't need to change
+    pub fn layout<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; 3] = wgpu::vertex_attr_array![
+            0 => Float32x3,
+            12 => Uint16x4,
+            13 => Float32x4
+        ];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<PositionVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+/// A summary of a mesh used to debug imported assets, see [`Mesh::stats`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshStats {
+    pub vertex_count: usize,
+    /// Only triangle lists have triangles
+    pub triangle_count: usize,
+    /// None when the mesh has no vertices
+    pub bounds: Option<Aabb>,
+    /// Triangles without any area, they can't be seen but they are still rasterized
+    pub degenerate_triangles: usize,
+    /// An attribute is missing when it's zero for every vertex
+    pub has_normals: bool,
+    pub has_uvs: bool,
+    pub has_tangents: bool,
+    /// Whether every normal has a length of 1, false when they are missing
+    pub normals_normalized: bool,
+    /// Whether every tangent has a length of 1, false when they are missing
+    pub tangents_normalized: bool,
+}
+
+impl std::fmt::Display for MeshStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let attribute = |present, normalized| match (present, normalized) {
+            (false, _) => "missing",
+            (true, true) => "ok",
+            (true, false) => "not normalized",
+        };
+        write!(
+            f,
+            "{} vertices, {} triangles ({} degenerate), normals: {}, uvs: {}, tangents: {}",
+            self.vertex_count,
+            self.triangle_count,
+            self.degenerate_triangles,
+            attribute(self.has_normals, self.normals_normalized),
+            if self.has_uvs { "ok" } else { "missing" },
+            attribute(self.has_tangents, self.tangents_normalized),
+        )?;
+        if let Some(bounds) = self.bounds {
+            write!(f, ", bounds: {} to {}", bounds.min, bounds.max)?;
+        }
+        Ok(())
+    }
+}
+
 // TODO use Map for attributes
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Option<Vec<u32>>,
     pub material_id: Option<usize>,
+    /// Line and point meshes are rendered unlit so their normals and uvs are ignored
+    pub topology: wgpu::PrimitiveTopology,
 }
 
 impl Mesh {
+    /// Restarts a [`TriangleStrip`](wgpu::PrimitiveTopology::TriangleStrip) mesh,
+    /// the next index starts a new strip
+    pub const PRIMITIVE_RESTART: u32 = u32::MAX;
+
+    /// An indexed triangle list without any material
+    ///
+    /// ```
+    /// use bevy::math::{Vec2, Vec3};
+    /// use glace::mesh::{Mesh, Vertex};
+    ///
+    /// let mesh = Mesh::new(
+    ///     vec![
+    ///         Vertex::new(Vec3::ZERO, Vec3::Z, Vec2::ZERO),
+    ///         Vertex::new(Vec3::X, Vec3::Z, Vec2::X),
+    ///         Vertex::new(Vec3::Y, Vec3::Z, Vec2::Y),
+    ///     ],
+    ///     vec![0, 1, 2],
+    /// );
+    /// assert_eq!(mesh.stats().triangle_count, 1);
+    /// assert_eq!(mesh.topology, wgpu::PrimitiveTopology::TriangleList);
+    /// ```
+    #[allow(unused)]
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Self {
+        Self {
+            vertices,
+            indices: Some(indices),
+            material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+        }
+    }
+
     pub fn compute_normals(&mut self) {
         fn face_normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
             let (a, b, c) = (Vec3::from(a), Vec3::from(b), Vec3::from(c));
@@ -166,4 +306,858 @@ impl Mesh {
             todo!("tangents only computed for indexed meshes");
         }
     }
+
+    /// Merges identical vertices and remaps the indices to the merged vertices.
+    /// The attributes are quantized so vertices that only differ by floating point noise are merged.
+    /// Returns the number of removed vertices, non indexed meshes are left unchanged.
+    pub fn deduplicate_vertices(&mut self) -> usize {
+        fn quantize<const N: usize>(values: [f32; N]) -> [i64; N] {
+            values.map(|v| (v * 100_000.0).round() as i64)
+        }
+
+        let indices = if let Some(indices) = self.indices.as_mut() {
+            indices
+        } else {
+            return 0;
+        };
+
+        let mut unique = HashMap::new();
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        let remap: Vec<u32> = self
+            .vertices
+            .iter()
+            .map(|v| {
+                let key = (
+                    quantize(v.position.to_array()),
+                    quantize(v.normal.to_array()),
+                    quantize(v.uv.to_array()),
+                    quantize(v.tangent.to_array()),
+                    quantize(v.bitangent.to_array()),
+                    v.joints,
+                    quantize(v.weights),
+                );
+                *unique.entry(key).or_insert_with(|| {
+                    vertices.push(*v);
+                    vertices.len() as u32 - 1
+                })
+            })
+            .collect();
+
+        for index in indices.iter_mut() {
+            *index = remap[*index as usize];
+        }
+
+        let removed = self.vertices.len() - vertices.len();
+        self.vertices = vertices;
+        removed
+    }
+
+    /// Merges vertices closer than `position_epsilon` to each other and remaps the indices.
+    /// The merged vertex keeps the attributes of the first vertex but its normal is the average
+    /// of all the merged normals. Triangles that collapse to a line are removed.
+    ///
+    /// Unlike [`Mesh::deduplicate_vertices`] this ignores every attribute except the position,
+    /// use it to close the seams of a mesh before calling [`Mesh::compute_normals`].
+    /// Returns the number of removed vertices, non indexed meshes are left unchanged.
+    #[allow(unused)]
+    pub fn weld(&mut self, position_epsilon: f32) -> usize {
+        let indices = if let Some(indices) = self.indices.as_mut() {
+            indices
+        } else {
+            return 0;
+        };
+
+        // Vertices are bucketed in a grid of epsilon sized cells so only the neighbouring cells
+        // need to be checked
+        let cell_size = position_epsilon.max(f32::EPSILON);
+        let cell = |position: Vec3| (position / cell_size).floor().as_ivec3().to_array();
+
+        let mut grid: HashMap<[i32; 3], Vec<u32>> = HashMap::new();
+        let mut vertices: Vec<Vertex> = Vec::with_capacity(self.vertices.len());
+        let mut remap = Vec::with_capacity(self.vertices.len());
+        for v in &self.vertices {
+            let [x, y, z] = cell(v.position);
+            let mut existing = None;
+            'search: for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let candidates =
+                            if let Some(candidates) = grid.get(&[x + dx, y + dy, z + dz]) {
+                                candidates
+                            } else {
+                                continue;
+                            };
+                        for &i in candidates {
+                            if vertices[i as usize].position.distance(v.position)
+                                <= position_epsilon
+                            {
+                                existing = Some(i);
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+            }
+
+            let index = if let Some(i) = existing {
+                vertices[i as usize].normal += v.normal;
+                i
+            } else {
+                vertices.push(*v);
+                let i = vertices.len() as u32 - 1;
+                grid.entry([x, y, z]).or_default().push(i);
+                i
+            };
+            remap.push(index);
+        }
+
+        for v in vertices.iter_mut() {
+            v.normal = v.normal.normalize_or_zero();
+        }
+
+        if self.topology == wgpu::PrimitiveTopology::TriangleList {
+            let mut welded_indices = Vec::with_capacity(indices.len());
+            for triangle in indices.chunks_exact(3) {
+                let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| remap[i as usize]);
+                if a != b && b != c && a != c {
+                    welded_indices.extend([a, b, c]);
+                }
+            }
+            *indices = welded_indices;
+        } else {
+            for index in indices.iter_mut() {
+                *index = remap[*index as usize];
+            }
+        }
+
+        let removed = self.vertices.len() - vertices.len();
+        self.vertices = vertices;
+        removed
+    }
+
+    /// Bakes the matrix in the vertices, use [`Transform::compute_matrix`](bevy::transform::prelude::Transform::compute_matrix)
+    /// to apply a transform. The normals use the inverse transpose of the matrix so they stay
+    /// perpendicular to the surface with a non uniform scale, the tangents and bitangents follow
+    /// the surface so they use the matrix itself. The directions are normalized again afterwards.
+    /// A matrix that mirrors the mesh also flips the winding of the triangles so they keep facing outward.
+    #[allow(unused)]
+    pub fn transform(&mut self, matrix: &Mat4) {
+        let linear = Mat3::from_mat4(*matrix);
+        let normal_matrix = linear.inverse().transpose();
+        for v in &mut self.vertices {
+            v.position = matrix.transform_point3(v.position);
+            v.normal = (normal_matrix * v.normal).normalize_or_zero();
+            v.tangent = (linear * v.tangent).normalize_or_zero();
+            v.bitangent = (linear * v.bitangent).normalize_or_zero();
+        }
+
+        if linear.determinant() >= 0.0 || self.topology != wgpu::PrimitiveTopology::TriangleList {
+            return;
+        }
+        if let Some(indices) = self.indices.as_mut() {
+            for triangle in indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        } else {
+            for triangle in self.vertices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+    }
+
+    /// Flips the triangles facing toward the centroid of the mesh so every triangle faces outward.
+    /// This assumes the mesh is roughly convex, concave parts can end up flipped the wrong way.
+    /// Returns the number of flipped triangles, only triangle lists are changed.
+    #[allow(unused)]
+    pub fn fix_winding(&mut self) -> usize {
+        if self.topology != wgpu::PrimitiveTopology::TriangleList || self.vertices.is_empty() {
+            return 0;
+        }
+
+        let centroid = self
+            .vertices
+            .iter()
+            .fold(Vec3::ZERO, |sum, v| sum + v.position)
+            / self.vertices.len() as f32;
+        let faces_inward = |[a, b, c]: [Vec3; 3]| {
+            let normal = (b - a).cross(c - a);
+            let outward = (a + b + c) / 3.0 - centroid;
+            normal.dot(outward) < 0.0
+        };
+
+        let mut flipped = 0;
+        if let Some(indices) = self.indices.as_mut() {
+            for triangle in indices.chunks_exact_mut(3) {
+                let positions = [triangle[0], triangle[1], triangle[2]]
+                    .map(|i| self.vertices[i as usize].position);
+                if faces_inward(positions) {
+                    triangle.swap(1, 2);
+                    flipped += 1;
+                }
+            }
+        } else {
+            for triangle in self.vertices.chunks_exact_mut(3) {
+                if faces_inward([
+                    triangle[0].position,
+                    triangle[1].position,
+                    triangle[2].position,
+                ]) {
+                    triangle.swap(1, 2);
+                    flipped += 1;
+                }
+            }
+        }
+        flipped
+    }
+
+    /// Reduces the number of triangles toward `target_ratio` of the current count by collapsing
+    /// the edges that change the shape the least, measured with quadric error metrics.
+    /// Use it to generate lower detail versions of dense meshes.
+    ///
+    /// Vertices on the border of an open mesh or on a seam, where vertices share a position
+    /// but not their other attributes, are never removed so the mesh doesn't tear.
+    /// Collapses that would flip a triangle or make the mesh non manifold are skipped,
+    /// which can stop the simplification before reaching the target.
+    /// A vertex is always collapsed into one of its neighbours which keeps its attributes,
+    /// call [`Mesh::deduplicate_vertices`] first if the triangles don't share their vertices.
+    /// Returns the number of removed triangles, only indexed triangle lists are simplified.
+    #[allow(unused)]
+    pub fn simplify(&mut self, target_ratio: f32) -> usize {
+        if self.topology != wgpu::PrimitiveTopology::TriangleList {
+            return 0;
+        }
+        let indices = if let Some(indices) = &self.indices {
+            indices
+        } else {
+            return 0;
+        };
+
+        let mut triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+        let triangle_count = triangles.len();
+        let target = (triangle_count as f32 * target_ratio.clamp(0.0, 1.0)).round() as usize;
+        if target >= triangle_count {
+            return 0;
+        }
+        let vertex_count = self.vertices.len();
+        // Collapsed vertices take the position of the kept vertex so positions never change
+        let positions: Vec<Vec3> = self.vertices.iter().map(|v| v.position).collect();
+        let position = |i: u32| positions[i as usize];
+
+        let mut vertex_triangles = vec![vec![]; vertex_count];
+        let mut quadrics = vec![Quadric::default(); vertex_count];
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for (t, triangle) in triangles.iter().enumerate() {
+            let quadric = Quadric::from_triangle(triangle.map(position));
+            for k in 0..3 {
+                let (a, b) = (triangle[k], triangle[(k + 1) % 3]);
+                vertex_triangles[a as usize].push(t);
+                quadrics[a as usize] = quadrics[a as usize].add(&quadric);
+                *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+
+        let mut locked = vec![false; vertex_count];
+        // Edges that aren't shared by exactly 2 triangles are on a border or already non manifold
+        for (&(a, b), &count) in &edges {
+            if count != 2 {
+                locked[a as usize] = true;
+                locked[b as usize] = true;
+            }
+        }
+        let mut seams = HashMap::new();
+        for (i, p) in positions.iter().enumerate() {
+            if let Some(&other) = seams.get(&p.to_array().map(f32::to_bits)) {
+                locked[i] = true;
+                locked[other] = true;
+            } else {
+                seams.insert(p.to_array().map(f32::to_bits), i);
+            }
+        }
+
+        // The cheapest collapse of an edge, the versions of the vertices invalidate it once they change.
+        // The costs are never negative so their bits can be compared instead of the floats.
+        let collapse = |a: u32, b: u32, quadrics: &[Quadric], versions: &[u32]| {
+            let quadric = quadrics[a as usize].add(&quadrics[b as usize]);
+            [(a, b), (b, a)]
+                .into_iter()
+                .filter(|(from, _)| !locked[*from as usize])
+                .map(|(from, to)| {
+                    let cost = quadric.error(position(to)).max(0.0);
+                    let versions = (versions[from as usize], versions[to as usize]);
+                    Reverse((cost.to_bits(), from, to, versions))
+                })
+                .min()
+        };
+
+        let mut versions = vec![0; vertex_count];
+        let mut heap: BinaryHeap<_> = edges
+            .keys()
+            .filter_map(|&(a, b)| collapse(a, b, &quadrics, &versions))
+            .collect();
+        let mut alive = vec![true; triangle_count];
+        let mut remaining = triangle_count;
+
+        while remaining > target {
+            let (from, to, collapse_versions) = if let Some(Reverse((_, from, to, v))) = heap.pop()
+            {
+                (from as usize, to as usize, v)
+            } else {
+                break;
+            };
+            if collapse_versions != (versions[from], versions[to]) {
+                continue;
+            }
+
+            let neighbours = |v: usize| -> HashSet<u32> {
+                vertex_triangles[v]
+                    .iter()
+                    .filter(|&&t| alive[t])
+                    .flat_map(|&t| triangles[t])
+                    .filter(|&i| i as usize != v)
+                    .collect()
+            };
+            let shared_triangles = vertex_triangles[from]
+                .iter()
+                .filter(|&&t| alive[t] && triangles[t].contains(&(to as u32)))
+                .count();
+            // Any other shared neighbour would end up with a duplicated edge
+            let shared_neighbours = neighbours(from).intersection(&neighbours(to)).count();
+            if shared_triangles == 0 || shared_neighbours != shared_triangles {
+                continue;
+            }
+
+            let flips = vertex_triangles[from]
+                .iter()
+                .filter(|&&t| alive[t] && !triangles[t].contains(&(to as u32)))
+                .any(|&t| {
+                    let [a, b, c] = triangles[t].map(position);
+                    let [d, e, f] = triangles[t]
+                        .map(|i| if i as usize == from { to as u32 } else { i })
+                        .map(position);
+                    let normal = (b - a).cross(c - a);
+                    let collapsed_normal = (e - d).cross(f - d);
+                    normal.dot(collapsed_normal) <= 0.0
+                });
+            if flips {
+                continue;
+            }
+
+            for t in std::mem::take(&mut vertex_triangles[from]) {
+                if !alive[t] {
+                    continue;
+                }
+                if triangles[t].contains(&(to as u32)) {
+                    alive[t] = false;
+                    remaining -= 1;
+                } else {
+                    for i in &mut triangles[t] {
+                        if *i as usize == from {
+                            *i = to as u32;
+                        }
+                    }
+                    vertex_triangles[to].push(t);
+                }
+            }
+            vertex_triangles[to].retain(|&t| alive[t]);
+            quadrics[to] = quadrics[to].add(&quadrics[from]);
+            // The collapses of the removed vertex are never valid again
+            versions[from] += 1;
+            versions[to] += 1;
+
+            let neighbours: HashSet<u32> = vertex_triangles[to]
+                .iter()
+                .flat_map(|&t| triangles[t])
+                .filter(|&i| i as usize != to)
+                .collect();
+            for neighbour in neighbours {
+                if let Some(collapse) = collapse(to as u32, neighbour, &quadrics, &versions) {
+                    heap.push(collapse);
+                }
+            }
+        }
+
+        // Only keep the vertices still used by a triangle
+        let mut remap = vec![None; vertex_count];
+        let mut vertices = vec![];
+        let mut indices = Vec::with_capacity(remaining * 3);
+        for (triangle, _) in triangles.iter().zip(&alive).filter(|(_, alive)| **alive) {
+            for &i in triangle {
+                let index = *remap[i as usize].get_or_insert_with(|| {
+                    vertices.push(self.vertices[i as usize]);
+                    vertices.len() as u32 - 1
+                });
+                indices.push(index);
+            }
+        }
+        self.vertices = vertices;
+        self.indices = Some(indices);
+        triangle_count - remaining
+    }
+
+    /// Counts the vertices and triangles and checks the attributes of the mesh
+    pub fn stats(&self) -> MeshStats {
+        let positions =
+            |[a, b, c]: [usize; 3]| [a, b, c].map(|i| self.vertices.get(i).map(|v| v.position));
+        // Compares the area to the length of the edges so it doesn't depend on the scale of the mesh
+        let is_degenerate = |triangle: [usize; 3]| match positions(triangle) {
+            [Some(a), Some(b), Some(c)] => {
+                let (ab, ac) = (b - a, c - a);
+                ab.cross(ac).length_squared()
+                    <= f32::EPSILON * ab.length_squared() * ac.length_squared()
+            }
+            // Out of bounds indices can't be drawn either
+            _ => true,
+        };
+
+        let (triangle_count, degenerate_triangles) =
+            if self.topology != wgpu::PrimitiveTopology::TriangleList {
+                (0, 0)
+            } else if let Some(indices) = &self.indices {
+                let triangles = indices.chunks_exact(3);
+                let count = triangles.len();
+                let degenerate = triangles
+                    .filter(|t| is_degenerate([t[0] as usize, t[1] as usize, t[2] as usize]))
+                    .count();
+                (count, degenerate)
+            } else {
+                let count = self.vertices.len() / 3;
+                let degenerate = (0..count)
+                    .filter(|i| is_degenerate([i * 3, i * 3 + 1, i * 3 + 2]))
+                    .count();
+                (count, degenerate)
+            };
+
+        let is_normalized = |v: Vec3| (v.length() - 1.0).abs() < 1e-3;
+        let has_normals = self.vertices.iter().any(|v| v.normal != Vec3::ZERO);
+        let has_tangents = self.vertices.iter().any(|v| v.tangent != Vec3::ZERO);
+        MeshStats {
+            vertex_count: self.vertices.len(),
+            triangle_count,
+            bounds: Aabb::from_points(self.vertices.iter().map(|v| v.position)),
+            degenerate_triangles,
+            has_normals,
+            has_uvs: self.vertices.iter().any(|v| v.uv != Vec2::ZERO),
+            has_tangents,
+            normals_normalized: has_normals
+                && self.vertices.iter().all(|v| is_normalized(v.normal)),
+            tangents_normalized: has_tangents
+                && self.vertices.iter().all(|v| is_normalized(v.tangent)),
+        }
+    }
+}
+
+/// The sum of the squared distances to a set of planes, see [`Mesh::simplify`].
+/// Only the upper half of the symmetric 4x4 matrix is stored.
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// The plane of the triangle weighted by its area so small triangles matter less
+    fn from_triangle([a, b, c]: [Vec3; 3]) -> Self {
+        let cross = (b - a).cross(c - a).as_dvec3();
+        let length = cross.length();
+        if length <= 0.0 {
+            return Self::default();
+        }
+        let normal = cross / length;
+        let d = -normal.dot(a.as_dvec3());
+        let [x, y, z] = normal.to_array();
+        let area = length * 0.5;
+        Self(
+            [
+                x * x,
+                x * y,
+                x * z,
+                x * d,
+                y * y,
+                y * z,
+                y * d,
+                z * z,
+                z * d,
+                d * d,
+            ]
+            .map(|v| v * area),
+        )
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let mut sum = *self;
+        for (a, b) in sum.0.iter_mut().zip(other.0) {
+            *a += b;
+        }
+        sum
+    }
+
+    fn error(&self, point: Vec3) -> f64 {
+        let [xx, xy, xz, xd, yy, yz, yd, zz, zd, dd] = self.0;
+        let [x, y, z] = point.as_dvec3().to_array();
+        xx * x * x
+            + yy * y * y
+            + zz * z * z
+            + dd
+            + 2.0 * (xy * x * y + xz * x * z + yz * y * z + xd * x + yd * y + zd * z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Quat;
+
+    use super::*;
+    use crate::shapes::{cube::Cube, sphere::UVSphere};
+
+    fn cube() -> Mesh {
+        Cube::new(1.0, 1.0, 1.0).to_mesh()
+    }
+
+    /// The positions of the corners of every triangle
+    fn triangle_positions(mesh: &Mesh) -> Vec<Vec3> {
+        let indices = mesh.indices.as_ref().unwrap();
+        indices
+            .iter()
+            .map(|&i| mesh.vertices[i as usize].position)
+            .collect()
+    }
+
+    #[test]
+    fn deduplicate_keeps_split_normals() {
+        let mut mesh = cube();
+        assert_eq!(mesh.deduplicate_vertices(), 0);
+        assert_eq!(mesh.vertices.len(), 24);
+    }
+
+    #[test]
+    fn deduplicate_shared_corners() {
+        let mut mesh = cube();
+        for v in &mut mesh.vertices {
+            v.normal = Vec3::Y;
+            v.uv = Vec2::ZERO;
+        }
+        let positions = triangle_positions(&mesh);
+
+        assert_eq!(mesh.deduplicate_vertices(), 16);
+        assert_eq!(mesh.vertices.len(), 8);
+        assert_eq!(mesh.indices.as_ref().unwrap().len(), 36);
+        assert_eq!(triangle_positions(&mesh), positions);
+    }
+
+    #[test]
+    fn weld_cube_corners() {
+        let mut mesh = cube();
+        // Offsets smaller than the epsilon, like the cracks of an imported mesh
+        for (i, v) in mesh.vertices.iter_mut().enumerate() {
+            v.position += Vec3::splat(i as f32 * 1e-5);
+        }
+
+        assert_eq!(mesh.weld(1e-3), 16);
+        assert_eq!(mesh.vertices.len(), 8);
+        assert_eq!(mesh.indices.as_ref().unwrap().len(), 36);
+        for v in &mesh.vertices {
+            // Each corner averages the normals of its 3 faces
+            let expected = v.position.signum() / 3f32.sqrt();
+            assert!(v.normal.abs_diff_eq(expected, 1e-5), "{}", v.normal);
+        }
+    }
+
+    #[test]
+    fn weld_drops_collapsed_triangles() {
+        let mut mesh = Mesh::new(
+            vec![
+                Vertex::new(Vec3::ZERO, Vec3::Z, Vec2::ZERO),
+                Vertex::new(Vec3::X, Vec3::Z, Vec2::ZERO),
+                Vertex::new(Vec3::Y, Vec3::Z, Vec2::ZERO),
+                // Closer than the epsilon to the first vertex
+                Vertex::new(Vec3::splat(0.01), Vec3::Z, Vec2::ZERO),
+            ],
+            vec![0, 1, 2, 3, 0, 2],
+        );
+
+        assert_eq!(mesh.weld(0.1), 1);
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.indices, Some(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn weld_keeps_distant_vertices() {
+        let mut mesh = Mesh::new(
+            vec![
+                Vertex::new(Vec3::ZERO, Vec3::Z, Vec2::ZERO),
+                Vertex::new(Vec3::X * 0.2, Vec3::Z, Vec2::ZERO),
+                Vertex::new(Vec3::Y * 0.2, Vec3::Z, Vec2::ZERO),
+            ],
+            vec![0, 1, 2],
+        );
+        assert_eq!(mesh.weld(0.1), 0);
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.indices, Some(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn fix_winding_flipped_face() {
+        let mut mesh = cube();
+        let indices = mesh.indices.clone();
+        mesh.indices.as_mut().unwrap()[..3].swap(1, 2);
+
+        assert_eq!(mesh.fix_winding(), 1);
+        assert_eq!(mesh.indices, indices);
+        assert_eq!(mesh.fix_winding(), 0);
+    }
+
+    #[test]
+    fn fix_winding_non_convex() {
+        // Two cubes side by side, the faces looking at the other cube face the centroid
+        let mut mesh = cube();
+        mesh.transform(&Mat4::from_translation(Vec3::X * -5.0));
+        let mut other = cube();
+        other.transform(&Mat4::from_translation(Vec3::X * 5.0));
+        let offset = mesh.vertices.len() as u32;
+        mesh.vertices.extend(other.vertices);
+        mesh.indices
+            .as_mut()
+            .unwrap()
+            .extend(other.indices.unwrap().iter().map(|i| i + offset));
+
+        // The right face of the first cube and the left face of the second are wrongly flipped
+        assert_eq!(mesh.fix_winding(), 4);
+    }
+
+    #[test]
+    fn fix_winding_open_mesh() {
+        // Every triangle of a flat mesh is in the plane of the centroid so none of them can be fixed
+        let mut mesh = Mesh::new(
+            vec![
+                Vertex::new(Vec3::new(-1.0, 0.0, -1.0), Vec3::Y, Vec2::ZERO),
+                Vertex::new(Vec3::new(1.0, 0.0, -1.0), Vec3::Y, Vec2::ZERO),
+                Vertex::new(Vec3::new(1.0, 0.0, 1.0), Vec3::Y, Vec2::ZERO),
+                Vertex::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::Y, Vec2::ZERO),
+            ],
+            // The second triangle faces down
+            vec![0, 2, 1, 0, 2, 3],
+        );
+        assert_eq!(mesh.fix_winding(), 0);
+    }
+
+    #[test]
+    fn stats_cube() {
+        let stats = cube().stats();
+        assert_eq!(stats.vertex_count, 24);
+        assert_eq!(stats.triangle_count, 12);
+        assert_eq!(stats.degenerate_triangles, 0);
+        assert_eq!(
+            stats.bounds,
+            Some(Aabb::new(Vec3::splat(-0.5), Vec3::splat(0.5)))
+        );
+        assert!(stats.has_normals && stats.normals_normalized);
+        assert!(stats.has_uvs);
+        assert!(!stats.has_tangents && !stats.tangents_normalized);
+    }
+
+    #[test]
+    fn stats_degenerate_triangles() {
+        let mesh = Mesh::new(
+            vec![
+                Vertex::new(Vec3::ZERO, Vec3::Z * 2.0, Vec2::ZERO),
+                Vertex::new(Vec3::X, Vec3::Z * 2.0, Vec2::ZERO),
+                Vertex::new(Vec3::Y, Vec3::Z * 2.0, Vec2::ZERO),
+                // On the line between the first 2 vertices
+                Vertex::new(Vec3::X * 0.5, Vec3::Z * 2.0, Vec2::ZERO),
+            ],
+            // A valid triangle, a line, a point and an index out of bounds
+            vec![0, 1, 2, 0, 3, 1, 2, 2, 2, 0, 1, 4],
+        );
+
+        let stats = mesh.stats();
+        assert_eq!(stats.vertex_count, 4);
+        assert_eq!(stats.triangle_count, 4);
+        assert_eq!(stats.degenerate_triangles, 3);
+        assert_eq!(
+            stats.bounds,
+            Some(Aabb::new(Vec3::ZERO, Vec3::new(1.0, 1.0, 0.0)))
+        );
+        assert!(stats.has_normals && !stats.normals_normalized);
+        assert!(!stats.has_uvs);
+    }
+
+    #[test]
+    fn stats_empty() {
+        let stats = Mesh::new(vec![], vec![]).stats();
+        assert_eq!(stats.vertex_count, 0);
+        assert_eq!(stats.triangle_count, 0);
+        assert_eq!(stats.bounds, None);
+    }
+
+    #[test]
+    fn transform_cube() {
+        let mut mesh = cube();
+        let rotation = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        mesh.transform(&Mat4::from_rotation_translation(
+            rotation,
+            Vec3::new(1.0, 2.0, 3.0),
+        ));
+
+        // The first vertex is the corner (-0.5, -0.5, 0.5) of the +Z face
+        let v = mesh.vertices[0];
+        assert!(
+            v.position.abs_diff_eq(Vec3::new(1.5, 1.5, 3.5), 1e-5),
+            "{}",
+            v.position
+        );
+        assert!(v.normal.abs_diff_eq(Vec3::X, 1e-5), "{}", v.normal);
+    }
+
+    #[test]
+    fn transform_non_uniform_scale() {
+        // A triangle with a diagonal normal
+        let normal = Vec3::ONE.normalize();
+        let mut mesh = Mesh::new(
+            vec![
+                Vertex::new(Vec3::X, normal, Vec2::ZERO),
+                Vertex::new(Vec3::Y, normal, Vec2::ZERO),
+                Vertex::new(Vec3::Z, normal, Vec2::ZERO),
+            ],
+            vec![0, 1, 2],
+        );
+        mesh.transform(&Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0)));
+
+        // Scaling the normal like the positions would tilt it toward the X axis
+        let [a, b, c] = [0, 1, 2].map(|i| mesh.vertices[i].position);
+        let face_normal = (b - a).cross(c - a).normalize();
+        assert!(face_normal.abs_diff_eq(Vec3::new(0.5, 1.0, 1.0).normalize(), 1e-5));
+        for v in &mesh.vertices {
+            assert!(v.normal.abs_diff_eq(face_normal, 1e-5), "{}", v.normal);
+        }
+    }
+
+    #[test]
+    fn transform_mirror_flips_winding() {
+        let mut mesh = cube();
+        mesh.transform(&Mat4::from_scale(Vec3::new(-1.0, 1.0, 1.0)));
+
+        let positions = triangle_positions(&mesh);
+        let indices = mesh.indices.as_ref().unwrap();
+        for (triangle, indices) in positions.chunks_exact(3).zip(indices.chunks_exact(3)) {
+            let face_normal = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+            let normal = mesh.vertices[indices[0] as usize].normal;
+            assert!(face_normal.dot(normal) > 0.0, "{face_normal} {normal}");
+        }
+        assert_eq!(mesh.fix_winding(), 0);
+    }
+
+    /// A flat square of `size` by `size` quads
+    fn grid(size: u32) -> Mesh {
+        let mut vertices = vec![];
+        for y in 0..=size {
+            for x in 0..=size {
+                let position = Vec3::new(x as f32, y as f32, 0.0);
+                vertices.push(Vertex::new(position, Vec3::Z, position.truncate()));
+            }
+        }
+        let mut indices = vec![];
+        for y in 0..size {
+            for x in 0..size {
+                let i = y * (size + 1) + x;
+                let j = i + size + 1;
+                indices.extend([i, i + 1, j + 1, j + 1, j, i]);
+            }
+        }
+        Mesh::new(vertices, indices)
+    }
+
+    /// The volume enclosed by a closed mesh
+    fn volume(mesh: &Mesh) -> f32 {
+        triangle_positions(mesh)
+            .chunks_exact(3)
+            .map(|t| t[0].dot(t[1].cross(t[2])) / 6.0)
+            .sum()
+    }
+
+    #[test]
+    fn simplify_sphere() {
+        let mut mesh = UVSphere::default().to_mesh();
+        let triangles = mesh.stats().triangle_count;
+        let original_volume = volume(&mesh);
+        let original_bounds = mesh.stats().bounds.unwrap();
+
+        let removed = mesh.simplify(0.5);
+        let stats = mesh.stats();
+        assert_eq!(stats.triangle_count, triangles - removed);
+        assert!(stats.triangle_count <= triangles * 6 / 10, "{stats}");
+        assert_eq!(stats.degenerate_triangles, 0);
+        assert!((volume(&mesh) / original_volume - 1.0).abs() < 0.1);
+        let bounds = stats.bounds.unwrap();
+        assert!(bounds.min.abs_diff_eq(original_bounds.min, 0.05));
+        assert!(bounds.max.abs_diff_eq(original_bounds.max, 0.05));
+
+        // Every edge is still shared by at most 2 triangles
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for t in mesh.indices.as_ref().unwrap().chunks_exact(3) {
+            for k in 0..3 {
+                let (a, b) = (t[k], t[(k + 1) % 3]);
+                *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        assert!(edges.values().all(|&count| count <= 2));
+    }
+
+    #[test]
+    fn simplify_keeps_seams() {
+        let sphere = UVSphere::default();
+        let mut mesh = sphere.to_mesh();
+        // The first and last vertex of each stack share their position but not their uvs.
+        // The poles are skipped, one of their vertices isn't used by any triangle.
+        let seam: Vec<Vec3> = mesh
+            .vertices
+            .chunks_exact(sphere.sectors + 1)
+            .skip(1)
+            .take(sphere.stacks - 1)
+            .flat_map(|stack| [stack[0].position, stack[sphere.sectors].position])
+            .collect();
+
+        assert!(mesh.simplify(0.0) > 0);
+        for position in seam {
+            assert!(
+                mesh.vertices.iter().any(|v| v.position == position),
+                "{position}"
+            );
+        }
+    }
+
+    #[test]
+    fn simplify_keeps_borders() {
+        let mut mesh = grid(8);
+        let border: Vec<Vec3> = mesh
+            .vertices
+            .iter()
+            .map(|v| v.position)
+            .filter(|p| p.x == 0.0 || p.y == 0.0 || p.x == 8.0 || p.y == 8.0)
+            .collect();
+
+        // A flat mesh can be simplified without any error
+        let removed = mesh.simplify(0.0);
+        assert!(removed > 0);
+        assert_eq!(mesh.vertices.len(), border.len());
+        for position in border {
+            assert!(mesh.vertices.iter().any(|v| v.position == position));
+        }
+        // The triangles still cover the whole square
+        let area: f32 = triangle_positions(&mesh)
+            .chunks_exact(3)
+            .map(|t| (t[1] - t[0]).cross(t[2] - t[0]).z * 0.5)
+            .sum();
+        assert!((area - 64.0).abs() < 1e-3, "{area}");
+    }
+
+    #[test]
+    fn simplify_full_ratio() {
+        let mut mesh = UVSphere::default().to_mesh();
+        let vertices = mesh.vertices.len();
+        let indices = mesh.indices.clone();
+
+        assert_eq!(mesh.simplify(1.0), 0);
+        assert_eq!(mesh.vertices.len(), vertices);
+        assert_eq!(mesh.indices, indices);
+    }
 }
@@ -1,9 +1,22 @@
 use crate::{
-    image_utils::image_from_color, mesh::Mesh, renderer::bind_groups::material::GpuModelMaterials,
+    image_utils::{image_from_bytes, image_from_color},
+    instances::GpuIndirectInstances,
+    mesh::{Mesh, MeshStats, PositionVertex, Vertex},
+    renderer::{
+        bind_groups::material::{create_gpu_materials, GpuModelMaterials},
+        WgpuRenderer,
+    },
 };
-use bevy::{ecs::prelude::*, math::prelude::*, render::color::Color};
+use anyhow::Context;
+use bevy::{
+    ecs::prelude::*,
+    math::prelude::*,
+    render::color::Color,
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use futures_lite::future;
 use image::RgbaImage;
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 use wgpu::util::DeviceExt;
 
 #[derive(Component)]
@@ -13,20 +26,99 @@ pub struct Model {
 }
 
 impl Model {
+    /// Creates a model with a single triangle list mesh from raw data.
+    /// Use this for geometry generated at runtime that doesn't come from an obj or gltf file.
+    ///
+    /// ```no_run
+    /// use bevy::prelude::*;
+    /// use glace::{mesh::Vertex, model::Material, prelude::*};
+    ///
+    /// fn spawn_triangle(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    ///     let vertices = [
+    ///         Vertex::new(Vec3::ZERO, Vec3::Z, Vec2::ZERO),
+    ///         Vertex::new(Vec3::X, Vec3::Z, Vec2::X),
+    ///         Vertex::new(Vec3::Y, Vec3::Z, Vec2::Y),
+    ///     ];
+    ///     let material = Material::from_color(Color::ORANGE);
+    ///     let triangle = Model::from_raw(&renderer.device, &vertices, &[0, 1, 2], material);
+    ///     commands.spawn((triangle, Transform::default()));
+    /// }
+    /// ```
+    #[allow(unused)]
+    pub fn from_raw(
+        device: &wgpu::Device,
+        vertices: &[Vertex],
+        indices: &[u32],
+        material: Material,
+    ) -> Self {
+        let mesh = Mesh::new(vertices.to_vec(), indices.to_vec());
+        Self {
+            meshes: vec![ModelMesh::from_mesh("raw", device, &mesh)],
+            materials: vec![material],
+        }
+    }
+
+    /// Creates a new Model that shares the gpu buffers of this one.
+    /// Use this to spawn multiple copies of the same model without duplicating the meshes in VRAM.
+    #[allow(unused)]
+    pub fn clone_gpu(&self) -> Self {
+        Self {
+            meshes: self.meshes.clone(),
+            materials: self.materials.clone(),
+        }
+    }
+
+    /// Decodes the bytes of an image and uses it as the diffuse texture of a material.
+    /// It needs to be called before the model is spawned, the textures are uploaded when it's added.
+    #[allow(unused)]
+    pub fn with_texture_bytes(
+        mut self,
+        material_index: usize,
+        bytes: &[u8],
+        format: Option<image::ImageFormat>,
+    ) -> anyhow::Result<Self> {
+        self.material_mut(material_index)?.diffuse_texture = image_from_bytes(bytes, format)
+            .with_context(|| format!("Failed to decode the diffuse texture of {material_index}"))?;
+        Ok(self)
+    }
+
+    /// Like [`Model::with_texture_bytes`] but for the normal map
+    #[allow(unused)]
+    pub fn with_normal_texture_bytes(
+        mut self,
+        material_index: usize,
+        bytes: &[u8],
+        format: Option<image::ImageFormat>,
+    ) -> anyhow::Result<Self> {
+        self.material_mut(material_index)?.normal_texture =
+            Some(image_from_bytes(bytes, format).with_context(|| {
+                format!("Failed to decode the normal texture of {material_index}")
+            })?);
+        Ok(self)
+    }
+
+    fn material_mut(&mut self, material_index: usize) -> anyhow::Result<&mut Material> {
+        let count = self.materials.len();
+        self.materials.get_mut(material_index).with_context(|| {
+            format!("Material {material_index} doesn't exist, the model has {count} materials")
+        })
+    }
+
+    /// Draws the meshes using a material with the given blend mode
     #[allow(unused)]
     pub fn draw<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
         gpu_materials: &'a GpuModelMaterials,
         mesh_view_bind_group: &'a wgpu::BindGroup,
-        transparent: bool,
+        blend_mode: BlendMode,
     ) {
         self.draw_instanced(
             render_pass,
             0..1,
             gpu_materials,
             mesh_view_bind_group,
-            transparent,
+            blend_mode,
         );
     }
 
@@ -36,27 +128,53 @@ impl Model {
         instances: Range<u32>,
         gpu_materials: &'a GpuModelMaterials,
         mesh_view_bind_group: &'a wgpu::BindGroup,
-        transparent: bool,
+        blend_mode: BlendMode,
     ) {
-        for mesh in &self.meshes {
+        for (mesh_index, mesh) in self.meshes.iter().enumerate() {
             // TODO get data from Handle
             // TODO handle material_id == None
-            let material = &gpu_materials.data[mesh.material_id.unwrap_or(0)];
+            let (material, material_bind_group, material_offset) =
+                gpu_materials.mesh_material(self, mesh_index);
 
-            if transparent && material.0.alpha < 1.0 {
+            let mesh_blend_mode = material.blend_mode;
+            // Lines and points need a different pipeline
+            let is_triangle_list = mesh.topology == wgpu::PrimitiveTopology::TriangleList;
+            if blend_mode == mesh_blend_mode && is_triangle_list {
                 mesh.draw_instanced(
                     render_pass,
                     instances.clone(),
-                    &material.2,
+                    material_bind_group,
+                    material_offset,
                     mesh_view_bind_group,
                 );
             }
+        }
+    }
 
-            if !transparent && material.0.alpha == 1.0 {
-                mesh.draw_instanced(
+    /// Like [`Model::draw_instanced`] but the instance count of each mesh comes from the draw args
+    /// written by the cull pass of [`IndirectInstances`](crate::instances::IndirectInstances)
+    pub fn draw_indirect<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        indirect: &'a GpuIndirectInstances,
+        gpu_materials: &'a GpuModelMaterials,
+        mesh_view_bind_group: &'a wgpu::BindGroup,
+        blend_mode: BlendMode,
+    ) {
+        render_pass.set_vertex_buffer(1, indirect.instance_buffer.slice(..));
+        for (mesh_index, mesh) in self.meshes.iter().enumerate() {
+            let (material, material_bind_group, material_offset) =
+                gpu_materials.mesh_material(self, mesh_index);
+
+            let mesh_blend_mode = material.blend_mode;
+            let is_triangle_list = mesh.topology == wgpu::PrimitiveTopology::TriangleList;
+            if blend_mode == mesh_blend_mode && is_triangle_list {
+                mesh.draw_indirect(
                     render_pass,
-                    instances.clone(),
-                    &material.2,
+                    &indirect.args_buffer,
+                    GpuIndirectInstances::args_offset(mesh_index),
+                    material_bind_group,
+                    material_offset,
                     mesh_view_bind_group,
                 );
             }
@@ -64,16 +182,184 @@ impl Model {
     }
 }
 
+/// Controls which parts of the gltf and obj loaders are spread on the `IoTaskPool`.
+/// Disabling them forces single threaded loads, for systems with few cores or to debug a loader.
+/// The loaders read this when the plugins are built so it needs to be inserted before them.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoaderSettings {
+    /// Generates the meshes in parallel, this includes the obj deduplication and winding fix
+    pub parallel_meshes: bool,
+    /// Loads and decodes the textures in parallel
+    pub parallel_textures: bool,
+    /// Uploads a [`ModelMesh::position_buffer`] for every mesh, useful with the depth prepass or the shadows.
+    /// Unlike the other settings it's read every time a model is uploaded.
+    pub position_buffers: bool,
+}
+
+impl Default for LoaderSettings {
+    fn default() -> Self {
+        Self {
+            parallel_meshes: true,
+            parallel_textures: true,
+            position_buffers: false,
+        }
+    }
+}
+
+impl LoaderSettings {
+    /// Used in the timing logs of the loaders to compare both modes
+    pub(crate) fn mode(parallel: bool) -> &'static str {
+        if parallel {
+            "parallel"
+        } else {
+            "serial"
+        }
+    }
+}
+
+/// A model whose gpu resources are being created on the [`AsyncComputeTaskPool`].
+/// Uploading big models on the main thread makes the frame hitch,
+/// the [`Model`] and its [`GpuModelMaterials`] are only inserted once everything is uploaded.
+#[derive(Component)]
+pub struct PendingModel(Task<(Model, GpuModelMaterials)>);
+
+impl PendingModel {
+    /// Uploads the meshes and materials in a task.
+    /// The meshes are only uploaded when no `cached_meshes` are given,
+    /// with a position buffer when `position_buffers` is true.
+    pub fn spawn(
+        renderer: &WgpuRenderer,
+        label: &'static str,
+        cached_meshes: Option<Vec<ModelMesh>>,
+        meshes: Vec<Mesh>,
+        materials: Vec<Material>,
+        position_buffers: bool,
+    ) -> Self {
+        let device = renderer.device.clone();
+        let queue = renderer.queue.clone();
+        let errors = renderer.errors.clone();
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let meshes = cached_meshes.unwrap_or_else(|| {
+                // TODO mesh label
+                meshes
+                    .iter()
+                    .filter_map(|mesh| match ModelMesh::try_from_mesh("", &device, mesh) {
+                        Ok(model_mesh) if position_buffers => {
+                            Some(model_mesh.with_position_buffer(&device, mesh))
+                        }
+                        Ok(mesh) => Some(mesh),
+                        Err(err) => {
+                            log::error!("Failed to spawn {label} mesh: {err}");
+                            None
+                        }
+                    })
+                    .collect()
+            });
+            let gpu_materials = create_gpu_materials(&device, &queue, &errors, &materials, None);
+            (Model { meshes, materials }, gpu_materials)
+        });
+        Self(task)
+    }
+
+    /// Returns the model once the task is done
+    pub fn poll(&mut self) -> Option<(Model, GpuModelMaterials)> {
+        future::block_on(future::poll_once(&mut self.0))
+    }
+}
+
+/// Controls how a material is blended with what was already rendered
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    /// Not blended, drawn in the opaque phase
+    #[default]
+    Opaque,
+    /// Opaque, but the fragments with an alpha under the `alpha_cutoff` of the material are discarded.
+    /// Useful for foliage and fences. When msaa is enabled the alpha is converted to coverage
+    /// which smooths the edges, without msaa it's a hard alpha test.
+    Mask,
+    /// Standard alpha blending, useful for glass
+    Alpha,
+    /// Adds the color to the destination, useful for particles and glowing effects
+    Additive,
+    /// Multiplies the destination by the color, useful for tinting
+    Multiply,
+    /// Blends each channel with the `blend_constant` of the material instead of the alpha,
+    /// the destination is multiplied by the constant and the color by one minus the constant.
+    /// Useful for tinted glass where each channel lets a different amount of the background through.
+    /// A per pixel tint would need dual source blending which isn't supported by wgpu 0.16.
+    Constant,
+}
+
+impl BlendMode {
+    /// `premultiplied_alpha` is true when the color has already been multiplied by the alpha
+    pub fn blend_state(&self, premultiplied_alpha: bool) -> wgpu::BlendState {
+        match self {
+            BlendMode::Opaque | BlendMode::Mask => wgpu::BlendState::REPLACE,
+            BlendMode::Alpha if premultiplied_alpha => {
+                wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING
+            }
+            BlendMode::Alpha => wgpu::BlendState::ALPHA_BLENDING,
+            BlendMode::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: if premultiplied_alpha {
+                        wgpu::BlendFactor::One
+                    } else {
+                        wgpu::BlendFactor::SrcAlpha
+                    },
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent::OVER,
+            },
+            BlendMode::Multiply => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Dst,
+                    dst_factor: wgpu::BlendFactor::Zero,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent::OVER,
+            },
+            BlendMode::Constant => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::OneMinusConstant,
+                    dst_factor: wgpu::BlendFactor::Constant,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent::OVER,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Material {
     pub name: String,
     pub base_color: Vec4,
     pub alpha: f32,
+    pub blend_mode: BlendMode,
+    /// Whether the color of the textures has already been multiplied by the alpha.
+    /// This avoids dark halos around the transparent edges of some assets.
+    /// The obj and gltf loaders always produce straight alpha, like the gltf spec requires.
+    pub premultiplied_alpha: bool,
+    /// Only used by [`BlendMode::Mask`]
+    pub alpha_cutoff: f32,
+    /// Only used by [`BlendMode::Constant`], it's set with `set_blend_constant` before each draw
+    pub blend_constant: Vec3,
     pub gloss: f32,
     pub specular: Vec3,
     pub diffuse_texture: RgbaImage,
     pub normal_texture: Option<RgbaImage>,
+    /// Scales the X and Y components of the sampled normal map
+    pub normal_scale: f32,
     pub specular_texture: Option<RgbaImage>,
+    /// The channels of the `specular_texture` holding the metallic, roughness and occlusion
+    pub channel_mapping: ChannelMapping,
+    /// Light emitted by the surface, it's added to the lit color and isn't affected by the lights
+    pub emissive: Vec3,
+    /// Flips the normal of the back faces so they are lit like the front faces,
+    /// for thin surfaces like leaves or paper.
+    /// This only changes the shading, the base_3d pipelines still cull the back faces.
+    pub double_sided: bool,
 }
 
 impl Default for Material {
@@ -82,15 +368,74 @@ impl Default for Material {
             name: "Default Material".to_string(),
             base_color: Color::WHITE.as_rgba_f32().into(),
             alpha: 1.0,
+            blend_mode: BlendMode::Opaque,
+            premultiplied_alpha: false,
+            alpha_cutoff: 0.5,
+            blend_constant: Vec3::ZERO,
             gloss: 1.0,
             specular: Vec3::ONE,
             diffuse_texture: image_from_color(Color::WHITE),
             normal_texture: None,
+            normal_scale: 1.0,
             specular_texture: None,
+            channel_mapping: ChannelMapping::default(),
+            emissive: Vec3::ZERO,
+            double_sided: false,
         }
     }
 }
 
+/// A channel of a texture
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureChannel {
+    R,
+    G,
+    B,
+    A,
+}
+
+/// Which channel of the `specular_texture` of a [`Material`] each property is read from.
+/// Asset pipelines don't agree on a layout, the default is the glTF metallic-roughness texture.
+/// The roughness and occlusion are only read when the material has a `specular_texture`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelMapping {
+    pub metallic: TextureChannel,
+    /// Scales down the gloss of the material, it's ignored when None
+    pub roughness: Option<TextureChannel>,
+    /// Multiplies the ambient light, it's ignored when None
+    pub occlusion: Option<TextureChannel>,
+}
+
+impl Default for ChannelMapping {
+    fn default() -> Self {
+        Self::GLTF
+    }
+}
+
+impl ChannelMapping {
+    /// Roughness in G and metallic in B, the occlusion is a separate texture in glTF
+    pub const GLTF: Self = Self {
+        metallic: TextureChannel::B,
+        roughness: Some(TextureChannel::G),
+        occlusion: None,
+    };
+
+    /// Occlusion in R, roughness in G and metallic in B
+    pub const ORM: Self = Self {
+        metallic: TextureChannel::B,
+        roughness: Some(TextureChannel::G),
+        occlusion: Some(TextureChannel::R),
+    };
+
+    /// The mapping packed in the material uniform, 3 bits per property with 4 meaning unused.
+    // WARN this must match mapped_channel() in shader.wgsl
+    pub fn bits(&self) -> u32 {
+        let channel = |channel: Option<TextureChannel>| channel.map_or(4, |c| c as u32);
+        channel(Some(self.metallic)) | channel(self.roughness) << 3 | channel(self.occlusion) << 6
+    }
+}
+
 impl Material {
     #[allow(unused)]
     pub fn from_color(color: Color) -> Self {
@@ -99,23 +444,84 @@ impl Material {
             base_color: color.as_rgba_f32().into(),
             diffuse_texture: image_from_color(color),
             alpha: color.a(),
+            blend_mode: if color.a() < 1.0 {
+                BlendMode::Alpha
+            } else {
+                BlendMode::Opaque
+            },
             ..Default::default()
         }
     }
+
+    /// Transparent materials are drawn back to front after the opaque and masked materials
+    pub fn is_transparent(&self) -> bool {
+        !matches!(self.blend_mode, BlendMode::Opaque | BlendMode::Mask)
+    }
 }
 
-#[derive(Debug)]
+/// The buffers are reference counted so cloning a ModelMesh is cheap and
+/// the clones will share the same gpu buffers
+#[derive(Debug, Clone)]
 pub struct ModelMesh {
     pub name: String,
     // TODO don't store buffer on mesh
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    pub vertex_buffer: Arc<wgpu::Buffer>,
+    /// A copy of the positions and the skinning data, see [`ModelMesh::position_buffer`]
+    position_buffer: Option<Arc<wgpu::Buffer>>,
+    pub index_buffer: Arc<wgpu::Buffer>,
+    /// The format of the index buffer, see [`ModelMesh::try_from_mesh`]
+    pub index_format: wgpu::IndexFormat,
     pub num_elements: u32,
+    /// The number of vertices in the vertex buffer, not the number of vertices drawn
+    pub num_vertices: u32,
     pub material_id: Option<usize>,
+    pub topology: wgpu::PrimitiveTopology,
+    /// Computed from the cpu mesh when the buffers are created
+    pub stats: MeshStats,
 }
 
 impl ModelMesh {
     pub fn from_mesh(label: &str, device: &wgpu::Device, mesh: &Mesh) -> Self {
+        Self::try_from_mesh(label, device, mesh).expect("Failed to create ModelMesh")
+    }
+
+    /// Creates the gpu buffers of the mesh.
+    /// Fails if the mesh has no indices or if any of the buffers is bigger than what the device supports.
+    ///
+    /// The indices are stored as `u16` when they all fit, this halves the size of the index buffer
+    /// of most small meshes. Triangle strips always use `u32` indices because the strip pipeline
+    /// is created with the format of its restart index.
+    pub fn try_from_mesh(label: &str, device: &wgpu::Device, mesh: &Mesh) -> anyhow::Result<Self> {
+        let indices = mesh
+            .indices
+            .as_ref()
+            .with_context(|| format!("Mesh {label:?} has no indices"))?;
+
+        let index_format = smallest_index_format(mesh.topology, indices);
+        let index_bytes: Vec<u8> = match index_format {
+            wgpu::IndexFormat::Uint16 => {
+                let indices: Vec<u16> = indices.iter().map(|&index| index as u16).collect();
+                bytemuck::cast_slice(&indices).to_vec()
+            }
+            wgpu::IndexFormat::Uint32 => bytemuck::cast_slice(indices).to_vec(),
+        };
+
+        let vertex_buffer_size = std::mem::size_of_val(&mesh.vertices[..]) as u64;
+        let index_buffer_size = index_bytes.len() as u64;
+        let max_buffer_size = device.limits().max_buffer_size;
+        for (buffer, size) in [("vertex", vertex_buffer_size), ("index", index_buffer_size)] {
+            if size > max_buffer_size {
+                anyhow::bail!(
+                    "The {buffer} buffer of mesh {label:?} is {size} bytes but the device only supports buffers up to {max_buffer_size} bytes"
+                );
+            }
+            if size > max_buffer_size / 2 {
+                log::warn!(
+                    "The {buffer} buffer of mesh {label:?} is {size} bytes, this is close to the device limit of {max_buffer_size} bytes"
+                );
+            }
+        }
+
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some(&format!("{label} vertex buffer")),
             contents: bytemuck::cast_slice(&mesh.vertices),
@@ -124,21 +530,78 @@ impl ModelMesh {
 
         let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some(&format!("{label} index buffer")),
-            contents: bytemuck::cast_slice(
-                mesh.indices
-                    .as_ref()
-                    .expect("tried to get index buffer without indices"),
-            ),
+            contents: &index_bytes,
             usage: wgpu::BufferUsages::INDEX,
         });
 
-        ModelMesh {
+        Ok(ModelMesh {
             name: label.to_string(),
-            vertex_buffer,
-            index_buffer,
-            num_elements: mesh.indices.clone().map(|i| i.len() as u32).unwrap_or(1),
+            vertex_buffer: Arc::new(vertex_buffer),
+            position_buffer: None,
+            index_buffer: Arc::new(index_buffer),
+            index_format,
+            num_elements: indices.len() as u32,
+            num_vertices: mesh.vertices.len() as u32,
             material_id: mesh.material_id,
-        }
+            topology: mesh.topology,
+            stats: mesh.stats(),
+        })
+    }
+
+    /// The number of vertices in the vertex buffer
+    #[allow(unused)]
+    pub fn vertex_count(&self) -> u32 {
+        self.num_vertices
+    }
+
+    /// The number of indices drawn by [`Self::draw`]
+    #[allow(unused)]
+    pub fn index_count(&self) -> u32 {
+        self.num_elements
+    }
+
+    /// The size in bytes of a single vertex in the vertex buffer
+    #[allow(unused)]
+    pub fn vertex_stride(&self) -> wgpu::BufferAddress {
+        std::mem::size_of::<Vertex>() as wgpu::BufferAddress
+    }
+
+    /// The size in bytes of the vertex buffer
+    #[allow(unused)]
+    pub fn vertex_buffer_size(&self) -> wgpu::BufferAddress {
+        self.vertex_buffer.size()
+    }
+
+    /// The size in bytes of the index buffer, it depends on the [`ModelMesh::index_format`]
+    #[allow(unused)]
+    pub fn index_buffer_size(&self) -> wgpu::BufferAddress {
+        self.index_buffer.size()
+    }
+
+    /// A second vertex buffer with only the [`PositionVertex`] attributes, in the same order as the
+    /// vertex buffer so the index buffer works with both. When it exists it's bound instead of the
+    /// vertex buffer by the depth prepass and the shadows, they read less than half as much.
+    /// It costs 45% more vertex memory so it's only created by [`ModelMesh::with_position_buffer`].
+    pub fn position_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.position_buffer.as_deref()
+    }
+
+    /// Uploads the [`ModelMesh::position_buffer`], `mesh` needs to be the mesh this was created from
+    pub fn with_position_buffer(mut self, device: &wgpu::Device, mesh: &Mesh) -> Self {
+        assert_eq!(
+            mesh.vertices.len() as u32,
+            self.num_vertices,
+            "The position buffer of {:?} needs the vertices it was created from",
+            self.name
+        );
+        let positions: Vec<PositionVertex> = mesh.vertices.iter().map(Into::into).collect();
+        let position_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} position buffer", self.name)),
+            contents: bytemuck::cast_slice(&positions),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        self.position_buffer = Some(Arc::new(position_buffer));
+        self
     }
 
     #[allow(unused)]
@@ -146,9 +609,16 @@ impl ModelMesh {
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
         material_bind_group: &'a wgpu::BindGroup,
+        material_offset: u32,
         mesh_view_bind_group: &'a wgpu::BindGroup,
     ) {
-        self.draw_instanced(render_pass, 0..1, material_bind_group, mesh_view_bind_group);
+        self.draw_instanced(
+            render_pass,
+            0..1,
+            material_bind_group,
+            material_offset,
+            mesh_view_bind_group,
+        );
     }
 
     pub fn draw_instanced<'a>(
@@ -156,12 +626,83 @@ impl ModelMesh {
         render_pass: &mut wgpu::RenderPass<'a>,
         instances: Range<u32>,
         material_bind_group: &'a wgpu::BindGroup,
+        material_offset: u32,
         mesh_view_bind_group: &'a wgpu::BindGroup,
     ) {
         render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.set_index_buffer(self.index_buffer.slice(..), self.index_format);
         render_pass.set_bind_group(0, mesh_view_bind_group, &[]);
-        render_pass.set_bind_group(1, material_bind_group, &[]);
+        // The offset selects the uniform of the material in the buffer of the model
+        render_pass.set_bind_group(1, material_bind_group, &[material_offset]);
         render_pass.draw_indexed(0..self.num_elements, 0, instances);
     }
+
+    /// Draws the mesh with the [`wgpu::util::DrawIndexedIndirect`] at `args_offset` in `args_buffer`.
+    /// The instance buffer needs to be bound before calling this.
+    ///
+    /// Each mesh has its own vertex and index buffers so the args of a model can't be drawn
+    /// with a single `multi_draw_indexed_indirect`.
+    pub fn draw_indirect<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        args_buffer: &'a wgpu::Buffer,
+        args_offset: wgpu::BufferAddress,
+        material_bind_group: &'a wgpu::BindGroup,
+        material_offset: u32,
+        mesh_view_bind_group: &'a wgpu::BindGroup,
+    ) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), self.index_format);
+        render_pass.set_bind_group(0, mesh_view_bind_group, &[]);
+        render_pass.set_bind_group(1, material_bind_group, &[material_offset]);
+        render_pass.draw_indexed_indirect(args_buffer, args_offset);
+    }
+}
+
+/// `u16` when every index fits, strips always use `u32`, see [`ModelMesh::try_from_mesh`]
+fn smallest_index_format(topology: wgpu::PrimitiveTopology, indices: &[u32]) -> wgpu::IndexFormat {
+    if topology != wgpu::PrimitiveTopology::TriangleStrip
+        && indices.iter().all(|&index| index <= u16::MAX as u32)
+    {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_format() {
+        use wgpu::{IndexFormat, PrimitiveTopology};
+
+        let small = [0, 1, u16::MAX as u32];
+        let large = [0, 1, u16::MAX as u32 + 1];
+        assert_eq!(
+            smallest_index_format(PrimitiveTopology::TriangleList, &small),
+            IndexFormat::Uint16
+        );
+        assert_eq!(
+            smallest_index_format(PrimitiveTopology::TriangleList, &large),
+            IndexFormat::Uint32
+        );
+        assert_eq!(
+            smallest_index_format(PrimitiveTopology::LineList, &small),
+            IndexFormat::Uint16
+        );
+        // The strip pipeline uses the u32 restart index even when the indices are small
+        assert_eq!(
+            smallest_index_format(PrimitiveTopology::TriangleStrip, &small),
+            IndexFormat::Uint32
+        );
+        assert_eq!(
+            smallest_index_format(
+                PrimitiveTopology::TriangleStrip,
+                &[0, 1, 2, Mesh::PRIMITIVE_RESTART]
+            ),
+            IndexFormat::Uint32
+        );
+    }
 }
@@ -1,16 +1,24 @@
 use anyhow::Context;
-use bevy::{asset::LoadContext, prelude::*, tasks::IoTaskPool};
+use bevy::{asset::LoadContext, prelude::*, tasks::IoTaskPool, utils::Instant};
 use image::RgbaImage;
 use std::io::{BufReader, Cursor};
 
-use crate::{image_utils::image_from_color, mesh::Mesh, mesh::Vertex, model::Material};
+use crate::{
+    image_utils::image_from_color,
+    mesh::Mesh,
+    mesh::Vertex,
+    model::{BlendMode, ChannelMapping, LoaderSettings, Material},
+};
 
-use super::LoadedObj;
+use super::{LoadedObj, ObjImportSettings};
 
+/// Also returns the number of vertices removed by the deduplication
 pub async fn load_obj<'a, 'b>(
     bytes: &'a [u8],
     load_context: &'a LoadContext<'b>,
-) -> anyhow::Result<LoadedObj> {
+    settings: &ObjImportSettings,
+    loader_settings: LoaderSettings,
+) -> anyhow::Result<(LoadedObj, usize)> {
     let (obj_models, obj_materials) = tobj::load_obj_buf_async(
         &mut BufReader::new(Cursor::new(bytes)),
         &tobj::LoadOptions {
@@ -30,13 +38,28 @@ pub async fn load_obj<'a, 'b>(
     .with_context(|| format!("Failed to load obj {:?}", load_context.path()))?;
 
     let obj_materials = obj_materials?;
-    let mut materials: Vec<Material> = IoTaskPool::get()
-        .scope(|scope| {
+    let start = Instant::now();
+    let results = if loader_settings.parallel_textures {
+        IoTaskPool::get().scope(|scope| {
             obj_materials.iter().for_each(|obj_material| {
                 log::info!("Loading {}", obj_material.name);
                 scope.spawn(async move { load_material(load_context, obj_material).await });
             });
         })
+    } else {
+        let mut results = Vec::with_capacity(obj_materials.len());
+        for obj_material in &obj_materials {
+            log::info!("Loading {}", obj_material.name);
+            results.push(load_material(load_context, obj_material).await);
+        }
+        results
+    };
+    log::info!(
+        "Loaded all materials in {}ms ({})",
+        (Instant::now() - start).as_millis(),
+        LoaderSettings::mode(loader_settings.parallel_textures)
+    );
+    let mut materials: Vec<Material> = results
         .into_iter()
         .filter_map(|res| {
             if let Err(err) = res.as_ref() {
@@ -50,9 +73,70 @@ pub async fn load_obj<'a, 'b>(
         materials.push(Material::default())
     }
 
-    let meshes = generate_mesh(&obj_models, &materials);
+    let names: Vec<_> = obj_models.iter().map(|m| m.name.clone()).collect();
+
+    // Also returns the number of flipped triangles and removed vertices
+    let process_mesh = |obj_model: &tobj::Model| {
+        let mut mesh = generate_mesh(obj_model, &materials, settings.flip_v);
+        let flipped = if settings.fix_winding {
+            mesh.fix_winding()
+        } else {
+            0
+        };
+        let removed_vertices = if settings.deduplicate_vertices {
+            mesh.deduplicate_vertices()
+        } else {
+            0
+        };
+        (mesh, flipped, removed_vertices)
+    };
+    let start = Instant::now();
+    let results = if loader_settings.parallel_meshes {
+        IoTaskPool::get().scope(|scope| {
+            for obj_model in &obj_models {
+                let process_mesh = &process_mesh;
+                scope.spawn(async move { process_mesh(obj_model) });
+            }
+        })
+    } else {
+        obj_models.iter().map(process_mesh).collect()
+    };
+    log::info!(
+        "Generated {} meshes in {}ms ({})",
+        results.len(),
+        (Instant::now() - start).as_millis(),
+        LoaderSettings::mode(loader_settings.parallel_meshes)
+    );
+
+    let mut meshes = Vec::with_capacity(results.len());
+    let mut flipped = 0;
+    let mut removed_vertices = 0;
+    for (mesh, mesh_flipped, mesh_removed_vertices) in results {
+        meshes.push(mesh);
+        flipped += mesh_flipped;
+        removed_vertices += mesh_removed_vertices;
+    }
+    if flipped > 0 {
+        log::warn!(
+            "Flipped {flipped} inconsistently wound triangles in {:?}",
+            load_context.path()
+        );
+    }
+
+    if settings.log_stats {
+        for (mesh, name) in meshes.iter().zip(&names) {
+            log::info!("{:?} {name:?}: {}", load_context.path(), mesh.stats());
+        }
+    }
 
-    Ok(LoadedObj { materials, meshes })
+    Ok((
+        LoadedObj {
+            materials,
+            meshes,
+            names,
+        },
+        removed_vertices,
+    ))
 }
 
 async fn load_material<'a>(
@@ -64,19 +148,70 @@ async fn load_material<'a>(
         .unwrap_or_else(|| image_from_color(Color::WHITE));
     let normal_texture = load_texture(load_context, &obj_material.normal_texture).await?;
     let specular_texture = load_texture(load_context, &obj_material.specular_texture).await?;
+    material_from_mtl(
+        obj_material,
+        diffuse_texture,
+        normal_texture,
+        specular_texture,
+    )
+}
 
+/// Maps the mtl parameters to a [`Material`] once its textures are loaded
+fn material_from_mtl(
+    obj_material: &tobj::Material,
+    diffuse_texture: RgbaImage,
+    normal_texture: Option<RgbaImage>,
+    specular_texture: Option<RgbaImage>,
+) -> anyhow::Result<Material> {
     Ok(Material {
         name: obj_material.name.clone(),
         base_color: Vec3::from(obj_material.diffuse).extend(obj_material.dissolve),
         diffuse_texture,
         alpha: obj_material.dissolve,
+        blend_mode: if obj_material.dissolve < 1.0 {
+            BlendMode::Alpha
+        } else {
+            BlendMode::Opaque
+        },
+        premultiplied_alpha: false,
+        alpha_cutoff: 0.5,
+        blend_constant: Vec3::ZERO,
         gloss: obj_material.shininess,
         specular: Vec3::from(obj_material.specular),
+        // tobj doesn't have a field for Ke so it ends up with the unknown parameters.
+        // Ni is also parsed but glace doesn't render refraction so it's ignored.
+        emissive: obj_material
+            .unknown_param
+            .get("Ke")
+            .map(|ke| parse_vec3(ke))
+            .transpose()?
+            .unwrap_or(Vec3::ZERO),
+        // mtl has no equivalent
+        double_sided: false,
         normal_texture,
+        normal_scale: 1.0,
         specular_texture,
+        // map_Ks is a specular color, not a packed texture
+        channel_mapping: ChannelMapping {
+            roughness: None,
+            occlusion: None,
+            ..Default::default()
+        },
     })
 }
 
+/// Parses a color made of 3 floats separated by whitespace like `Ke 1.0 0.5 0.0`
+fn parse_vec3(value: &str) -> anyhow::Result<Vec3> {
+    let values = value
+        .split_whitespace()
+        .map(|v| v.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => anyhow::bail!("Expected 3 values but got {value:?}"),
+    }
+}
+
 async fn load_texture<'a>(
     load_context: &LoadContext<'a>,
     texture_path: &str,
@@ -93,56 +228,97 @@ async fn load_texture<'a>(
     })
 }
 
-fn generate_mesh(obj_models: &[tobj::Model], materials: &[Material]) -> Vec<Mesh> {
-    obj_models
-        .iter()
-        .map(|m| {
-            let vertices: Vec<_> = (0..m.mesh.positions.len() / 3)
-                .map(|i| Vertex {
-                    position: Vec3::new(
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ),
-                    uv: if m.mesh.texcoords.is_empty() {
-                        Vec2::ZERO
-                    } else {
-                        // UVs are flipped
-                        Vec2::new(m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1])
-                    },
-                    normal: if m.mesh.normals.is_empty() {
-                        Vec3::ZERO
-                    } else {
-                        Vec3::new(
-                            m.mesh.normals[i * 3],
-                            m.mesh.normals[i * 3 + 1],
-                            m.mesh.normals[i * 3 + 2],
-                        )
-                    },
-                    tangent: Vec3::ZERO,
-                    bitangent: Vec3::ZERO,
-                })
-                .collect();
-
-            let mut mesh = crate::mesh::Mesh {
-                vertices,
-                indices: Some(m.mesh.indices.clone()),
-                material_id: m.mesh.material_id,
-            };
-
-            if m.mesh.normals.is_empty() {
-                mesh.compute_normals();
-            }
-            if !m.mesh.normals.is_empty()
-                && m.mesh
-                    .material_id
-                    .and_then(|m_id| materials[m_id].normal_texture.clone())
-                    .is_some()
-            {
-                mesh.compute_tangents();
-            }
-
-            mesh
+fn generate_mesh(m: &tobj::Model, materials: &[Material], flip_v: bool) -> Mesh {
+    let vertices: Vec<_> = (0..m.mesh.positions.len() / 3)
+        .map(|i| Vertex {
+            position: Vec3::new(
+                m.mesh.positions[i * 3],
+                m.mesh.positions[i * 3 + 1],
+                m.mesh.positions[i * 3 + 2],
+            ),
+            uv: if m.mesh.texcoords.is_empty() {
+                Vec2::ZERO
+            } else {
+                let v = m.mesh.texcoords[i * 2 + 1];
+                Vec2::new(m.mesh.texcoords[i * 2], if flip_v { 1.0 - v } else { v })
+            },
+            normal: if m.mesh.normals.is_empty() {
+                Vec3::ZERO
+            } else {
+                Vec3::new(
+                    m.mesh.normals[i * 3],
+                    m.mesh.normals[i * 3 + 1],
+                    m.mesh.normals[i * 3 + 2],
+                )
+            },
+            tangent: Vec3::ZERO,
+            bitangent: Vec3::ZERO,
+            joints: Vertex::DEFAULT_JOINTS,
+            weights: Vertex::DEFAULT_WEIGHTS,
         })
-        .collect()
+        .collect();
+
+    let mut mesh = crate::mesh::Mesh {
+        vertices,
+        indices: Some(m.mesh.indices.clone()),
+        material_id: m.mesh.material_id,
+        topology: wgpu::PrimitiveTopology::TriangleList,
+    };
+
+    if m.mesh.normals.is_empty() {
+        mesh.compute_normals();
+    }
+    if !m.mesh.normals.is_empty()
+        && m.mesh
+            .material_id
+            .and_then(|m_id| materials[m_id].normal_texture.clone())
+            .is_some()
+    {
+        mesh.compute_tangents();
+    }
+
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_mtl(mtl: &str) -> Vec<tobj::Material> {
+        let (materials, _) = tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mtl))).unwrap();
+        materials
+    }
+
+    fn material(obj_material: &tobj::Material) -> anyhow::Result<Material> {
+        material_from_mtl(obj_material, image_from_color(Color::WHITE), None, None)
+    }
+
+    #[test]
+    fn emissive() {
+        let materials = load_mtl(
+            "newmtl lamp
+            Kd 0.8 0.8 0.8
+            Ke 1 0.5 0
+            Ni 1.45
+            newmtl plain
+            Kd 0.8 0.8 0.8",
+        );
+
+        let lamp = material(&materials[0]).unwrap();
+        assert_eq!(lamp.name, "lamp");
+        assert_eq!(lamp.emissive, Vec3::new(1.0, 0.5, 0.0));
+        assert_eq!(lamp.base_color, Vec4::new(0.8, 0.8, 0.8, 1.0));
+
+        let plain = material(&materials[1]).unwrap();
+        assert_eq!(plain.emissive, Vec3::ZERO);
+    }
+
+    #[test]
+    fn invalid_emissive() {
+        let materials = load_mtl(
+            "newmtl broken
+            Ke 1 0.5",
+        );
+        assert!(material(&materials[0]).is_err());
+    }
 }
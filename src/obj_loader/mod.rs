@@ -1,14 +1,14 @@
 use crate::{
     mesh::Mesh,
-    model::{Material, Model, ModelMesh},
+    model::{LoaderSettings, Material, Model, ModelMesh, PendingModel},
     obj_loader::loader::load_obj,
     renderer::WgpuRenderer,
 };
 use bevy::{
-    asset::{AssetLoader, LoadedAsset},
+    asset::{AssetLoader, HandleId, LoadedAsset},
     prelude::*,
     reflect::{TypePath, TypeUuid},
-    utils::Instant,
+    utils::{HashMap, HashSet, Instant},
 };
 
 mod loader;
@@ -21,21 +21,69 @@ mod loader;
 pub struct ObjLoaderPlugin;
 impl Plugin for ObjLoaderPlugin {
     fn build(&self, app: &mut App) {
-        app.add_asset::<LoadedObj>()
+        app.init_resource::<ObjImportSettings>()
+            .init_resource::<LoaderSettings>()
+            .add_asset::<LoadedObj>()
             .init_asset_loader::<ObjLoader>()
             .add_systems(Update, obj_spawner);
     }
 }
 
+/// Controls how obj files are imported.
+/// The loader reads this when the plugin is built so it needs to be inserted before the ObjLoaderPlugin.
+#[derive(Resource, Debug, Clone)]
+pub struct ObjImportSettings {
+    /// Merges the duplicate vertices of each mesh to reduce the size of the vertex buffers
+    pub deduplicate_vertices: bool,
+    /// Flips the v coordinate of the UVs. Obj files put the origin of the UVs at the bottom left,
+    /// disable this if the textures of a model are upside down.
+    pub flip_v: bool,
+    /// Flips the triangles facing inward, see [`Mesh::fix_winding`].
+    /// Disabled by default because it can break concave meshes.
+    pub fix_winding: bool,
+    /// Logs the [`MeshStats`](crate::mesh::MeshStats) of every mesh once it's loaded
+    pub log_stats: bool,
+}
+
+impl Default for ObjImportSettings {
+    fn default() -> Self {
+        Self {
+            deduplicate_vertices: true,
+            flip_v: true,
+            fix_winding: false,
+            log_stats: false,
+        }
+    }
+}
+
 #[derive(Debug, TypeUuid, TypePath)]
 #[uuid = "39cadc56-aa9c-4543-8640-a018b74b5052"]
 pub struct LoadedObj {
     pub materials: Vec<Material>,
     pub meshes: Vec<Mesh>,
+    /// The name of the object or group each mesh was loaded from
+    pub names: Vec<String>,
+}
+
+pub struct ObjLoader {
+    settings: ObjImportSettings,
+    loader_settings: LoaderSettings,
 }
 
-#[derive(Default)]
-pub struct ObjLoader;
+impl FromWorld for ObjLoader {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            settings: world
+                .get_resource::<ObjImportSettings>()
+                .cloned()
+                .unwrap_or_default(),
+            loader_settings: world
+                .get_resource::<LoaderSettings>()
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+}
 impl AssetLoader for ObjLoader {
     fn extensions(&self) -> &[&str] {
         &["obj"]
@@ -51,11 +99,13 @@ impl AssetLoader for ObjLoader {
 
             log::info!("Loading {:?}", load_context.path());
 
-            let obj = load_obj(bytes, load_context).await?;
+            let (obj, removed_vertices) =
+                load_obj(bytes, load_context, &self.settings, self.loader_settings).await?;
+            let vertex_count: usize = obj.meshes.iter().map(|mesh| mesh.vertices.len()).sum();
             load_context.set_default_asset(LoadedAsset::new(obj));
 
             log::info!(
-                "Finished loading {:?} {}ms",
+                "Finished loading {:?} {}ms {vertex_count} vertices ({removed_vertices} duplicates removed)",
                 load_context.path(),
                 (Instant::now() - start).as_millis(),
             );
@@ -65,33 +115,186 @@ impl AssetLoader for ObjLoader {
     }
 }
 
+/// The [`Model`] is uploaded again when the asset is modified, for example by bevy's asset watcher.
+/// The children of [`SplitObjObjects`] are spawned again.
 #[derive(Default, Bundle)]
 pub struct ObjBundle {
     pub obj: Handle<LoadedObj>,
 }
 
+/// Add this next to an [`ObjBundle`] to spawn each object of the obj file as a separate child entity
+/// instead of a single [`Model`] containing every mesh.
+///
+/// The children start with the Transform of the loaded entity but they aren't moved with it,
+/// the renderer doesn't propagate transforms.
+#[derive(Component, Default)]
+pub struct SplitObjObjects;
+
+/// Inserted on an entity with [`SplitObjObjects`] once its children are spawned.
+/// Contains one entity per object, in the same order as [`LoadedObj::meshes`].
+#[derive(Component)]
+pub struct ObjObjects(pub Vec<Entity>);
+
+/// Marks the child entities spawned for each object of an entity with [`SplitObjObjects`]
+#[derive(Component)]
+pub struct ObjObject;
+
 fn obj_spawner(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
-    query: Query<(Entity, &Handle<LoadedObj>), Without<Model>>,
+    query: Query<
+        (
+            Entity,
+            &Handle<LoadedObj>,
+            Option<&Transform>,
+            Option<&SplitObjObjects>,
+        ),
+        (Without<Model>, Without<PendingModel>, Without<ObjObjects>),
+    >,
+    loaded_query: Query<
+        (Entity, &Handle<LoadedObj>, Option<&ObjObjects>),
+        (Or<(With<Model>, With<ObjObjects>)>, Without<PendingModel>),
+    >,
+    mut pending_query: Query<(Entity, &Handle<LoadedObj>, &mut PendingModel)>,
+    mut pending_objects_query: Query<
+        (Entity, &mut PendingModel),
+        (With<ObjObject>, Without<Handle<LoadedObj>>),
+    >,
     obj_assets: Res<Assets<LoadedObj>>,
+    mut obj_events: EventReader<AssetEvent<LoadedObj>>,
+    loader_settings: Res<LoaderSettings>,
+    // Entities spawned from the same asset share the same gpu buffers
+    mut mesh_cache: Local<HashMap<HandleId, Vec<ModelMesh>>>,
 ) {
-    for (entity, obj_handle) in query.iter() {
+    let mut modified = HashSet::new();
+    for event in obj_events.iter() {
+        match event {
+            AssetEvent::Removed { handle } => {
+                mesh_cache.remove(&handle.id());
+            }
+            AssetEvent::Modified { handle } => {
+                mesh_cache.remove(&handle.id());
+                modified.insert(handle.id());
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+
+    for (entity, obj_handle, objects) in loaded_query.iter() {
+        if !modified.contains(&obj_handle.id()) {
+            continue;
+        }
+        log::info!("Reloading obj model");
+        if let Some(objects) = objects {
+            // The objects might not match anymore so they are spawned again by the query below
+            for object in &objects.0 {
+                commands.entity(*object).despawn_recursive();
+            }
+            commands.entity(entity).remove::<ObjObjects>();
+        } else if let Some(obj) = obj_assets.get(obj_handle) {
+            // The current model is kept until the new one is uploaded
+            commands.entity(entity).insert(PendingModel::spawn(
+                &renderer,
+                "obj",
+                None,
+                obj.meshes.clone(),
+                obj.materials.clone(),
+                loader_settings.position_buffers,
+            ));
+        }
+    }
+
+    for (entity, obj_handle, transform, split_objects) in query.iter() {
         if let Some(obj) = obj_assets.get(obj_handle) {
-            let LoadedObj { materials, meshes } = obj;
+            let LoadedObj {
+                materials,
+                meshes,
+                names,
+            } = obj;
 
-            // TODO mesh label for obj
-            let model_meshes = meshes
-                .iter()
-                .map(|mesh| ModelMesh::from_mesh("", &renderer.device, mesh))
-                .collect();
+            if split_objects.is_some() {
+                // The meshes that failed to upload aren't cached,
+                // the cache only lines up with the objects when none of them failed
+                let cached_meshes = mesh_cache
+                    .get(&obj_handle.id())
+                    .filter(|cached_meshes| cached_meshes.len() == meshes.len());
+                let transform = transform.copied().unwrap_or_default();
+                let mut objects = vec![];
+                commands.entity(entity).with_children(|parent| {
+                    for (i, mesh) in meshes.iter().enumerate() {
+                        // Each object only gets its own material
+                        let material_id = mesh.material_id.unwrap_or(0);
+                        let cached_mesh = cached_meshes.map(|cached_meshes| {
+                            let mut cached_mesh = cached_meshes[i].clone();
+                            cached_mesh.material_id = Some(0);
+                            vec![cached_mesh]
+                        });
+                        let mesh = Mesh {
+                            material_id: Some(0),
+                            ..mesh.clone()
+                        };
+                        let object = parent
+                            .spawn((
+                                ObjObject,
+                                Name::new(names[i].clone()),
+                                transform,
+                                PendingModel::spawn(
+                                    &renderer,
+                                    "obj object",
+                                    cached_mesh,
+                                    vec![mesh],
+                                    vec![materials[material_id].clone()],
+                                    loader_settings.position_buffers,
+                                ),
+                            ))
+                            .id();
+                        objects.push(object);
+                    }
+                });
+                commands.entity(entity).insert(ObjObjects(objects));
+                continue;
+            }
 
-            commands.entity(entity).insert(Model {
-                materials: materials.clone(),
-                meshes: model_meshes,
-            });
+            let cached_meshes = mesh_cache.get(&obj_handle.id()).cloned();
+            let meshes = if cached_meshes.is_some() {
+                vec![]
+            } else {
+                meshes.clone()
+            };
+            commands.entity(entity).insert(PendingModel::spawn(
+                &renderer,
+                "obj",
+                cached_meshes,
+                meshes,
+                materials.clone(),
+                loader_settings.position_buffers,
+            ));
+        }
+    }
+
+    for (entity, obj_handle, mut pending_model) in pending_query.iter_mut() {
+        if let Some((mut model, gpu_materials)) = pending_model.poll() {
+            // Another entity using the same asset might have finished uploading first
+            model.meshes = mesh_cache
+                .entry(obj_handle.id())
+                .or_insert_with(|| model.meshes.clone())
+                .clone();
+
+            commands
+                .entity(entity)
+                .remove::<PendingModel>()
+                .insert((model, gpu_materials));
 
             log::info!("Obj Model spawned");
         }
     }
+
+    for (entity, mut pending_model) in pending_objects_query.iter_mut() {
+        if let Some((model, gpu_materials)) = pending_model.poll() {
+            commands
+                .entity(entity)
+                .remove::<PendingModel>()
+                .insert((model, gpu_materials));
+        }
+    }
 }
@@ -0,0 +1,427 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{ecs::prelude::*, time::Time};
+
+use super::{Exposure, WgpuEncoder, WgpuRenderer, WgpuSurfaceTexture};
+use crate::egui_plugin::viewport::EguiViewport;
+
+/// The luminance the average of the frame is brought to
+const MIDDLE_GRAY: f32 = 0.18;
+/// The size of the average log luminance written by the shader
+const RESULT_SIZE: u64 = std::mem::size_of::<f32>() as u64;
+
+/// Adapts the [`Exposure`] to the average luminance of the frame, the exposure is manual without it.
+///
+/// The frame is measured after the 3d passes and the exposure is moved toward the ev that brings its
+/// average to middle gray. The sky and the unlit meshes are measured but aren't exposed
+/// and there's no hdr target so the luminance is clamped by the surface, the feedback still
+/// converges but bright frames take longer to adapt.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct AutoExposure {
+    /// The lowest ev the exposure can adapt to
+    pub min: f32,
+    /// The highest ev the exposure can adapt to
+    pub max: f32,
+    /// How fast the exposure reaches the target, it covers `1 - e^-speed` of the distance every second
+    pub adaptation_speed: f32,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            min: -4.0,
+            max: 4.0,
+            adaptation_speed: 1.0,
+        }
+    }
+}
+
+impl AutoExposure {
+    /// The ev that brings a frame rendered with `measured_ev` and an average log2 luminance
+    /// of `average_log_luminance` to middle gray, clamped to `min` and `max`
+    pub fn target_ev(&self, measured_ev: f32, average_log_luminance: f32) -> f32 {
+        (measured_ev + MIDDLE_GRAY.log2() - average_log_luminance)
+            .max(self.min)
+            .min(self.max)
+    }
+
+    /// Moves `ev` toward `target` for a frame lasting `delta` seconds
+    pub fn adapt(&self, ev: f32, target: f32, delta: f32) -> f32 {
+        ev + (target - ev) * (1.0 - (-self.adaptation_speed * delta).exp())
+    }
+}
+
+/// The targets are recreated when the size of the render target changes
+struct LuminanceTargets {
+    size: [u32; 2],
+    /// A copy of the frame, the surface can't be sampled
+    color: wgpu::Texture,
+    bind_group: wgpu::BindGroup,
+}
+
+enum ReadbackState {
+    /// The frame can be measured
+    Idle,
+    /// The luminance is copied to the readback buffer by the encoder of this frame
+    Recorded { measured_ev: f32 },
+    /// Waiting for the readback buffer to be mapped, the callback stores the result
+    Mapping {
+        measured_ev: f32,
+        result: Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>,
+    },
+}
+
+/// Measures the average luminance of the frame for the [`AutoExposure`].
+/// The result is read back without blocking so the exposure lags a few frames behind.
+#[derive(Resource)]
+pub struct AutoExposurePass {
+    pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+    result_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    targets: Option<LuminanceTargets>,
+    state: ReadbackState,
+    /// The ev computed from the last measurement, None until a frame was measured
+    target_ev: Option<f32>,
+}
+
+impl AutoExposurePass {
+    fn new(renderer: &WgpuRenderer) -> Self {
+        let device = &renderer.device;
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("auto_exposure_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(RESULT_SIZE),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let shader = renderer.errors.scope(device, "Auto Exposure Shader", || {
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Auto Exposure Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/auto_exposure.wgsl").into()),
+            })
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Auto Exposure Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = renderer.errors.scope(device, "Auto Exposure Pipeline", || {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Auto Exposure Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: "main",
+            })
+        });
+
+        Self {
+            pipeline,
+            layout,
+            result_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Auto Exposure Result Buffer"),
+                size: RESULT_SIZE,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Auto Exposure Readback Buffer"),
+                size: RESULT_SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            targets: None,
+            state: ReadbackState::Idle,
+            target_ev: None,
+        }
+    }
+
+    fn create_targets(&self, renderer: &WgpuRenderer, size: [u32; 2]) -> LuminanceTargets {
+        let device = &renderer.device;
+        let color = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("auto_exposure_color"),
+            size: wgpu::Extent3d {
+                width: size[0].max(1),
+                height: size[1].max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: renderer.config.format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let color_view = color.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = renderer
+            .errors
+            .scope(device, "auto exposure bind group", || {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("auto_exposure_bind_group"),
+                    layout: &self.layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&color_view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: self.result_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+            });
+
+        LuminanceTargets {
+            size,
+            color,
+            bind_group,
+        }
+    }
+}
+
+pub fn setup(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    commands.insert_resource(AutoExposurePass::new(&renderer));
+}
+
+/// Recreates the targets when the size of the render target changes
+pub fn prepare(
+    mut pass: ResMut<AutoExposurePass>,
+    renderer: Res<WgpuRenderer>,
+    auto_exposure: Option<Res<AutoExposure>>,
+    viewport: Option<Res<EguiViewport>>,
+) {
+    let auto_exposure = if let Some(auto_exposure) = auto_exposure {
+        auto_exposure
+    } else {
+        pass.targets = None;
+        pass.target_ev = None;
+        return;
+    };
+
+    let target = viewport.as_ref().and_then(|viewport| viewport.target());
+    // The window is copied to the targets, the viewport texture can always be copied
+    if target.is_none()
+        && !renderer
+            .config
+            .usage
+            .contains(wgpu::TextureUsages::COPY_SRC)
+    {
+        if auto_exposure.is_added() {
+            log::warn!("The surface can't be copied, auto exposure is disabled");
+        }
+        pass.targets = None;
+        return;
+    }
+
+    let size = target
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    if pass.targets.as_ref().map(|targets| targets.size) != Some(size) {
+        pass.targets = Some(pass.create_targets(&renderer, size));
+    }
+}
+
+/// Copies the frame and computes its average luminance.
+/// This needs to run after every 3d pass and before egui.
+pub fn render(
+    mut pass: ResMut<AutoExposurePass>,
+    mut encoder: ResMut<WgpuEncoder>,
+    output: Res<WgpuSurfaceTexture>,
+    viewport: Option<Res<EguiViewport>>,
+    exposure: Res<Exposure>,
+) {
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+    let targets = if let Some(targets) = pass.targets.as_ref() {
+        targets
+    } else {
+        return;
+    };
+    // The previous measurement is still being read
+    if !matches!(pass.state, ReadbackState::Idle) {
+        return;
+    }
+
+    let texture = match viewport.as_ref().and_then(|v| v.target()) {
+        Some(target) => &target.texture,
+        None => match output.0.as_ref() {
+            Some(output) => &output.texture,
+            None => return,
+        },
+    };
+    // The window can be resized after the targets were prepared for this frame
+    if [texture.width(), texture.height()] != targets.size {
+        return;
+    }
+
+    encoder.copy_texture_to_texture(
+        texture.as_image_copy(),
+        targets.color.as_image_copy(),
+        texture.size(),
+    );
+    {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Auto Exposure Pass"),
+        });
+        compute_pass.set_pipeline(&pass.pipeline);
+        compute_pass.set_bind_group(0, &targets.bind_group, &[]);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+    }
+    encoder.copy_buffer_to_buffer(
+        &pass.result_buffer,
+        0,
+        &pass.readback_buffer,
+        0,
+        RESULT_SIZE,
+    );
+    pass.state = ReadbackState::Recorded {
+        measured_ev: exposure.ev,
+    };
+}
+
+/// Maps the readback buffer once the encoder with the measurement is submitted
+/// and moves the [`Exposure`] toward the ev of the last measurement every frame
+pub fn adapt_exposure(
+    mut pass: ResMut<AutoExposurePass>,
+    renderer: Res<WgpuRenderer>,
+    auto_exposure: Option<Res<AutoExposure>>,
+    mut exposure: ResMut<Exposure>,
+    time: Res<Time>,
+) {
+    let auto_exposure = if let Some(auto_exposure) = auto_exposure {
+        auto_exposure
+    } else {
+        return;
+    };
+
+    match &pass.state {
+        ReadbackState::Idle => {}
+        ReadbackState::Recorded { measured_ev } => {
+            let measured_ev = *measured_ev;
+            let result = Arc::new(Mutex::new(None));
+            let callback_result = result.clone();
+            pass.readback_buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |mapped| {
+                    *callback_result.lock().unwrap() = Some(mapped);
+                });
+            pass.state = ReadbackState::Mapping {
+                measured_ev,
+                result,
+            };
+        }
+        ReadbackState::Mapping {
+            measured_ev,
+            result,
+        } => {
+            renderer.device.poll(wgpu::Maintain::Poll);
+            let measured_ev = *measured_ev;
+            let mapped = result.lock().unwrap().take();
+            match mapped {
+                None => {}
+                Some(Err(err)) => {
+                    log::error!("Failed to map auto exposure buffer: {err}");
+                    pass.state = ReadbackState::Idle;
+                }
+                Some(Ok(())) => {
+                    let average_log_luminance: f32 = {
+                        let data = pass.readback_buffer.slice(..).get_mapped_range();
+                        bytemuck::pod_read_unaligned(&data)
+                    };
+                    pass.readback_buffer.unmap();
+                    pass.state = ReadbackState::Idle;
+                    pass.target_ev =
+                        Some(auto_exposure.target_ev(measured_ev, average_log_luminance));
+                }
+            }
+        }
+    }
+
+    if let Some(target_ev) = pass.target_ev {
+        let ev = auto_exposure.adapt(exposure.ev, target_ev, time.delta_seconds());
+        // Only touch the exposure when it moves so the camera buffer isn't written every frame
+        if ev != exposure.ev {
+            exposure.ev = ev;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_ev_reaches_middle_gray() {
+        let auto_exposure = AutoExposure {
+            min: -10.0,
+            max: 10.0,
+            ..Default::default()
+        };
+        // Already at middle gray
+        let ev = auto_exposure.target_ev(1.0, MIDDLE_GRAY.log2());
+        assert!((ev - 1.0).abs() < 1e-6);
+        // Twice as bright as middle gray needs one stop less
+        let ev = auto_exposure.target_ev(0.0, (MIDDLE_GRAY * 2.0).log2());
+        assert!((ev + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn target_ev_is_clamped() {
+        let auto_exposure = AutoExposure {
+            min: -2.0,
+            max: 3.0,
+            ..Default::default()
+        };
+        assert_eq!(auto_exposure.target_ev(0.0, -20.0), 3.0);
+        assert_eq!(auto_exposure.target_ev(0.0, 20.0), -2.0);
+    }
+
+    #[test]
+    fn adapt_moves_toward_target() {
+        let auto_exposure = AutoExposure {
+            adaptation_speed: 2.0,
+            ..Default::default()
+        };
+        let ev = auto_exposure.adapt(0.0, 4.0, 0.1);
+        assert!(ev > 0.0 && ev < 4.0);
+        // Long frames don't overshoot
+        let ev = auto_exposure.adapt(0.0, 4.0, 100.0);
+        assert!((ev - 4.0).abs() < 1e-6);
+        // Adapting down works the same way
+        let ev = auto_exposure.adapt(1.0, -1.0, 0.5);
+        assert!(ev < 1.0 && ev > -1.0);
+    }
+
+    #[test]
+    fn adapt_without_speed_keeps_ev() {
+        let auto_exposure = AutoExposure {
+            adaptation_speed: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(auto_exposure.adapt(1.5, 4.0, 1.0), 1.5);
+    }
+}
@@ -1,28 +1,199 @@
-use bevy::ecs::prelude::*;
+use bevy::{ecs::prelude::*, math::prelude::*, transform::prelude::*, utils::HashMap};
 
 use super::{
-    bind_groups::material::{self, GpuModelMaterials},
-    DepthTexture, GlaceClearColor, Msaa, WgpuEncoder, WgpuRenderer, WgpuView,
+    bind_groups::material::{self, GpuColorOverride, GpuModelMaterials},
+    create_multisampled_framebuffer,
+    frame_stats::PassTimer,
+    shadow::{self, ShadowPass},
+    sky::GradientSky,
+    ssao::{self, SsaoPass},
+    DepthOnlyPipelines, DepthTexture, Fog, GlaceClearColor, Msaa, WgpuEncoder, WgpuRenderer,
+    WgpuView,
 };
 
-use crate::renderer::bind_groups::mesh_view::{MeshViewBindGroup, MeshViewBindGroupLayout};
+use crate::renderer::bind_groups::mesh_view::{
+    LightBuffer, MeshViewBindGroup, MeshViewBindGroupLayout,
+};
 use crate::{
-    instances::{InstanceBuffer, Instances},
+    camera::{Camera, VisibilityQuery},
+    egui_plugin::viewport::EguiViewport,
+    instances::{CompactInstances, GpuIndirectInstances, InstanceBuffer, Instances},
     light::{draw_light_model, Light},
     mesh,
-    model::Model,
-    texture::Texture,
-    transform::TransformRaw,
+    model::{BlendMode, Model, ModelMesh},
+    transform::{CompactTransformRaw, TransformRaw},
 };
 
+/// The shader of the lit meshes, with the helpers shared by every mesh shader
+const SHADER: &str = concat!(
+    include_str!("shaders/common.wgsl"),
+    include_str!("shaders/shader.wgsl")
+);
+
 #[derive(Component)]
 pub struct Transparent;
 
+/// Writes the reference to the stencil buffer where the opaque meshes of the entity are visible.
+/// The meshes don't write any color or depth, they cut a window that [`StencilTest`] entities are seen through.
+/// It needs a [`DepthFormat`](super::DepthFormat) with a stencil, the entity isn't rendered otherwise.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct StencilWrite(pub u32);
+
+/// Only draws the opaque meshes of the entity where the stencil buffer is equal to the reference.
+/// It needs a [`DepthFormat`](super::DepthFormat) with a stencil, the entity isn't rendered otherwise.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct StencilTest(pub u32);
+
+/// Renders the depth of opaque models before the main pass.
+/// The main pass then only shades the visible fragments which reduces overdraw
+/// in scenes with a lot of overlapping geometry.
+#[derive(Resource, Default, PartialEq, Eq)]
+pub struct DepthPrepass(pub bool);
+
+/// Controls what the base 3d pass clears before drawing, everything is cleared by default.
+/// Keep the color to draw the scene over something rendered before the pass,
+/// the content of the window is undefined when nothing was rendered to it this frame.
+/// The color is always kept when the [`GradientSky`] is visible since it's drawn before the pass.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClearFlags {
+    pub color: bool,
+    /// Also clears the stencil
+    pub depth: bool,
+}
+
+impl Default for ClearFlags {
+    fn default() -> Self {
+        Self {
+            color: true,
+            depth: true,
+        }
+    }
+}
+
+/// Makes the base 3d pass also render the view space normals of opaque meshes to the [`GBuffer`].
+/// The depth is already available in the [`DepthTexture`] or in the egui viewport target.
+#[derive(Resource, Default, PartialEq, Eq)]
+pub struct GBufferEnabled(pub bool);
+
+/// The extra targets rendered by the base 3d pass, None when the [`GBufferEnabled`] is false
+#[derive(Resource, Default)]
+pub struct GBuffer {
+    target: Option<GBufferTarget>,
+}
+
+pub struct GBufferTarget {
+    pub size: [u32; 2],
+    pub sample_count: u32,
+    /// The view space normals, `view` is the resolved texture that can be sampled
+    pub normal: WgpuView,
+}
+
+impl GBuffer {
+    pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    #[allow(unused)]
+    pub fn target(&self) -> Option<&GBufferTarget> {
+        self.target.as_ref()
+    }
+
+    /// The resolved view space normals. Pixels without any opaque mesh have an alpha of 0
+    #[allow(unused)]
+    pub fn normal(&self) -> Option<&wgpu::TextureView> {
+        self.target.as_ref().map(|target| &target.normal.view)
+    }
+
+    /// Recreates the target when it doesn't exist or doesn't match the size and sample count
+    fn prepare(
+        &mut self,
+        renderer: &WgpuRenderer,
+        size: [u32; 2],
+        sample_count: u32,
+    ) -> &GBufferTarget {
+        if self
+            .target
+            .as_ref()
+            .is_some_and(|target| target.size != size || target.sample_count != sample_count)
+        {
+            self.target = None;
+        }
+        self.target
+            .get_or_insert_with(|| create_gbuffer_target(renderer, size, sample_count))
+    }
+}
+
+fn create_gbuffer_target(
+    renderer: &WgpuRenderer,
+    size: [u32; 2],
+    sample_count: u32,
+) -> GBufferTarget {
+    log::info!("Resizing g-buffer to {}x{}", size[0], size[1]);
+
+    let config = wgpu::SurfaceConfiguration {
+        width: size[0],
+        height: size[1],
+        format: GBuffer::NORMAL_FORMAT,
+        ..renderer.config.clone()
+    };
+    let normal_texture = renderer.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("g-buffer normal texture"),
+        size: wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: GBuffer::NORMAL_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+
+    GBufferTarget {
+        size,
+        sample_count,
+        normal: WgpuView {
+            view: normal_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            sampled_view: if sample_count > 1 {
+                Some(create_multisampled_framebuffer(
+                    &renderer.device,
+                    &config,
+                    sample_count,
+                ))
+            } else {
+                None
+            },
+        },
+    }
+}
+
 #[derive(Resource)]
 pub struct Base3dPass {
+    sample_count: u32,
+    /// Whether the pipelines have the g-buffer targets
+    gbuffer: bool,
+    /// The depth texture is cleared to it, see [`ReverseZ`](super::ReverseZ)
+    far_depth: f32,
+    depth_prepass_pipelines: Option<DepthOnlyPipelines>,
     render_pipeline: wgpu::RenderPipeline,
+    /// Used by [`BlendMode::Mask`], it uses alpha to coverage when msaa is enabled
+    mask_render_pipeline: wgpu::RenderPipeline,
+    /// Used by [`CompactInstances`], only for opaque meshes
+    compact_render_pipeline: wgpu::RenderPipeline,
+    /// Used by the meshes with a triangle strip topology, they are always drawn opaque
+    strip_render_pipeline: wgpu::RenderPipeline,
     light_render_pipeline: wgpu::RenderPipeline,
-    transparent_render_pipeline: wgpu::RenderPipeline,
+    /// One pipeline per transparent blend mode, with straight and premultiplied alpha
+    transparent_render_pipelines: HashMap<(BlendMode, bool), wgpu::RenderPipeline>,
+    /// Unlit pipelines used for line and point meshes
+    topology_render_pipelines: HashMap<wgpu::PrimitiveTopology, wgpu::RenderPipeline>,
+    /// Used by [`StencilWrite`] and [`StencilTest`], None when the depth format has no stencil
+    stencil_pipelines: Option<StencilPipelines>,
+}
+
+struct StencilPipelines {
+    write: wgpu::RenderPipeline,
+    test: wgpu::RenderPipeline,
 }
 
 impl Base3dPass {
@@ -30,6 +201,8 @@ impl Base3dPass {
         renderer: &WgpuRenderer,
         mesh_view_layout: &MeshViewBindGroupLayout,
         sample_count: u32,
+        depth_prepass: bool,
+        gbuffer: bool,
     ) -> Self {
         let render_pipeline_layout =
             renderer
@@ -39,42 +212,108 @@ impl Base3dPass {
                     bind_group_layouts: &[
                         &mesh_view_layout.0,
                         &material::bind_group_layout(&renderer.device),
+                        &ssao::bind_group_layout(&renderer.device),
+                        &shadow::bind_group_layout(&renderer.device),
                     ],
                     push_constant_ranges: &[],
                 });
 
+        let depth_prepass_pipelines = if depth_prepass {
+            Some(create_depth_prepass_pipelines(
+                renderer,
+                mesh_view_layout,
+                sample_count,
+            ))
+        } else {
+            None
+        };
+
         // TODO have a better way to attach draw commands to a pipeline
         let render_pipeline = renderer.create_render_pipeline(
             "Opaque Render Pipeline",
-            include_str!("shaders/shader.wgsl"),
+            SHADER,
             &render_pipeline_layout,
             &[mesh::Vertex::layout(), TransformRaw::layout()],
             Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
+                format: renderer.depth_format,
+                // The depth is already written by the prepass
+                depth_write_enabled: !depth_prepass,
+                depth_compare: if depth_prepass {
+                    wgpu::CompareFunction::Equal
+                } else {
+                    renderer.depth_compare(wgpu::CompareFunction::Less)
+                },
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
-            wgpu::BlendState::REPLACE,
+            &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, true),
             sample_count,
         );
 
-        let transparent_render_pipeline = renderer.create_render_pipeline(
-            "Transparent Render Pipeline",
-            include_str!("shaders/shader.wgsl"),
-            &render_pipeline_layout,
-            &[mesh::Vertex::layout(), TransformRaw::layout()],
-            Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            wgpu::BlendState::ALPHA_BLENDING,
-            sample_count,
-        );
+        let mask_render_pipeline =
+            create_mask_pipeline(renderer, &render_pipeline_layout, sample_count, gbuffer);
+        let compact_render_pipeline =
+            create_compact_pipeline(renderer, &render_pipeline_layout, sample_count, gbuffer);
+        let strip_render_pipeline =
+            create_strip_pipeline(renderer, &render_pipeline_layout, sample_count, gbuffer);
+
+        let transparent_render_pipelines = [
+            BlendMode::Alpha,
+            BlendMode::Additive,
+            BlendMode::Multiply,
+            BlendMode::Constant,
+        ]
+        .into_iter()
+        .flat_map(|blend_mode| [(blend_mode, false), (blend_mode, true)])
+        .map(|(blend_mode, premultiplied_alpha)| {
+            let label = if premultiplied_alpha {
+                format!("Transparent Premultiplied {blend_mode:?} Render Pipeline")
+            } else {
+                format!("Transparent {blend_mode:?} Render Pipeline")
+            };
+            let pipeline = renderer.create_render_pipeline(
+                &label,
+                SHADER,
+                &render_pipeline_layout,
+                &[mesh::Vertex::layout(), TransformRaw::layout()],
+                // Transparent meshes are tested against the opaque ones but don't write depth,
+                // otherwise the ones drawn first would hide the ones behind them.
+                // They are sorted back to front instead.
+                Some(wgpu::DepthStencilState {
+                    format: renderer.depth_format,
+                    depth_write_enabled: false,
+                    depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                &color_targets(
+                    renderer,
+                    blend_mode.blend_state(premultiplied_alpha),
+                    gbuffer,
+                    false,
+                ),
+                sample_count,
+            );
+            ((blend_mode, premultiplied_alpha), pipeline)
+        })
+        .collect();
+
+        let topology_render_pipelines = [
+            wgpu::PrimitiveTopology::LineList,
+            wgpu::PrimitiveTopology::PointList,
+        ]
+        .into_iter()
+        .map(|topology| {
+            let pipeline = create_unlit_pipeline(
+                renderer,
+                &render_pipeline_layout,
+                topology,
+                sample_count,
+                gbuffer,
+            );
+            (topology, pipeline)
+        })
+        .collect();
 
         let light_render_pipeline = renderer.create_render_pipeline(
             "Light Render Pipeline",
@@ -88,62 +327,597 @@ impl Base3dPass {
                 }),
             &[mesh::Vertex::layout()],
             Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
+                format: renderer.depth_format,
                 depth_write_enabled: false,
-                depth_compare: wgpu::CompareFunction::Less,
+                depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
-            wgpu::BlendState::REPLACE,
+            &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, false),
             sample_count,
         );
 
+        let stencil_pipelines = if renderer.has_stencil() {
+            Some(create_stencil_pipelines(
+                renderer,
+                &render_pipeline_layout,
+                sample_count,
+                gbuffer,
+            ))
+        } else {
+            None
+        };
+
         Self {
+            sample_count,
+            gbuffer,
+            far_depth: renderer.far_depth(),
+            depth_prepass_pipelines,
             render_pipeline,
+            mask_render_pipeline,
+            compact_render_pipeline,
+            strip_render_pipeline,
             light_render_pipeline,
-            transparent_render_pipeline,
+            transparent_render_pipelines,
+            topology_render_pipelines,
+            stencil_pipelines,
         }
     }
 }
 
+/// Every pipeline of the pass needs the same targets.
+/// Only the opaque pipeline writes the normals, the other ones only output a color.
+fn color_targets(
+    renderer: &WgpuRenderer,
+    blend: wgpu::BlendState,
+    gbuffer: bool,
+    write_normal: bool,
+) -> Vec<Option<wgpu::ColorTargetState>> {
+    let mut targets = vec![Some(wgpu::ColorTargetState {
+        format: renderer.config.format,
+        blend: Some(blend),
+        write_mask: wgpu::ColorWrites::ALL,
+    })];
+    if gbuffer {
+        targets.push(Some(wgpu::ColorTargetState {
+            format: GBuffer::NORMAL_FORMAT,
+            blend: None,
+            write_mask: if write_normal {
+                wgpu::ColorWrites::ALL
+            } else {
+                wgpu::ColorWrites::empty()
+            },
+        }));
+    }
+    targets
+}
+
+/// Stencil entities aren't in the depth prepass so these pipelines never compare the depth for equality
+fn create_stencil_pipelines(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+    gbuffer: bool,
+) -> StencilPipelines {
+    let write_targets: Vec<_> = color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, false)
+        .into_iter()
+        .map(|target| {
+            target.map(|target| wgpu::ColorTargetState {
+                write_mask: wgpu::ColorWrites::empty(),
+                ..target
+            })
+        })
+        .collect();
+    let write = renderer.create_render_pipeline(
+        "Stencil Write Render Pipeline",
+        SHADER,
+        pipeline_layout,
+        &[mesh::Vertex::layout(), TransformRaw::layout()],
+        Some(wgpu::DepthStencilState {
+            format: renderer.depth_format,
+            depth_write_enabled: false,
+            depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+            stencil: stencil_state(
+                wgpu::CompareFunction::Always,
+                wgpu::StencilOperation::Replace,
+            ),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        &write_targets,
+        sample_count,
+    );
+
+    let test = renderer.create_render_pipeline(
+        "Stencil Test Render Pipeline",
+        SHADER,
+        pipeline_layout,
+        &[mesh::Vertex::layout(), TransformRaw::layout()],
+        Some(wgpu::DepthStencilState {
+            format: renderer.depth_format,
+            depth_write_enabled: true,
+            depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+            stencil: stencil_state(wgpu::CompareFunction::Equal, wgpu::StencilOperation::Keep),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, true),
+        sample_count,
+    );
+
+    StencilPipelines { write, test }
+}
+
+/// Compares the reference to the stencil buffer and applies `pass_op` when both the stencil and depth tests pass
+fn stencil_state(
+    compare: wgpu::CompareFunction,
+    pass_op: wgpu::StencilOperation,
+) -> wgpu::StencilState {
+    let face = wgpu::StencilFaceState {
+        compare,
+        fail_op: wgpu::StencilOperation::Keep,
+        depth_fail_op: wgpu::StencilOperation::Keep,
+        pass_op,
+    };
+    wgpu::StencilState {
+        front: face,
+        back: face,
+        read_mask: 0xff,
+        write_mask: if pass_op == wgpu::StencilOperation::Keep {
+            0
+        } else {
+            0xff
+        },
+    }
+}
+
+/// The stencil is cleared by the first pass using the depth texture and loaded by the other ones
+fn stencil_ops(has_stencil: bool, clear: bool) -> Option<wgpu::Operations<u32>> {
+    has_stencil.then_some(wgpu::Operations {
+        load: if clear {
+            wgpu::LoadOp::Clear(0)
+        } else {
+            wgpu::LoadOp::Load
+        },
+        store: true,
+    })
+}
+
+fn create_unlit_pipeline(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    topology: wgpu::PrimitiveTopology,
+    sample_count: u32,
+    gbuffer: bool,
+) -> wgpu::RenderPipeline {
+    let label = format!("Unlit {topology:?} Pipeline");
+    renderer.errors.scope(&renderer.device, &label, || {
+        let shader = renderer
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Unlit Shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("shaders/common.wgsl"),
+                        include_str!("shaders/unlit.wgsl")
+                    )
+                    .into(),
+                ),
+            });
+
+        renderer
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(&label),
+                layout: Some(pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[mesh::Vertex::layout(), TransformRaw::layout()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, false),
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology,
+                    cull_mode: None,
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: renderer.depth_format,
+                    depth_write_enabled: true,
+                    depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    ..Default::default()
+                },
+                multiview: None,
+            })
+    })
+}
+
+/// Masked meshes aren't in the depth prepass so this pipeline always writes the depth
+fn create_mask_pipeline(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+    gbuffer: bool,
+) -> wgpu::RenderPipeline {
+    // Alpha to coverage needs more than one sample, the shader does a hard alpha test otherwise
+    let alpha_to_coverage_enabled = sample_count > 1;
+    renderer
+        .errors
+        .scope(&renderer.device, "Mask Render Pipeline", || {
+            let shader = renderer
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Mask Render Pipeline Shader"),
+                    source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+                });
+
+            renderer
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Mask Render Pipeline"),
+                    layout: Some(pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[mesh::Vertex::layout(), TransformRaw::layout()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: if alpha_to_coverage_enabled {
+                            "fragment_alpha_to_coverage"
+                        } else {
+                            "fragment"
+                        },
+                        targets: &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, true),
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        ..Default::default()
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: renderer.depth_format,
+                        depth_write_enabled: true,
+                        depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: sample_count,
+                        alpha_to_coverage_enabled,
+                        ..Default::default()
+                    },
+                    multiview: None,
+                })
+        })
+}
+
+/// Compact entities aren't in the depth prepass so this always writes the depth
+fn create_compact_pipeline(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+    gbuffer: bool,
+) -> wgpu::RenderPipeline {
+    renderer
+        .errors
+        .scope(&renderer.device, "Compact Render Pipeline", || {
+            let shader = renderer
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Compact Render Pipeline Shader"),
+                    source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+                });
+
+            renderer
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Compact Render Pipeline"),
+                    layout: Some(pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex_compact",
+                        buffers: &[mesh::Vertex::layout(), CompactTransformRaw::layout()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment",
+                        targets: &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, true),
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        ..Default::default()
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: renderer.depth_format,
+                        depth_write_enabled: true,
+                        depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: sample_count,
+                        ..Default::default()
+                    },
+                    multiview: None,
+                })
+        })
+}
+
+/// Strips aren't in the depth prepass so this always writes the depth.
+/// The strips are split with [`Mesh::PRIMITIVE_RESTART`](crate::mesh::Mesh::PRIMITIVE_RESTART).
+fn create_strip_pipeline(
+    renderer: &WgpuRenderer,
+    pipeline_layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+    gbuffer: bool,
+) -> wgpu::RenderPipeline {
+    renderer
+        .errors
+        .scope(&renderer.device, "Strip Render Pipeline", || {
+            let shader = renderer
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Strip Render Pipeline Shader"),
+                    source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+                });
+
+            renderer
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Strip Render Pipeline"),
+                    layout: Some(pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[mesh::Vertex::layout(), TransformRaw::layout()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment",
+                        targets: &color_targets(renderer, wgpu::BlendState::REPLACE, gbuffer, true),
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleStrip,
+                        strip_index_format: Some(wgpu::IndexFormat::Uint32),
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        ..Default::default()
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: renderer.depth_format,
+                        depth_write_enabled: true,
+                        depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: sample_count,
+                        ..Default::default()
+                    },
+                    multiview: None,
+                })
+        })
+}
+
+/// The meshes with a position buffer are drawn with their own pipeline, see [`DepthOnlyPipelines`]
+fn create_depth_prepass_pipelines(
+    renderer: &WgpuRenderer,
+    mesh_view_layout: &MeshViewBindGroupLayout,
+    sample_count: u32,
+) -> DepthOnlyPipelines {
+    renderer
+        .errors
+        .scope(&renderer.device, "Depth Prepass Pipeline", || {
+            let shader = renderer
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Depth Prepass Shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        concat!(
+                            include_str!("shaders/common.wgsl"),
+                            include_str!("shaders/depth_prepass.wgsl")
+                        )
+                        .into(),
+                    ),
+                });
+
+            let pipeline_layout =
+                renderer
+                    .device
+                    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some("Depth Prepass Pipeline Layout"),
+                        bind_group_layouts: &[&mesh_view_layout.0],
+                        push_constant_ranges: &[],
+                    });
+
+            DepthOnlyPipelines::new(|vertex_layout| {
+                renderer
+                    .device
+                    .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        label: Some("Depth Prepass Pipeline"),
+                        layout: Some(&pipeline_layout),
+                        vertex: wgpu::VertexState {
+                            module: &shader,
+                            entry_point: "vertex",
+                            buffers: &[vertex_layout, TransformRaw::layout()],
+                        },
+                        // Only the depth is needed
+                        fragment: None,
+                        primitive: wgpu::PrimitiveState {
+                            topology: wgpu::PrimitiveTopology::TriangleList,
+                            front_face: wgpu::FrontFace::Ccw,
+                            cull_mode: Some(wgpu::Face::Back),
+                            ..Default::default()
+                        },
+                        depth_stencil: Some(wgpu::DepthStencilState {
+                            format: renderer.depth_format,
+                            depth_write_enabled: true,
+                            depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                            stencil: wgpu::StencilState::default(),
+                            bias: wgpu::DepthBiasState::default(),
+                        }),
+                        multisample: wgpu::MultisampleState {
+                            count: sample_count,
+                            ..Default::default()
+                        },
+                        multiview: None,
+                    })
+            })
+        })
+}
+
 pub fn setup(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
     mesh_view_layout: Res<MeshViewBindGroupLayout>,
     msaa: Res<Msaa>,
+    depth_prepass: Res<DepthPrepass>,
+    gbuffer: Res<GBufferEnabled>,
 ) {
-    commands.insert_resource(Base3dPass::new(&renderer, &mesh_view_layout, msaa.samples));
+    commands.insert_resource(Base3dPass::new(
+        &renderer,
+        &mesh_view_layout,
+        msaa.sample_count(),
+        depth_prepass.0,
+        gbuffer.0,
+    ));
 }
 
 pub fn update_render_pass(
     mut render_pass: ResMut<Base3dPass>,
     msaa: Res<Msaa>,
+    depth_prepass: Res<DepthPrepass>,
+    gbuffer: Res<GBufferEnabled>,
     mesh_view_layout: Res<MeshViewBindGroupLayout>,
     renderer: Res<WgpuRenderer>,
+    depth_texture: Res<DepthTexture>,
 ) {
-    if msaa.is_changed() {
+    if msaa.is_changed() || depth_prepass.is_changed() || gbuffer.is_changed() {
         log::info!("updating base_3d render pass");
-        *render_pass = Base3dPass::new(&renderer, &mesh_view_layout, msaa.samples);
+        *render_pass = Base3dPass::new(
+            &renderer,
+            &mesh_view_layout,
+            msaa.sample_count(),
+            depth_prepass.0,
+            gbuffer.0,
+        );
+
+        // The depth texture is recreated by update_depth_texture before this runs
+        let mismatched = msaa.mismatched(&[
+            ("depth texture", depth_texture.0.texture.sample_count()),
+            ("base 3d pipelines", render_pass.sample_count),
+        ]);
+        if !mismatched.is_empty() {
+            log::error!(
+                "{mismatched:?} don't use the {} samples of the msaa",
+                msaa.sample_count()
+            );
+        }
     }
 }
 
+/// Creates the [`GBuffer`] textures with the same size and sample count as the render target
+pub fn update_gbuffer(
+    mut gbuffer: ResMut<GBuffer>,
+    enabled: Res<GBufferEnabled>,
+    renderer: Res<WgpuRenderer>,
+    msaa: Res<Msaa>,
+    viewport: Option<Res<EguiViewport>>,
+) {
+    if !enabled.0 {
+        if gbuffer.target.is_some() {
+            gbuffer.target = None;
+        }
+        return;
+    }
+
+    let size = viewport
+        .as_ref()
+        .and_then(|viewport| viewport.target())
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    gbuffer.prepare(&renderer, size, msaa.sample_count());
+}
+
+struct TransparentDraw<'a> {
+    distance: f32,
+    blend_mode: BlendMode,
+    premultiplied_alpha: bool,
+    blend_constant: Vec3,
+    mesh: &'a ModelMesh,
+    instance_buffer: &'a InstanceBuffer,
+    instance_count: u32,
+    material_bind_group: &'a wgpu::BindGroup,
+    material_offset: u32,
+}
+
+/// The scratch materials of a [`material::ColorOverride`] replace the materials of the model
+fn active_materials<'a>(
+    (gpu_materials, color_override): (&'a GpuModelMaterials, Option<&'a GpuColorOverride>),
+) -> &'a GpuModelMaterials {
+    color_override.map_or(gpu_materials, |color_override| &color_override.0)
+}
+
 pub fn render(
     mesh_view_bind_group: Res<MeshViewBindGroup>,
     depth_texture: Res<DepthTexture>,
     mut encoder: ResMut<WgpuEncoder>,
     view: Res<WgpuView>,
     pass: Res<Base3dPass>,
-    light_query: Query<&Model, With<Light>>,
+    light_query: Query<(&Model, VisibilityQuery), With<Light>>,
+    light_buffer: Res<LightBuffer>,
     model_query: Query<
         (
             &Model,
             &InstanceBuffer,
             Option<&Instances>,
-            &GpuModelMaterials,
+            (&GpuModelMaterials, Option<&GpuColorOverride>),
+            Option<&Transform>,
+            VisibilityQuery,
+            Option<&CompactInstances>,
+            Option<&GpuIndirectInstances>,
+        ),
+        (
+            Without<Light>,
+            Without<Transparent>,
+            Without<StencilWrite>,
+            Without<StencilTest>,
+        ),
+    >,
+    stencil_query: Query<
+        (
+            &Model,
+            &InstanceBuffer,
+            Option<&Instances>,
+            (&GpuModelMaterials, Option<&GpuColorOverride>),
+            Option<&StencilWrite>,
+            Option<&StencilTest>,
+            VisibilityQuery,
         ),
-        (Without<Light>, Without<Transparent>),
+        (Without<Light>, Or<(With<StencilWrite>, With<StencilTest>)>),
     >,
-    clear_color: Res<GlaceClearColor>,
+    (clear_color, clear_flags): (Res<GlaceClearColor>, Res<ClearFlags>),
+    fog: Res<Fog>,
+    camera: Res<Camera>,
+    viewport: Option<Res<EguiViewport>>,
+    (ssao, shadows): (Res<SsaoPass>, Res<ShadowPass>),
+    (mut gbuffer, renderer, timer): (
+        ResMut<GBuffer>,
+        Res<WgpuRenderer>,
+        Option<ResMut<PassTimer>>,
+    ),
+    sky: Option<Res<GradientSky>>,
 ) {
     let encoder = if let Some(encoder) = encoder.0.as_mut() {
         encoder
@@ -153,25 +927,140 @@ pub fn render(
 
     // log::info!("render base");
 
+    // Render to the egui viewport instead of the window when it's used
+    let (view, depth_texture, size) = match viewport.as_ref().and_then(|v| v.target()) {
+        Some(target) => (&target.view, &target.depth_texture, target.size),
+        None => (
+            &*view,
+            &depth_texture.0,
+            [renderer.config.width, renderer.config.height],
+        ),
+    };
+
+    // Skipped while the times of a previous frame are read
+    let mut timer = timer.filter(|timer| timer.is_idle());
+    if let Some(timer) = &timer {
+        timer.write_timestamp(encoder, 0);
+    }
+
+    if let Some(depth_prepass_pipelines) = &pass.depth_prepass_pipelines {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Depth Prepass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: if clear_flags.depth {
+                        wgpu::LoadOp::Clear(pass.far_depth)
+                    } else {
+                        wgpu::LoadOp::Load
+                    },
+                    store: true,
+                }),
+                stencil_ops: stencil_ops(pass.stencil_pipelines.is_some(), clear_flags.depth),
+            }),
+        });
+
+        render_pass.set_bind_group(0, &mesh_view_bind_group.0, &[]);
+        for (model, instance_buffer, instances, gpu_materials, _, visibility, compact, _) in
+            &model_query
+        {
+            let gpu_materials = active_materials(gpu_materials);
+            if !camera.is_visible(visibility) || compact.is_some() {
+                continue;
+            }
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+                let (material, _, _) = gpu_materials.mesh_material(model, mesh_index);
+                // Masked meshes would write the depth of the discarded fragments
+                if material.blend_mode != BlendMode::Opaque
+                    || mesh.topology != wgpu::PrimitiveTopology::TriangleList
+                {
+                    continue;
+                }
+                let (pipeline, vertex_buffer) = depth_prepass_pipelines.select(mesh);
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+                render_pass.draw_indexed(
+                    0..mesh.num_elements,
+                    0,
+                    0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                );
+            }
+        }
+    }
+
+    if let Some(timer) = &timer {
+        timer.write_timestamp(encoder, 1);
+    }
+
+    let mut color_attachments = vec![Some(view.get_color_attachment(wgpu::Operations {
+        load: if GradientSky::is_visible(sky.as_deref(), &fog) || !clear_flags.color {
+            // The sky pass already filled the background
+            wgpu::LoadOp::Load
+        } else if fog.is_enabled() {
+            // The background is infinitely far so it's completely covered by the fog
+            wgpu::LoadOp::Clear(fog.color.into())
+        } else {
+            wgpu::LoadOp::Clear(clear_color.0.into())
+        },
+        store: true,
+    }))];
+    if pass.gbuffer {
+        // The pipelines have a normal target so the pass always needs the attachment,
+        // it's usually already created by update_gbuffer
+        let target = gbuffer.prepare(&renderer, size, pass.sample_count);
+        color_attachments.push(Some(target.normal.get_color_attachment(wgpu::Operations {
+            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            store: true,
+        })));
+    }
+
     let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
         label: Some("Base 3d Render Pass"),
-        color_attachments: &[Some(view.get_color_attachment(wgpu::Operations {
-            load: wgpu::LoadOp::Clear(clear_color.0.into()),
-            store: true,
-        }))],
+        color_attachments: &color_attachments,
         depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-            view: &depth_texture.0.view,
+            view: &depth_texture.view,
             depth_ops: Some(wgpu::Operations {
-                load: wgpu::LoadOp::Clear(1.0),
+                // The depth prepass already cleared it
+                load: if pass.depth_prepass_pipelines.is_some() || !clear_flags.depth {
+                    wgpu::LoadOp::Load
+                } else {
+                    wgpu::LoadOp::Clear(pass.far_depth)
+                },
                 store: true,
             }),
-            stencil_ops: None,
+            stencil_ops: stencil_ops(
+                pass.stencil_pipelines.is_some(),
+                pass.depth_prepass_pipelines.is_none() && clear_flags.depth,
+            ),
         }),
     });
 
     // TODO figure out how to sort models
     render_pass.set_pipeline(&pass.render_pipeline);
-    for (model, instance_buffer, instances, gpu_materials) in &model_query {
+    // Shared by every pipeline using the base_3d layout
+    render_pass.set_bind_group(2, ssao.output_bind_group(), &[]);
+    render_pass.set_bind_group(3, shadows.bind_group(), &[]);
+    for (model, instance_buffer, instances, gpu_materials, _, visibility, compact, indirect) in
+        &model_query
+    {
+        let gpu_materials = active_materials(gpu_materials);
+        if !camera.is_visible(visibility) || compact.is_some() {
+            continue;
+        }
+        // Only created when the device supports indirect draws
+        if let Some(indirect) = indirect {
+            model.draw_indirect(
+                &mut render_pass,
+                indirect,
+                gpu_materials,
+                &mesh_view_bind_group.0,
+                BlendMode::Opaque,
+            );
+            continue;
+        }
         // The draw function also uses the instance buffer under the hood it simply is of size 1
         render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
         model.draw_instanced(
@@ -179,26 +1068,245 @@ pub fn render(
             0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
             gpu_materials,
             &mesh_view_bind_group.0,
-            false,
+            BlendMode::Opaque,
         );
     }
 
-    // TODO I need a better way to identify transparent meshes in a model
-    render_pass.set_pipeline(&pass.transparent_render_pipeline);
-    for (model, instance_buffer, instances, gpu_materials) in &model_query {
-        // The draw function also uses the instance buffer under the hood it simply is of size 1
+    render_pass.set_pipeline(&pass.compact_render_pipeline);
+    for (model, instance_buffer, instances, gpu_materials, _, visibility, compact, _) in
+        &model_query
+    {
+        let gpu_materials = active_materials(gpu_materials);
+        if !camera.is_visible(visibility) || compact.is_none() {
+            continue;
+        }
         render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
         model.draw_instanced(
             &mut render_pass,
             0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
             gpu_materials,
             &mesh_view_bind_group.0,
-            true,
+            BlendMode::Opaque,
+        );
+    }
+
+    render_pass.set_pipeline(&pass.mask_render_pipeline);
+    for (model, instance_buffer, instances, gpu_materials, _, visibility, compact, indirect) in
+        &model_query
+    {
+        let gpu_materials = active_materials(gpu_materials);
+        if !camera.is_visible(visibility) || compact.is_some() {
+            continue;
+        }
+        if let Some(indirect) = indirect {
+            model.draw_indirect(
+                &mut render_pass,
+                indirect,
+                gpu_materials,
+                &mesh_view_bind_group.0,
+                BlendMode::Mask,
+            );
+            continue;
+        }
+        render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+        model.draw_instanced(
+            &mut render_pass,
+            0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+            gpu_materials,
+            &mesh_view_bind_group.0,
+            BlendMode::Mask,
+        );
+    }
+
+    if let Some(stencil_pipelines) = &pass.stencil_pipelines {
+        // Every window needs to be in the stencil buffer before the entities seen through them
+        render_pass.set_pipeline(&stencil_pipelines.write);
+        for (model, instance_buffer, instances, gpu_materials, stencil_write, _, visibility) in
+            &stencil_query
+        {
+            let gpu_materials = active_materials(gpu_materials);
+            if !camera.is_visible(visibility) {
+                continue;
+            }
+            let reference = if let Some(stencil_write) = stencil_write {
+                stencil_write.0
+            } else {
+                continue;
+            };
+            render_pass.set_stencil_reference(reference);
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            model.draw_instanced(
+                &mut render_pass,
+                0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                gpu_materials,
+                &mesh_view_bind_group.0,
+                BlendMode::Opaque,
+            );
+        }
+
+        render_pass.set_pipeline(&stencil_pipelines.test);
+        for (model, instance_buffer, instances, gpu_materials, _, stencil_test, visibility) in
+            &stencil_query
+        {
+            let gpu_materials = active_materials(gpu_materials);
+            if !camera.is_visible(visibility) {
+                continue;
+            }
+            let reference = if let Some(stencil_test) = stencil_test {
+                stencil_test.0
+            } else {
+                continue;
+            };
+            render_pass.set_stencil_reference(reference);
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            model.draw_instanced(
+                &mut render_pass,
+                0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                gpu_materials,
+                &mesh_view_bind_group.0,
+                BlendMode::Opaque,
+            );
+        }
+    }
+
+    render_pass.set_pipeline(&pass.strip_render_pipeline);
+    for (model, instance_buffer, instances, gpu_materials, _, visibility, compact, _) in
+        &model_query
+    {
+        let gpu_materials = active_materials(gpu_materials);
+        if !camera.is_visible(visibility) || compact.is_some() {
+            continue;
+        }
+        render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+        let strips = model
+            .meshes
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.topology == wgpu::PrimitiveTopology::TriangleStrip);
+        for (mesh_index, mesh) in strips {
+            let (_, material_bind_group, material_offset) =
+                gpu_materials.mesh_material(model, mesh_index);
+            mesh.draw_instanced(
+                &mut render_pass,
+                0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                material_bind_group,
+                material_offset,
+                &mesh_view_bind_group.0,
+            );
+        }
+    }
+
+    // Lines and points are always opaque
+    for (topology, pipeline) in &pass.topology_render_pipelines {
+        render_pass.set_pipeline(pipeline);
+        for (model, instance_buffer, instances, gpu_materials, _, visibility, compact, _) in
+            &model_query
+        {
+            let gpu_materials = active_materials(gpu_materials);
+            if !camera.is_visible(visibility) || compact.is_some() {
+                continue;
+            }
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            let meshes = model
+                .meshes
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| m.topology == *topology);
+            for (mesh_index, mesh) in meshes {
+                let (_, material_bind_group, material_offset) =
+                    gpu_materials.mesh_material(model, mesh_index);
+                mesh.draw_instanced(
+                    &mut render_pass,
+                    0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                    material_bind_group,
+                    material_offset,
+                    &mesh_view_bind_group.0,
+                );
+            }
+        }
+    }
+
+    // Transparent meshes need to be drawn back to front to blend correctly
+    let mut transparent_draws = vec![];
+    for (model, instance_buffer, instances, gpu_materials, transform, visibility, compact, _) in
+        &model_query
+    {
+        let gpu_materials = active_materials(gpu_materials);
+        if !camera.is_visible(visibility) || compact.is_some() {
+            continue;
+        }
+        // Instanced entities are sorted as a whole based on their first instance
+        let position = transform
+            .or_else(|| instances.and_then(|i| i.0.first()))
+            .map(|t| t.translation)
+            .unwrap_or(Vec3::ZERO);
+        let distance = position.distance_squared(camera.eye);
+        for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+            let (material, material_bind_group, material_offset) =
+                gpu_materials.mesh_material(model, mesh_index);
+            let blend_mode = material.blend_mode;
+            if !material.is_transparent() || mesh.topology != wgpu::PrimitiveTopology::TriangleList
+            {
+                continue;
+            }
+            transparent_draws.push(TransparentDraw {
+                distance,
+                blend_mode,
+                premultiplied_alpha: material.premultiplied_alpha,
+                blend_constant: material.blend_constant,
+                mesh,
+                instance_buffer,
+                instance_count: instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                material_bind_group,
+                material_offset,
+            });
+        }
+    }
+    transparent_draws.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+
+    let mut current_blend_mode = None;
+    for draw in transparent_draws {
+        let key = (draw.blend_mode, draw.premultiplied_alpha);
+        if current_blend_mode != Some(key) {
+            render_pass.set_pipeline(&pass.transparent_render_pipelines[&key]);
+            current_blend_mode = Some(key);
+        }
+        if draw.blend_mode == BlendMode::Constant {
+            render_pass.set_blend_constant(wgpu::Color {
+                r: draw.blend_constant.x as f64,
+                g: draw.blend_constant.y as f64,
+                b: draw.blend_constant.z as f64,
+                a: 1.0,
+            });
+        }
+        render_pass.set_vertex_buffer(1, draw.instance_buffer.0.slice(..));
+        draw.mesh.draw_instanced(
+            &mut render_pass,
+            0..draw.instance_count,
+            draw.material_bind_group,
+            draw.material_offset,
+            &mesh_view_bind_group.0,
         );
     }
 
     render_pass.set_pipeline(&pass.light_render_pipeline);
-    for light_model in &light_query {
-        draw_light_model(&mut render_pass, light_model, &mesh_view_bind_group.0);
+    for (light_index, entity) in light_buffer.entities.iter().enumerate() {
+        if let Ok((light_model, visibility)) = light_query.get(*entity) {
+            if !camera.is_visible(visibility) {
+                continue;
+            }
+            draw_light_model(
+                &mut render_pass,
+                light_model,
+                light_index as u32,
+                &mesh_view_bind_group.0,
+            );
+        }
+    }
+    drop(render_pass);
+
+    if let Some(timer) = &mut timer {
+        timer.write_timestamp(encoder, 2);
+        timer.resolve(encoder);
     }
 }
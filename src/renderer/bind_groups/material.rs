@@ -4,10 +4,14 @@ use bevy::{
     render::color::Color,
     render::render_resource::{encase::UniformBuffer, ShaderType},
 };
+use image::{imageops::FilterType, RgbaImage};
 use wgpu::util::DeviceExt;
 
 use crate::{
-    image_utils::image_from_color, model::Model, renderer::WgpuRenderer, texture::Texture,
+    image_utils::image_from_color,
+    model::{BlendMode, Material, Model},
+    renderer::{validation::RendererErrors, WgpuRenderer},
+    texture::Texture,
 };
 
 // TODO
@@ -15,12 +19,130 @@ use crate::{
 // Models are just a list of Mesh handles
 #[derive(Component)]
 pub struct GpuModelMaterials {
-    pub data: Vec<(
-        MaterialUniform,
-        wgpu::Buffer,
-        wgpu::BindGroup,
-        UniformBuffer<Vec<u8>>,
-    )>,
+    pub uniforms: Vec<MaterialUniform>,
+    /// The uniforms of every material, each material is bound with the dynamic offset of its uniform
+    pub buffer: wgpu::Buffer,
+    /// Distance in bytes between two uniforms of the buffer
+    stride: u32,
+    /// One per material, or a single one shared by every material when the textures are packed
+    bind_groups: Vec<wgpu::BindGroup>,
+    /// The textures used by each material, kept around so a single texture can be replaced
+    /// without recreating the other ones. It only has the texture arrays when the textures are packed.
+    pub textures: Vec<MaterialTextures>,
+    /// The settings the materials were created with, the textures may not be packed if it failed
+    texture_array: Option<MaterialTextureArray>,
+    packed: bool,
+    /// See [`MaterialOverrides`], empty unless the component is on the entity
+    overrides: Vec<GpuMaterialOverride>,
+}
+
+impl GpuModelMaterials {
+    /// The bind group of a material and the dynamic offset of its uniform
+    pub fn bind_group(&self, material_id: usize) -> (&wgpu::BindGroup, u32) {
+        let bind_group = if self.packed {
+            &self.bind_groups[0]
+        } else {
+            &self.bind_groups[material_id]
+        };
+        (bind_group, material_id as u32 * self.stride)
+    }
+
+    /// The material used to draw a mesh of the model, its [`MaterialOverride`] if it has one,
+    /// with its bind group and the dynamic offset of its uniform
+    pub fn mesh_material<'a>(
+        &'a self,
+        model: &'a Model,
+        mesh_index: usize,
+    ) -> (&'a Material, &'a wgpu::BindGroup, u32) {
+        if let Some(mesh_override) = self
+            .overrides
+            .iter()
+            .find(|mesh_override| mesh_override.mesh_index == mesh_index)
+        {
+            let (bind_group, offset) = mesh_override.gpu_materials.bind_group(0);
+            return (&mesh_override.material, bind_group, offset);
+        }
+        let material_id = model.meshes[mesh_index].material_id.unwrap_or(0);
+        let (bind_group, offset) = self.bind_group(material_id);
+        (&model.materials[material_id], bind_group, offset)
+    }
+
+    /// Whether every material shares the same bind group, see [`MaterialTextureArray`]
+    #[allow(unused)]
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+}
+
+/// Packs the textures of every material of the [`Model`] on the same entity in texture arrays,
+/// so all its meshes share a single material bind group instead of one per material.
+/// The material is selected with the dynamic offset of its uniform which also has its layer in the arrays.
+///
+/// Every layer of an array must have the same size and format so the textures are resized to `size`,
+/// bigger textures are downscaled and smaller ones are blurred.
+/// Models with more materials than the `max_texture_array_layers` of the device aren't packed.
+#[allow(unused)]
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaterialTextureArray {
+    /// The width and height of every layer
+    pub size: [u32; 2],
+}
+
+impl Default for MaterialTextureArray {
+    fn default() -> Self {
+        Self { size: [512, 512] }
+    }
+}
+
+pub struct MaterialTextures {
+    pub diffuse: Texture,
+    pub normal: Texture,
+    pub specular: Texture,
+}
+
+/// Replaces the diffuse texture of a material of the [`Model`] on the same entity.
+/// The component is removed once the new texture is uploaded.
+#[allow(unused)]
+#[derive(Component)]
+pub struct SetDiffuseTexture {
+    pub material_index: usize,
+    pub image: RgbaImage,
+}
+
+/// Draws the [`Model`] on the same entity in a flat color that ignores the lights,
+/// useful for selection highlights or debugging. The materials of the model aren't modified,
+/// a scratch copy of them is bound instead and removing the component restores the normal shading.
+/// The alpha of the color is used as the alpha of the materials, the blend modes aren't changed.
+#[allow(unused)]
+#[derive(Component, Debug, Clone, Copy)]
+pub struct ColorOverride(pub Color);
+
+/// The scratch materials of a [`ColorOverride`], bound by `base_3d::render` instead of the [`GpuModelMaterials`]
+#[derive(Component)]
+pub struct GpuColorOverride(pub GpuModelMaterials);
+
+/// Draws a single mesh of a [`Model`] with another material
+#[derive(Debug, Clone)]
+pub struct MaterialOverride {
+    /// The index of the mesh in the meshes of the model
+    pub mesh_index: usize,
+    pub material: Material,
+}
+
+/// Replaces the material of some meshes of the [`Model`] on the same entity without modifying
+/// the materials of the model, useful to highlight one part of a model made of multiple meshes.
+/// When a mesh has multiple overrides the first one is used and a [`ColorOverride`] takes precedence.
+/// The shadows and the ssao still use the material of the model.
+#[allow(unused)]
+#[derive(Component, Debug, Clone, Default)]
+pub struct MaterialOverrides(pub Vec<MaterialOverride>);
+
+/// The uploaded material of a [`MaterialOverride`], stored in the [`GpuModelMaterials`] of the model
+struct GpuMaterialOverride {
+    mesh_index: usize,
+    /// Used to pick the pipeline of the mesh
+    material: Material,
+    gpu_materials: Box<GpuModelMaterials>,
 }
 
 #[derive(ShaderType)]
@@ -30,6 +152,48 @@ pub struct MaterialUniform {
     pub gloss: f32,
     pub specular: Vec3,
     pub flags: u32,
+    pub normal_scale: f32,
+    pub alpha_cutoff: f32,
+    /// The layer of the texture arrays, always 0 unless the textures are packed
+    pub texture_layer: u32,
+    /// See [`ChannelMapping::bits`](crate::model::ChannelMapping::bits)
+    pub channel_mapping: u32,
+    pub emissive: Vec3,
+}
+
+impl From<&Material> for MaterialUniform {
+    fn from(material: &Material) -> Self {
+        Self {
+            base_color: material.base_color,
+            alpha: material.alpha,
+            gloss: material.gloss,
+            specular: material.specular,
+            flags: {
+                let mut flags = MaterialFlags::NONE;
+                if material.normal_texture.is_some() {
+                    flags |= MaterialFlags::USE_NORMAL_MAP;
+                }
+                if material.premultiplied_alpha {
+                    flags |= MaterialFlags::PREMULTIPLIED_ALPHA;
+                }
+                if material.blend_mode == BlendMode::Mask {
+                    flags |= MaterialFlags::ALPHA_MASK;
+                }
+                if material.specular_texture.is_some() {
+                    flags |= MaterialFlags::USE_SPECULAR_MAP;
+                }
+                if material.double_sided {
+                    flags |= MaterialFlags::DOUBLE_SIDED;
+                }
+                flags.bits()
+            },
+            normal_scale: material.normal_scale,
+            alpha_cutoff: material.alpha_cutoff,
+            texture_layer: 0,
+            channel_mapping: material.channel_mapping.bits(),
+            emissive: material.emissive,
+        }
+    }
 }
 
 // WARN these must match the flags in shader.wgsl
@@ -37,11 +201,11 @@ bitflags::bitflags! {
     #[repr(transparent)]
     pub struct MaterialFlags: u32 {
         const USE_NORMAL_MAP = (1 << 0);
-        const _1 = (1 << 1);
-        const _2 = (1 << 2);
-        const _3 = (1 << 3);
-        const _4 = (1 << 4);
-        const _5 = (1 << 5);
+        const PREMULTIPLIED_ALPHA = (1 << 1);
+        const ALPHA_MASK = (1 << 2);
+        const USE_SPECULAR_MAP = (1 << 3);
+        const UNLIT = (1 << 4);
+        const DOUBLE_SIDED = (1 << 5);
         const _6 = (1 << 6);
         const _7 = (1 << 7);
         const _8 = (1 << 8);
@@ -62,8 +226,8 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(MaterialUniform::min_size()),
                 },
                 count: None,
             },
@@ -73,7 +237,7 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
                     multisampled: false,
                 },
                 count: None,
@@ -90,7 +254,7 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
                     multisampled: false,
                 },
                 count: None,
@@ -107,7 +271,7 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Texture {
                     sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
                     multisampled: false,
                 },
                 count: None,
@@ -122,54 +286,283 @@ pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
     })
 }
 
+fn create_bind_group(
+    device: &wgpu::Device,
+    errors: &RendererErrors,
+    material_name: &str,
+    buffer: &wgpu::Buffer,
+    diffuse_texture: &Texture,
+    normal_texture: &Texture,
+    specular_texture: &Texture,
+) -> wgpu::BindGroup {
+    // The shaders always sample arrays, the textures that aren't packed only have one layer
+    let array_view = |texture: &Texture| {
+        texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        })
+    };
+    let diffuse_view = array_view(diffuse_texture);
+    let normal_view = array_view(normal_texture);
+    let specular_view = array_view(specular_texture);
+
+    let label = format!("{material_name}_material_bind_group");
+    errors.scope(device, &label, || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&label),
+            layout: &bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer,
+                        offset: 0,
+                        size: Some(MaterialUniform::min_size()),
+                    }),
+                },
+                // diffuse
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+                // normal
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&normal_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                },
+                // specular
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&specular_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(&specular_texture.sampler),
+                },
+            ],
+        })
+    })
+}
+
+/// Writes every uniform at its dynamic offset
+fn uniform_bytes(uniforms: &[MaterialUniform], stride: u32) -> Vec<u8> {
+    let mut bytes = vec![0; uniforms.len().max(1) * stride as usize];
+    for (i, uniform) in uniforms.iter().enumerate() {
+        let mut uniform_buffer = UniformBuffer::new(Vec::new());
+        uniform_buffer.write(uniform).unwrap();
+        let offset = i * stride as usize;
+        bytes[offset..offset + uniform_buffer.as_ref().len()]
+            .copy_from_slice(uniform_buffer.as_ref());
+    }
+    bytes
+}
+
+/// Resizes the image to the size of the array before writing it to the layer
+fn write_layer(queue: &wgpu::Queue, texture: &wgpu::Texture, layer: u32, image: &RgbaImage) {
+    let (width, height) = (texture.width(), texture.height());
+    let resized;
+    let image = if image.dimensions() == (width, height) {
+        image
+    } else {
+        resized = image::imageops::resize(image, width, height, FilterType::Triangle);
+        &resized
+    };
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: 0,
+                y: 0,
+                z: layer,
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        image,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * width),
+            rows_per_image: Some(height),
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+fn create_texture_array(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    label: &str,
+    images: &[&RgbaImage],
+    size: [u32; 2],
+    format: wgpu::TextureFormat,
+) -> Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size[0].max(1),
+            height: size[1].max(1),
+            depth_or_array_layers: images.len() as u32,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    for (layer, image) in images.iter().enumerate() {
+        write_layer(queue, &texture, layer as u32, image);
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    // Same as the sampler of Texture::from_image, without the mips since the layers don't have any
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::Repeat,
+        address_mode_v: wgpu::AddressMode::Repeat,
+        address_mode_w: wgpu::AddressMode::Repeat,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
 pub fn create_material_uniform(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
-    query: Query<(Entity, &Model), (Added<Model>, Without<GpuModelMaterials>)>,
+    query: Query<
+        (Entity, &Model, Option<&MaterialTextureArray>),
+        (Added<Model>, Without<GpuModelMaterials>),
+    >,
 ) {
-    for (entity, model) in query.iter() {
+    for (entity, model, texture_array) in query.iter() {
         log::info!("New model detected");
 
-        let mut gpu_materials = vec![];
-        for material in &model.materials {
-            let uniform = MaterialUniform {
-                base_color: material.base_color,
-                alpha: material.alpha,
-                gloss: material.gloss,
-                specular: material.specular,
-                flags: if material.normal_texture.is_some() {
-                    MaterialFlags::USE_NORMAL_MAP.bits()
-                } else {
-                    MaterialFlags::NONE.bits()
-                },
-            };
+        commands.entity(entity).insert(create_gpu_materials(
+            &renderer.device,
+            &renderer.queue,
+            &renderer.errors,
+            &model.materials,
+            texture_array.copied(),
+        ));
+    }
+}
 
-            let byte_buffer = Vec::new();
-            let mut uniform_buffer = UniformBuffer::new(byte_buffer);
-            uniform_buffer.write(&uniform).unwrap();
+/// Uploads the textures and uniforms of the materials.
+/// This only needs the device, queue and errors so it can also be used from a task.
+pub fn create_gpu_materials(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    errors: &RendererErrors,
+    materials: &[Material],
+    texture_array: Option<MaterialTextureArray>,
+) -> GpuModelMaterials {
+    let limits = device.limits();
+    let packed = match texture_array {
+        Some(_) if materials.is_empty() => false,
+        Some(_) if materials.len() as u32 > limits.max_texture_array_layers => {
+            log::warn!(
+                "Can't pack the textures of {} materials, the device supports up to {} layers",
+                materials.len(),
+                limits.max_texture_array_layers
+            );
+            false
+        }
+        Some(_) => true,
+        None => false,
+    };
 
-            let buffer = renderer
-                .device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    contents: uniform_buffer.as_ref(),
-                    label: None,
-                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                });
+    let uniforms: Vec<_> = materials
+        .iter()
+        .enumerate()
+        .map(|(i, material)| MaterialUniform {
+            texture_layer: if packed { i as u32 } else { 0 },
+            ..MaterialUniform::from(material)
+        })
+        .collect();
+    let align = limits.min_uniform_buffer_offset_alignment;
+    let size = MaterialUniform::min_size().get() as u32;
+    let stride = size.div_ceil(align) * align;
+    let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        contents: &uniform_bytes(&uniforms, stride),
+        label: Some("material_uniform_buffer"),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
 
+    let default_white = image_from_color(Color::WHITE);
+    let mut bind_groups = vec![];
+    let mut textures = vec![];
+    if let (true, Some(texture_array)) = (packed, texture_array) {
+        let diffuse: Vec<_> = materials.iter().map(|m| &m.diffuse_texture).collect();
+        let normal: Vec<_> = materials
+            .iter()
+            .map(|m| m.normal_texture.as_ref().unwrap_or(&default_white))
+            .collect();
+        let specular: Vec<_> = materials
+            .iter()
+            .map(|m| m.specular_texture.as_ref().unwrap_or(&default_white))
+            .collect();
+        let size = texture_array.size;
+        let texture = |label, images: &[&RgbaImage], format| {
+            create_texture_array(device, queue, label, images, size, format)
+        };
+        let diffuse = texture(
+            "diffuse_texture_array",
+            &diffuse,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+        );
+        let normal = texture(
+            "normal_texture_array",
+            &normal,
+            wgpu::TextureFormat::Rgba8Unorm,
+        );
+        let specular = texture(
+            "specular_texture_array",
+            &specular,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+        );
+
+        bind_groups.push(create_bind_group(
+            device, errors, "packed", &buffer, &diffuse, &normal, &specular,
+        ));
+        textures.push(MaterialTextures {
+            diffuse,
+            normal,
+            specular,
+        });
+    } else {
+        for material in materials {
             let diffuse_texture = Texture::from_image(
-                &renderer.device,
-                &renderer.queue,
+                device,
+                queue,
                 &material.diffuse_texture,
                 Some(&format!("{}_diffuse_texture", material.name)),
                 None,
             )
             .unwrap();
 
-            let default_white = image_from_color(Color::WHITE);
-
             let normal_texture = Texture::from_image(
-                &renderer.device,
-                &renderer.queue,
+                device,
+                queue,
                 material.normal_texture.as_ref().unwrap_or(&default_white),
                 Some(&format!("{}_normal_texture", material.name)),
                 Some(wgpu::TextureFormat::Rgba8Unorm),
@@ -177,58 +570,40 @@ pub fn create_material_uniform(
             .unwrap();
 
             let specular_texture = Texture::from_image(
-                &renderer.device,
-                &renderer.queue,
+                device,
+                queue,
                 material.specular_texture.as_ref().unwrap_or(&default_white),
                 Some(&format!("{}_specular_texture", material.name)),
                 None,
             )
             .unwrap();
 
-            let bind_group = renderer
-                .device
-                .create_bind_group(&wgpu::BindGroupDescriptor {
-                    label: Some(&format!("{}_material_bind_group", material.name)),
-                    layout: &bind_group_layout(&renderer.device),
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: buffer.as_entire_binding(),
-                        },
-                        // diffuse
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 2,
-                            resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                        },
-                        // normal
-                        wgpu::BindGroupEntry {
-                            binding: 3,
-                            resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 4,
-                            resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                        },
-                        // specular
-                        wgpu::BindGroupEntry {
-                            binding: 5,
-                            resource: wgpu::BindingResource::TextureView(&specular_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 6,
-                            resource: wgpu::BindingResource::Sampler(&specular_texture.sampler),
-                        },
-                    ],
-                });
-            gpu_materials.push((uniform, buffer, bind_group, uniform_buffer));
+            bind_groups.push(create_bind_group(
+                device,
+                errors,
+                &material.name,
+                &buffer,
+                &diffuse_texture,
+                &normal_texture,
+                &specular_texture,
+            ));
+            textures.push(MaterialTextures {
+                diffuse: diffuse_texture,
+                normal: normal_texture,
+                specular: specular_texture,
+            });
         }
-        commands.entity(entity).insert(GpuModelMaterials {
-            data: gpu_materials,
-        });
+    }
+
+    GpuModelMaterials {
+        uniforms,
+        buffer,
+        stride,
+        bind_groups,
+        textures,
+        texture_array,
+        packed,
+        overrides: vec![],
     }
 }
 
@@ -237,29 +612,227 @@ pub fn update_material_buffer(
     mut query: Query<(&Model, &mut GpuModelMaterials), Changed<Model>>,
 ) {
     for (model, mut gpu_materials) in query.iter_mut() {
-        for (i, mat) in model.materials.iter().enumerate() {
-            let u = MaterialUniform {
-                base_color: mat.base_color,
-                alpha: mat.alpha,
-                gloss: mat.gloss,
-                specular: mat.specular,
-                flags: if mat.normal_texture.is_some() {
-                    MaterialFlags::USE_NORMAL_MAP.bits()
+        let packed = gpu_materials.packed;
+        gpu_materials.uniforms = model
+            .materials
+            .iter()
+            .enumerate()
+            .map(|(i, material)| MaterialUniform {
+                texture_layer: if packed { i as u32 } else { 0 },
+                ..MaterialUniform::from(material)
+            })
+            .collect();
+        // TODO I have no idea if this actually works since I don't change any material at runtime
+        renderer.queue.write_buffer(
+            &gpu_materials.buffer,
+            0,
+            &uniform_bytes(&gpu_materials.uniforms, gpu_materials.stride),
+        );
+    }
+}
+
+/// Recreates the materials when a [`MaterialTextureArray`] is added, changed or removed.
+/// The loaders upload the materials without packing them so they are also packed here once they are ready.
+pub fn update_material_texture_arrays(
+    renderer: Res<WgpuRenderer>,
+    mut query: Query<(
+        &Model,
+        &mut GpuModelMaterials,
+        Option<&MaterialTextureArray>,
+    )>,
+) {
+    for (model, mut gpu_materials, texture_array) in query.iter_mut() {
+        if gpu_materials.texture_array.as_ref() == texture_array {
+            continue;
+        }
+        let overrides = std::mem::take(&mut gpu_materials.overrides);
+        *gpu_materials = create_gpu_materials(
+            &renderer.device,
+            &renderer.queue,
+            &renderer.errors,
+            &model.materials,
+            texture_array.copied(),
+        );
+        gpu_materials.overrides = overrides;
+    }
+}
+
+/// Uploads the new diffuse texture and only rebuilds the bind group of that material.
+/// The image is moved to the [`Model`] afterwards so the cpu side stays in sync,
+/// this triggers [`update_material_buffer`] but it only rewrites the uniform.
+/// Packed textures are resized and written to the layer of the material instead.
+pub fn set_diffuse_texture(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    mut query: Query<(
+        Entity,
+        &mut Model,
+        &mut GpuModelMaterials,
+        &mut SetDiffuseTexture,
+    )>,
+) {
+    for (entity, mut model, mut gpu_materials, mut request) in query.iter_mut() {
+        commands.entity(entity).remove::<SetDiffuseTexture>();
+
+        let index = request.material_index;
+        if index >= model.materials.len() {
+            log::warn!(
+                "Tried to set the diffuse texture of material {index} but the model only has {} materials",
+                model.materials.len()
+            );
+            continue;
+        }
+
+        if gpu_materials.packed {
+            write_layer(
+                &renderer.queue,
+                &gpu_materials.textures[0].diffuse.texture,
+                index as u32,
+                &request.image,
+            );
+            model.materials[index].diffuse_texture = std::mem::take(&mut request.image);
+            continue;
+        }
+
+        let name = model.materials[index].name.clone();
+        let diffuse_texture = match Texture::from_image(
+            &renderer.device,
+            &renderer.queue,
+            &request.image,
+            Some(&format!("{name}_diffuse_texture")),
+            None,
+        ) {
+            Ok(texture) => texture,
+            Err(err) => {
+                log::error!("Failed to upload diffuse texture of {name}: {err}");
+                continue;
+            }
+        };
+
+        let gpu_materials = &mut *gpu_materials;
+        let textures = &mut gpu_materials.textures[index];
+        gpu_materials.bind_groups[index] = create_bind_group(
+            &renderer.device,
+            &renderer.errors,
+            &name,
+            &gpu_materials.buffer,
+            &diffuse_texture,
+            &textures.normal,
+            &textures.specular,
+        );
+        textures.diffuse = diffuse_texture;
+
+        model.materials[index].diffuse_texture = std::mem::take(&mut request.image);
+    }
+}
+
+/// Creates the scratch materials of a [`ColorOverride`].
+/// Opaque materials use a white texture, the other ones keep their diffuse texture
+/// so the alpha mask and the transparency still work.
+pub fn update_color_override(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    query: Query<(Entity, &Model, &ColorOverride), Or<(Changed<ColorOverride>, Changed<Model>)>>,
+) {
+    for (entity, model, color_override) in query.iter() {
+        let color = color_override.0.as_rgba_f32();
+        let materials: Vec<_> = model
+            .materials
+            .iter()
+            .map(|material| Material {
+                name: format!("{}_color_override", material.name),
+                base_color: color.into(),
+                alpha: color[3],
+                blend_mode: material.blend_mode,
+                premultiplied_alpha: material.premultiplied_alpha,
+                alpha_cutoff: material.alpha_cutoff,
+                blend_constant: material.blend_constant,
+                diffuse_texture: if material.blend_mode == BlendMode::Opaque {
+                    image_from_color(Color::WHITE)
                 } else {
-                    MaterialFlags::NONE.bits()
+                    material.diffuse_texture.clone()
                 },
-            };
-            gpu_materials.data[i]
-                .3
-                .write(&u)
-                .expect("failed to write to material buffer");
-            // TODO I have no idea if this actually works since I don't change any material at runtime
-            renderer.queue.write_buffer(
-                &gpu_materials.data[i].1,
-                0,
-                gpu_materials.data[i].3.as_ref(),
-            );
-            gpu_materials.data[i].0 = u;
+                ..Default::default()
+            })
+            .collect();
+        let mut gpu_materials = create_gpu_materials(
+            &renderer.device,
+            &renderer.queue,
+            &renderer.errors,
+            &materials,
+            None,
+        );
+        for uniform in &mut gpu_materials.uniforms {
+            uniform.flags |= MaterialFlags::UNLIT.bits();
+        }
+        renderer.queue.write_buffer(
+            &gpu_materials.buffer,
+            0,
+            &uniform_bytes(&gpu_materials.uniforms, gpu_materials.stride),
+        );
+        commands
+            .entity(entity)
+            .insert(GpuColorOverride(gpu_materials));
+    }
+}
+
+pub fn remove_color_override(
+    mut commands: Commands,
+    mut removed: RemovedComponents<ColorOverride>,
+) {
+    for entity in removed.iter() {
+        if let Some(mut entity_commands) = commands.get_entity(entity) {
+            entity_commands.remove::<GpuColorOverride>();
+        }
+    }
+}
+
+/// Uploads the materials of the [`MaterialOverrides`] in the [`GpuModelMaterials`] of the model.
+/// The loaders insert the [`GpuModelMaterials`] once the model is ready so it's also done when they are added.
+pub fn update_material_overrides(
+    renderer: Res<WgpuRenderer>,
+    mut query: Query<
+        (&Model, &MaterialOverrides, &mut GpuModelMaterials),
+        Or<(Changed<MaterialOverrides>, Added<GpuModelMaterials>)>,
+    >,
+) {
+    for (model, overrides, mut gpu_materials) in query.iter_mut() {
+        gpu_materials.overrides = overrides
+            .0
+            .iter()
+            .filter(|mesh_override| {
+                let exists = mesh_override.mesh_index < model.meshes.len();
+                if !exists {
+                    log::warn!(
+                        "Tried to override the material of mesh {} but the model only has {} meshes",
+                        mesh_override.mesh_index,
+                        model.meshes.len()
+                    );
+                }
+                exists
+            })
+            .map(|mesh_override| GpuMaterialOverride {
+                mesh_index: mesh_override.mesh_index,
+                material: mesh_override.material.clone(),
+                gpu_materials: Box::new(create_gpu_materials(
+                    &renderer.device,
+                    &renderer.queue,
+                    &renderer.errors,
+                    std::slice::from_ref(&mesh_override.material),
+                    None,
+                )),
+            })
+            .collect();
+    }
+}
+
+pub fn remove_material_overrides(
+    mut removed: RemovedComponents<MaterialOverrides>,
+    mut query: Query<&mut GpuModelMaterials>,
+) {
+    for entity in removed.iter() {
+        if let Ok(mut gpu_materials) = query.get_mut(entity) {
+            gpu_materials.overrides.clear();
         }
     }
 }
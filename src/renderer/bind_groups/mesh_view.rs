@@ -1,13 +1,47 @@
-use bevy::{ecs::prelude::*, math::prelude::*, render::color::Color};
+use bevy::{ecs::prelude::*, math::prelude::*, render::color::Color, time::Time};
 use wgpu::util::DeviceExt;
 
-use crate::{camera::Camera, light::Light, renderer::WgpuRenderer};
+use crate::{
+    camera::Camera,
+    egui_plugin::viewport::EguiViewport,
+    light::{DirectionalLight, Light},
+    renderer::{
+        shadow::{DirectionalLightShadow, PointLightShadow, MAX_SHADOW_LIGHTS},
+        DebugView, Exposure, Fog, FogMode, WgpuRenderer,
+    },
+    skinning::JointMatrices,
+};
 
 #[derive(Resource)]
 pub struct CameraBuffer(pub wgpu::Buffer);
 
+/// Storage buffer containing every light in the scene
 #[derive(Resource)]
-pub struct LightBuffer(pub wgpu::Buffer);
+pub struct LightBuffer {
+    pub buffer: wgpu::Buffer,
+    /// The number of lights the buffer can hold before it needs to be reallocated
+    pub capacity: usize,
+    /// The light entities in the order they are stored in the buffer
+    pub entities: Vec<Entity>,
+    /// The lights with a [`PointLightShadow`] in the order of their shadow maps
+    pub shadow_casters: Vec<Entity>,
+    /// The first directional light with a [`DirectionalLightShadow`], it's the only one with cascades
+    pub directional_shadow_caster: Option<Entity>,
+}
+
+#[derive(Resource)]
+pub struct FogBuffer(pub wgpu::Buffer);
+
+#[derive(Resource)]
+pub struct GlobalsBuffer(pub wgpu::Buffer);
+
+/// Storage buffer containing the JointMatrices
+#[derive(Resource)]
+pub struct JointMatrixBuffer {
+    pub buffer: wgpu::Buffer,
+    /// The number of matrices the buffer can hold before it needs to be reallocated
+    pub capacity: usize,
+}
 
 #[derive(Resource)]
 pub struct MeshViewBindGroup(pub wgpu::BindGroup);
@@ -20,6 +54,10 @@ pub struct MeshViewBindGroupLayout(pub wgpu::BindGroupLayout);
 pub struct CameraUniform {
     view_position: [f32; 4],
     view_proj: [[f32; 4]; 4],
+    view: [[f32; 4]; 4],
+    /// The multiplier computed from the [`Exposure`]
+    exposure: f32,
+    _padding: [f32; 3],
 }
 
 impl CameraUniform {
@@ -27,12 +65,20 @@ impl CameraUniform {
         Self {
             view_position: [0.0; 4],
             view_proj: Mat4::IDENTITY.to_cols_array_2d(),
+            view: Mat4::IDENTITY.to_cols_array_2d(),
+            exposure: 1.0,
+            _padding: [0.0; 3],
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera) {
         self.view_position = [camera.eye.x, camera.eye.y, camera.eye.z, 1.0];
         self.view_proj = camera.build_view_projection_matrix().to_cols_array_2d();
+        self.view = camera.build_view_matrix().to_cols_array_2d();
+    }
+
+    pub fn update_exposure(&mut self, exposure: &Exposure) {
+        self.exposure = exposure.ev.exp2();
     }
 }
 
@@ -42,28 +88,209 @@ impl Default for CameraUniform {
     }
 }
 
+/// Values that change every frame and that any shader can use for animated effects
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Resource)]
+pub struct GlobalsUniform {
+    /// Seconds since the app started
+    pub time: f32,
+    /// Seconds since the last frame
+    pub delta_time: f32,
+    /// Size of the render target in pixels, this is the egui viewport when it's used
+    pub resolution: Vec2,
+    /// Number of frames since the app started, it wraps around on overflow
+    pub frame: u32,
+    /// The [`DebugView`] used by the main pass
+    pub debug_view: u32,
+    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
+    _padding: [u32; 2],
+}
+
+impl GlobalsUniform {
+    pub fn update(&mut self, time: &Time, resolution: Vec2, debug_view: DebugView) {
+        self.time = time.elapsed_seconds();
+        self.delta_time = time.delta_seconds();
+        self.resolution = resolution;
+        self.frame = self.frame.wrapping_add(1);
+        // WARN these must match the constants in shader.wgsl
+        self.debug_view = match debug_view {
+            DebugView::None => 0,
+            DebugView::Normals => 1,
+            DebugView::Uvs => 2,
+            DebugView::Tangents => 3,
+            DebugView::Depth => 4,
+        };
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LightUniform {
+    /// The position of a point light or the normalized direction of a directional light
     pub position: [f32; 3],
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    _padding: u32,
+    /// The index of the light in the point shadow maps, 0 for the directional light with the cascades
+    /// or [`LightUniform::NO_SHADOW`]
+    pub shadow_index: u32,
     pub color: [f32; 3],
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    _padding2: u32,
+    /// [`LightUniform::POINT`] or [`LightUniform::DIRECTIONAL`]
+    pub kind: u32,
 }
 
 impl LightUniform {
+    // WARN these must match the values in shader.wgsl
+    pub const NO_SHADOW: u32 = u32::MAX;
+    pub const POINT: u32 = 0;
+    pub const DIRECTIONAL: u32 = 1;
+
     pub fn new(position: Vec3, color: Color) -> Self {
         Self {
             position: position.to_array(),
-            _padding: 0,
+            shadow_index: Self::NO_SHADOW,
             color: [color.r(), color.g(), color.b()],
-            _padding2: 0,
+            kind: Self::POINT,
+        }
+    }
+
+    pub fn directional(direction: Vec3, color: Color) -> Self {
+        Self {
+            position: direction.normalize_or_zero().to_array(),
+            kind: Self::DIRECTIONAL,
+            ..Self::new(Vec3::ZERO, color)
         }
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogUniform {
+    pub color: [f32; 4],
+    pub start: f32,
+    pub end: f32,
+    pub mode: u32,
+    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
+    _padding: u32,
+}
+
+impl From<&Fog> for FogUniform {
+    fn from(fog: &Fog) -> Self {
+        Self {
+            color: fog.color.as_linear_rgba_f32(),
+            start: fog.start,
+            end: fog.end,
+            // WARN these must match the constants in common.wgsl
+            mode: match fog.mode {
+                FogMode::Linear => 0,
+                FogMode::Exponential => 1,
+            },
+            _padding: 0,
+        }
+    }
+}
+
+fn create_joint_matrix_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Joint Matrix Buffer"),
+        size: (capacity * std::mem::size_of::<Mat4>()) as u64,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn write_joint_matrix_buffer(
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    joint_matrices: &JointMatrices,
+) {
+    let data: Vec<_> = joint_matrices
+        .0
+        .iter()
+        .map(|matrix| matrix.to_cols_array_2d())
+        .collect();
+    if !data.is_empty() {
+        queue.write_buffer(buffer, 0, bytemuck::cast_slice(&data));
+    }
+}
+
+/// Header of the light storage buffer, the lights are stored right after it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsHeader {
+    count: u32,
+    // The light array needs to be aligned to 16 bytes
+    _padding: [u32; 3],
+}
+
+fn light_buffer_size(capacity: usize) -> u64 {
+    (std::mem::size_of::<LightsHeader>() + capacity * std::mem::size_of::<LightUniform>()) as u64
+}
+
+fn create_light_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Light Buffer"),
+        size: light_buffer_size(capacity),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn write_light_buffer(queue: &wgpu::Queue, buffer: &wgpu::Buffer, lights: &[LightUniform]) {
+    let header = LightsHeader {
+        count: lights.len() as u32,
+        _padding: [0; 3],
+    };
+    queue.write_buffer(buffer, 0, bytemuck::bytes_of(&header));
+    if !lights.is_empty() {
+        queue.write_buffer(
+            buffer,
+            std::mem::size_of::<LightsHeader>() as u64,
+            bytemuck::cast_slice(lights),
+        );
+    }
+}
+
+fn create_mesh_view_bind_group(
+    renderer: &WgpuRenderer,
+    layout: &wgpu::BindGroupLayout,
+    camera_buffer: &wgpu::Buffer,
+    light_buffer: &wgpu::Buffer,
+    fog_buffer: &wgpu::Buffer,
+    joint_matrix_buffer: &wgpu::Buffer,
+    globals_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    renderer
+        .errors
+        .scope(&renderer.device, "mesh view bind group", || {
+            renderer
+                .device
+                .create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("camera_bind_group"),
+                    layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: camera_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: light_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: fog_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: joint_matrix_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 4,
+                            resource: globals_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+        })
+}
+
 impl From<&Light> for LightUniform {
     fn from(light: &Light) -> Self {
         LightUniform::new(light.position, light.color)
@@ -76,11 +303,19 @@ impl From<Light> for LightUniform {
     }
 }
 
+impl From<&DirectionalLight> for LightUniform {
+    fn from(light: &DirectionalLight) -> Self {
+        LightUniform::directional(light.direction, light.color)
+    }
+}
+
 pub fn setup_mesh_view_bind_group(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
     camera_uniform: Res<CameraUniform>,
-    light: Query<&Light>,
+    lights: Query<(Entity, &Light)>,
+    fog: Res<Fog>,
+    joint_matrices: Res<JointMatrices>,
 ) {
     log::info!("setting up mesh view bind group");
     let device = &renderer.device;
@@ -99,10 +334,43 @@ pub fn setup_mesh_view_bind_group(
                 },
                 count: None,
             },
-            // Light
+            // Lights
             wgpu::BindGroupLayoutEntry {
                 binding: 1,
                 visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(light_buffer_size(1)),
+                },
+                count: None,
+            },
+            // Fog
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // Joint matrices
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<Mat4>() as u64),
+                },
+                count: None,
+            },
+            // Globals
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
                     has_dynamic_offset: false,
@@ -119,30 +387,55 @@ pub fn setup_mesh_view_bind_group(
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
-    let light = light.single();
-    let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Light VB"),
-        contents: bytemuck::cast_slice(&[LightUniform::from(light)]),
+    let (entities, light_uniforms): (Vec<_>, Vec<_>) = lights
+        .iter()
+        .map(|(entity, light)| (entity, LightUniform::from(light)))
+        .unzip();
+    // Always allocate at least one light so the buffer is never empty
+    let capacity = light_uniforms.len().max(1);
+    let light_buffer = create_light_buffer(device, capacity);
+    write_light_buffer(&renderer.queue, &light_buffer, &light_uniforms);
+
+    let fog_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Fog Buffer"),
+        contents: bytemuck::cast_slice(&[FogUniform::from(fog.as_ref())]),
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
-    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: Some("camera_bind_group"),
-        layout: &mesh_view_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: light_buffer.as_entire_binding(),
-            },
-        ],
+    let joint_matrix_capacity = joint_matrices.0.len().max(1);
+    let joint_matrix_buffer = create_joint_matrix_buffer(device, joint_matrix_capacity);
+    write_joint_matrix_buffer(&renderer.queue, &joint_matrix_buffer, &joint_matrices);
+
+    let globals_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Globals Buffer"),
+        contents: bytemuck::cast_slice(&[GlobalsUniform::default()]),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
+    let bind_group = create_mesh_view_bind_group(
+        &renderer,
+        &mesh_view_layout,
+        &camera_buffer,
+        &light_buffer,
+        &fog_buffer,
+        &joint_matrix_buffer,
+        &globals_buffer,
+    );
+
     commands.insert_resource(CameraBuffer(camera_buffer));
-    commands.insert_resource(LightBuffer(light_buffer));
+    commands.insert_resource(FogBuffer(fog_buffer));
+    commands.insert_resource(GlobalsBuffer(globals_buffer));
+    commands.insert_resource(JointMatrixBuffer {
+        buffer: joint_matrix_buffer,
+        capacity: joint_matrix_capacity,
+    });
+    commands.insert_resource(LightBuffer {
+        buffer: light_buffer,
+        capacity,
+        entities,
+        shadow_casters: vec![],
+        directional_shadow_caster: None,
+    });
     log::info!("inserting mesh view bind group layout");
     commands.insert_resource(MeshViewBindGroupLayout(mesh_view_layout));
     commands.insert_resource(MeshViewBindGroup(bind_group));
@@ -153,9 +446,11 @@ pub fn update_camera_buffer(
     camera: Res<Camera>,
     camera_buffer: Res<CameraBuffer>,
     mut camera_uniform: ResMut<CameraUniform>,
+    exposure: Res<Exposure>,
 ) {
-    if camera.is_changed() {
+    if camera.is_changed() || exposure.is_changed() {
         camera_uniform.update_view_proj(&camera);
+        camera_uniform.update_exposure(&exposure);
         renderer.queue.write_buffer(
             &camera_buffer.0,
             0,
@@ -164,16 +459,129 @@ pub fn update_camera_buffer(
     }
 }
 
+pub fn update_fog_buffer(renderer: Res<WgpuRenderer>, fog: Res<Fog>, fog_buffer: Res<FogBuffer>) {
+    if fog.is_changed() {
+        renderer.queue.write_buffer(
+            &fog_buffer.0,
+            0,
+            bytemuck::cast_slice(&[FogUniform::from(fog.as_ref())]),
+        );
+    }
+}
+
+pub fn update_globals_buffer(
+    renderer: Res<WgpuRenderer>,
+    time: Res<Time>,
+    viewport: Option<Res<EguiViewport>>,
+    globals_buffer: Res<GlobalsBuffer>,
+    mut globals_uniform: ResMut<GlobalsUniform>,
+    debug_view: Res<DebugView>,
+) {
+    let [width, height] = viewport
+        .as_ref()
+        .and_then(|viewport| viewport.target())
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    globals_uniform.update(&time, Vec2::new(width as f32, height as f32), *debug_view);
+    renderer.queue.write_buffer(
+        &globals_buffer.0,
+        0,
+        bytemuck::cast_slice(&[*globals_uniform]),
+    );
+}
+
 pub fn update_light_buffer(
     renderer: Res<WgpuRenderer>,
-    query: Query<&Light>,
+    query: Query<(Entity, &Light, Option<&PointLightShadow>)>,
+    directional_query: Query<(Entity, &DirectionalLight, Option<&DirectionalLightShadow>)>,
+    mut light_buffer: ResMut<LightBuffer>,
+    camera_buffer: Res<CameraBuffer>,
+    fog_buffer: Res<FogBuffer>,
+    joint_matrix_buffer: Res<JointMatrixBuffer>,
+    globals_buffer: Res<GlobalsBuffer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+    mut mesh_view_bind_group: ResMut<MeshViewBindGroup>,
+) {
+    let light_buffer = light_buffer.as_mut();
+    light_buffer.entities.clear();
+    light_buffer.shadow_casters.clear();
+    light_buffer.directional_shadow_caster = None;
+    let mut light_uniforms = vec![];
+    for (entity, light, shadow) in query.iter() {
+        let mut uniform = LightUniform::from(light);
+        if shadow.is_some() && light_buffer.shadow_casters.len() < MAX_SHADOW_LIGHTS {
+            uniform.shadow_index = light_buffer.shadow_casters.len() as u32;
+            light_buffer.shadow_casters.push(entity);
+        }
+        light_buffer.entities.push(entity);
+        light_uniforms.push(uniform);
+    }
+    // After the point lights so their index doesn't depend on the directional lights
+    for (entity, light, shadow) in directional_query.iter() {
+        let mut uniform = LightUniform::from(light);
+        if shadow.is_some() && light_buffer.directional_shadow_caster.is_none() {
+            uniform.shadow_index = 0;
+            light_buffer.directional_shadow_caster = Some(entity);
+        }
+        light_buffer.entities.push(entity);
+        light_uniforms.push(uniform);
+    }
+
+    if light_uniforms.len() > light_buffer.capacity {
+        let capacity = light_uniforms.len().next_power_of_two();
+        log::info!("Reallocating light buffer with a capacity of {capacity} lights");
+        light_buffer.buffer = create_light_buffer(&renderer.device, capacity);
+        light_buffer.capacity = capacity;
+        // The bind group still points to the old buffer
+        mesh_view_bind_group.0 = create_mesh_view_bind_group(
+            &renderer,
+            &mesh_view_layout.0,
+            &camera_buffer.0,
+            &light_buffer.buffer,
+            &fog_buffer.0,
+            &joint_matrix_buffer.buffer,
+            &globals_buffer.0,
+        );
+    }
+
+    write_light_buffer(&renderer.queue, &light_buffer.buffer, &light_uniforms);
+}
+
+pub fn update_joint_matrix_buffer(
+    renderer: Res<WgpuRenderer>,
+    joint_matrices: Res<JointMatrices>,
+    mut joint_matrix_buffer: ResMut<JointMatrixBuffer>,
+    camera_buffer: Res<CameraBuffer>,
     light_buffer: Res<LightBuffer>,
+    fog_buffer: Res<FogBuffer>,
+    globals_buffer: Res<GlobalsBuffer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+    mut mesh_view_bind_group: ResMut<MeshViewBindGroup>,
 ) {
-    for light in query.iter() {
-        renderer.queue.write_buffer(
-            &light_buffer.0,
-            0,
-            bytemuck::cast_slice(&[LightUniform::from(light)]),
+    if !joint_matrices.is_changed() {
+        return;
+    }
+
+    if joint_matrices.0.len() > joint_matrix_buffer.capacity {
+        let capacity = joint_matrices.0.len().next_power_of_two();
+        log::info!("Reallocating joint matrix buffer with a capacity of {capacity} joints");
+        joint_matrix_buffer.buffer = create_joint_matrix_buffer(&renderer.device, capacity);
+        joint_matrix_buffer.capacity = capacity;
+        // The bind group still points to the old buffer
+        mesh_view_bind_group.0 = create_mesh_view_bind_group(
+            &renderer,
+            &mesh_view_layout.0,
+            &camera_buffer.0,
+            &light_buffer.buffer,
+            &fog_buffer.0,
+            &joint_matrix_buffer.buffer,
+            &globals_buffer.0,
         );
     }
+
+    write_joint_matrix_buffer(
+        &renderer.queue,
+        &joint_matrix_buffer.buffer,
+        &joint_matrices,
+    );
 }
@@ -0,0 +1,249 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{ecs::prelude::*, time::Time};
+
+use super::WgpuRenderer;
+
+/// How much a new frame contributes to the smoothed frame time
+const SMOOTHING: f32 = 0.1;
+
+/// Frame timings updated by the renderer plugin every frame.
+/// It doesn't need bevy's diagnostics plugins so it also works with `MinimalPlugins`.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// Exponential moving average of the frame time in seconds
+    pub frame_time: f32,
+    /// Frames per second based on the smoothed frame time
+    pub fps: f32,
+    /// Number of frames since the app started
+    pub frame_count: u64,
+    /// Gpu time of the depth prepass in seconds, 0 when it's disabled. See [`PassTimer`]
+    pub depth_prepass_time: Option<f32>,
+    /// Gpu time of the base 3d pass in seconds, without the depth prepass. See [`PassTimer`]
+    pub base_3d_time: Option<f32>,
+}
+
+impl FrameStats {
+    fn update(&mut self, delta_seconds: f32) {
+        self.frame_count += 1;
+        if delta_seconds <= 0.0 {
+            return;
+        }
+        // The first frame would otherwise take a while to converge from 0
+        self.frame_time = if self.frame_time == 0.0 {
+            delta_seconds
+        } else {
+            self.frame_time + (delta_seconds - self.frame_time) * SMOOTHING
+        };
+        self.fps = 1.0 / self.frame_time;
+    }
+
+    /// Draws the fps and the frame time in the top left corner of the window
+    #[allow(unused)]
+    pub fn show_overlay(&self, ctx: &egui::Context) {
+        egui::Area::new("Frame stats area")
+            .interactable(false)
+            .anchor(egui::Align2::LEFT_TOP, [0., 0.])
+            .show(ctx, |ui| {
+                egui::Frame::none()
+                    .fill(egui::Color32::from_rgba_premultiplied(
+                        0,
+                        0,
+                        0,
+                        (0.75 * 256.0) as u8,
+                    ))
+                    .show(ui, |ui| {
+                        ui.visuals_mut().override_text_color = Some(egui::Color32::WHITE);
+                        ui.label(format!("fps: {:.2}", self.fps));
+                        ui.label(format!("dt: {:.2}ms", self.frame_time * 1000.0));
+                        if let (Some(prepass), Some(base_3d)) =
+                            (self.depth_prepass_time, self.base_3d_time)
+                        {
+                            ui.label(format!("prepass: {:.2}ms", prepass * 1000.0));
+                            ui.label(format!("base 3d: {:.2}ms", base_3d * 1000.0));
+                        }
+                    });
+            });
+    }
+}
+
+pub fn update_frame_stats(mut stats: ResMut<FrameStats>, time: Res<Time>) {
+    stats.update(time.delta_seconds());
+}
+
+/// Measures the gpu time of the depth prepass and of the base 3d pass with timestamp queries.
+/// Compare the times with and without the [`DepthPrepass`](super::base_3d::DepthPrepass)
+/// to know if it saves more overdraw than it costs in a scene.
+///
+/// It only exists when the device supports [`wgpu::Features::TIMESTAMP_QUERY`].
+/// The timestamps are read without blocking so the [`FrameStats`] are a few frames late,
+/// the frames rendered while they are read aren't measured.
+#[derive(Resource)]
+pub struct PassTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    state: TimerState,
+}
+
+enum TimerState {
+    /// The timestamps can be written
+    Idle,
+    /// The timestamps are copied to the readback buffer by the encoder of this frame
+    Recorded,
+    /// Waiting for the readback buffer to be mapped, the callback stores the result
+    Mapping(Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>),
+}
+
+impl PassTimer {
+    /// Before the depth prepass, between the two passes and after the base 3d pass
+    pub const QUERY_COUNT: u32 = 3;
+    const SIZE: u64 = Self::QUERY_COUNT as u64 * std::mem::size_of::<u64>() as u64;
+
+    fn new(device: &wgpu::Device) -> Self {
+        Self {
+            query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("pass_timer_query_set"),
+                ty: wgpu::QueryType::Timestamp,
+                count: Self::QUERY_COUNT,
+            }),
+            resolve_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Pass Timer Resolve Buffer"),
+                size: Self::SIZE,
+                usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Pass Timer Readback Buffer"),
+                size: Self::SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            state: TimerState::Idle,
+        }
+    }
+
+    /// False while the timestamps of a previous frame are being read
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, TimerState::Idle)
+    }
+
+    pub fn write_timestamp(&self, encoder: &mut wgpu::CommandEncoder, index: u32) {
+        encoder.write_timestamp(&self.query_set, index);
+    }
+
+    /// Copies the timestamps to the readback buffer, call it after writing the last one
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.resolve_query_set(
+            &self.query_set,
+            0..Self::QUERY_COUNT,
+            &self.resolve_buffer,
+            0,
+        );
+        encoder.copy_buffer_to_buffer(
+            &self.resolve_buffer,
+            0,
+            &self.readback_buffer,
+            0,
+            Self::SIZE,
+        );
+        self.state = TimerState::Recorded;
+    }
+}
+
+/// The time of the depth prepass and of the base 3d pass in seconds.
+/// The period is the number of nanoseconds per tick given by [`wgpu::Queue::get_timestamp_period`].
+fn pass_times(timestamps: [u64; 3], period: f32) -> (f32, f32) {
+    let seconds = |start: u64, end: u64| end.saturating_sub(start) as f32 * period / 1e9;
+    (
+        seconds(timestamps[0], timestamps[1]),
+        seconds(timestamps[1], timestamps[2]),
+    )
+}
+
+pub fn setup_pass_timer(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    if renderer
+        .device
+        .features()
+        .contains(wgpu::Features::TIMESTAMP_QUERY)
+    {
+        commands.insert_resource(PassTimer::new(&renderer.device));
+    } else {
+        log::info!("Timestamp queries aren't supported, the pass times won't be measured");
+    }
+}
+
+/// Maps the readback buffer once the encoder with the timestamps is submitted
+/// and updates the [`FrameStats`] when it's mapped
+pub fn read_pass_timer(
+    timer: Option<ResMut<PassTimer>>,
+    renderer: Res<WgpuRenderer>,
+    mut stats: ResMut<FrameStats>,
+) {
+    let mut timer = if let Some(timer) = timer {
+        timer
+    } else {
+        return;
+    };
+    match &timer.state {
+        TimerState::Idle => {}
+        TimerState::Recorded => {
+            let result = Arc::new(Mutex::new(None));
+            let callback_result = result.clone();
+            timer
+                .readback_buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |mapped| {
+                    *callback_result.lock().unwrap() = Some(mapped);
+                });
+            timer.state = TimerState::Mapping(result);
+        }
+        TimerState::Mapping(result) => {
+            renderer.device.poll(wgpu::Maintain::Poll);
+            let mapped = if let Some(mapped) = result.lock().unwrap().take() {
+                mapped
+            } else {
+                return;
+            };
+            if let Err(err) = mapped {
+                log::error!("Failed to map pass timer buffer: {err}");
+                timer.state = TimerState::Idle;
+                return;
+            }
+            let timestamps: [u64; 3] = {
+                let data = timer.readback_buffer.slice(..).get_mapped_range();
+                bytemuck::pod_read_unaligned(&data)
+            };
+            timer.readback_buffer.unmap();
+            timer.state = TimerState::Idle;
+
+            let (depth_prepass_time, base_3d_time) =
+                pass_times(timestamps, renderer.queue.get_timestamp_period());
+            stats.depth_prepass_time = Some(depth_prepass_time);
+            stats.base_3d_time = Some(base_3d_time);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_times_in_seconds() {
+        let (prepass, base_3d) = pass_times([1_000, 1_000, 3_001_000], 1.0);
+        assert_eq!(prepass, 0.0);
+        assert!((base_3d - 0.003).abs() < 1e-6);
+
+        // Some gpus count in ticks longer than a nanosecond
+        let (prepass, base_3d) = pass_times([0, 500_000, 1_000_000], 2.0);
+        assert!((prepass - 0.001).abs() < 1e-6);
+        assert!((base_3d - 0.001).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pass_times_out_of_order() {
+        // The timestamps aren't guaranteed to be monotonic on every backend
+        assert_eq!(pass_times([10, 5, 0], 1.0), (0.0, 0.0));
+    }
+}
@@ -1,22 +1,56 @@
 use bevy::{
-    app::prelude::*, ecs::prelude::*, render::color::Color, utils::default, window::WindowResized,
+    app::prelude::*,
+    ecs::{
+        prelude::*,
+        schedule::{ExecutorKind, SystemConfigs},
+    },
+    render::color::Color,
+    utils::default,
+    window::WindowResized,
     winit::WinitWindows,
 };
 use futures_lite::future;
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use wgpu::{CommandEncoder, SurfaceTexture, TextureView};
-use winit::{dpi::PhysicalSize, window::Window};
+use winit::{
+    dpi::PhysicalSize,
+    window::{Fullscreen, Icon, Window},
+};
 
 use crate::{
-    camera::{Camera, CameraPlugin},
-    egui_plugin::{self, EguiScreenDesciptorRes},
-    instances,
+    camera::{self, Camera, CameraPlugin},
+    egui_plugin::{self, EguiCtxRes, EguiScreenDesciptorRes, UiScale},
+    instances, light,
+    mesh::{PositionVertex, Vertex},
+    model::ModelMesh,
+    skinning::JointMatrices,
     texture::Texture,
 };
 
-use self::{bind_groups::mesh_view::CameraUniform, wireframe::WireframePlugin};
+use self::{
+    bind_groups::mesh_view::CameraUniform,
+    screenshot::TakeScreenshot,
+    validation::{RendererErrorEvent, RendererErrors},
+    wireframe::WireframePlugin,
+};
 
+pub mod auto_exposure;
 pub mod base_3d;
 pub mod bind_groups;
+pub mod frame_stats;
+pub mod outline;
+pub mod picking;
+pub mod render_phase;
+pub mod screenshot;
+pub mod shadow;
+pub mod sky;
+pub mod smaa;
+pub mod ssao;
+pub mod validation;
 pub mod wireframe;
 
 #[derive(Resource)]
@@ -25,8 +59,230 @@ pub struct DepthTexture(pub Texture);
 #[derive(Default, Resource)]
 pub struct GlaceClearColor(pub Color);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FogMode {
+    /// The fog goes linearly from transparent at `start` to opaque at `end`
+    Linear,
+    /// The fog density increases exponentially after `start` and is almost opaque at `end`
+    Exponential,
+}
+
+/// Blends the color of the meshes toward the fog color based on their distance to the camera.
+/// The default fog ends so far away that it has no visible effect.
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct Fog {
+    pub color: Color,
+    pub start: f32,
+    pub end: f32,
+    pub mode: FogMode,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            color: Color::WHITE,
+            start: 0.0,
+            end: f32::MAX,
+            mode: FogMode::Linear,
+        }
+    }
+}
+
+impl Fog {
+    pub fn is_enabled(&self) -> bool {
+        self.end < f32::MAX
+    }
+}
+
+/// Scales the color of the lit meshes by `2^ev`, the default of 0 leaves them unchanged.
+/// There's no hdr target or tonemapping yet so the result is still clamped by the surface.
+/// It's set every frame when an [`auto_exposure::AutoExposure`] is inserted.
+#[derive(Debug, Clone, Copy, Default, Resource)]
+pub struct Exposure {
+    pub ev: f32,
+}
+
+/// Replaces the shading of the lit meshes to debug their attributes.
+/// Unlit meshes and the other passes are unaffected.
+#[allow(unused)]
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    None,
+    /// World space normals, including the normal map
+    Normals,
+    Uvs,
+    /// World space tangents, black for materials without a normal map
+    Tangents,
+    /// Distance to the camera, black close to the camera and white far from it
+    Depth,
+}
+
+impl DebugView {
+    #[allow(unused)]
+    pub const ALL: [DebugView; 5] = [
+        DebugView::None,
+        DebugView::Normals,
+        DebugView::Uvs,
+        DebugView::Tangents,
+        DebugView::Depth,
+    ];
+}
+
+/// The color space of the window surface.
+/// It's only read when the renderer is created, changing it afterwards has no effect.
+/// Unsupported color spaces fall back to sRGB.
+#[allow(unused)]
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColorSpace {
+    /// 8 bit sRGB, supported everywhere
+    #[default]
+    Srgb,
+    /// Needs a PQ encoded surface which wgpu can't create yet so this always falls back to sRGB
+    Hdr10,
+    /// 16 bit float scRGB, the shaders already output linear colors so no extra encoding is needed
+    ExtendedLinear,
+}
+
+/// The format of the depth texture used by the 3d passes.
+/// It's only read when the renderer is created, changing it afterwards has no effect.
+/// Unsupported formats fall back to [`DepthFormat::Depth32Float`].
+#[allow(unused)]
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthFormat {
+    /// No stencil buffer, supported everywhere
+    #[default]
+    Depth32Float,
+    /// 8 bit stencil, supported everywhere
+    Depth24PlusStencil8,
+    /// 8 bit stencil with a full precision depth, needs the `DEPTH32FLOAT_STENCIL8` feature
+    Depth32FloatStencil8,
+}
+
+impl DepthFormat {
+    pub fn texture_format(self) -> wgpu::TextureFormat {
+        match self {
+            DepthFormat::Depth32Float => wgpu::TextureFormat::Depth32Float,
+            DepthFormat::Depth24PlusStencil8 => wgpu::TextureFormat::Depth24PlusStencil8,
+            DepthFormat::Depth32FloatStencil8 => wgpu::TextureFormat::Depth32FloatStencil8,
+        }
+    }
+}
+
+/// Maps the near plane to a depth of 1 and the far plane to 0, this is known as reverse-Z.
+/// The float depth formats have a lot more precision close to 0, which is where the perspective
+/// divide puts most of the scene without it. This mostly removes the z-fighting far from the camera
+/// in big scenes like Bistro, at the cost of nothing but a flipped depth test.
+///
+/// Every pipeline compares with `Greater` instead of `Less` and the depth is cleared to 0.
+/// The passes reading the depth texture account for it.
+/// It's only read when the renderer is created, changing it afterwards has no effect.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReverseZ(pub bool);
+
+/// Configures the window when the renderer is created, changing it afterwards has no effect.
+/// Anything set to None keeps what was configured in bevy's `WindowPlugin`.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct WindowConfig {
+    pub title: Option<String>,
+    /// In physical pixels
+    pub inner_size: Option<[u32; 2]>,
+    /// Path to a png used as the window icon
+    pub icon: Option<PathBuf>,
+}
+
+impl WindowConfig {
+    fn apply(&self, window: &Window) {
+        if let Some(title) = &self.title {
+            window.set_title(title);
+        }
+        if let Some([width, height]) = self.inner_size {
+            window.set_inner_size(PhysicalSize::new(width, height));
+        }
+        if let Some(path) = &self.icon {
+            match load_icon(path) {
+                Ok(icon) => window.set_window_icon(Some(icon)),
+                Err(err) => log::error!("Failed to load window icon {path:?}: {err}"),
+            }
+        }
+    }
+}
+
+fn load_icon(path: &Path) -> anyhow::Result<Icon> {
+    let image = image::open(path)?.into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(Icon::from_rgba(image.into_raw(), width, height)?)
+}
+
+/// Changes the fullscreen mode of the window.
+/// The surface is reconfigured by the resize that follows, like any other resize.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullscreenRequest {
+    /// Switches between windowed and borderless fullscreen on the current monitor
+    Toggle,
+    /// Borderless fullscreen on the monitor at this index of the available monitors.
+    /// None or an index without a monitor uses the monitor the window is currently on.
+    #[allow(unused)]
+    Borderless { monitor: Option<usize> },
+    /// Restores the window to the size and position it had before going fullscreen
+    #[allow(unused)]
+    Windowed,
+}
+
+fn handle_fullscreen_requests(
+    mut requests: EventReader<FullscreenRequest>,
+    windows: Query<Entity, With<bevy::window::Window>>,
+    winit_windows: NonSend<WinitWindows>,
+) {
+    let winit_window = match windows
+        .get_single()
+        .ok()
+        .and_then(|window| winit_windows.get_window(window))
+    {
+        Some(winit_window) => winit_window,
+        None => return,
+    };
+
+    for request in requests.iter() {
+        let borderless_monitor = match *request {
+            FullscreenRequest::Toggle if winit_window.fullscreen().is_some() => None,
+            FullscreenRequest::Toggle => Some(None),
+            FullscreenRequest::Borderless { monitor } => Some(monitor),
+            FullscreenRequest::Windowed => None,
+        };
+
+        let fullscreen = borderless_monitor.map(|index| {
+            let monitor = index.and_then(|index| {
+                let monitor = winit_window.available_monitors().nth(index);
+                if monitor.is_none() {
+                    log::warn!("There's no monitor {index}, using the current monitor instead");
+                }
+                monitor
+            });
+            // A None monitor is the current monitor for winit
+            let monitor = monitor.or_else(|| winit_window.current_monitor());
+            log::info!(
+                "Switching to borderless fullscreen on {}",
+                monitor
+                    .as_ref()
+                    .and_then(|monitor| monitor.name())
+                    .unwrap_or_else(|| "an unknown monitor".into())
+            );
+            Fullscreen::Borderless(monitor)
+        });
+        if fullscreen.is_none() {
+            log::info!("Switching to windowed mode");
+        }
+        winit_window.set_fullscreen(fullscreen);
+    }
+}
+
+/// The sample count used by every pass and attachment.
+/// Anything that depends on it must be recreated when it changes, like [`base_3d::update_render_pass`] does.
+/// egui is drawn after the msaa target is resolved so it's always single sampled.
 #[derive(Resource)]
 pub struct Msaa {
+    /// Read it with [`Msaa::sample_count`]
     pub samples: u32,
 }
 impl Default for Msaa {
@@ -35,15 +291,110 @@ impl Default for Msaa {
     }
 }
 
+impl Msaa {
+    /// The sample count of the attachments and pipelines. wgpu only guarantees 1 and 4 samples
+    /// so any other count above 1 uses 4, and 0 disables msaa.
+    pub fn sample_count(&self) -> u32 {
+        if self.samples > 1 {
+            4
+        } else {
+            1
+        }
+    }
+
+    /// The names of the `counts` that aren't the [`Msaa::sample_count`]
+    pub(crate) fn mismatched<'a>(&self, counts: &[(&'a str, u32)]) -> Vec<&'a str> {
+        counts
+            .iter()
+            .filter(|(_, count)| *count != self.sample_count())
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// Anti aliasing applied to the 3d scene as a post process, it's independent of the [`Msaa`].
+/// SMAA is skipped when msaa is enabled, they aren't meant to be combined.
+#[allow(unused)]
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiAliasing {
+    #[default]
+    None,
+    /// Smooths the edges found in the final image, it's cheaper than msaa but blurs some details
+    Smaa,
+}
+
+/// Limits how many frames can be submitted before the gpu is done with them.
+/// Lower values reduce the input latency but the cpu and the gpu work less in parallel.
+///
+/// wgpu 0.16 can't set `desired_maximum_frame_latency` on the surface so the renderer waits for
+/// older submissions after presenting instead. The swapchain still applies its own limit on top of it.
+/// The surface uses the `Immediate` present mode so presenting never waits for vsync
+/// and this is the only thing that stops the cpu from running ahead.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLatency {
+    /// None keeps the default of the driver, it's clamped between 1 and [`FrameLatency::MAX`]
+    pub max_frames: Option<u32>,
+}
+
+impl FrameLatency {
+    /// wgpu doesn't expose the swapchain image count, most drivers don't queue more frames than this
+    pub const MAX: u32 = 3;
+
+    fn max_frames(&self) -> Option<u32> {
+        self.max_frames.map(|frames| frames.clamp(1, Self::MAX))
+    }
+}
+
+/// The frames submitted but not waited on yet, only used with a [`FrameLatency`]
+#[derive(Resource, Default)]
+struct InFlightFrames(VecDeque<wgpu::SubmissionIndex>);
+
 pub struct WgpuRendererPlugin;
 impl Plugin for WgpuRendererPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Msaa>()
+            .init_resource::<AntiAliasing>()
+            .init_resource::<OutputColorSpace>()
+            .init_resource::<DepthFormat>()
+            .init_resource::<ReverseZ>()
+            .init_resource::<Fog>()
+            .init_resource::<Exposure>()
+            .init_resource::<DebugView>()
+            .init_resource::<FrameLatency>()
+            .init_resource::<InFlightFrames>()
+            .init_resource::<frame_stats::FrameStats>()
+            .init_resource::<bind_groups::mesh_view::GlobalsUniform>()
+            .init_resource::<JointMatrices>()
+            .init_resource::<instances::InstanceStagingBuffer>()
+            .init_resource::<base_3d::DepthPrepass>()
+            .init_resource::<base_3d::ClearFlags>()
+            .init_resource::<base_3d::GBufferEnabled>()
+            .init_resource::<base_3d::GBuffer>()
+            .init_resource::<ssao::SsaoSettings>()
+            .init_resource::<shadow::PointShadowSettings>()
+            .init_resource::<shadow::CascadeSettings>()
+            .init_resource::<outline::OutlineSettings>()
+            .init_resource::<outline::Selection>()
+            .init_resource::<render_phase::RenderPhases>()
+            .add_event::<RendererErrorEvent>()
+            .add_event::<TakeScreenshot>()
+            .add_event::<FullscreenRequest>()
+            .add_event::<picking::PickRequest>()
+            .add_event::<picking::PickResult>()
             // Add the camera plugin here because it's required for the renderer to work
             .add_plugins((CameraPlugin, WireframePlugin))
             // This startup system needs to be run before any startup that needs the WgpuRenderer
             .add_systems(PreStartup, init_renderer)
-            .add_systems(Startup, init_depth_texture)
+            .add_systems(First, validation::send_renderer_errors)
+            .add_systems(
+                Startup,
+                (
+                    init_depth_texture,
+                    instances::setup_instance_animation_pipeline,
+                    instances::setup_instance_cull_pipeline,
+                    frame_stats::setup_pass_timer,
+                ),
+            )
             // Needs to be in PostStartup because it sets up the bind_group based on
             // what was spawned in the startup
             .add_systems(
@@ -51,47 +402,118 @@ impl Plugin for WgpuRendererPlugin {
                 (
                     bind_groups::mesh_view::setup_mesh_view_bind_group,
                     apply_deferred,
+                    ssao::setup,
+                    shadow::setup,
+                    outline::setup,
+                    sky::setup,
+                    smaa::setup,
+                    auto_exposure::setup,
+                    picking::setup,
                     base_3d::setup,
                 )
                     .chain(),
             )
-            //
-            .add_systems(
-                Update,
-                (
-                    update_depth_texture,
-                    apply_deferred,
-                    start_render,
-                    apply_deferred,
-                    base_3d::update_render_pass,
-                    base_3d::render,
-                    apply_deferred,
-                    egui_plugin::update_render_pass,
-                    egui_plugin::render,
-                    apply_deferred,
-                    end_render,
-                )
-                    .chain(),
-            )
-            .add_systems(
-                Update,
-                (
-                    bind_groups::mesh_view::update_light_buffer,
-                    bind_groups::mesh_view::update_camera_buffer,
-                    bind_groups::material::update_material_buffer,
-                    bind_groups::material::create_material_uniform,
-                    instances::update_instance_buffer,
-                    instances::create_instance_buffer,
-                    resize,
-                ),
-            );
+            .add_systems(Update, (render_systems(), prepare_systems()));
     }
 }
 
+/// The systems rendering a frame, from acquiring the surface texture to presenting it
+fn render_systems() -> SystemConfigs {
+    (
+        update_depth_texture,
+        apply_deferred,
+        start_render,
+        apply_deferred,
+        base_3d::update_render_pass,
+        egui_plugin::viewport::update_egui_viewport
+            .after(resize)
+            .before(bind_groups::mesh_view::update_camera_buffer),
+        base_3d::update_gbuffer,
+        (
+            ssao::prepare,
+            shadow::prepare,
+            outline::prepare,
+            sky::prepare,
+            smaa::prepare,
+            auto_exposure::prepare,
+        )
+            .chain(),
+        (ssao::render, shadow::render).chain(),
+        sky::render,
+        base_3d::render,
+        picking::id_pass,
+        outline::render,
+        render_phase::render,
+        (auto_exposure::render, smaa::render).chain(),
+        egui_plugin::texture_viewer::update_texture_viewer,
+        apply_deferred,
+        egui_plugin::render,
+        apply_deferred,
+        (
+            end_render,
+            frame_stats::read_pass_timer,
+            auto_exposure::adapt_exposure,
+        )
+            .chain(),
+    )
+        .chain()
+}
+
+/// The systems updating the gpu buffers and the render targets used by [`render_systems`]
+fn prepare_systems() -> SystemConfigs {
+    (
+        light::follow_camera
+            .after(camera::fly_camera)
+            .before(bind_groups::mesh_view::update_light_buffer),
+        frame_stats::update_frame_stats,
+        // The shadow maps use the lights in the order of the buffer
+        bind_groups::mesh_view::update_light_buffer.before(shadow::prepare),
+        bind_groups::mesh_view::update_camera_buffer,
+        bind_groups::mesh_view::update_fog_buffer,
+        bind_groups::mesh_view::update_globals_buffer
+            .after(egui_plugin::viewport::update_egui_viewport),
+        bind_groups::mesh_view::update_joint_matrix_buffer,
+        bind_groups::material::update_material_buffer,
+        bind_groups::material::create_material_uniform,
+        bind_groups::material::set_diffuse_texture,
+        bind_groups::material::update_material_texture_arrays,
+        (
+            bind_groups::material::update_color_override,
+            bind_groups::material::remove_color_override,
+            bind_groups::material::update_material_overrides,
+            bind_groups::material::remove_material_overrides,
+        ),
+        // The copies need to be submitted before the frame that uses them
+        instances::update_instance_buffer.before(start_render),
+        instances::create_instance_buffer,
+        (
+            instances::prepare_instance_animation,
+            instances::animate_instances,
+            // The cull pass copies the animated instances
+            instances::prepare_indirect_instances,
+            instances::cull_instances
+                .after(instances::update_instance_buffer)
+                .after(camera::fly_camera),
+        )
+            .chain(),
+        (
+            instances::remove_instance_animation,
+            instances::remove_indirect_instances,
+        ),
+        handle_fullscreen_requests,
+        // The surface, the msaa target and the depth texture of a frame need to have the same size
+        resize.before(update_depth_texture),
+    )
+        .into_configs()
+}
+
 fn init_renderer(
     mut commands: Commands,
     windows: Query<Entity, With<bevy::window::Window>>,
     winit_windows: NonSendMut<WinitWindows>,
+    color_space: Res<OutputColorSpace>,
+    (depth_format, reverse_z): (Res<DepthFormat>, Res<ReverseZ>),
+    window_config: Option<Res<WindowConfig>>,
 ) {
     let winit_window = windows
         .get_single()
@@ -102,13 +524,27 @@ fn init_renderer(
         })
         .expect("Failed to get window");
 
-    let renderer = future::block_on(WgpuRenderer::new(winit_window));
+    // Applied first so the surface is created with the configured size
+    if let Some(window_config) = window_config {
+        window_config.apply(winit_window);
+    }
+
+    let renderer = future::block_on(WgpuRenderer::new(
+        winit_window,
+        *color_space,
+        *depth_format,
+        *reverse_z,
+    ));
     commands.insert_resource(renderer);
 }
 
 fn init_depth_texture(mut commands: Commands, renderer: Res<WgpuRenderer>, msaa: Res<Msaa>) {
-    let depth_texture =
-        Texture::create_depth_texture(&renderer.device, &renderer.config, msaa.samples);
+    let depth_texture = Texture::create_depth_texture(
+        &renderer.device,
+        &renderer.config,
+        msaa.sample_count(),
+        renderer.depth_format,
+    );
     commands.insert_resource(DepthTexture(depth_texture));
 }
 
@@ -118,7 +554,12 @@ fn update_depth_texture(
     mut texture: ResMut<DepthTexture>,
 ) {
     if msaa.is_changed() {
-        texture.0 = Texture::create_depth_texture(&renderer.device, &renderer.config, msaa.samples);
+        texture.0 = Texture::create_depth_texture(
+            &renderer.device,
+            &renderer.config,
+            msaa.sample_count(),
+            renderer.depth_format,
+        );
     }
 }
 
@@ -152,6 +593,9 @@ fn start_render(
     renderer: Res<WgpuRenderer>,
     windows: Query<(), With<bevy::window::Window>>,
     msaa: Res<Msaa>,
+    mut errors: EventWriter<RendererErrorEvent>,
+    // The number of frames in a row skipped because the surface texture couldn't be acquired
+    mut skipped_frames: Local<u32>,
 ) {
     if windows.get_single().is_err() {
         return;
@@ -160,18 +604,43 @@ fn start_render(
     // log::info!("start render");
 
     let output = match renderer.surface.get_current_texture() {
-        Ok(swap_chain_frame) => swap_chain_frame,
-        Err(wgpu::SurfaceError::Outdated) => {
+        Err(err @ (wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost)) => {
+            // Outdated happens on every resize but Lost means the display or the gpu changed
+            if err == wgpu::SurfaceError::Lost && *skipped_frames == 0 {
+                log::warn!("The surface was lost, reconfiguring it");
+            }
             renderer
                 .surface
                 .configure(&renderer.device, &renderer.config);
-            renderer
-                .surface
-                .get_current_texture()
-                .expect("Failed to reconfigure surface")
+            renderer.surface.get_current_texture()
         }
-        err => {
-            log::error!("failed  to get surface texture. {err:?}");
+        result => result,
+    };
+
+    // The frames are skipped until the surface works again, this is only reported once.
+    // TODO recreate the renderer when the device is lost. This is blocked on a wgpu upgrade,
+    // 0.16 has no device lost callback so a lost device only shows up here
+    // as a surface that stays lost and the app keeps skipping frames.
+    let output = match output {
+        Ok(output) => {
+            if *skipped_frames > 0 {
+                log::info!(
+                    "Got a surface texture again after skipping {} frames",
+                    *skipped_frames
+                );
+                *skipped_frames = 0;
+            }
+            output
+        }
+        Err(err) => {
+            if *skipped_frames == 0 {
+                log::error!("Failed to get the surface texture, skipping frames until it works again: {err:?}");
+                errors.send(RendererErrorEvent {
+                    label: "surface texture".to_string(),
+                    message: err.to_string(),
+                });
+            }
+            *skipped_frames = skipped_frames.saturating_add(1);
             return;
         }
     };
@@ -189,11 +658,11 @@ fn start_render(
     commands.insert_resource(WgpuSurfaceTexture(Some(output)));
     commands.insert_resource(WgpuView {
         view,
-        sampled_view: if msaa.samples > 1 {
+        sampled_view: if msaa.sample_count() > 1 {
             Some(create_multisampled_framebuffer(
                 &renderer.device,
                 &renderer.config,
-                msaa.samples,
+                msaa.sample_count(),
             ))
         } else {
             None
@@ -207,14 +676,53 @@ fn end_render(
     windows: Query<(), With<bevy::window::Window>>,
     mut encoder: ResMut<WgpuEncoder>,
     mut output: ResMut<WgpuSurfaceTexture>,
+    mut screenshots: EventReader<TakeScreenshot>,
+    frame_latency: Res<FrameLatency>,
+    mut in_flight: ResMut<InFlightFrames>,
 ) {
     if windows.get_single().is_err() {
         return;
     }
 
-    if let Some(encoder) = encoder.0.take() {
-        renderer.queue.submit(std::iter::once(encoder.finish()));
-        output.0.take().unwrap().present();
+    if frame_latency.is_changed() {
+        if let Some(max_frames) = frame_latency.max_frames {
+            if max_frames != frame_latency.max_frames().unwrap_or(max_frames) {
+                log::warn!(
+                    "A frame latency of {max_frames} isn't supported, it's clamped between 1 and {}",
+                    FrameLatency::MAX
+                );
+            }
+        }
+    }
+
+    if let Some(mut encoder) = encoder.0.take() {
+        let output = output.0.take().unwrap();
+        let paths: Vec<_> = screenshots.iter().map(|event| event.0.clone()).collect();
+        // The surface is the msaa resolve target so this reads the resolved image
+        let readback = if paths.is_empty() {
+            None
+        } else {
+            screenshot::copy_to_buffer(&renderer, &mut encoder, &output.texture)
+        };
+
+        let submission = renderer.queue.submit(std::iter::once(encoder.finish()));
+
+        if let Some(readback) = readback {
+            readback.save(&renderer.device, paths);
+        }
+        output.present();
+
+        if let Some(max_frames) = frame_latency.max_frames() {
+            in_flight.0.push_back(submission);
+            while in_flight.0.len() > max_frames as usize {
+                let oldest = in_flight.0.pop_front().unwrap();
+                renderer
+                    .device
+                    .poll(wgpu::Maintain::WaitForSubmissionIndex(oldest));
+            }
+        } else {
+            in_flight.0.clear();
+        }
     } else {
         log::warn!("No encoder found");
     }
@@ -229,6 +737,7 @@ fn resize(
     mut camera: ResMut<Camera>,
     mut screen_descriptor: ResMut<EguiScreenDesciptorRes>,
     msaa: Res<Msaa>,
+    (egui_ctx, ui_scale): (Res<EguiCtxRes>, Option<Res<UiScale>>),
 ) {
     for event in events.iter() {
         let window = windows.get(event.window).expect("window not found");
@@ -241,25 +750,51 @@ fn resize(
 
         renderer.resize(PhysicalSize { width, height });
 
-        depth_texture.0 =
-            Texture::create_depth_texture(&renderer.device, &renderer.config, msaa.samples);
+        depth_texture.0 = Texture::create_depth_texture(
+            &renderer.device,
+            &renderer.config,
+            msaa.sample_count(),
+            renderer.depth_format,
+        );
 
         // Should probably be done in EguiPlugin
         screen_descriptor.0.size_in_pixels = [width, height];
     }
+
+    // Checked every frame so changing or removing the UiScale and moving the window
+    // to a screen with a different scale factor are both applied
+    if let Ok(window) = windows.get_single() {
+        let pixels_per_point = UiScale::pixels_per_point(ui_scale.as_deref(), window);
+        if screen_descriptor.0.pixels_per_point != pixels_per_point {
+            screen_descriptor.0.pixels_per_point = pixels_per_point;
+            egui_ctx.0.set_pixels_per_point(pixels_per_point);
+        }
+    }
 }
 
 #[derive(Resource)]
 pub struct WgpuRenderer {
     pub surface: wgpu::Surface,
-    pub device: wgpu::Device,
-    pub queue: wgpu::Queue,
+    /// Shared so gpu resources can be created from tasks
+    pub device: Arc<wgpu::Device>,
+    pub queue: Arc<wgpu::Queue>,
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
+    /// Errors reported by wgpu, they are sent as [`RendererErrorEvent`] at the start of the frame
+    pub errors: RendererErrors,
+    /// The format selected from the [`DepthFormat`], every pipeline using the [`DepthTexture`] needs it
+    pub depth_format: wgpu::TextureFormat,
+    /// See [`ReverseZ`], every pipeline using a depth texture needs to use [`WgpuRenderer::depth_compare`]
+    pub reverse_z: bool,
 }
 
 impl WgpuRenderer {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(
+        window: &Window,
+        color_space: OutputColorSpace,
+        depth_format: DepthFormat,
+        reverse_z: ReverseZ,
+    ) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -276,10 +811,21 @@ impl WgpuRenderer {
             .await
             .expect("Failed to request adapter");
 
+        let depth_format = select_depth_format(&adapter, depth_format);
+        log::info!("Using depth format {depth_format:?}");
+
+        // Only requested when available, IndirectInstances fall back to the regular draws without it
+        // The timestamps are only used by the PassTimer
+        let optional_features = adapter.features()
+            & (wgpu::Features::MULTI_DRAW_INDIRECT | wgpu::Features::TIMESTAMP_QUERY);
+        log::info!("Optional features: {optional_features:?}");
+
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::POLYGON_MODE_LINE,
+                    features: wgpu::Features::POLYGON_MODE_LINE
+                        | depth_format.required_features()
+                        | optional_features,
                     limits: wgpu::Limits::default(),
                     label: None,
                 },
@@ -287,17 +833,21 @@ impl WgpuRenderer {
             )
             .await
             .expect("Failed to request device");
+        let errors = RendererErrors::default();
+        errors.handle_uncaptured_errors(&device);
 
         let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
+        log::info!(
+            "Surface formats: {:?}, alpha modes: {:?}",
+            surface_caps.formats,
+            surface_caps.alpha_modes
+        );
+        let surface_format = select_surface_format(&surface_caps, color_space);
+        log::info!("Using surface format {surface_format:?} for {color_space:?}");
 
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        // COPY_SRC is needed to read back the surface for screenshots
+        let mut config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
             format: surface_format,
             width: size.width,
             height: size.height,
@@ -305,17 +855,70 @@ impl WgpuRenderer {
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![],
         };
+        // wgpu doesn't expose the supported surface usages so try to configure it first
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
         surface.configure(&device, &config);
+        if let Some(err) = future::block_on(device.pop_error_scope()) {
+            log::warn!("The surface can't be copied, screenshots will be disabled: {err}");
+            config.usage = wgpu::TextureUsages::RENDER_ATTACHMENT;
+            surface.configure(&device, &config);
+        }
 
         Self {
             surface,
-            device,
-            queue,
+            device: Arc::new(device),
+            queue: Arc::new(queue),
             config,
             size,
+            errors,
+            depth_format,
+            reverse_z: reverse_z.0,
         }
     }
 
+    /// Flips the comparison when [`ReverseZ`] is enabled so closer fragments still pass.
+    /// Write the comparison as if the near plane was at 0.
+    pub fn depth_compare(&self, compare: wgpu::CompareFunction) -> wgpu::CompareFunction {
+        use wgpu::CompareFunction::*;
+        if !self.reverse_z {
+            return compare;
+        }
+        match compare {
+            Less => Greater,
+            LessEqual => GreaterEqual,
+            Greater => Less,
+            GreaterEqual => LessEqual,
+            compare => compare,
+        }
+    }
+
+    /// The depth of the far plane, the depth textures are cleared to it
+    pub fn far_depth(&self) -> f32 {
+        if self.reverse_z {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    /// Whether the [`DepthTexture`] has a stencil buffer
+    pub fn has_stencil(&self) -> bool {
+        self.depth_format.has_stencil_aspect()
+    }
+
+    /// Whether [`IndirectInstances`](crate::instances::IndirectInstances) are culled on the gpu
+    /// and drawn with indirect draws
+    pub fn supports_indirect_draw(&self) -> bool {
+        self.device
+            .features()
+            .contains(wgpu::Features::MULTI_DRAW_INDIRECT)
+    }
+
+    /// The limits of the device used by the renderer
+    pub fn limits(&self) -> wgpu::Limits {
+        self.device.limits()
+    }
+
     pub fn create_render_pipeline(
         &self,
         label: &str,
@@ -323,46 +926,44 @@ impl WgpuRenderer {
         pipeline_layout: &wgpu::PipelineLayout,
         vertex_layouts: &[wgpu::VertexBufferLayout],
         depth_stencil: Option<wgpu::DepthStencilState>,
-        blend: wgpu::BlendState,
+        targets: &[Option<wgpu::ColorTargetState>],
         sample_count: u32,
     ) -> wgpu::RenderPipeline {
-        let shader = self
-            .device
-            .create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some(&format!("{label} Shader")),
-                source: wgpu::ShaderSource::Wgsl(shader.into()),
-            });
-        self.device
-            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some(label),
-                layout: Some(pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: "vertex",
-                    buffers: vertex_layouts,
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: "fragment",
-                    targets: &[Some(wgpu::ColorTargetState {
-                        format: self.config.format,
-                        blend: Some(blend),
-                        write_mask: wgpu::ColorWrites::ALL,
-                    })],
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: Some(wgpu::Face::Back),
-                    ..default()
-                },
-                depth_stencil,
-                multisample: wgpu::MultisampleState {
-                    count: sample_count,
-                    ..default()
-                },
-                multiview: None,
-            })
+        self.errors.scope(&self.device, label, || {
+            let shader = self
+                .device
+                .create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some(&format!("{label} Shader")),
+                    source: wgpu::ShaderSource::Wgsl(shader.into()),
+                });
+            self.device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: vertex_layouts,
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment",
+                        targets,
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        ..default()
+                    },
+                    depth_stencil,
+                    multisample: wgpu::MultisampleState {
+                        count: sample_count,
+                        ..default()
+                    },
+                    multiview: None,
+                })
+        })
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -375,6 +976,96 @@ impl WgpuRenderer {
             log::info!("window has been minimized")
         }
     }
+
+    /// Renders a single frame of `world` without running the app, for tools, tests or thumbnails.
+    /// It runs the systems of the [`WgpuRendererPlugin`] in the same order, so the plugin
+    /// needs to have been added and its startup systems need to have run.
+    ///
+    /// The renderer is a resource of the world that the systems use, so this takes the world
+    /// instead of `&mut self`. Returns the first wgpu error reported while rendering the frame.
+    #[allow(unused)]
+    pub fn render_once(world: &mut World) -> anyhow::Result<()> {
+        let errors = world
+            .get_resource::<WgpuRenderer>()
+            .ok_or_else(|| anyhow::anyhow!("The renderer needs to be initialized first"))?
+            .errors
+            .clone();
+        if world
+            .query_filtered::<(), With<bevy::window::Window>>()
+            .get_single(world)
+            .is_err()
+        {
+            anyhow::bail!("Rendering a frame needs a single window");
+        }
+
+        let error_count = errors.len();
+        let mut schedule = Schedule::new();
+        schedule
+            .set_executor_kind(ExecutorKind::SingleThreaded)
+            .add_systems((
+                egui_plugin::begin_frame.before(egui_plugin::render),
+                render_systems(),
+                prepare_systems(),
+            ));
+        schedule.run(world);
+
+        match errors.since(error_count).into_iter().next() {
+            Some(error) => Err(anyhow::anyhow!("{}: {}", error.label, error.message)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn select_depth_format(adapter: &wgpu::Adapter, depth_format: DepthFormat) -> wgpu::TextureFormat {
+    let format = depth_format.texture_format();
+    let supported = format
+        .required_features()
+        .difference(adapter.features())
+        .is_empty()
+        && adapter
+            .get_texture_format_features(format)
+            .allowed_usages
+            .contains(wgpu::TextureUsages::RENDER_ATTACHMENT);
+    if supported {
+        format
+    } else {
+        log::warn!("The adapter doesn't support {format:?}, falling back to Depth32Float");
+        wgpu::TextureFormat::Depth32Float
+    }
+}
+
+fn select_surface_format(
+    surface_caps: &wgpu::SurfaceCapabilities,
+    color_space: OutputColorSpace,
+) -> wgpu::TextureFormat {
+    let srgb_format = surface_caps
+        .formats
+        .iter()
+        .copied()
+        .find(|f| f.is_srgb())
+        .unwrap_or(surface_caps.formats[0]);
+
+    match color_space {
+        OutputColorSpace::Srgb => srgb_format,
+        OutputColorSpace::Hdr10 => {
+            log::warn!("HDR10 output isn't supported by wgpu yet, falling back to sRGB");
+            srgb_format
+        }
+        OutputColorSpace::ExtendedLinear => {
+            // wgpu uses the extended linear sRGB color space for this format
+            if surface_caps
+                .formats
+                .contains(&wgpu::TextureFormat::Rgba16Float)
+            {
+                wgpu::TextureFormat::Rgba16Float
+            } else {
+                log::warn!(
+                    "The surface doesn't support extended linear output, falling back to sRGB"
+                );
+                srgb_format
+            }
+        }
+    }
 }
 
 pub fn create_multisampled_framebuffer(
@@ -402,3 +1093,64 @@ pub fn create_multisampled_framebuffer(
         .create_texture(multisampled_frame_descriptor)
         .create_view(&wgpu::TextureViewDescriptor::default())
 }
+
+/// The depth only passes need a pipeline for each vertex buffer a [`ModelMesh`] can be drawn with.
+/// The meshes with a [`ModelMesh::position_buffer`] read it, the other ones read the full vertices.
+pub(crate) struct DepthOnlyPipelines {
+    vertex: wgpu::RenderPipeline,
+    position: wgpu::RenderPipeline,
+}
+
+impl DepthOnlyPipelines {
+    /// Calls `create` with the [`Vertex`] and the [`PositionVertex`] layouts,
+    /// the shader needs to use the locations shared by both
+    pub fn new(
+        mut create: impl FnMut(wgpu::VertexBufferLayout<'static>) -> wgpu::RenderPipeline,
+    ) -> Self {
+        Self {
+            vertex: create(Vertex::layout()),
+            position: create(PositionVertex::layout()),
+        }
+    }
+
+    /// The pipeline and the vertex buffer used to draw the mesh
+    pub fn select<'a>(
+        &'a self,
+        mesh: &'a ModelMesh,
+    ) -> (&'a wgpu::RenderPipeline, &'a wgpu::Buffer) {
+        match mesh.position_buffer() {
+            Some(position_buffer) => (&self.position, position_buffer),
+            None => (&self.vertex, &mesh.vertex_buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msaa_sample_count() {
+        let sample_count = |samples| Msaa { samples }.sample_count();
+        assert_eq!(Msaa::default().sample_count(), 1);
+        assert_eq!(sample_count(0), 1);
+        assert_eq!(sample_count(1), 1);
+        assert_eq!(sample_count(4), 4);
+        // The counts that aren't always supported use 4 samples
+        assert_eq!(sample_count(2), 4);
+        assert_eq!(sample_count(8), 4);
+    }
+
+    #[test]
+    fn msaa_mismatched() {
+        let counts = [("depth", 4), ("g-buffer", 1), ("outline", 4)];
+        assert_eq!(Msaa { samples: 4 }.mismatched(&counts), vec!["g-buffer"]);
+        assert_eq!(
+            Msaa { samples: 1 }.mismatched(&counts),
+            vec!["depth", "outline"]
+        );
+        assert!(Msaa { samples: 4 }
+            .mismatched(&[("depth", 4), ("g-buffer", 4)])
+            .is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+use bevy::{ecs::prelude::*, hierarchy::Children, render::color::Color, utils::default};
+
+use super::{
+    bind_groups::mesh_view::{MeshViewBindGroup, MeshViewBindGroupLayout},
+    Msaa, WgpuEncoder, WgpuRenderer, WgpuView,
+};
+use crate::{
+    camera::{Camera, VisibilityQuery},
+    egui_plugin::viewport::EguiViewport,
+    instances::{CompactInstances, InstanceBuffer, Instances},
+    mesh,
+    model::Model,
+    texture::Texture,
+    transform::TransformRaw,
+};
+
+const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// The entity outlined by the [`OutlinePass`], its children are also outlined
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Selection(pub Option<Entity>);
+
+#[derive(Resource, Debug, Clone)]
+pub struct OutlineSettings {
+    pub color: Color,
+    /// Width of the outline in pixels, clamped to 8
+    pub thickness: f32,
+}
+
+impl Default for OutlineSettings {
+    fn default() -> Self {
+        Self {
+            color: Color::ORANGE,
+            thickness: 2.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct OutlineUniform {
+    color: [f32; 4],
+    thickness: f32,
+    z_near: f32,
+    z_far: f32,
+    far_depth: f32,
+}
+
+fn create_target(
+    device: &wgpu::Device,
+    label: &str,
+    size: [u32; 2],
+    format: wgpu::TextureFormat,
+) -> wgpu::TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size[0].max(1),
+                height: size[1].max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// The textures are recreated when the size of the render target changes
+struct OutlineTargets {
+    size: [u32; 2],
+    depth: wgpu::TextureView,
+    normal: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Draws an outline around the [`Selection`].
+///
+/// The depth and normals of the selection are rendered to their own textures,
+/// then a fullscreen pass draws the outline where they have a discontinuity.
+/// The outline is drawn on top of everything so it's still visible when the selection is occluded.
+#[derive(Resource)]
+pub struct OutlinePass {
+    prepass_pipeline: wgpu::RenderPipeline,
+    outline_pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    uniform_buffer: wgpu::Buffer,
+    /// The outline is drawn directly to the msaa target
+    sample_count: u32,
+    targets: Option<OutlineTargets>,
+}
+
+impl OutlinePass {
+    fn new(
+        renderer: &WgpuRenderer,
+        mesh_view_layout: &MeshViewBindGroupLayout,
+        sample_count: u32,
+    ) -> Self {
+        let device = &renderer.device;
+
+        let prepass_pipeline = renderer
+            .errors
+            .scope(device, "Outline Prepass Pipeline", || {
+                let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some("Outline Prepass Shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        concat!(
+                            include_str!("shaders/common.wgsl"),
+                            include_str!("shaders/ssao_prepass.wgsl")
+                        )
+                        .into(),
+                    ),
+                });
+                let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Outline Prepass Pipeline Layout"),
+                    bind_group_layouts: &[&mesh_view_layout.0],
+                    push_constant_ranges: &[],
+                });
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Outline Prepass Pipeline"),
+                    layout: Some(&layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[mesh::Vertex::layout(), TransformRaw::layout()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment",
+                        targets: &[Some(NORMAL_FORMAT.into())],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        ..default()
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        depth_write_enabled: true,
+                        depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            });
+
+        let unfilterable_texture = |binding, sample_type| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("outline_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                unfilterable_texture(1, wgpu::TextureSampleType::Depth),
+                unfilterable_texture(2, wgpu::TextureSampleType::Float { filterable: false }),
+            ],
+        });
+
+        let outline_pipeline = renderer.errors.scope(device, "Outline Pipeline", || {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Outline Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/outline.wgsl").into()),
+            });
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Outline Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Outline Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: renderer.config.format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    ..default()
+                },
+                multiview: None,
+            })
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Outline Uniform Buffer"),
+            size: std::mem::size_of::<OutlineUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            prepass_pipeline,
+            outline_pipeline,
+            layout,
+            uniform_buffer,
+            sample_count,
+            targets: None,
+        }
+    }
+
+    fn create_targets(&self, renderer: &WgpuRenderer, size: [u32; 2]) -> OutlineTargets {
+        let device = &renderer.device;
+        let depth = create_target(device, "outline_depth_texture", size, Texture::DEPTH_FORMAT);
+        let normal = create_target(device, "outline_normal_texture", size, NORMAL_FORMAT);
+
+        let bind_group = renderer.errors.scope(device, "outline bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("outline_bind_group"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniform_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&depth),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&normal),
+                    },
+                ],
+            })
+        });
+
+        OutlineTargets {
+            size,
+            depth,
+            normal,
+            bind_group,
+        }
+    }
+}
+
+pub fn setup(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+    msaa: Res<Msaa>,
+) {
+    commands.insert_resource(OutlinePass::new(
+        &renderer,
+        &mesh_view_layout,
+        msaa.sample_count(),
+    ));
+}
+
+/// Recreates the pipelines when the msaa changes, the targets when the size of the render target changes
+/// and uploads the settings
+pub fn prepare(
+    mut pass: ResMut<OutlinePass>,
+    renderer: Res<WgpuRenderer>,
+    settings: Res<OutlineSettings>,
+    selection: Res<Selection>,
+    camera: Res<Camera>,
+    msaa: Res<Msaa>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+    viewport: Option<Res<EguiViewport>>,
+) {
+    if pass.sample_count != msaa.sample_count() {
+        log::info!("updating outline pass");
+        *pass = OutlinePass::new(&renderer, &mesh_view_layout, msaa.sample_count());
+    }
+
+    if selection.0.is_none() {
+        pass.targets = None;
+        return;
+    }
+
+    let size = viewport
+        .as_ref()
+        .and_then(|viewport| viewport.target())
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    if pass.targets.as_ref().map(|targets| targets.size) != Some(size) {
+        log::info!("Resizing outline targets to {}x{}", size[0], size[1]);
+        pass.targets = Some(pass.create_targets(&renderer, size));
+    }
+
+    let uniform = OutlineUniform {
+        color: settings.color.as_linear_rgba_f32(),
+        thickness: settings.thickness,
+        z_near: camera.projection.z_near,
+        z_far: camera.projection.z_far,
+        far_depth: renderer.far_depth(),
+    };
+    renderer
+        .queue
+        .write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+}
+
+/// Renders the selection to the outline targets and draws the outline on top of the main pass
+pub fn render(
+    pass: Res<OutlinePass>,
+    mut encoder: ResMut<WgpuEncoder>,
+    view: Res<WgpuView>,
+    mesh_view_bind_group: Res<MeshViewBindGroup>,
+    selection: Res<Selection>,
+    model_query: Query<
+        (&Model, &InstanceBuffer, Option<&Instances>, VisibilityQuery),
+        Without<CompactInstances>,
+    >,
+    children_query: Query<&Children>,
+    viewport: Option<Res<EguiViewport>>,
+    camera: Res<Camera>,
+    renderer: Res<WgpuRenderer>,
+) {
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+    let (targets, selected) = if let (Some(targets), Some(selected)) = (&pass.targets, selection.0)
+    {
+        (targets, selected)
+    } else {
+        return;
+    };
+
+    {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Outline Prepass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &targets.normal,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &targets.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(renderer.far_depth()),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_pipeline(&pass.prepass_pipeline);
+        render_pass.set_bind_group(0, &mesh_view_bind_group.0, &[]);
+        let mut entities = vec![selected];
+        while let Some(entity) = entities.pop() {
+            if let Ok(children) = children_query.get(entity) {
+                entities.extend(children.iter());
+            }
+            let (model, instance_buffer, instances, visibility) =
+                if let Ok(model) = model_query.get(entity) {
+                    model
+                } else {
+                    continue;
+                };
+            if !camera.is_visible(visibility) {
+                continue;
+            }
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            for mesh in &model.meshes {
+                if mesh.topology != wgpu::PrimitiveTopology::TriangleList {
+                    continue;
+                }
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+                render_pass.draw_indexed(
+                    0..mesh.num_elements,
+                    0,
+                    0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                );
+            }
+        }
+    }
+
+    let view = match viewport.as_ref().and_then(|v| v.target()) {
+        Some(target) => &target.view,
+        None => &*view,
+    };
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Outline Pass"),
+        color_attachments: &[Some(view.get_color_attachment(wgpu::Operations {
+            load: wgpu::LoadOp::Load,
+            store: true,
+        }))],
+        depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(&pass.outline_pipeline);
+    render_pass.set_bind_group(0, &targets.bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+}
@@ -0,0 +1,477 @@
+use bevy::{ecs::prelude::*, math::Vec2, utils::default};
+
+use super::{
+    bind_groups::mesh_view::{MeshViewBindGroup, MeshViewBindGroupLayout},
+    WgpuRenderer,
+};
+use crate::{
+    camera::{Camera, VisibilityQuery},
+    egui_plugin::viewport::EguiViewport,
+    instances::{CompactInstances, InstanceBuffer, Instances},
+    light::Light,
+    mesh,
+    model::Model,
+    texture::Texture,
+    transform::TransformRaw,
+};
+
+const ID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+
+/// Asks for the entity under a pixel of the render target, the answer is sent as a [`PickResult`].
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PickRequest {
+    /// In physical pixels from the top left of the render target,
+    /// the egui viewport when it's used or the window otherwise
+    pub position: Vec2,
+}
+
+#[allow(unused)]
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PickResult {
+    pub position: Vec2,
+    /// The entity with the [`Model`] that was hit, None if the pixel is empty
+    pub entity: Option<Entity>,
+    /// Depth of the pixel between 0 and 1, it's 1 when nothing was hit.
+    /// It's always 1 at the far plane, even with [`ReverseZ`](super::ReverseZ).
+    pub depth: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PickingUniform {
+    id: u32,
+    _padding: [u32; 3],
+}
+
+/// The textures are recreated when the size of the render target changes
+struct IdTargets {
+    size: [u32; 2],
+    id: wgpu::Texture,
+    depth: wgpu::Texture,
+}
+
+/// The ids are written at a dynamic offset, one slot per drawn entity
+struct IdBuffer {
+    capacity: u64,
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Renders the id of every visible entity to an `R32Uint` target to find the entity under a pixel.
+///
+/// It only runs when a [`PickRequest`] is sent and only the requested pixel is rasterized.
+/// Reading the result blocks until the gpu is done so it's meant for clicks, not for every frame.
+/// Meshes using [`CompactInstances`] and meshes that aren't triangle lists can't be picked.
+#[derive(Resource)]
+pub struct IdPass {
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    /// Distance between two ids in the uniform buffer
+    stride: u64,
+    ids: Option<IdBuffer>,
+    targets: Option<IdTargets>,
+}
+
+impl IdPass {
+    fn new(renderer: &WgpuRenderer, mesh_view_layout: &MeshViewBindGroupLayout) -> Self {
+        let device = &renderer.device;
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("picking_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(
+                        std::mem::size_of::<PickingUniform>() as u64
+                    ),
+                },
+                count: None,
+            }],
+        });
+
+        let pipeline = renderer.errors.scope(device, "Id Pipeline", || {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Id Shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("shaders/common.wgsl"),
+                        include_str!("shaders/picking.wgsl")
+                    )
+                    .into(),
+                ),
+            });
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Id Pipeline Layout"),
+                bind_group_layouts: &[&mesh_view_layout.0, &layout],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Id Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[mesh::Vertex::layout(), TransformRaw::layout()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(ID_FORMAT.into())],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    ..default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        });
+
+        let align = renderer.limits().min_uniform_buffer_offset_alignment as u64;
+        let size = std::mem::size_of::<PickingUniform>() as u64;
+        Self {
+            pipeline,
+            layout,
+            stride: size.div_ceil(align) * align,
+            ids: None,
+            targets: None,
+        }
+    }
+
+    fn create_ids(&self, renderer: &WgpuRenderer, capacity: u64) -> IdBuffer {
+        let device = &renderer.device;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Id Uniform Buffer"),
+            size: capacity * self.stride,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = renderer.errors.scope(device, "picking bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("picking_bind_group"),
+                layout: &self.layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(std::mem::size_of::<PickingUniform>() as u64),
+                    }),
+                }],
+            })
+        });
+        IdBuffer {
+            capacity,
+            buffer,
+            bind_group,
+        }
+    }
+
+    fn create_targets(renderer: &WgpuRenderer, size: [u32; 2]) -> IdTargets {
+        let create_target = |label, format| {
+            renderer.device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: size[0].max(1),
+                    height: size[1].max(1),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            })
+        };
+        IdTargets {
+            size,
+            id: create_target("picking_id_texture", ID_FORMAT),
+            depth: create_target("picking_depth_texture", Texture::DEPTH_FORMAT),
+        }
+    }
+}
+
+/// The id and the depth of a pixel waiting to be mapped once the encoder is submitted
+pub struct PickReadback {
+    buffer: wgpu::Buffer,
+}
+
+impl PickReadback {
+    /// Each copy needs its own row and rows need to be aligned
+    const DEPTH_OFFSET: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64;
+
+    /// Waits for the copy to finish and returns the id and the depth of the pixel.
+    /// This blocks until the gpu is done with every submitted encoder.
+    pub fn read(self, device: &wgpu::Device) -> Option<(u32, f32)> {
+        let slice = self.buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        if let Err(err) = receiver.recv().ok()? {
+            log::error!("Failed to map picking buffer: {err}");
+            return None;
+        }
+
+        let result = {
+            let data = slice.get_mapped_range();
+            let offset = Self::DEPTH_OFFSET as usize;
+            let id: u32 = bytemuck::pod_read_unaligned(&data[..4]);
+            let depth: f32 = bytemuck::pod_read_unaligned(&data[offset..offset + 4]);
+            (id, depth)
+        };
+        self.buffer.unmap();
+        Some(result)
+    }
+}
+
+/// Copies the pixel of the id and depth textures to a buffer that can be read once the encoder is submitted
+pub fn copy_pixel_to_buffer(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    id_texture: &wgpu::Texture,
+    depth_texture: &wgpu::Texture,
+    pixel: [u32; 2],
+) -> PickReadback {
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Picking Buffer"),
+        size: PickReadback::DEPTH_OFFSET + 4,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    for (texture, aspect, offset) in [
+        (id_texture, wgpu::TextureAspect::All, 0),
+        (
+            depth_texture,
+            wgpu::TextureAspect::DepthOnly,
+            PickReadback::DEPTH_OFFSET,
+        ),
+    ] {
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: pixel[0],
+                    y: pixel[1],
+                    z: 0,
+                },
+                aspect,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    PickReadback { buffer }
+}
+
+pub fn setup(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+) {
+    commands.insert_resource(IdPass::new(&renderer, &mesh_view_layout));
+}
+
+/// Renders the ids for every [`PickRequest`] and sends the [`PickResult`].
+/// It uses its own encoder that is submitted and read right away.
+pub fn id_pass(
+    mut pass: ResMut<IdPass>,
+    renderer: Res<WgpuRenderer>,
+    mut requests: EventReader<PickRequest>,
+    mut results: EventWriter<PickResult>,
+    mesh_view_bind_group: Res<MeshViewBindGroup>,
+    model_query: Query<
+        (
+            Entity,
+            &Model,
+            &InstanceBuffer,
+            Option<&Instances>,
+            VisibilityQuery,
+        ),
+        (Without<Light>, Without<CompactInstances>),
+    >,
+    viewport: Option<Res<EguiViewport>>,
+    camera: Res<Camera>,
+) {
+    if requests.is_empty() {
+        return;
+    }
+
+    let size = viewport
+        .as_ref()
+        .and_then(|viewport| viewport.target())
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    if pass.targets.as_ref().map(|targets| targets.size) != Some(size) {
+        pass.targets = Some(IdPass::create_targets(&renderer, size));
+    }
+
+    // The id 0 is the cleared background so the entity at index i uses the id i + 1
+    let entities: Vec<_> = model_query
+        .iter()
+        .filter(|(.., visibility)| camera.is_visible(*visibility))
+        .map(|(entity, ..)| entity)
+        .collect();
+    if pass.ids.as_ref().map(|ids| ids.capacity).unwrap_or(0) < entities.len() as u64 {
+        let capacity = (entities.len() as u64).next_power_of_two();
+        pass.ids = Some(pass.create_ids(&renderer, capacity));
+    }
+
+    let pass = pass.into_inner();
+    let targets = pass
+        .targets
+        .as_ref()
+        .expect("The targets were just created");
+    if let Some(ids) = &pass.ids {
+        let mut data = vec![0u8; (entities.len() as u64 * pass.stride) as usize];
+        for i in 0..entities.len() {
+            let uniform = PickingUniform {
+                id: i as u32 + 1,
+                _padding: [0; 3],
+            };
+            let offset = i * pass.stride as usize;
+            data[offset..offset + std::mem::size_of::<PickingUniform>()]
+                .copy_from_slice(bytemuck::bytes_of(&uniform));
+        }
+        renderer.queue.write_buffer(&ids.buffer, 0, &data);
+    }
+
+    let id_view = targets
+        .id
+        .create_view(&wgpu::TextureViewDescriptor::default());
+    let depth_view = targets
+        .depth
+        .create_view(&wgpu::TextureViewDescriptor::default());
+
+    for request in requests.iter() {
+        let position = request.position;
+        if position.x < 0.0
+            || position.y < 0.0
+            || position.x >= size[0] as f32
+            || position.y >= size[1] as f32
+        {
+            results.send(PickResult {
+                position,
+                entity: None,
+                depth: 1.0,
+            });
+            continue;
+        }
+        let pixel = [position.x as u32, position.y as u32];
+
+        let mut encoder = renderer
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Picking Encoder"),
+            });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Id Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &id_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(renderer.far_depth()),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            // Only the requested pixel is rasterized
+            render_pass.set_scissor_rect(pixel[0], pixel[1], 1, 1);
+            render_pass.set_pipeline(&pass.pipeline);
+            render_pass.set_bind_group(0, &mesh_view_bind_group.0, &[]);
+            if let Some(ids) = &pass.ids {
+                for (i, entity) in entities.iter().enumerate() {
+                    let (_, model, instance_buffer, instances, _) =
+                        if let Ok(model) = model_query.get(*entity) {
+                            model
+                        } else {
+                            continue;
+                        };
+                    render_pass.set_bind_group(
+                        1,
+                        &ids.bind_group,
+                        &[(i as u64 * pass.stride) as u32],
+                    );
+                    render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+                    for mesh in &model.meshes {
+                        if mesh.topology != wgpu::PrimitiveTopology::TriangleList {
+                            continue;
+                        }
+                        render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                        render_pass
+                            .set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+                        render_pass.draw_indexed(
+                            0..mesh.num_elements,
+                            0,
+                            0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                        );
+                    }
+                }
+            }
+        }
+
+        let readback = copy_pixel_to_buffer(
+            &renderer.device,
+            &mut encoder,
+            &targets.id,
+            &targets.depth,
+            pixel,
+        );
+        renderer.queue.submit(std::iter::once(encoder.finish()));
+
+        let (id, depth) = if let Some(result) = readback.read(&renderer.device) {
+            result
+        } else {
+            continue;
+        };
+        results.send(PickResult {
+            position,
+            entity: id
+                .checked_sub(1)
+                .and_then(|index| entities.get(index as usize).copied()),
+            depth: if renderer.reverse_z {
+                1.0 - depth
+            } else {
+                depth
+            },
+        });
+    }
+}
@@ -0,0 +1,93 @@
+use bevy::{app::App, ecs::prelude::*};
+use wgpu::{CommandEncoder, TextureView};
+
+use super::{WgpuEncoder, WgpuView};
+use crate::egui_plugin::viewport::EguiViewport;
+
+/// A custom pass rendered every frame after the 3d passes, before SMAA and egui.
+/// Register it with [`RenderPhaseAppExt::add_render_phase`].
+pub trait RenderPhase: Send + Sync + 'static {
+    /// Called every frame before [`RenderPhase::render`] to prepare the pipelines and the buffers
+    fn update(&mut self, world: &mut World);
+
+    /// `view` is the resolved render target, it's never multisampled.
+    /// It's the egui viewport texture when the 3d scene is rendered in a viewport.
+    fn render(&self, world: &World, view: &TextureView, encoder: &mut CommandEncoder);
+}
+
+/// The custom phases, rendered in order
+#[derive(Resource, Default)]
+pub struct RenderPhases(Vec<Box<dyn RenderPhase>>);
+
+impl RenderPhases {
+    /// Renders the phase after every phase already added
+    #[allow(unused)]
+    pub fn push(&mut self, phase: impl RenderPhase) {
+        self.0.push(Box::new(phase));
+    }
+
+    /// Renders the phase before the phase currently at `index`
+    #[allow(unused)]
+    pub fn insert(&mut self, index: usize, phase: impl RenderPhase) {
+        self.0.insert(index, Box::new(phase));
+    }
+
+    #[allow(unused)]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[allow(unused)]
+pub trait RenderPhaseAppExt {
+    /// Adds a phase rendered after the phases that were already added.
+    /// It can be called before or after adding the renderer plugin.
+    fn add_render_phase(&mut self, phase: impl RenderPhase) -> &mut Self;
+}
+
+impl RenderPhaseAppExt for App {
+    fn add_render_phase(&mut self, phase: impl RenderPhase) -> &mut Self {
+        self.init_resource::<RenderPhases>();
+        self.world.resource_mut::<RenderPhases>().push(phase);
+        self
+    }
+}
+
+pub fn render(world: &mut World) {
+    world.resource_scope(|world, mut phases: Mut<RenderPhases>| {
+        if phases.is_empty() {
+            return;
+        }
+
+        for phase in &mut phases.0 {
+            phase.update(world);
+        }
+
+        // Taken out of the world so the phases can still read every resource
+        let mut encoder = if let Some(encoder) = world.resource_mut::<WgpuEncoder>().0.take() {
+            encoder
+        } else {
+            return;
+        };
+
+        let view = match world
+            .get_resource::<EguiViewport>()
+            .and_then(|viewport| viewport.target())
+        {
+            Some(target) => Some(&target.view.view),
+            None => world.get_resource::<WgpuView>().map(|view| &view.view),
+        };
+        if let Some(view) = view {
+            for phase in &phases.0 {
+                phase.render(world, view, &mut encoder);
+            }
+        }
+
+        world.resource_mut::<WgpuEncoder>().0 = Some(encoder);
+    });
+}
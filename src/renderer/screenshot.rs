@@ -0,0 +1,141 @@
+use bevy::{ecs::prelude::*, tasks::IoTaskPool};
+use image::RgbaImage;
+use std::path::PathBuf;
+
+use super::WgpuRenderer;
+
+/// Saves the next frame to the given path, the image format is based on the extension.
+/// The frame is read after the msaa resolve and after egui is rendered.
+#[derive(Event, Debug, Clone)]
+pub struct TakeScreenshot(pub PathBuf);
+
+/// A copy of the surface waiting to be mapped once the frame is submitted
+pub struct ScreenshotReadback {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    format: wgpu::TextureFormat,
+}
+
+/// Copies the texture to a buffer that can be read once the encoder is submitted.
+/// Returns None if the texture can't be copied or if its format isn't supported.
+pub fn copy_to_buffer(
+    renderer: &WgpuRenderer,
+    encoder: &mut wgpu::CommandEncoder,
+    texture: &wgpu::Texture,
+) -> Option<ScreenshotReadback> {
+    if !renderer
+        .config
+        .usage
+        .contains(wgpu::TextureUsages::COPY_SRC)
+    {
+        log::warn!("The surface doesn't support screenshots");
+        return None;
+    }
+
+    let format = texture.format();
+    if !matches!(
+        format,
+        wgpu::TextureFormat::Rgba8Unorm
+            | wgpu::TextureFormat::Rgba8UnormSrgb
+            | wgpu::TextureFormat::Bgra8Unorm
+            | wgpu::TextureFormat::Bgra8UnormSrgb
+    ) {
+        log::warn!("Screenshots of {format:?} surfaces aren't supported");
+        return None;
+    }
+
+    let width = texture.width();
+    let height = texture.height();
+    // Each row of the buffer needs to be aligned
+    let bytes_per_row = width * 4;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_bytes_per_row = bytes_per_row.div_ceil(align) * align;
+
+    let buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Screenshot Buffer"),
+        size: (padded_bytes_per_row * height) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        texture.size(),
+    );
+
+    Some(ScreenshotReadback {
+        buffer,
+        width,
+        height,
+        padded_bytes_per_row,
+        format,
+    })
+}
+
+impl ScreenshotReadback {
+    /// Waits for the copy to finish and reads the image.
+    /// This blocks until the gpu is done with the frame.
+    pub fn read(self, device: &wgpu::Device) -> Option<RgbaImage> {
+        let slice = self.buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        if let Err(err) = receiver.recv().ok()? {
+            log::error!("Failed to map screenshot buffer: {err}");
+            return None;
+        }
+
+        let mut pixels = Vec::with_capacity((self.width * self.height * 4) as usize);
+        for row in slice
+            .get_mapped_range()
+            .chunks_exact(self.padded_bytes_per_row as usize)
+        {
+            pixels.extend_from_slice(&row[..(self.width * 4) as usize]);
+        }
+        self.buffer.unmap();
+
+        if matches!(
+            self.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        RgbaImage::from_raw(self.width, self.height, pixels)
+    }
+
+    /// Reads the image and saves it to every path.
+    /// The encoding is done on the [`IoTaskPool`] so it doesn't block the frame.
+    pub fn save(self, device: &wgpu::Device, paths: Vec<PathBuf>) {
+        let image = if let Some(image) = self.read(device) {
+            image
+        } else {
+            return;
+        };
+
+        IoTaskPool::get()
+            .spawn(async move {
+                for path in paths {
+                    match image.save(&path) {
+                        Ok(()) => log::info!("Saved screenshot to {path:?}"),
+                        Err(err) => log::error!("Failed to save screenshot to {path:?}: {err}"),
+                    }
+                }
+            })
+            .detach();
+    }
+}
@@ -0,0 +1,48 @@
+// Average log2 luminance of the frame, read back by AutoExposure to adapt the exposure.
+// A single workgroup samples a 64x64 grid of the frame and sums it in workgroup memory.
+
+@group(0) @binding(0)
+var t_frame: texture_2d<f32>;
+@group(0) @binding(1)
+var<storage, read_write> average_log_luminance: f32;
+
+const WORKGROUP_SIZE: u32 = 16u;
+// Samples per invocation along each axis
+const SAMPLES: u32 = 4u;
+const GRID_SIZE: f32 = 64.0;
+// Black pixels would pull the average to -inf
+const MIN_LUMINANCE: f32 = 0.0009765625;
+
+var<workgroup> sums: array<f32, 256>;
+
+@compute @workgroup_size(16, 16)
+fn main(
+    @builtin(local_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) index: u32,
+) {
+    let size = vec2<f32>(textureDimensions(t_frame));
+    var sum = 0.0;
+    for (var y = 0u; y < SAMPLES; y += 1u) {
+        for (var x = 0u; x < SAMPLES; x += 1u) {
+            let cell = vec2<f32>(id.xy * SAMPLES + vec2<u32>(x, y)) + 0.5;
+            let coords = vec2<i32>(cell / GRID_SIZE * size);
+            // The srgb formats are decoded when loaded so the color is linear
+            let color = textureLoad(t_frame, coords, 0).rgb;
+            let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+            sum += log2(max(luminance, MIN_LUMINANCE));
+        }
+    }
+    sums[index] = sum;
+    workgroupBarrier();
+
+    for (var stride = WORKGROUP_SIZE * WORKGROUP_SIZE / 2u; stride > 0u; stride /= 2u) {
+        if index < stride {
+            sums[index] += sums[index + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if index == 0u {
+        average_log_luminance = sums[0] / (GRID_SIZE * GRID_SIZE);
+    }
+}
@@ -0,0 +1,36 @@
+// Prepended with concat! to the shaders drawing meshes, they all bind the mesh view bind group
+// at group 0. The bindings a shader doesn't use aren't part of its pipeline layout.
+
+struct Fog {
+    color: vec4<f32>,
+    start: f32,
+    end: f32,
+    mode: u32,
+}
+@group(0) @binding(2)
+var<uniform> fog: Fog;
+
+// WARN these must match the values in mesh_view.rs
+const FOG_MODE_LINEAR: u32 = 0u;
+const FOG_MODE_EXPONENTIAL: u32 = 1u;
+
+// Returns how much the fog covers a fragment at the given distance from the camera
+fn fog_factor(distance: f32) -> f32 {
+    let t = max(distance - fog.start, 0.0) / max(fog.end - fog.start, 0.0001);
+    if (fog.mode == FOG_MODE_EXPONENTIAL) {
+        // exp(-3) is close enough to 0 for the fog to look opaque at the end
+        return 1.0 - exp(-3.0 * t);
+    }
+    return clamp(t, 0.0, 1.0);
+}
+
+@group(0) @binding(3)
+var<storage> joint_matrices: array<mat4x4<f32>>;
+
+// Blends the joint matrices influencing a vertex
+fn skin_matrix(joints: vec4<u32>, weights: vec4<f32>) -> mat4x4<f32> {
+    return weights.x * joint_matrices[joints.x]
+        + weights.y * joint_matrices[joints.y]
+        + weights.z * joint_matrices[joints.z]
+        + weights.w * joint_matrices[joints.w];
+}
@@ -0,0 +1,44 @@
+// This shader only writes depth, it's used to reduce overdraw in the main pass.
+// The position is invariant so the depth is exactly the same as in the main pass,
+// it uses an Equal depth compare.
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+fn build_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+@vertex
+fn vertex(
+    vertex: Vertex,
+    instance: InstanceInput,
+) -> @builtin(position) @invariant vec4<f32> {
+    let model_matrix = build_model_matrix(instance);
+    let skin = skin_matrix(vertex.joints, vertex.weights);
+    let world_position = model_matrix * skin * vec4<f32>(vertex.position, 1.0);
+    return camera.view_proj * world_position;
+}
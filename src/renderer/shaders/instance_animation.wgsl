@@ -0,0 +1,79 @@
+struct InstanceParams {
+    position: vec3<f32>,
+    phase: f32,
+    rotation: vec4<f32>,
+    scale: vec3<f32>,
+    _padding: f32,
+};
+
+struct Animation {
+    time: f32,
+    amplitude: f32,
+    speed: f32,
+    count: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> animation: Animation;
+@group(0) @binding(1)
+var<storage, read> params: array<InstanceParams>;
+// TransformRaw is a mat4x4 followed by a tightly packed mat3x3
+// so it doesn't match the wgsl layout and needs to be written as floats
+@group(0) @binding(2)
+var<storage, read_write> transforms: array<f32>;
+
+const TRANSFORM_RAW_SIZE: u32 = 25u;
+
+fn quat_to_mat3(q: vec4<f32>) -> mat3x3<f32> {
+    let x2 = q.x + q.x;
+    let y2 = q.y + q.y;
+    let z2 = q.z + q.z;
+    let xx = q.x * x2;
+    let xy = q.x * y2;
+    let xz = q.x * z2;
+    let yy = q.y * y2;
+    let yz = q.y * z2;
+    let zz = q.z * z2;
+    let wx = q.w * x2;
+    let wy = q.w * y2;
+    let wz = q.w * z2;
+    return mat3x3<f32>(
+        vec3<f32>(1.0 - (yy + zz), xy + wz, xz - wy),
+        vec3<f32>(xy - wz, 1.0 - (xx + zz), yz + wx),
+        vec3<f32>(xz + wy, yz - wx, 1.0 - (xx + yy)),
+    );
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= animation.count {
+        return;
+    }
+
+    // Variables so they can be indexed in the loops
+    var instance = params[i];
+    var position = instance.position;
+    position.y += animation.amplitude * sin(instance.phase - animation.time * animation.speed);
+    var rotation = quat_to_mat3(instance.rotation);
+
+    let base = i * TRANSFORM_RAW_SIZE;
+    // model
+    for (var c = 0u; c < 3u; c += 1u) {
+        let column = rotation[c] * instance.scale[c];
+        transforms[base + c * 4u] = column.x;
+        transforms[base + c * 4u + 1u] = column.y;
+        transforms[base + c * 4u + 2u] = column.z;
+        transforms[base + c * 4u + 3u] = 0.0;
+    }
+    transforms[base + 12u] = position.x;
+    transforms[base + 13u] = position.y;
+    transforms[base + 14u] = position.z;
+    transforms[base + 15u] = 1.0;
+    // normal
+    for (var c = 0u; c < 3u; c += 1u) {
+        transforms[base + 16u + c * 3u] = rotation[c].x;
+        transforms[base + 16u + c * 3u + 1u] = rotation[c].y;
+        transforms[base + 16u + c * 3u + 2u] = rotation[c].z;
+    }
+}
@@ -0,0 +1,64 @@
+struct Cull {
+    // Left, right, bottom, top, near and far, the normals point inside the frustum
+    planes: array<vec4<f32>, 6>,
+    // The bounding sphere of the model in local space
+    center: vec3<f32>,
+    radius: f32,
+    count: u32,
+    mesh_count: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> cull: Cull;
+// TransformRaw is a mat4x4 followed by a tightly packed mat3x3
+// so it doesn't match the wgsl layout and needs to be read as floats
+@group(0) @binding(1)
+var<storage, read> instances: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> visible_instances: array<f32>;
+// One DrawIndexedIndirect per mesh, 5 u32 each with the instance count second
+@group(0) @binding(3)
+var<storage, read_write> draw_args: array<atomic<u32>>;
+
+const TRANSFORM_RAW_SIZE: u32 = 25u;
+const DRAW_ARGS_SIZE: u32 = 5u;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= cull.count {
+        return;
+    }
+
+    let base = i * TRANSFORM_RAW_SIZE;
+    var model: mat4x4<f32>;
+    for (var c = 0u; c < 4u; c += 1u) {
+        model[c] = vec4<f32>(
+            instances[base + c * 4u],
+            instances[base + c * 4u + 1u],
+            instances[base + c * 4u + 2u],
+            instances[base + c * 4u + 3u],
+        );
+    }
+
+    let center = (model * vec4<f32>(cull.center, 1.0)).xyz;
+    let scale = max(length(model[0].xyz), max(length(model[1].xyz), length(model[2].xyz)));
+    let radius = cull.radius * scale;
+    for (var p = 0u; p < 6u; p += 1u) {
+        let plane = cull.planes[p];
+        if dot(plane.xyz, center) + plane.w < -radius {
+            return;
+        }
+    }
+
+    // Every mesh of the model draws the same instances
+    let slot = atomicAdd(&draw_args[1u], 1u);
+    for (var m = 1u; m < cull.mesh_count; m += 1u) {
+        atomicAdd(&draw_args[m * DRAW_ARGS_SIZE + 1u], 1u);
+    }
+
+    let visible_base = slot * TRANSFORM_RAW_SIZE;
+    for (var f = 0u; f < TRANSFORM_RAW_SIZE; f += 1u) {
+        visible_instances[visible_base + f] = instances[base + f];
+    }
+}
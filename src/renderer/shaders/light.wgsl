@@ -9,10 +9,16 @@ var<uniform> camera: Camera;
 
 struct Light {
     position: vec3<f32>,
+    shadow_index: u32,
     color: vec3<f32>,
+    kind: u32,
+};
+struct Lights {
+    count: u32,
+    data: array<Light>,
 };
 @group(0) @binding(1)
-var<uniform> light: Light;
+var<storage> lights: Lights;
 
 struct VertexInput {
     @location(0) position: vec3<f32>
@@ -26,7 +32,10 @@ struct VertexOutput {
 @vertex
 fn vertex(
     in: VertexInput,
+    // The index of the light to draw
+    @builtin(instance_index) instance_index: u32,
 ) -> VertexOutput {
+    let light = lights.data[instance_index];
     let scale = 0.25;
     var out: VertexOutput;
     out.clip_position = camera.view_proj * vec4<f32>(in.position * scale + light.position, 1.0);
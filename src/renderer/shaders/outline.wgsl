@@ -0,0 +1,84 @@
+// Draws an outline where the depth or the normals of the selection have a discontinuity
+
+struct Outline {
+    color: vec4<f32>,
+    thickness: f32,
+    z_near: f32,
+    z_far: f32,
+    // 1, or 0 with reverse-Z
+    far_depth: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> outline: Outline;
+@group(0) @binding(1)
+var t_depth: texture_depth_2d;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+
+// Difference of linear depth, relative to the depth of the pixel, that is considered an edge
+const DEPTH_THRESHOLD: f32 = 0.1;
+// Normals with a smaller cosine than this are considered an edge
+const NORMAL_THRESHOLD: f32 = 0.5;
+// The loop isn't unrolled but a big outline still samples a lot of pixels
+const MAX_RADIUS: i32 = 8;
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Draws a triangle covering the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+fn linear_depth(raw_depth: f32) -> f32 {
+    // The reversed depth is 1 minus the regular one for the same near and far planes
+    let depth = select(raw_depth, 1.0 - raw_depth, outline.far_depth == 0.0);
+    return outline.z_near * outline.z_far / (outline.z_far - depth * (outline.z_far - outline.z_near));
+}
+
+@fragment
+fn fragment(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_depth));
+    let center = clamp(vec2<i32>(in.uv * vec2<f32>(size)), vec2<i32>(0), size - 1);
+    let center_normal = textureLoad(t_normal, center, 0);
+    let center_depth = linear_depth(textureLoad(t_depth, center, 0));
+
+    let radius = clamp(i32(ceil(outline.thickness)), 1, MAX_RADIUS);
+    for (var x = -radius; x <= radius; x = x + 1) {
+        for (var y = -radius; y <= radius; y = y + 1) {
+            let offset = vec2<i32>(x, y);
+            if (length(vec2<f32>(offset)) > outline.thickness) {
+                continue;
+            }
+            let coords = clamp(center + offset, vec2<i32>(0), size - 1);
+            let normal = textureLoad(t_normal, coords, 0);
+            // The alpha is 0 where the selection wasn't rendered,
+            // the silhouette is drawn outside of the selection
+            if (center_normal.a == 0.0) {
+                if (normal.a > 0.0) {
+                    return outline.color;
+                }
+                continue;
+            }
+            if (normal.a == 0.0) {
+                continue;
+            }
+
+            let depth = linear_depth(textureLoad(t_depth, coords, 0));
+            let depth_edge = abs(depth - center_depth) > DEPTH_THRESHOLD * center_depth;
+            let normal_edge = dot(normal.xyz, center_normal.xyz) < NORMAL_THRESHOLD;
+            if (depth_edge || normal_edge) {
+                return outline.color;
+            }
+        }
+    }
+    discard;
+}
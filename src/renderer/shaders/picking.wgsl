@@ -0,0 +1,49 @@
+// Writes the id of the entity being drawn, used to find the entity under a pixel
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct PickingUniform {
+    id: u32,
+}
+
+@group(1) @binding(0)
+var<uniform> picking: PickingUniform;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+@vertex
+fn vertex(
+    vertex: Vertex,
+    instance: InstanceInput,
+) -> @builtin(position) vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let skin = skin_matrix(vertex.joints, vertex.weights);
+    return camera.view_proj * model_matrix * skin * vec4<f32>(vertex.position, 1.0);
+}
+
+@fragment
+fn fragment() -> @location(0) u32 {
+    return picking.id;
+}
@@ -0,0 +1,40 @@
+// Renders the depth of a face of a point light shadow map
+
+struct Face {
+    view_proj: mat4x4<f32>,
+}
+@group(1) @binding(0)
+var<uniform> face: Face;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+fn build_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+@vertex
+fn vertex(
+    vertex: Vertex,
+    instance: InstanceInput,
+) -> @builtin(position) vec4<f32> {
+    let model_matrix = build_model_matrix(instance);
+    let skin = skin_matrix(vertex.joints, vertex.weights);
+    let world_position = model_matrix * skin * vec4<f32>(vertex.position, 1.0);
+    return face.view_proj * world_position;
+}
@@ -1,16 +1,44 @@
 struct CameraUniform {
     view_pos: vec4<f32>,
     view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    exposure: f32,
 }
 @group(0) @binding(0)
 var<uniform> camera: CameraUniform;
 
 struct Light {
+    // The direction the light travels in for directional lights
     position: vec3<f32>,
+    // The first face of the light in the point shadow maps divided by 6, 0 for the directional
+    // light with the cascades, or NO_SHADOW
+    shadow_index: u32,
     color: vec3<f32>,
+    kind: u32,
+}
+struct Lights {
+    count: u32,
+    data: array<Light>,
 }
 @group(0) @binding(1)
-var<uniform> light: Light;
+var<storage> lights: Lights;
+
+struct Globals {
+    time: f32,
+    delta_time: f32,
+    resolution: vec2<f32>,
+    frame: u32,
+    debug_view: u32,
+}
+@group(0) @binding(4)
+var<uniform> globals: Globals;
+
+// WARN these must match the values in mesh_view.rs
+const DEBUG_VIEW_NONE: u32 = 0u;
+const DEBUG_VIEW_NORMALS: u32 = 1u;
+const DEBUG_VIEW_UVS: u32 = 2u;
+const DEBUG_VIEW_TANGENTS: u32 = 3u;
+const DEBUG_VIEW_DEPTH: u32 = 4u;
 
 struct Material {
     base_color: vec4<f32>,
@@ -18,14 +46,21 @@ struct Material {
     gloss: f32,
     specular_color: vec3<f32>,
     flags: u32,
+    normal_scale: f32,
+    alpha_cutoff: f32,
+    // The layer of the texture arrays, always 0 unless the model packs its textures
+    texture_layer: u32,
+    // Which channel of the specular texture the metallic, roughness and occlusion use, 3 bits each
+    channel_mapping: u32,
+    emissive: vec3<f32>,
 }
 
 const MATERIAL_FLAGS_USE_NORMAL_MAP: u32 = 1u;
-const MATERIAL_FLAGS_1: u32 = 2u;
-const MATERIAL_FLAGS_2: u32 = 4u;
-const MATERIAL_FLAGS_3: u32 = 8u;
-const MATERIAL_FLAGS_4: u32 = 16u;
-const MATERIAL_FLAGS_5: u32 = 32u;
+const MATERIAL_FLAGS_PREMULTIPLIED_ALPHA: u32 = 2u;
+const MATERIAL_FLAGS_ALPHA_MASK: u32 = 4u;
+const MATERIAL_FLAGS_USE_SPECULAR_MAP: u32 = 8u;
+const MATERIAL_FLAGS_UNLIT: u32 = 16u;
+const MATERIAL_FLAGS_DOUBLE_SIDED: u32 = 32u;
 const MATERIAL_FLAGS_6: u32 = 64u;
 const MATERIAL_FLAGS_7: u32 = 128u;
 const MATERIAL_FLAGS_8: u32 = 256u;
@@ -36,26 +71,126 @@ const MATERIAL_FLAGS_0: u32 = 1024u;
 var<uniform> material: Material;
 
 @group(1) @binding(1)
-var t_diffuse: texture_2d<f32>;
+var t_diffuse: texture_2d_array<f32>;
 @group(1) @binding(2)
 var s_diffuse: sampler;
 
 @group(1) @binding(3)
-var t_normal: texture_2d<f32>;
+var t_normal: texture_2d_array<f32>;
 @group(1) @binding(4)
 var s_normal: sampler;
 
 @group(1) @binding(5)
-var t_spec: texture_2d<f32>;
+var t_spec: texture_2d_array<f32>;
 @group(1) @binding(6)
 var s_spec: sampler;
 
+// White when ssao is disabled
+@group(2) @binding(0)
+var t_ssao: texture_2d<f32>;
+@group(2) @binding(1)
+var s_ssao: sampler;
+
+// WARN these must match the values in mesh_view.rs
+const NO_SHADOW: u32 = 0xFFFFFFFFu;
+const LIGHT_KIND_POINT: u32 = 0u;
+const LIGHT_KIND_DIRECTIONAL: u32 = 1u;
+
+struct PointShadows {
+    // The view projection of every face, 6 per light
+    // WARN the size must match MAX_SHADOW_LIGHTS * 6 in shadow.rs
+    view_proj: array<mat4x4<f32>, 24>,
+    normal_bias: f32,
+}
+@group(3) @binding(0)
+var<uniform> point_shadows: PointShadows;
+@group(3) @binding(1)
+var t_point_shadow: texture_depth_2d_array;
+@group(3) @binding(2)
+var s_point_shadow: sampler_comparison;
+
+// Returns 0.0 when the position is in the shadow of the light and 1.0 when it's lit
+fn point_shadow(light: Light, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (light.shadow_index == NO_SHADOW) {
+        return 1.0;
+    }
+    // The face looking toward the major axis of the direction from the light, in the order +X -X +Y -Y +Z -Z
+    let direction = world_position - light.position;
+    let d = abs(direction);
+    var face = 0u;
+    if (d.x >= d.y && d.x >= d.z) {
+        face = select(1u, 0u, direction.x > 0.0);
+    } else if (d.y >= d.z) {
+        face = select(3u, 2u, direction.y > 0.0);
+    } else {
+        face = select(5u, 4u, direction.z > 0.0);
+    }
+    let layer = light.shadow_index * 6u + face;
+    let biased_position = world_position + normal * point_shadows.normal_bias;
+    let clip = point_shadows.view_proj[layer] * vec4<f32>(biased_position, 1.0);
+    let ndc = clip.xyz / clip.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    // The level variant can be used in the non uniform control flow of the light loop
+    return textureSampleCompareLevel(t_point_shadow, s_point_shadow, uv, i32(layer), ndc.z);
+}
+
+struct Cascades {
+    // WARN the sizes must match MAX_CASCADES in shadow.rs
+    view_proj: array<mat4x4<f32>, 4>,
+    // The distance from the camera where each cascade ends
+    splits: vec4<f32>,
+    count: u32,
+    // The fraction of each cascade blended with the next one
+    blend: f32,
+    normal_bias: f32,
+}
+@group(3) @binding(3)
+var<uniform> cascades: Cascades;
+@group(3) @binding(4)
+var t_cascade_shadow: texture_depth_2d_array;
+
+fn sample_cascade(cascade: u32, world_position: vec3<f32>) -> f32 {
+    let clip = cascades.view_proj[cascade] * vec4<f32>(world_position, 1.0);
+    let ndc = clip.xyz / clip.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    // The level variant can be used in the non uniform control flow of the light loop
+    return textureSampleCompareLevel(t_cascade_shadow, s_point_shadow, uv, i32(cascade), ndc.z);
+}
+
+// Returns 0.0 when the position is in the shadow of the directional light and 1.0 when it's lit.
+// The cascade is selected by the view depth and blended with the next one at its far end.
+fn directional_shadow(light: Light, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (light.shadow_index == NO_SHADOW) {
+        return 1.0;
+    }
+    let depth = -(camera.view * vec4<f32>(world_position, 1.0)).z;
+    let biased_position = world_position + normal * cascades.normal_bias;
+    var start = 0.0;
+    for (var i = 0u; i < cascades.count; i = i + 1u) {
+        let end = cascades.splits[i];
+        if (depth < end) {
+            let shadow = sample_cascade(i, biased_position);
+            let band = (end - start) * cascades.blend;
+            if (i + 1u >= cascades.count || band <= 0.0 || depth < end - band) {
+                return shadow;
+            }
+            let t = (depth - (end - band)) / band;
+            return mix(shadow, sample_cascade(i + 1u, biased_position), t);
+        }
+        start = end;
+    }
+    // Past the last cascade
+    return 1.0;
+}
+
 struct Vertex {
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
     @location(3) tangent: vec3<f32>,
     @location(4) bitangent: vec3<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
 }
 
 struct InstanceInput {
@@ -68,14 +203,22 @@ struct InstanceInput {
     @location(11) normal_matrix_2: vec3<f32>,
 }
 
+// Used by CompactInstances, only the model matrix is uploaded
+struct CompactInstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
 struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
+    // Invariant so it matches the depth prepass exactly
+    @builtin(position) @invariant clip_position: vec4<f32>,
     @location(0) world_position: vec4<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
-    @location(3) tangent_position: vec3<f32>,
-    @location(4) tangent_light_position: vec3<f32>,
-    @location(5) tangent_view_position: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
 }
 
 fn build_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
@@ -95,16 +238,16 @@ fn build_normal_matrix(instance: InstanceInput) -> mat3x3<f32> {
     );
 }
 
-@vertex
-fn vertex(
+fn vertex_output(
     vertex: Vertex,
-    instance: InstanceInput,
+    model_matrix: mat4x4<f32>,
+    normal_matrix: mat3x3<f32>,
 ) -> VertexOutput {
-    let model_matrix = build_model_matrix(instance);
-    let normal_matrix = build_normal_matrix(instance);
+    let skin = skin_matrix(vertex.joints, vertex.weights);
+    let skin_normal_matrix = mat3x3<f32>(skin[0].xyz, skin[1].xyz, skin[2].xyz);
 
-    let world_normal = normal_matrix * vertex.normal;
-    let world_position = model_matrix * vec4<f32>(vertex.position, 1.0);
+    let world_normal = normal_matrix * skin_normal_matrix * vertex.normal;
+    let world_position = model_matrix * skin * vec4<f32>(vertex.position, 1.0);
 
     var out: VertexOutput;
     out.clip_position = camera.view_proj * world_position;
@@ -113,70 +256,203 @@ fn vertex(
     out.uv = vertex.uv;
 
     if ((material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) != 0u) {
-        let world_tangent = normalize(normal_matrix * vertex.tangent);
-        let world_bitangent = normalize(normal_matrix * vertex.bitangent);
-        let tangent_matrix = transpose(mat3x3<f32>(
-            world_tangent,
-            world_bitangent,
-            world_normal,
-        ));
-
-        out.tangent_position = tangent_matrix * world_position.xyz;
-        out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
-        out.tangent_light_position = tangent_matrix * light.position;
+        out.world_tangent = normal_matrix * skin_normal_matrix * vertex.tangent;
+        out.world_bitangent = normal_matrix * skin_normal_matrix * vertex.bitangent;
     }
 
     return out;
 }
 
-@fragment
-fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.uv);
-    var object_specular: vec4<f32> = textureSample(t_spec, s_spec, in.uv);
-    let metallic = object_specular.b;
-    object_specular = vec4<f32>(metallic, metallic, metallic, 1.0);
-    // object_specular = vec4<f32>(1.0, 1.0, 1.0, 1.0) - object_specular;
+@vertex
+fn vertex(
+    vertex: Vertex,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return vertex_output(vertex, build_model_matrix(instance), build_normal_matrix(instance));
+}
 
-    var N: vec3<f32>;
-    var L: vec3<f32>;
-    var V: vec3<f32>;
+// The normal matrix is the rotation of the model matrix, like the one uploaded with the regular format
+@vertex
+fn vertex_compact(
+    vertex: Vertex,
+    instance: CompactInstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        normalize(model_matrix[0].xyz),
+        normalize(model_matrix[1].xyz),
+        normalize(model_matrix[2].xyz),
+    );
+    return vertex_output(vertex, model_matrix, normal_matrix);
+}
 
-    if ((material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) != 0u) {
-        let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.uv);
-        N = object_normal.xyz * 2.0 - 1.0;
-        L = normalize(in.tangent_light_position - in.tangent_position);
-        V = normalize(in.tangent_view_position - in.tangent_position);
-        // return vec4<f32>(0.0, 0.0, 1.0, 1.0);
-    } else {
-        N = normalize(in.world_normal);
-        L = normalize(light.position - in.world_position.xyz);
-        V = normalize(camera.view_pos.xyz - in.world_position.xyz);
-        // return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // Only written when the g-buffer is enabled
+    @location(1) normal: vec4<f32>,
+}
+
+// The color used instead of the shading when a debug view is selected
+fn debug_color(in: VertexOutput, N: vec3<f32>) -> vec3<f32> {
+    if (globals.debug_view == DEBUG_VIEW_NORMALS) {
+        return N * 0.5 + 0.5;
+    }
+    if (globals.debug_view == DEBUG_VIEW_UVS) {
+        return vec3<f32>(fract(in.uv), 0.0);
     }
+    if (globals.debug_view == DEBUG_VIEW_TANGENTS) {
+        if ((material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) == 0u) {
+            return vec3<f32>(0.0);
+        }
+        return normalize(in.world_tangent) * 0.5 + 0.5;
+    }
+    if (globals.debug_view == DEBUG_VIEW_DEPTH) {
+        let distance = length(camera.view_pos.xyz - in.world_position.xyz);
+        return vec3<f32>(1.0 - exp(-0.1 * distance));
+    }
+    return vec3<f32>(0.0);
+}
+
+// WARN this must match ChannelMapping::bits() in model.rs
+const TEXTURE_CHANNEL_NONE: u32 = 4u;
+
+// The channel selected by the 3 bits at `shift` of the channel mapping
+fn mapped_channel(color: vec4<f32>, shift: u32, default_value: f32) -> f32 {
+    let channel = (material.channel_mapping >> shift) & 7u;
+    if (channel == TEXTURE_CHANNEL_NONE) {
+        return default_value;
+    }
+    return color[channel];
+}
+
+fn shade(in: VertexOutput, front_facing: bool) -> FragmentOutput {
+    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.uv, i32(material.texture_layer));
+    let specular_sample: vec4<f32> = textureSample(t_spec, s_spec, in.uv, i32(material.texture_layer));
+    let metallic = mapped_channel(specular_sample, 0u, 1.0);
+    var object_specular = vec4<f32>(metallic, metallic, metallic, 1.0);
+    var gloss = material.gloss;
+    var texture_occlusion = 1.0;
+    if ((material.flags & MATERIAL_FLAGS_USE_SPECULAR_MAP) != 0u) {
+        gloss = gloss * (1.0 - mapped_channel(specular_sample, 3u, 0.0));
+        texture_occlusion = mapped_channel(specular_sample, 6u, 1.0);
+    }
+    // object_specular = vec4<f32>(1.0, 1.0, 1.0, 1.0) - object_specular;
 
-    let H = normalize(L + V);
+    var geometry_normal = normalize(in.world_normal);
+    // The back faces of double sided materials are lit as if they were facing the camera
+    if ((material.flags & MATERIAL_FLAGS_DOUBLE_SIDED) != 0u && !front_facing) {
+        geometry_normal = -geometry_normal;
+    }
+    var N = geometry_normal;
+    // Lighting is done in world space so the normal map needs to be converted
+    // from tangent space
+    if ((material.flags & MATERIAL_FLAGS_USE_NORMAL_MAP) != 0u) {
+        let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.uv, i32(material.texture_layer));
+        let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+        let tangent_matrix = mat3x3<f32>(
+            normalize(in.world_tangent),
+            normalize(in.world_bitangent),
+            N,
+        );
+        N = normalize(tangent_matrix * vec3<f32>(tangent_normal.xy * material.normal_scale, tangent_normal.z));
+    }
+    let V = normalize(camera.view_pos.xyz - in.world_position.xyz);
 
     // TODO load ambient values from uniform buffer
     let ambient_strength = 0.1;
+    let clip_position = camera.view_proj * in.world_position;
+    let screen_uv = clip_position.xy / clip_position.w * vec2<f32>(0.5, -0.5) + 0.5;
+    let ambient_occlusion = textureSample(t_ssao, s_ssao, screen_uv).r * texture_occlusion;
+    let specular_exp = exp2(gloss * 11.0) + 2.0;
 
-    let diffuse_strength = max(dot(N, L), 0.0);
+    // The ambient light is added once, not once per light
+    var result = ambient_strength * ambient_occlusion * object_color.rgb * material.base_color.rgb;
+    for (var i = 0u; i < lights.count; i = i + 1u) {
+        let light = lights.data[i];
+        var L = normalize(light.position - in.world_position.xyz);
+        if (light.kind == LIGHT_KIND_DIRECTIONAL) {
+            L = -light.position;
+        }
+        let H = normalize(L + V);
 
-    var specular_strength = max(dot(N, H), 0.0);
-    // Make sure the specular light doesn't go pass the lambertian diffuse light
-    // this fixes a small artifact, but introduces very sharp cutoff
-    specular_strength = specular_strength * f32(diffuse_strength > 0.0);
-    let specular_exp = exp2(material.gloss * 11.0) + 2.0;
-    specular_strength = pow(specular_strength, specular_exp);
+        let diffuse_strength = max(dot(N, L), 0.0);
 
-    let ambient_color = ambient_strength * object_color.rgb * material.base_color.rgb;
-    let diffuse_color = diffuse_strength * object_color.rgb * material.base_color.rgb;
-    let specular_color = specular_strength * object_specular.rgb * material.specular_color;
-    let result = (ambient_color + diffuse_color + specular_color) * light.color;
+        var specular_strength = max(dot(N, H), 0.0);
+        // Make sure the specular light doesn't go pass the lambertian diffuse light
+        // this fixes a small artifact, but introduces very sharp cutoff
+        specular_strength = specular_strength * f32(diffuse_strength > 0.0);
+        specular_strength = pow(specular_strength, specular_exp);
+
+        let diffuse_color = diffuse_strength * object_color.rgb * material.base_color.rgb;
+        let specular_color = specular_strength * object_specular.rgb * material.specular_color;
+        var shadow = 1.0;
+        if (light.kind == LIGHT_KIND_DIRECTIONAL) {
+            shadow = directional_shadow(light, in.world_position.xyz, geometry_normal);
+        } else {
+            shadow = point_shadow(light, in.world_position.xyz, geometry_normal);
+        }
+        result = result + (diffuse_color + specular_color) * shadow * light.color;
+    }
+    var emissive = material.emissive;
+    if ((material.flags & MATERIAL_FLAGS_PREMULTIPLIED_ALPHA) != 0u) {
+        emissive = emissive * object_color.a;
+    }
+    result = result + emissive;
+
+    if ((material.flags & MATERIAL_FLAGS_UNLIT) != 0u) {
+        // Flat color used by ColorOverride, it ignores the lights, the fog and the exposure
+        result = material.base_color.rgb;
+        if ((material.flags & MATERIAL_FLAGS_PREMULTIPLIED_ALPHA) != 0u) {
+            result = result * object_color.a;
+        }
+    } else {
+        let distance = length(camera.view_pos.xyz - in.world_position.xyz);
+        var fog_color = fog.color.rgb;
+        // The color is already multiplied by the alpha so the fog needs to be too
+        if ((material.flags & MATERIAL_FLAGS_PREMULTIPLIED_ALPHA) != 0u) {
+            fog_color = fog_color * object_color.a;
+        }
+        result = mix(result, fog_color, fog_factor(distance));
+        result = result * camera.exposure;
+    }
     // let result = diffuse_color;
     // let result = specular_color;
     // let result = object_color.rgb;
     // let result = material.base_color.rgb;
     // let result = N;
 
-    return vec4<f32>(result, object_color.a);
+    if (globals.debug_view != DEBUG_VIEW_NONE) {
+        result = debug_color(in, N);
+    }
+
+    var out: FragmentOutput;
+    out.color = vec4<f32>(result, object_color.a);
+    out.normal = vec4<f32>(normalize((camera.view * vec4<f32>(N, 0.0)).xyz), 1.0);
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
+    let out = shade(in, front_facing);
+    // Hard alpha test used when msaa is disabled
+    if ((material.flags & MATERIAL_FLAGS_ALPHA_MASK) != 0u && out.color.a < material.alpha_cutoff) {
+        discard;
+    }
+    return out;
+}
+
+// Used by the alpha mask pipeline when msaa is enabled.
+// The alpha is sharpened around the cutoff so the edge is about a pixel wide,
+// the hardware then converts it to a coverage mask.
+@fragment
+fn fragment_alpha_to_coverage(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
+    var out = shade(in, front_facing);
+    let alpha = out.color.a;
+    out.color.a = clamp((alpha - material.alpha_cutoff) / max(fwidth(alpha), 0.0001) + 0.5, 0.0, 1.0);
+    return out;
 }
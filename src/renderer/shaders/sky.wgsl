@@ -0,0 +1,35 @@
+// Fills the background with a gradient based on the view direction
+
+struct Sky {
+    inverse_view_proj: mat4x4<f32>,
+    top: vec4<f32>,
+    bottom: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> sky: Sky;
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+// Draws a triangle covering the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenOutput;
+    out.ndc = uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+    out.position = vec4<f32>(out.ndc, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fragment(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    // The camera is the point projected to infinity, this works with and without reverse-Z
+    let eye = sky.inverse_view_proj * vec4<f32>(0.0, 0.0, 1.0, 0.0);
+    let point = sky.inverse_view_proj * vec4<f32>(in.ndc, 0.5, 1.0);
+    let direction = normalize(point.xyz / point.w - eye.xyz / eye.w);
+    // Everything below the horizon uses the bottom color
+    return mix(sky.bottom, sky.top, clamp(direction.y, 0.0, 1.0));
+}
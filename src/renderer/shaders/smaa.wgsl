@@ -0,0 +1,213 @@
+// Morphological anti aliasing based on SMAA 1x: edge detection, blending weights and neighborhood blending.
+// The areas are computed analytically instead of reading the precomputed area and search textures
+// and diagonal edges aren't detected.
+
+@group(0) @binding(0)
+var t_color: texture_2d<f32>;
+@group(0) @binding(1)
+var s_color: sampler;
+@group(0) @binding(2)
+var t_edges: texture_2d<f32>;
+@group(0) @binding(3)
+var t_weights: texture_2d<f32>;
+
+// Luma difference, in gamma space, that is considered an edge
+const THRESHOLD: f32 = 0.1;
+// Edges are ignored when a neighbouring edge has this much more contrast
+const LOCAL_CONTRAST_ADAPTATION_FACTOR: f32 = 2.0;
+// The loop isn't unrolled but every pixel of an edge walks along it in both directions
+const MAX_SEARCH_STEPS: i32 = 16;
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Draws a triangle covering the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+fn luma(coords: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(t_color));
+    let color = textureLoad(t_color, clamp(coords, vec2<i32>(0), size - 1), 0).rgb;
+    return dot(sqrt(color), vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Red is an edge on the left of the pixel and green an edge on top of it
+@fragment
+fn edge_detection(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    let center = luma(coords);
+    let left = luma(coords + vec2<i32>(-1, 0));
+    let top = luma(coords + vec2<i32>(0, -1));
+    let delta = abs(center - vec2<f32>(left, top));
+    var edges = step(vec2<f32>(THRESHOLD), delta);
+    if edges.x + edges.y == 0.0 {
+        return vec4<f32>(0.0);
+    }
+
+    // Only keep the edges that have about as much contrast as their neighbours
+    let right = luma(coords + vec2<i32>(1, 0));
+    let bottom = luma(coords + vec2<i32>(0, 1));
+    let left_left = luma(coords + vec2<i32>(-2, 0));
+    let top_top = luma(coords + vec2<i32>(0, -2));
+    var max_delta = max(delta, abs(center - vec2<f32>(right, bottom)));
+    max_delta = max(max_delta, abs(vec2<f32>(left, top) - vec2<f32>(left_left, top_top)));
+    let final_delta = max(max_delta.x, max_delta.y);
+    edges *= step(vec2<f32>(final_delta), LOCAL_CONTRAST_ADAPTATION_FACTOR * delta);
+    return vec4<f32>(edges, 0.0, 0.0);
+}
+
+fn load_edges(coords: vec2<i32>) -> vec2<f32> {
+    let size = vec2<i32>(textureDimensions(t_edges));
+    if any(coords < vec2<i32>(0)) || any(coords >= size) {
+        return vec2<f32>(0.0);
+    }
+    return textureLoad(t_edges, coords, 0).rg;
+}
+
+// The number of pixels the edge continues for in that direction
+fn search(coords: vec2<i32>, direction: vec2<i32>, channel: i32) -> i32 {
+    var distance = 0;
+    for (var i = 1; i <= MAX_SEARCH_STEPS; i = i + 1) {
+        if load_edges(coords + direction * i)[channel] < 0.5 {
+            break;
+        }
+        distance = i;
+    }
+    return distance;
+}
+
+// The height of the reconstructed line at the end of an edge, based on the edge crossing it.
+// It's negative when the crossing edge is on the side of the current pixel.
+fn crossing_height(pixel_side: f32, other_side: f32) -> f32 {
+    if pixel_side > 0.5 && other_side < 0.5 {
+        return -0.5;
+    }
+    if other_side > 0.5 && pixel_side < 0.5 {
+        return 0.5;
+    }
+    return 0.0;
+}
+
+// The area between the edge and the line from p1 to p2 inside the pixel starting at x.
+// x is the area on the side of the current pixel and y the area on the other side.
+fn line_area(p1: vec2<f32>, p2: vec2<f32>, x: f32) -> vec2<f32> {
+    let start = max(x, p1.x);
+    let end = min(x + 1.0, p2.x);
+    if end <= start {
+        return vec2<f32>(0.0);
+    }
+    let slope = (p2.y - p1.y) / (p2.x - p1.x);
+    let h1 = p1.y + slope * (start - p1.x);
+    let h2 = p1.y + slope * (end - p1.x);
+    if h1 * h2 >= 0.0 {
+        let area = abs(h1 + h2) * 0.5 * (end - start);
+        return select(vec2<f32>(0.0, area), vec2<f32>(area, 0.0), h1 + h2 < 0.0);
+    }
+    // The line crosses the edge inside the pixel
+    let zero = start + (end - start) * h1 / (h1 - h2);
+    let a1 = abs(h1) * 0.5 * (zero - start);
+    let a2 = abs(h2) * 0.5 * (end - zero);
+    return select(vec2<f32>(a2, a1), vec2<f32>(a1, a2), h1 < 0.0);
+}
+
+// The area covered by the line reconstructed from the shape of the edge, like in MLAA.
+// Z shapes use a line across the whole edge, L and U shapes a line from each end to the middle.
+fn edge_area(start_height: f32, end_height: f32, to_start: f32, to_end: f32) -> vec2<f32> {
+    let length = to_start + to_end + 1.0;
+    if start_height != 0.0 && start_height == -end_height {
+        return line_area(vec2<f32>(0.0, start_height), vec2<f32>(length, end_height), to_start);
+    }
+    var area = vec2<f32>(0.0);
+    if start_height != 0.0 && to_start <= to_end {
+        area += line_area(vec2<f32>(0.0, start_height), vec2<f32>(length * 0.5, 0.0), to_start);
+    }
+    if end_height != 0.0 && to_start >= to_end {
+        area += line_area(vec2<f32>(length * 0.5, 0.0), vec2<f32>(length, end_height), to_start);
+    }
+    return area;
+}
+
+// Red is how much the pixel blends with the one on top and green how much the one on top blends with it.
+// Blue and alpha are the same for the pixel on the left.
+@fragment
+fn blending_weights(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    let edges = load_edges(coords);
+    var weights = vec4<f32>(0.0);
+
+    if edges.y > 0.5 {
+        let left = search(coords, vec2<i32>(-1, 0), 1);
+        let right = search(coords, vec2<i32>(1, 0), 1);
+        let start = coords + vec2<i32>(-left, 0);
+        let end = coords + vec2<i32>(right + 1, 0);
+        let start_height = crossing_height(load_edges(start).x, load_edges(start + vec2<i32>(0, -1)).x);
+        let end_height = crossing_height(load_edges(end).x, load_edges(end + vec2<i32>(0, -1)).x);
+        let area = edge_area(start_height, end_height, f32(left), f32(right));
+        weights.x = area.x;
+        weights.y = area.y;
+    }
+
+    if edges.x > 0.5 {
+        let up = search(coords, vec2<i32>(0, -1), 0);
+        let down = search(coords, vec2<i32>(0, 1), 0);
+        let start = coords + vec2<i32>(0, -up);
+        let end = coords + vec2<i32>(0, down + 1);
+        let start_height = crossing_height(load_edges(start).y, load_edges(start + vec2<i32>(-1, 0)).y);
+        let end_height = crossing_height(load_edges(end).y, load_edges(end + vec2<i32>(-1, 0)).y);
+        let area = edge_area(start_height, end_height, f32(up), f32(down));
+        weights.z = area.x;
+        weights.w = area.y;
+    }
+
+    return weights;
+}
+
+fn load_weights(coords: vec2<i32>) -> vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_weights));
+    if any(coords < vec2<i32>(0)) || any(coords >= size) {
+        return vec4<f32>(0.0);
+    }
+    return textureLoad(t_weights, coords, 0);
+}
+
+@fragment
+fn neighborhood_blending(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    let pixel_size = 1.0 / vec2<f32>(textureDimensions(t_color));
+    let uv = in.position.xy * pixel_size;
+
+    // How much the pixel blends with the right, bottom, left and top pixels
+    let weights = load_weights(coords);
+    let a = vec4<f32>(
+        load_weights(coords + vec2<i32>(1, 0)).w,
+        load_weights(coords + vec2<i32>(0, 1)).y,
+        weights.z,
+        weights.x,
+    );
+    if dot(a, vec4<f32>(1.0)) < 1e-5 {
+        return textureSampleLevel(t_color, s_color, uv, 0.0);
+    }
+
+    // Only blend along the strongest direction, the bilinear filtering does the blending
+    var offset: vec4<f32>;
+    var blend: vec2<f32>;
+    if max(a.x, a.z) > max(a.y, a.w) {
+        offset = vec4<f32>(a.x, 0.0, a.z, 0.0);
+        blend = a.xz;
+    } else {
+        offset = vec4<f32>(0.0, a.y, 0.0, a.w);
+        blend = a.yw;
+    }
+    blend /= dot(blend, vec2<f32>(1.0));
+
+    return blend.x * textureSampleLevel(t_color, s_color, uv + offset.xy * pixel_size, 0.0)
+        + blend.y * textureSampleLevel(t_color, s_color, uv - offset.zw * pixel_size, 0.0);
+}
@@ -0,0 +1,109 @@
+// Computes the ambient occlusion by sampling a hemisphere around each pixel.
+// Everything is done in world space, the positions are reconstructed from the depth.
+
+struct Ssao {
+    view_proj: mat4x4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+    eye: vec4<f32>,
+    radius: f32,
+    intensity: f32,
+    sample_count: u32,
+    // 1, or 0 with reverse-Z
+    far_depth: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> ssao: Ssao;
+@group(0) @binding(1)
+var t_depth: texture_depth_2d;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+
+const TAU: f32 = 6.28318530718;
+const GOLDEN_ANGLE: f32 = 2.39996322973;
+// Avoids self occlusion on flat surfaces
+const BIAS: f32 = 0.02;
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Draws a triangle covering the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+fn texel(uv: vec2<f32>) -> vec2<i32> {
+    let size = vec2<i32>(textureDimensions(t_depth));
+    return clamp(vec2<i32>(uv * vec2<f32>(size)), vec2<i32>(0), size - 1);
+}
+
+fn world_position(uv: vec2<f32>, depth: f32) -> vec3<f32> {
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let position = ssao.inverse_view_proj * ndc;
+    return position.xyz / position.w;
+}
+
+fn hash(p: vec2<f32>) -> f32 {
+    return fract(sin(dot(p, vec2<f32>(12.9898, 78.233))) * 43758.5453);
+}
+
+@fragment
+fn fragment(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let depth = textureLoad(t_depth, texel(in.uv), 0);
+    // Nothing was rendered here
+    if (depth == ssao.far_depth) {
+        return vec4<f32>(1.0);
+    }
+
+    let P = world_position(in.uv, depth);
+    let N = normalize(textureLoad(t_normal, texel(in.uv), 0).xyz);
+
+    // Randomly rotate the kernel around the normal for each pixel,
+    // the noise is removed by the blur pass
+    let angle = hash(in.position.xy) * TAU;
+    let random = vec3<f32>(cos(angle), sin(angle), hash(in.position.yx));
+    var T = random - N * dot(random, N);
+    if (dot(T, T) < 0.0001) {
+        T = cross(N, vec3<f32>(0.0, 0.0, 1.0));
+    }
+    T = normalize(T);
+    let B = cross(N, T);
+
+    let sample_count = f32(ssao.sample_count);
+    var occlusion = 0.0;
+    for (var i = 0u; i < ssao.sample_count; i = i + 1u) {
+        // Spiral over the hemisphere, more samples are placed close to the center
+        let t = (f32(i) + 0.5) / sample_count;
+        let z = 1.0 - t;
+        let r = sqrt(1.0 - z * z);
+        let phi = f32(i) * GOLDEN_ANGLE;
+        let scale = mix(0.1, 1.0, t * t);
+        let offset = vec3<f32>(r * cos(phi), r * sin(phi), z) * scale * ssao.radius;
+        let sample_position = P + T * offset.x + B * offset.y + N * offset.z;
+
+        let clip = ssao.view_proj * vec4<f32>(sample_position, 1.0);
+        let sample_uv = clip.xy / clip.w * vec2<f32>(0.5, -0.5) + 0.5;
+        if (any(sample_uv < vec2<f32>(0.0)) || any(sample_uv > vec2<f32>(1.0))) {
+            continue;
+        }
+
+        let scene_position = world_position(sample_uv, textureLoad(t_depth, texel(sample_uv), 0));
+        let sample_distance = distance(ssao.eye.xyz, sample_position);
+        let scene_distance = distance(ssao.eye.xyz, scene_position);
+        // Ignore the geometry that is far from the sampled point, like the background behind an edge
+        let range = smoothstep(0.0, 1.0, ssao.radius / max(distance(P, scene_position), 0.0001));
+        if (scene_distance < sample_distance - BIAS) {
+            occlusion = occlusion + range;
+        }
+    }
+
+    let ao = clamp(1.0 - occlusion / sample_count * ssao.intensity, 0.0, 1.0);
+    return vec4<f32>(ao, ao, ao, 1.0);
+}
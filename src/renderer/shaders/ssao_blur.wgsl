@@ -0,0 +1,34 @@
+// Removes the noise of the ssao with a box blur the size of the noise pattern
+
+@group(0) @binding(0)
+var t_ssao: texture_2d<f32>;
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Draws a triangle covering the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@fragment
+fn fragment(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_ssao));
+    let center = vec2<i32>(in.position.xy);
+    var result = 0.0;
+    for (var x = -2; x < 2; x = x + 1) {
+        for (var y = -2; y < 2; y = y + 1) {
+            let coords = clamp(center + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            result = result + textureLoad(t_ssao, coords, 0).r;
+        }
+    }
+    result = result / 16.0;
+    return vec4<f32>(result, result, result, 1.0);
+}
@@ -0,0 +1,49 @@
+// Copies a render target to a color texture that egui can display
+
+@group(0) @binding(0)
+var t_color: texture_2d<f32>;
+@group(0) @binding(1)
+var t_depth: texture_depth_2d;
+@group(0) @binding(2)
+var t_depth_multisampled: texture_depth_multisampled_2d;
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Draws a triangle covering the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: FullscreenOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@fragment
+fn color(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let color = textureLoad(t_color, vec2<i32>(in.position.xy), 0);
+    return vec4<f32>(color.rgb, 1.0);
+}
+
+// Single channel textures are shown in grayscale instead of red
+@fragment
+fn red(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let value = textureLoad(t_color, vec2<i32>(in.position.xy), 0).r;
+    return vec4<f32>(value, value, value, 1.0);
+}
+
+@fragment
+fn depth(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let depth = textureLoad(t_depth, vec2<i32>(in.position.xy), 0);
+    return vec4<f32>(depth, depth, depth, 1.0);
+}
+
+// Only shows the first sample
+@fragment
+fn depth_multisampled(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let depth = textureLoad(t_depth_multisampled, vec2<i32>(in.position.xy), 0);
+    return vec4<f32>(depth, depth, depth, 1.0);
+}
@@ -0,0 +1,83 @@
+// This shader is used for line and point meshes, they don't have normals so they are not lit
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct Material {
+    base_color: vec4<f32>,
+    alpha: f32,
+    gloss: f32,
+    specular_color: vec3<f32>,
+    flags: u32,
+    normal_scale: f32,
+    alpha_cutoff: f32,
+    // The layer of the texture arrays, always 0 unless the model packs its textures
+    texture_layer: u32,
+    // Which channel of the specular texture the metallic, roughness and occlusion use, 3 bits each
+    channel_mapping: u32,
+    emissive: vec3<f32>,
+}
+@group(1) @binding(0)
+var<uniform> material: Material;
+
+@group(1) @binding(1)
+var t_diffuse: texture_2d_array<f32>;
+@group(1) @binding(2)
+var s_diffuse: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) world_position: vec4<f32>,
+}
+
+@vertex
+fn vertex(
+    vertex: Vertex,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    let skin = skin_matrix(vertex.joints, vertex.weights);
+    let world_position = model_matrix * skin * vec4<f32>(vertex.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.uv = vertex.uv;
+    out.world_position = world_position;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_color = textureSample(t_diffuse, s_diffuse, in.uv, i32(material.texture_layer));
+    let distance = length(camera.view_pos.xyz - in.world_position.xyz);
+    let color = mix(object_color.rgb * material.base_color.rgb, fog.color.rgb, fog_factor(distance));
+    return vec4<f32>(color, 1.0);
+}
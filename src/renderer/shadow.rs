@@ -0,0 +1,824 @@
+use bevy::{ecs::prelude::*, math::prelude::*, utils::default};
+
+use super::{
+    base_3d::Transparent,
+    bind_groups::mesh_view::{LightBuffer, MeshViewBindGroup, MeshViewBindGroupLayout},
+    DepthOnlyPipelines, WgpuEncoder, WgpuRenderer,
+};
+use crate::{
+    camera::{Camera, VisibilityQuery},
+    instances::{CompactInstances, InstanceBuffer, Instances},
+    light::{DirectionalLight, Light},
+    model::{BlendMode, Model},
+    texture::Texture,
+    transform::TransformRaw,
+};
+
+/// The maximum number of [`PointLightShadow`], the other lights are rendered without shadows
+pub const MAX_SHADOW_LIGHTS: usize = 4;
+/// The maximum [`CascadeSettings::count`]
+pub const MAX_CASCADES: usize = 4;
+const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+/// The direction and up vector of each face, in the order of the cube map faces
+const FACES: [(Vec3, Vec3); 6] = [
+    (Vec3::X, Vec3::NEG_Y),
+    (Vec3::NEG_X, Vec3::NEG_Y),
+    (Vec3::Y, Vec3::Z),
+    (Vec3::NEG_Y, Vec3::NEG_Z),
+    (Vec3::Z, Vec3::NEG_Y),
+    (Vec3::NEG_Z, Vec3::NEG_Y),
+];
+
+/// Makes the [`Light`] on the same entity cast shadows in every direction.
+///
+/// The scene is rendered to the 6 faces of a depth cube map for each of these lights,
+/// so each one costs 6 extra depth passes over every opaque mesh per frame and
+/// `6 * size * size * 4` bytes of memory, 6MB with the default [`PointShadowSettings`].
+/// Only the first [`MAX_SHADOW_LIGHTS`] lights get shadows.
+/// Transparent, masked and compact instanced meshes don't cast shadows.
+#[allow(unused)]
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct PointLightShadow;
+
+#[derive(Resource, Debug, Clone)]
+pub struct PointShadowSettings {
+    /// The width and height of each face of the cube maps
+    pub size: u32,
+    /// Meshes closer than this to a light don't cast shadows
+    pub near: f32,
+    /// Offsets the shaded position along its normal before comparing it to the shadow map,
+    /// in world units. Increase it if the lit surfaces have shadow acne.
+    pub normal_bias: f32,
+}
+
+impl Default for PointShadowSettings {
+    fn default() -> Self {
+        Self {
+            size: 512,
+            near: 0.05,
+            normal_bias: 0.02,
+        }
+    }
+}
+
+/// Makes the [`DirectionalLight`] on the same entity cast shadows with cascaded shadow maps.
+///
+/// The view frustum of the camera is split in [`CascadeSettings::count`] depth ranges and the scene
+/// is rendered to one shadow map per range, so the cascades close to the camera get more texels per
+/// world unit. Only the first of these lights gets shadows.
+/// Transparent, masked and compact instanced meshes don't cast shadows.
+#[allow(unused)]
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct DirectionalLightShadow;
+
+#[derive(Resource, Debug, Clone)]
+pub struct CascadeSettings {
+    /// The number of cascades, clamped between 1 and [`MAX_CASCADES`]
+    pub count: usize,
+    /// Blends the split distances from evenly spaced at 0 to logarithmic at 1.
+    /// Logarithmic splits give more resolution close to the camera.
+    pub split_lambda: f32,
+    /// The width and height of each cascade
+    pub size: u32,
+    /// Fragments farther than this from the camera aren't shadowed, in world units
+    pub max_distance: f32,
+    /// The fraction of each cascade, at its far end, that is blended with the next one
+    pub blend: f32,
+    /// Offsets the shaded position along its normal before comparing it to the shadow map,
+    /// in world units. Increase it if the lit surfaces have shadow acne.
+    pub normal_bias: f32,
+}
+
+impl Default for CascadeSettings {
+    fn default() -> Self {
+        Self {
+            count: 4,
+            split_lambda: 0.75,
+            size: 1024,
+            max_distance: 100.0,
+            blend: 0.1,
+            normal_bias: 0.05,
+        }
+    }
+}
+
+impl CascadeSettings {
+    fn cascade_count(&self) -> usize {
+        self.count.clamp(1, MAX_CASCADES)
+    }
+}
+
+/// Sampled by the main pass, every face uses the same projection so only the view changes
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PointShadowUniform {
+    view_proj: [[[f32; 4]; 4]; MAX_SHADOW_LIGHTS * 6],
+    normal_bias: f32,
+    _padding: [f32; 3],
+}
+
+/// Sampled by the main pass to select and sample the cascades
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CascadeUniform {
+    view_proj: [[[f32; 4]; 4]; MAX_CASCADES],
+    /// The distance from the camera where each cascade ends
+    splits: [f32; MAX_CASCADES],
+    count: u32,
+    blend: f32,
+    normal_bias: f32,
+    _padding: f32,
+}
+
+/// The view projection of a single face, bound with a dynamic offset when rendering it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FaceUniform {
+    view_proj: [[f32; 4]; 4],
+}
+
+/// The layout of the bind group used by the main pass to sample the shadow maps
+pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let uniform = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let texture = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2Array,
+            sample_type: wgpu::TextureSampleType::Depth,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("shadow_bind_group_layout"),
+        entries: &[
+            uniform(0),
+            texture(1),
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                count: None,
+            },
+            // The cascades of the directional light
+            uniform(3),
+            texture(4),
+        ],
+    })
+}
+
+/// The size and number of every shadow map, the targets are recreated when it changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ShadowSizes {
+    point_size: u32,
+    light_count: usize,
+    cascade_size: u32,
+    /// 0 when no directional light casts shadows
+    cascade_count: usize,
+}
+
+/// The faces of every light are stored as the layers of a single texture array instead of a cube array.
+/// The main pass projects with the same matrices used to render the faces so it doesn't depend on
+/// the orientation conventions of cube maps.
+struct ShadowTargets {
+    sizes: ShadowSizes,
+    /// One view per face used as the depth attachment
+    faces: Vec<wgpu::TextureView>,
+    /// One view per cascade used as the depth attachment
+    cascades: Vec<wgpu::TextureView>,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Renders the cube shadow maps of the point lights and the cascades of the directional light
+#[derive(Resource)]
+pub struct ShadowPass {
+    pipelines: DepthOnlyPipelines,
+    uniform_buffer: wgpu::Buffer,
+    cascade_buffer: wgpu::Buffer,
+    /// The faces of the point lights followed by the cascades
+    face_buffer: wgpu::Buffer,
+    /// Distance in bytes between two face uniforms
+    face_stride: u32,
+    face_bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    targets: ShadowTargets,
+}
+
+impl ShadowPass {
+    fn new(renderer: &WgpuRenderer, mesh_view_layout: &MeshViewBindGroupLayout) -> Self {
+        let device = &renderer.device;
+
+        let face_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("point_shadow_face_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(
+                        std::mem::size_of::<FaceUniform>() as u64
+                    ),
+                },
+                count: None,
+            }],
+        });
+
+        let pipelines = renderer.errors.scope(device, "Point Shadow Pipeline", || {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Point Shadow Shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("shaders/common.wgsl"),
+                        include_str!("shaders/point_shadow.wgsl")
+                    )
+                    .into(),
+                ),
+            });
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Point Shadow Pipeline Layout"),
+                bind_group_layouts: &[&mesh_view_layout.0, &face_layout],
+                push_constant_ranges: &[],
+            });
+            DepthOnlyPipelines::new(|vertex_layout| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Point Shadow Pipeline"),
+                    layout: Some(&layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[vertex_layout, TransformRaw::layout()],
+                    },
+                    fragment: None,
+                    // Single sided meshes like planes still need to cast shadows
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: None,
+                        ..default()
+                    },
+                    // The faces use an infinite reverse-Z projection and the cascades a
+                    // reverse-Z orthographic projection so closer is greater
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: SHADOW_FORMAT,
+                        depth_write_enabled: true,
+                        depth_compare: wgpu::CompareFunction::Greater,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState {
+                            constant: 0,
+                            slope_scale: -2.0,
+                            clamp: 0.0,
+                        },
+                    }),
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            })
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Point Shadow Uniform Buffer"),
+            size: std::mem::size_of::<PointShadowUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cascade_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cascade Uniform Buffer"),
+            size: std::mem::size_of::<CascadeUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let align = renderer.limits().min_uniform_buffer_offset_alignment;
+        let size = std::mem::size_of::<FaceUniform>() as u32;
+        let face_stride = size.div_ceil(align) * align;
+        let face_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Point Shadow Face Buffer"),
+            size: (face_stride as usize * (MAX_SHADOW_LIGHTS * 6 + MAX_CASCADES)) as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let face_bind_group = renderer
+            .errors
+            .scope(device, "point shadow face bind group", || {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("point_shadow_face_bind_group"),
+                    layout: &face_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: &face_buffer,
+                            offset: 0,
+                            size: wgpu::BufferSize::new(size as u64),
+                        }),
+                    }],
+                })
+            });
+
+        // The shadow maps are always reverse-Z, whether the ReverseZ setting is enabled or not,
+        // so this doesn't use WgpuRenderer::depth_compare
+        let sampler = Texture::create_comparison_sampler(
+            device,
+            "shadow_sampler",
+            wgpu::CompareFunction::GreaterEqual,
+        );
+
+        let targets = create_targets(
+            renderer,
+            [&uniform_buffer, &cascade_buffer],
+            &sampler,
+            ShadowSizes {
+                point_size: 1,
+                light_count: 0,
+                cascade_size: 1,
+                cascade_count: 0,
+            },
+        );
+        Self {
+            pipelines,
+            uniform_buffer,
+            cascade_buffer,
+            face_buffer,
+            face_stride,
+            face_bind_group,
+            sampler,
+            targets,
+        }
+    }
+
+    /// The bind group sampled by the main pass
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.targets.bind_group
+    }
+
+    /// The 6 faces of every light in the order of the light buffer, they are all `size` x `size`
+    pub fn faces(&self) -> (&[wgpu::TextureView], u32) {
+        (&self.targets.faces, self.targets.sizes.point_size)
+    }
+
+    /// The cascades of the directional light from the closest to the farthest,
+    /// they are all `size` x `size`
+    pub fn cascades(&self) -> (&[wgpu::TextureView], u32) {
+        (&self.targets.cascades, self.targets.sizes.cascade_size)
+    }
+}
+
+/// Creates a depth texture array with a view per layer used as depth attachments and a view of
+/// the whole array. It has at least one layer so the main pass always has something to bind.
+fn create_layers(
+    device: &wgpu::Device,
+    label: &str,
+    size: u32,
+    layers: usize,
+) -> (Vec<wgpu::TextureView>, wgpu::TextureView) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size.max(1),
+            height: size.max(1),
+            depth_or_array_layers: layers.max(1) as u32,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: SHADOW_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let layer_views = (0..layers as u32)
+        .map(|layer| {
+            texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some(label),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..default()
+            })
+        })
+        .collect();
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..default()
+    });
+    (layer_views, view)
+}
+
+/// Creates a texture array with 6 layers per light and one with a layer per cascade
+fn create_targets(
+    renderer: &WgpuRenderer,
+    [uniform_buffer, cascade_buffer]: [&wgpu::Buffer; 2],
+    sampler: &wgpu::Sampler,
+    sizes: ShadowSizes,
+) -> ShadowTargets {
+    let device = &renderer.device;
+    let (faces, view) = create_layers(
+        device,
+        "point_shadow_texture",
+        sizes.point_size,
+        sizes.light_count * 6,
+    );
+    let (cascades, cascade_view) = create_layers(
+        device,
+        "cascade_shadow_texture",
+        sizes.cascade_size,
+        sizes.cascade_count,
+    );
+
+    let bind_group = renderer.errors.scope(device, "shadow bind group", || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_bind_group"),
+            layout: &bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: cascade_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&cascade_view),
+                },
+            ],
+        })
+    });
+
+    ShadowTargets {
+        sizes,
+        faces,
+        cascades,
+        bind_group,
+    }
+}
+
+/// The distance from the camera where each cascade ends, blending between evenly spaced splits
+/// and logarithmic splits with `lambda`. Only the first `count` distances are used.
+fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> [f32; MAX_CASCADES] {
+    let mut splits = [far; MAX_CASCADES];
+    for (i, split) in splits.iter_mut().enumerate().take(count) {
+        let p = (i + 1) as f32 / count as f32;
+        let logarithmic = near * (far / near).powf(p);
+        let uniform = near + (far - near) * p;
+        *split = uniform + (logarithmic - uniform) * lambda.clamp(0.0, 1.0);
+    }
+    splits
+}
+
+/// The world space corners of the part of the view frustum between `near` and `far`
+/// from the camera. `corners` are the 4 corners of the near plane of the camera in view space.
+fn frustum_slice(inverse_view: Mat4, corners: [Vec3; 4], near: f32, far: f32) -> [Vec3; 8] {
+    let mut slice = [Vec3::ZERO; 8];
+    for (i, corner) in corners.into_iter().enumerate() {
+        // The corners are on the plane at -z so scaling them moves them along the edges of the frustum
+        let direction = corner / -corner.z;
+        slice[i] = inverse_view.transform_point3(direction * near);
+        slice[i + 4] = inverse_view.transform_point3(direction * far);
+    }
+    slice
+}
+
+/// A reverse-Z orthographic view projection of the light covering the bounding sphere of `corners`.
+/// The depth range extends `caster_distance` toward the light so the meshes between the light
+/// and the slice still cast shadows. The center is snapped to the texels of the shadow map
+/// so the shadows don't shimmer when the camera moves.
+fn cascade_view_proj(
+    corners: &[Vec3; 8],
+    direction: Vec3,
+    size: u32,
+    caster_distance: f32,
+) -> Mat4 {
+    let center = corners.iter().copied().sum::<Vec3>() / corners.len() as f32;
+    let radius = corners
+        .iter()
+        .map(|corner| corner.distance(center))
+        .fold(0.0, f32::max)
+        .max(f32::EPSILON);
+
+    let direction = direction.normalize();
+    let up = if direction.abs().abs_diff_eq(Vec3::Y, 1e-3) {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    };
+    let rotation = Mat4::look_to_rh(Vec3::ZERO, direction, up);
+    let texel = 2.0 * radius / size.max(1) as f32;
+    let mut light_center = rotation.transform_point3(center);
+    light_center.x = (light_center.x / texel).floor() * texel;
+    light_center.y = (light_center.y / texel).floor() * texel;
+    let center = rotation.inverse().transform_point3(light_center);
+
+    let view = Mat4::look_to_rh(center, direction, up);
+    // The near and far planes are swapped for reverse-Z, the light looks along -z
+    let projection = Mat4::orthographic_rh(
+        -radius,
+        radius,
+        -radius,
+        radius,
+        radius,
+        -(radius + caster_distance),
+    );
+    projection * view
+}
+
+pub fn setup(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+) {
+    commands.insert_resource(ShadowPass::new(&renderer, &mesh_view_layout));
+}
+
+/// Recreates the shadow maps when the number of shadow casting lights or their size changes
+/// and uploads the matrices of every face and cascade
+pub fn prepare(
+    mut pass: ResMut<ShadowPass>,
+    renderer: Res<WgpuRenderer>,
+    (settings, cascade_settings): (Res<PointShadowSettings>, Res<CascadeSettings>),
+    light_buffer: Res<LightBuffer>,
+    lights: Query<&Light>,
+    directional_lights: Query<&DirectionalLight>,
+    camera: Res<Camera>,
+) {
+    let directional_light = light_buffer
+        .directional_shadow_caster
+        .and_then(|entity| directional_lights.get(entity).ok());
+    let sizes = ShadowSizes {
+        point_size: settings.size.max(1),
+        light_count: light_buffer.shadow_casters.len(),
+        cascade_size: cascade_settings.size.max(1),
+        cascade_count: if directional_light.is_some() {
+            cascade_settings.cascade_count()
+        } else {
+            0
+        },
+    };
+    if pass.targets.sizes != sizes {
+        log::info!(
+            "Creating shadow maps of {0}x{0} for {1} point lights and {2} cascades of {3}x{3}",
+            sizes.point_size,
+            sizes.light_count,
+            sizes.cascade_count,
+            sizes.cascade_size
+        );
+        pass.targets = create_targets(
+            &renderer,
+            [&pass.uniform_buffer, &pass.cascade_buffer],
+            &pass.sampler,
+            sizes,
+        );
+    }
+
+    let projection =
+        Mat4::perspective_infinite_reverse_rh(std::f32::consts::FRAC_PI_2, 1.0, settings.near);
+    let mut uniform = PointShadowUniform {
+        view_proj: [Mat4::IDENTITY.to_cols_array_2d(); MAX_SHADOW_LIGHTS * 6],
+        normal_bias: settings.normal_bias,
+        _padding: [0.0; 3],
+    };
+    let write_face = |layer: usize, view_proj: Mat4| {
+        renderer.queue.write_buffer(
+            &pass.face_buffer,
+            (layer * pass.face_stride as usize) as u64,
+            bytemuck::cast_slice(&[FaceUniform {
+                view_proj: view_proj.to_cols_array_2d(),
+            }]),
+        );
+    };
+    for (i, entity) in light_buffer.shadow_casters.iter().enumerate() {
+        let position = lights
+            .get(*entity)
+            .map(|light| light.position)
+            .unwrap_or(Vec3::ZERO);
+        for (face, (direction, up)) in FACES.into_iter().enumerate() {
+            let layer = i * 6 + face;
+            let view_proj = projection * Mat4::look_to_rh(position, direction, up);
+            uniform.view_proj[layer] = view_proj.to_cols_array_2d();
+            write_face(layer, view_proj);
+        }
+    }
+    renderer
+        .queue
+        .write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+    let mut cascade_uniform = CascadeUniform {
+        view_proj: [Mat4::IDENTITY.to_cols_array_2d(); MAX_CASCADES],
+        splits: [0.0; MAX_CASCADES],
+        count: sizes.cascade_count as u32,
+        blend: cascade_settings.blend.clamp(0.0, 1.0),
+        normal_bias: cascade_settings.normal_bias,
+        _padding: 0.0,
+    };
+    if let Some(light) = directional_light {
+        let near = camera.projection.z_near;
+        let far = cascade_settings
+            .max_distance
+            .min(camera.projection.z_far)
+            .max(near);
+        cascade_uniform.splits = cascade_splits(
+            near,
+            far,
+            sizes.cascade_count,
+            cascade_settings.split_lambda,
+        );
+
+        // The near plane of the camera in view space, it's at 0 with reverse-Z
+        let inverse_projection = camera.projection.compute_matrix().inverse();
+        let near_depth = if camera.projection.reverse_z {
+            1.0
+        } else {
+            0.0
+        };
+        let corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .map(|(x, y)| inverse_projection.project_point3(Vec3::new(x, y, near_depth)));
+        let inverse_view = camera.build_view_matrix().inverse();
+
+        let mut start = near;
+        for cascade in 0..sizes.cascade_count {
+            let end = cascade_uniform.splits[cascade];
+            let slice = frustum_slice(inverse_view, corners, start, end);
+            let view_proj = cascade_view_proj(&slice, light.direction, sizes.cascade_size, far);
+            cascade_uniform.view_proj[cascade] = view_proj.to_cols_array_2d();
+            write_face(MAX_SHADOW_LIGHTS * 6 + cascade, view_proj);
+            start = end;
+        }
+    }
+    renderer.queue.write_buffer(
+        &pass.cascade_buffer,
+        0,
+        bytemuck::cast_slice(&[cascade_uniform]),
+    );
+}
+
+/// Renders every face of the shadow maps and every cascade, this needs to run before the main pass
+/// samples them. Meshes outside of the view of the camera still cast shadows so only the visibility
+/// is checked.
+pub fn render(
+    pass: Res<ShadowPass>,
+    mut encoder: ResMut<WgpuEncoder>,
+    mesh_view_bind_group: Res<MeshViewBindGroup>,
+    model_query: Query<
+        (&Model, &InstanceBuffer, Option<&Instances>, VisibilityQuery),
+        (
+            Without<Light>,
+            Without<Transparent>,
+            Without<CompactInstances>,
+        ),
+    >,
+    camera: Res<Camera>,
+) {
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+
+    // The cascades use the face uniforms after the ones of the point lights
+    let faces = pass
+        .targets
+        .faces
+        .iter()
+        .enumerate()
+        .map(|(layer, face)| ("Point Shadow Pass", layer, face));
+    let cascades = pass
+        .targets
+        .cascades
+        .iter()
+        .enumerate()
+        .map(|(cascade, view)| ("Cascade Shadow Pass", MAX_SHADOW_LIGHTS * 6 + cascade, view));
+    for (label, slot, view) in faces.chain(cascades) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_bind_group(0, &mesh_view_bind_group.0, &[]);
+        render_pass.set_bind_group(1, &pass.face_bind_group, &[slot as u32 * pass.face_stride]);
+        for (model, instance_buffer, instances, visibility) in &model_query {
+            if !camera.is_visible(visibility) {
+                continue;
+            }
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            for mesh in &model.meshes {
+                if model.materials[mesh.material_id.unwrap_or(0)].blend_mode != BlendMode::Opaque
+                    || mesh.topology != wgpu::PrimitiveTopology::TriangleList
+                {
+                    continue;
+                }
+                let (pipeline, vertex_buffer) = pass.pipelines.select(mesh);
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+                render_pass.draw_indexed(
+                    0..mesh.num_elements,
+                    0,
+                    0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cascade_splits_blend() {
+        let uniform = cascade_splits(1.0, 100.0, 4, 0.0);
+        assert_eq!(&uniform, &[25.75, 50.5, 75.25, 100.0]);
+
+        let logarithmic = cascade_splits(1.0, 100.0, 2, 1.0);
+        assert!((logarithmic[0] - 10.0).abs() < 1e-4);
+        assert!((logarithmic[1] - 100.0).abs() < 1e-4);
+        // The unused cascades end at the far distance
+        assert_eq!(&logarithmic[2..], &[100.0, 100.0]);
+
+        let practical = cascade_splits(1.0, 100.0, 4, 0.5);
+        for i in 0..4 {
+            let expected = (uniform[i] + cascade_splits(1.0, 100.0, 4, 1.0)[i]) / 2.0;
+            assert!((practical[i] - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn frustum_slice_distances() {
+        let corners = [
+            Vec3::new(-1.0, -1.0, -1.0),
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(1.0, 1.0, -1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+        ];
+        let inverse_view = Mat4::from_translation(Vec3::new(0.0, 0.0, 5.0));
+        let slice = frustum_slice(inverse_view, corners, 2.0, 10.0);
+        assert_eq!(slice[0], Vec3::new(-2.0, -2.0, 3.0));
+        assert_eq!(slice[6], Vec3::new(10.0, 10.0, -5.0));
+    }
+
+    #[test]
+    fn cascade_covers_slice() {
+        let corners = [
+            Vec3::new(-1.0, -1.0, -1.0),
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(1.0, 1.0, -1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+        ];
+        let slice = frustum_slice(Mat4::IDENTITY, corners, 1.0, 20.0);
+        let direction = Vec3::new(1.0, -2.0, 0.5);
+        let view_proj = cascade_view_proj(&slice, direction, 1024, 50.0);
+        for corner in slice {
+            let ndc = view_proj.project_point3(corner);
+            // The snapping moves the center by less than a texel
+            assert!(ndc.x.abs() <= 1.01 && ndc.y.abs() <= 1.01, "{ndc}");
+            assert!((0.0..=1.0).contains(&ndc.z), "{ndc}");
+        }
+        // Reverse-Z, closer to the light is greater
+        let center = slice.iter().copied().sum::<Vec3>() / 8.0;
+        let toward_light = center - direction.normalize() * 10.0;
+        assert!(view_proj.project_point3(toward_light).z > view_proj.project_point3(center).z);
+    }
+
+    #[test]
+    fn cascade_straight_down() {
+        // The up vector can't be parallel to the direction
+        let slice = [
+            Vec3::ONE,
+            -Vec3::ONE,
+            Vec3::X,
+            Vec3::Y,
+            Vec3::Z,
+            -Vec3::X,
+            -Vec3::Y,
+            -Vec3::Z,
+        ];
+        let view_proj = cascade_view_proj(&slice, Vec3::NEG_Y, 512, 10.0);
+        assert!(view_proj.is_finite());
+    }
+}
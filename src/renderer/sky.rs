@@ -0,0 +1,197 @@
+use bevy::{ecs::prelude::*, render::color::Color, utils::default};
+
+use super::{Fog, Msaa, WgpuEncoder, WgpuRenderer, WgpuView};
+use crate::{camera::Camera, egui_plugin::viewport::EguiViewport};
+
+/// Fills the background with a vertical gradient instead of the [`GlaceClearColor`](super::GlaceClearColor).
+/// Insert it to enable it, the background is still covered by the [`Fog`] when it's enabled.
+#[derive(Resource, Debug, Clone)]
+pub struct GradientSky {
+    /// The color when looking straight up
+    pub top: Color,
+    /// The color at the horizon and below it
+    pub bottom: Color,
+}
+
+impl Default for GradientSky {
+    fn default() -> Self {
+        Self {
+            top: Color::rgb(0.25, 0.45, 0.85),
+            bottom: Color::rgb(0.75, 0.8, 0.85),
+        }
+    }
+}
+
+impl GradientSky {
+    /// Whether the sky is drawn instead of clearing the background
+    pub fn is_visible(sky: Option<&GradientSky>, fog: &Fog) -> bool {
+        sky.is_some() && !fog.is_enabled()
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyUniform {
+    inverse_view_proj: [[f32; 4]; 4],
+    top: [f32; 4],
+    bottom: [f32; 4],
+}
+
+/// Draws the [`GradientSky`] with a fullscreen triangle before the base 3d pass
+#[derive(Resource)]
+pub struct SkyPass {
+    pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// The sky is drawn directly to the msaa target
+    sample_count: u32,
+}
+
+impl SkyPass {
+    fn new(renderer: &WgpuRenderer, sample_count: u32) -> Self {
+        let device = &renderer.device;
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("sky_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+
+        let pipeline = renderer.errors.scope(device, "Sky Pipeline", || {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Sky Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/sky.wgsl").into()),
+            });
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Sky Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Sky Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(renderer.config.format.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    ..default()
+                },
+                multiview: None,
+            })
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sky Uniform Buffer"),
+            size: std::mem::size_of::<SkyUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = renderer.errors.scope(device, "sky bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("sky_bind_group"),
+                layout: &layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                }],
+            })
+        });
+
+        Self {
+            pipeline,
+            uniform_buffer,
+            bind_group,
+            sample_count,
+        }
+    }
+}
+
+pub fn setup(mut commands: Commands, renderer: Res<WgpuRenderer>, msaa: Res<Msaa>) {
+    commands.insert_resource(SkyPass::new(&renderer, msaa.sample_count()));
+}
+
+/// Recreates the pipeline when the msaa changes and uploads the colors and the camera
+pub fn prepare(
+    mut pass: ResMut<SkyPass>,
+    renderer: Res<WgpuRenderer>,
+    sky: Option<Res<GradientSky>>,
+    camera: Res<Camera>,
+    msaa: Res<Msaa>,
+) {
+    if pass.sample_count != msaa.sample_count() {
+        log::info!("updating sky pass");
+        *pass = SkyPass::new(&renderer, msaa.sample_count());
+    }
+
+    let sky = if let Some(sky) = sky {
+        sky
+    } else {
+        return;
+    };
+
+    let uniform = SkyUniform {
+        inverse_view_proj: camera
+            .build_view_projection_matrix()
+            .inverse()
+            .to_cols_array_2d(),
+        top: sky.top.as_linear_rgba_f32(),
+        bottom: sky.bottom.as_linear_rgba_f32(),
+    };
+    renderer
+        .queue
+        .write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+}
+
+pub fn render(
+    pass: Res<SkyPass>,
+    mut encoder: ResMut<WgpuEncoder>,
+    view: Res<WgpuView>,
+    sky: Option<Res<GradientSky>>,
+    fog: Res<Fog>,
+    viewport: Option<Res<EguiViewport>>,
+) {
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+    if !GradientSky::is_visible(sky.as_deref(), &fog) {
+        return;
+    }
+
+    let view = match viewport.as_ref().and_then(|v| v.target()) {
+        Some(target) => &target.view,
+        None => &*view,
+    };
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Sky Pass"),
+        // Every pixel is overwritten
+        color_attachments: &[Some(view.get_color_attachment(wgpu::Operations {
+            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+            store: true,
+        }))],
+        depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(&pass.pipeline);
+    render_pass.set_bind_group(0, &pass.bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+}
@@ -0,0 +1,376 @@
+use bevy::{ecs::prelude::*, utils::default};
+
+use super::{AntiAliasing, Msaa, WgpuEncoder, WgpuRenderer, WgpuSurfaceTexture, WgpuView};
+use crate::egui_plugin::viewport::EguiViewport;
+
+const EDGES_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg8Unorm;
+const WEIGHTS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+fn create_target(
+    device: &wgpu::Device,
+    label: &str,
+    size: [u32; 2],
+    format: wgpu::TextureFormat,
+    usage: wgpu::TextureUsages,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size[0].max(1),
+            height: size[1].max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | usage,
+        view_formats: &[],
+    })
+}
+
+/// The textures are recreated when the size of the render target changes
+struct SmaaTargets {
+    size: [u32; 2],
+    /// A copy of the frame, the blended result is written back to the frame
+    color: wgpu::Texture,
+    edges: wgpu::TextureView,
+    weights: wgpu::TextureView,
+    edges_bind_group: wgpu::BindGroup,
+    weights_bind_group: wgpu::BindGroup,
+    blend_bind_group: wgpu::BindGroup,
+}
+
+/// Subpixel morphological anti aliasing, enabled with [`AntiAliasing::Smaa`].
+///
+/// It finds the edges of the frame, computes how much each side of an edge should blend based on its shape
+/// and blends the pixels with their neighbours. It runs after the 3d passes so egui isn't affected.
+#[derive(Resource)]
+pub struct SmaaPass {
+    edges_pipeline: wgpu::RenderPipeline,
+    weights_pipeline: wgpu::RenderPipeline,
+    blend_pipeline: wgpu::RenderPipeline,
+    edges_layout: wgpu::BindGroupLayout,
+    weights_layout: wgpu::BindGroupLayout,
+    blend_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    targets: Option<SmaaTargets>,
+}
+
+impl SmaaPass {
+    fn new(renderer: &WgpuRenderer) -> Self {
+        let device = &renderer.device;
+
+        let texture = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let edges_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("smaa_edges_bind_group_layout"),
+            entries: &[texture(0)],
+        });
+        let weights_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("smaa_weights_bind_group_layout"),
+            entries: &[texture(2)],
+        });
+        let blend_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("smaa_blend_bind_group_layout"),
+            entries: &[
+                texture(0),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                texture(3),
+            ],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("SMAA Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/smaa.wgsl").into()),
+        });
+        // Every pass uses the same shader with a different fragment entry point
+        let fullscreen_pipeline = |label: &str,
+                                   entry_point: &str,
+                                   layout: &wgpu::BindGroupLayout,
+                                   format: wgpu::TextureFormat| {
+            renderer.errors.scope(device, label, || {
+                let pipeline_layout =
+                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some(&format!("{label} Layout")),
+                        bind_group_layouts: &[layout],
+                        push_constant_ranges: &[],
+                    });
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point,
+                        targets: &[Some(format.into())],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            })
+        };
+        let edges_pipeline = fullscreen_pipeline(
+            "SMAA Edge Detection Pipeline",
+            "edge_detection",
+            &edges_layout,
+            EDGES_FORMAT,
+        );
+        let weights_pipeline = fullscreen_pipeline(
+            "SMAA Blending Weights Pipeline",
+            "blending_weights",
+            &weights_layout,
+            WEIGHTS_FORMAT,
+        );
+        let blend_pipeline = fullscreen_pipeline(
+            "SMAA Neighborhood Blending Pipeline",
+            "neighborhood_blending",
+            &blend_layout,
+            renderer.config.format,
+        );
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("smaa_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..default()
+        });
+
+        Self {
+            edges_pipeline,
+            weights_pipeline,
+            blend_pipeline,
+            edges_layout,
+            weights_layout,
+            blend_layout,
+            sampler,
+            targets: None,
+        }
+    }
+
+    fn create_targets(&self, renderer: &WgpuRenderer, size: [u32; 2]) -> SmaaTargets {
+        let device = &renderer.device;
+        // Same format as the frame so it can be copied
+        let color = create_target(
+            device,
+            "smaa_color_texture",
+            size,
+            renderer.config.format,
+            wgpu::TextureUsages::COPY_DST,
+        );
+        let edges = create_target(
+            device,
+            "smaa_edges_texture",
+            size,
+            EDGES_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT,
+        )
+        .create_view(&wgpu::TextureViewDescriptor::default());
+        let weights = create_target(
+            device,
+            "smaa_weights_texture",
+            size,
+            WEIGHTS_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT,
+        )
+        .create_view(&wgpu::TextureViewDescriptor::default());
+        let color_view = color.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let edges_bind_group = renderer.errors.scope(device, "smaa edges bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("smaa_edges_bind_group"),
+                layout: &self.edges_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&color_view),
+                }],
+            })
+        });
+        let weights_bind_group = renderer
+            .errors
+            .scope(device, "smaa weights bind group", || {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("smaa_weights_bind_group"),
+                    layout: &self.weights_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&edges),
+                    }],
+                })
+            });
+        let blend_bind_group = renderer.errors.scope(device, "smaa blend bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("smaa_blend_bind_group"),
+                layout: &self.blend_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&color_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&weights),
+                    },
+                ],
+            })
+        });
+
+        SmaaTargets {
+            size,
+            color,
+            edges,
+            weights,
+            edges_bind_group,
+            weights_bind_group,
+            blend_bind_group,
+        }
+    }
+}
+
+pub fn setup(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    commands.insert_resource(SmaaPass::new(&renderer));
+}
+
+/// Recreates the targets when the size of the render target changes
+pub fn prepare(
+    mut pass: ResMut<SmaaPass>,
+    renderer: Res<WgpuRenderer>,
+    anti_aliasing: Res<AntiAliasing>,
+    viewport: Option<Res<EguiViewport>>,
+) {
+    if *anti_aliasing != AntiAliasing::Smaa {
+        pass.targets = None;
+        return;
+    }
+
+    let target = viewport.as_ref().and_then(|viewport| viewport.target());
+    // The window is copied to the targets, the viewport texture can always be copied
+    if target.is_none()
+        && !renderer
+            .config
+            .usage
+            .contains(wgpu::TextureUsages::COPY_SRC)
+    {
+        if anti_aliasing.is_changed() {
+            log::warn!("The surface can't be copied, SMAA is disabled");
+        }
+        pass.targets = None;
+        return;
+    }
+
+    let size = target
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    if pass.targets.as_ref().map(|targets| targets.size) != Some(size) {
+        log::info!("Resizing smaa targets to {}x{}", size[0], size[1]);
+        pass.targets = Some(pass.create_targets(&renderer, size));
+    }
+}
+
+/// Copies the frame and writes the anti aliased result back to it.
+/// This needs to run after every 3d pass and before egui.
+pub fn render(
+    pass: Res<SmaaPass>,
+    mut encoder: ResMut<WgpuEncoder>,
+    view: Res<WgpuView>,
+    output: Res<WgpuSurfaceTexture>,
+    viewport: Option<Res<EguiViewport>>,
+    msaa: Res<Msaa>,
+) {
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+    let targets = if let Some(targets) = pass.targets.as_ref() {
+        targets
+    } else {
+        return;
+    };
+    // The edges were already smoothed by msaa, the two aren't meant to be combined
+    if msaa.sample_count() > 1 {
+        return;
+    }
+
+    let (texture, view) = match viewport.as_ref().and_then(|v| v.target()) {
+        Some(target) => (&target.texture, &target.view.view),
+        None => match output.0.as_ref() {
+            Some(output) => (&output.texture, &view.view),
+            None => return,
+        },
+    };
+    // The window can be resized after the targets were prepared for this frame
+    if [texture.width(), texture.height()] != targets.size {
+        return;
+    }
+
+    encoder.copy_texture_to_texture(
+        texture.as_image_copy(),
+        targets.color.as_image_copy(),
+        texture.size(),
+    );
+
+    let passes = [
+        (
+            "SMAA Edge Detection Pass",
+            &pass.edges_pipeline,
+            &targets.edges_bind_group,
+            &targets.edges,
+        ),
+        (
+            "SMAA Blending Weights Pass",
+            &pass.weights_pipeline,
+            &targets.weights_bind_group,
+            &targets.weights,
+        ),
+        (
+            "SMAA Neighborhood Blending Pass",
+            &pass.blend_pipeline,
+            &targets.blend_bind_group,
+            view,
+        ),
+    ];
+    for (label, pipeline, bind_group, view) in passes {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            // Every pixel is overwritten
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,544 @@
+use bevy::{ecs::prelude::*, utils::default};
+
+use super::{
+    base_3d::Transparent,
+    bind_groups::mesh_view::{MeshViewBindGroup, MeshViewBindGroupLayout},
+    WgpuEncoder, WgpuRenderer,
+};
+use crate::{
+    camera::{Camera, VisibilityQuery},
+    egui_plugin::viewport::EguiViewport,
+    instances::{CompactInstances, InstanceBuffer, Instances},
+    light::Light,
+    mesh,
+    model::{BlendMode, Model},
+    texture::Texture,
+    transform::TransformRaw,
+};
+
+const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const AO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+/// The shader loop isn't unrolled but too many samples will still tank the framerate
+const MAX_SAMPLE_COUNT: u32 = 64;
+
+/// Screen space ambient occlusion, it darkens the ambient light in creases and where objects meet.
+///
+/// It uses its own depth and normal prepass so it works with any msaa sample count.
+/// The occlusion is computed at half resolution and blurred before being sampled by the main pass.
+#[derive(Resource, Debug, Clone)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// Radius of the sampled hemisphere in world units
+    pub radius: f32,
+    /// Multiplies the occlusion, 1.0 means fully occluded pixels have no ambient light
+    pub intensity: f32,
+    /// Number of samples per pixel, clamped to 64
+    pub sample_count: u32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            radius: 0.5,
+            intensity: 1.0,
+            sample_count: 16,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniform {
+    view_proj: [[f32; 4]; 4],
+    inverse_view_proj: [[f32; 4]; 4],
+    eye: [f32; 4],
+    radius: f32,
+    intensity: f32,
+    sample_count: u32,
+    far_depth: f32,
+}
+
+/// The layout of the bind group used by the main pass to sample the occlusion
+pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("ssao_output_bind_group_layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+    })
+}
+
+fn create_output_bind_group(
+    renderer: &WgpuRenderer,
+    view: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    renderer
+        .errors
+        .scope(&renderer.device, "ssao output bind group", || {
+            renderer
+                .device
+                .create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("ssao_output_bind_group"),
+                    layout: &bind_group_layout(&renderer.device),
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(sampler),
+                        },
+                    ],
+                })
+        })
+}
+
+fn create_target(
+    device: &wgpu::Device,
+    label: &str,
+    size: [u32; 2],
+    format: wgpu::TextureFormat,
+) -> wgpu::TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size[0].max(1),
+                height: size[1].max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// The textures are recreated when the size of the render target changes
+struct SsaoTargets {
+    size: [u32; 2],
+    depth: wgpu::TextureView,
+    normal: wgpu::TextureView,
+    ao: wgpu::TextureView,
+    blurred: wgpu::TextureView,
+    ssao_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    output_bind_group: wgpu::BindGroup,
+}
+
+#[derive(Resource)]
+pub struct SsaoPass {
+    prepass_pipeline: wgpu::RenderPipeline,
+    ssao_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    ssao_layout: wgpu::BindGroupLayout,
+    blur_layout: wgpu::BindGroupLayout,
+    uniform_buffer: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    /// Bound instead of the occlusion when ssao is disabled
+    white_bind_group: wgpu::BindGroup,
+    targets: Option<SsaoTargets>,
+}
+
+impl SsaoPass {
+    fn new(renderer: &WgpuRenderer, mesh_view_layout: &MeshViewBindGroupLayout) -> Self {
+        let device = &renderer.device;
+
+        let prepass_pipeline = renderer.errors.scope(device, "SSAO Prepass Pipeline", || {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("SSAO Prepass Shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("shaders/common.wgsl"),
+                        include_str!("shaders/ssao_prepass.wgsl")
+                    )
+                    .into(),
+                ),
+            });
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("SSAO Prepass Pipeline Layout"),
+                bind_group_layouts: &[&mesh_view_layout.0],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("SSAO Prepass Pipeline"),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vertex",
+                    buffers: &[mesh::Vertex::layout(), TransformRaw::layout()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(NORMAL_FORMAT.into())],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    ..default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: renderer.depth_compare(wgpu::CompareFunction::Less),
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        });
+
+        let unfilterable_texture = |binding, sample_type| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type,
+            },
+            count: None,
+        };
+        let ssao_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                unfilterable_texture(1, wgpu::TextureSampleType::Depth),
+                unfilterable_texture(2, wgpu::TextureSampleType::Float { filterable: false }),
+            ],
+        });
+        let blur_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_blur_bind_group_layout"),
+            entries: &[unfilterable_texture(
+                0,
+                wgpu::TextureSampleType::Float { filterable: false },
+            )],
+        });
+
+        let fullscreen_pipeline = |label: &str, shader: &str, layout: &wgpu::BindGroupLayout| {
+            renderer.errors.scope(device, label, || {
+                let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label: Some(&format!("{label} Shader")),
+                    source: wgpu::ShaderSource::Wgsl(shader.into()),
+                });
+                let pipeline_layout =
+                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some(&format!("{label} Layout")),
+                        bind_group_layouts: &[layout],
+                        push_constant_ranges: &[],
+                    });
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vertex",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fragment",
+                        targets: &[Some(AO_FORMAT.into())],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            })
+        };
+        let ssao_pipeline = fullscreen_pipeline(
+            "SSAO Pipeline",
+            include_str!("shaders/ssao.wgsl"),
+            &ssao_layout,
+        );
+        let blur_pipeline = fullscreen_pipeline(
+            "SSAO Blur Pipeline",
+            include_str!("shaders/ssao_blur.wgsl"),
+            &blur_layout,
+        );
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO Uniform Buffer"),
+            size: std::mem::size_of::<SsaoUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("ssao_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..default()
+        });
+
+        let white = Texture::default_white(device, &renderer.queue)
+            .expect("Failed to create white texture");
+        let white_bind_group = create_output_bind_group(renderer, &white.view, &sampler);
+
+        Self {
+            prepass_pipeline,
+            ssao_pipeline,
+            blur_pipeline,
+            ssao_layout,
+            blur_layout,
+            uniform_buffer,
+            sampler,
+            white_bind_group,
+            targets: None,
+        }
+    }
+
+    fn create_targets(&self, renderer: &WgpuRenderer, size: [u32; 2]) -> SsaoTargets {
+        let device = &renderer.device;
+        let half_size = [size[0] / 2, size[1] / 2];
+        let depth = create_target(device, "ssao_depth_texture", size, Texture::DEPTH_FORMAT);
+        let normal = create_target(device, "ssao_normal_texture", size, NORMAL_FORMAT);
+        let ao = create_target(device, "ssao_texture", half_size, AO_FORMAT);
+        let blurred = create_target(device, "ssao_blurred_texture", half_size, AO_FORMAT);
+
+        let ssao_bind_group = renderer.errors.scope(device, "ssao bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("ssao_bind_group"),
+                layout: &self.ssao_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniform_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&depth),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&normal),
+                    },
+                ],
+            })
+        });
+        let blur_bind_group = renderer.errors.scope(device, "ssao blur bind group", || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("ssao_blur_bind_group"),
+                layout: &self.blur_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&ao),
+                }],
+            })
+        });
+        let output_bind_group = create_output_bind_group(renderer, &blurred, &self.sampler);
+
+        SsaoTargets {
+            size,
+            depth,
+            normal,
+            ao,
+            blurred,
+            ssao_bind_group,
+            blur_bind_group,
+            output_bind_group,
+        }
+    }
+
+    /// The bind group sampled by the main pass
+    pub fn output_bind_group(&self) -> &wgpu::BindGroup {
+        self.targets
+            .as_ref()
+            .map(|targets| &targets.output_bind_group)
+            .unwrap_or(&self.white_bind_group)
+    }
+
+    /// The blurred occlusion and its size, it's half the size of the render target.
+    /// None when ssao is disabled
+    pub fn occlusion(&self) -> Option<(&wgpu::TextureView, [u32; 2])> {
+        self.targets
+            .as_ref()
+            .map(|targets| (&targets.blurred, [targets.size[0] / 2, targets.size[1] / 2]))
+    }
+}
+
+pub fn setup(
+    mut commands: Commands,
+    renderer: Res<WgpuRenderer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+) {
+    commands.insert_resource(SsaoPass::new(&renderer, &mesh_view_layout));
+}
+
+/// Recreates the targets when the size of the render target changes and uploads the settings
+pub fn prepare(
+    mut pass: ResMut<SsaoPass>,
+    renderer: Res<WgpuRenderer>,
+    settings: Res<SsaoSettings>,
+    camera: Res<Camera>,
+    viewport: Option<Res<EguiViewport>>,
+) {
+    if !settings.enabled {
+        pass.targets = None;
+        return;
+    }
+
+    let size = viewport
+        .as_ref()
+        .and_then(|viewport| viewport.target())
+        .map(|target| target.size)
+        .unwrap_or([renderer.config.width, renderer.config.height]);
+    if pass.targets.as_ref().map(|targets| targets.size) != Some(size) {
+        log::info!("Resizing ssao targets to {}x{}", size[0], size[1]);
+        pass.targets = Some(pass.create_targets(&renderer, size));
+    }
+
+    let view_proj = camera.build_view_projection_matrix();
+    let uniform = SsaoUniform {
+        view_proj: view_proj.to_cols_array_2d(),
+        inverse_view_proj: view_proj.inverse().to_cols_array_2d(),
+        eye: camera.eye.extend(1.0).to_array(),
+        radius: settings.radius,
+        intensity: settings.intensity,
+        sample_count: settings.sample_count.clamp(1, MAX_SAMPLE_COUNT),
+        far_depth: renderer.far_depth(),
+    };
+    renderer
+        .queue
+        .write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+}
+
+/// Renders the prepass, computes the occlusion and blurs it.
+/// This needs to run before the main pass samples it.
+pub fn render(
+    pass: Res<SsaoPass>,
+    mut encoder: ResMut<WgpuEncoder>,
+    mesh_view_bind_group: Res<MeshViewBindGroup>,
+    model_query: Query<
+        (&Model, &InstanceBuffer, Option<&Instances>, VisibilityQuery),
+        (
+            Without<Light>,
+            Without<Transparent>,
+            Without<CompactInstances>,
+        ),
+    >,
+    camera: Res<Camera>,
+    renderer: Res<WgpuRenderer>,
+) {
+    let encoder = if let Some(encoder) = encoder.0.as_mut() {
+        encoder
+    } else {
+        return;
+    };
+    let targets = if let Some(targets) = pass.targets.as_ref() {
+        targets
+    } else {
+        return;
+    };
+
+    {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSAO Prepass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &targets.normal,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &targets.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(renderer.far_depth()),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_pipeline(&pass.prepass_pipeline);
+        render_pass.set_bind_group(0, &mesh_view_bind_group.0, &[]);
+        for (model, instance_buffer, instances, visibility) in &model_query {
+            if !camera.is_visible(visibility) {
+                continue;
+            }
+            render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
+            for mesh in &model.meshes {
+                if model.materials[mesh.material_id.unwrap_or(0)].blend_mode != BlendMode::Opaque
+                    || mesh.topology != wgpu::PrimitiveTopology::TriangleList
+                {
+                    continue;
+                }
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+                render_pass.draw_indexed(
+                    0..mesh.num_elements,
+                    0,
+                    0..instances.map(|i| i.0.len() as u32).unwrap_or(1),
+                );
+            }
+        }
+    }
+
+    for (label, pipeline, bind_group, target) in [
+        (
+            "SSAO Pass",
+            &pass.ssao_pipeline,
+            &targets.ssao_bind_group,
+            &targets.ao,
+        ),
+        (
+            "SSAO Blur Pass",
+            &pass.blur_pipeline,
+            &targets.blur_bind_group,
+            &targets.blurred,
+        ),
+    ] {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
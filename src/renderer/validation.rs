@@ -0,0 +1,72 @@
+use bevy::ecs::prelude::*;
+use futures_lite::future;
+use std::sync::{Arc, Mutex};
+
+use super::WgpuRenderer;
+
+/// Sent when wgpu reports a validation error or runs out of memory.
+/// It's also sent once when the renderer starts skipping frames because the surface is lost.
+#[derive(Event, Debug, Clone)]
+pub struct RendererErrorEvent {
+    /// What glace was creating when the error happened
+    pub label: String,
+    pub message: String,
+}
+
+/// Collects the wgpu errors until they are sent as [`RendererErrorEvent`].
+/// It's cheap to clone so it can be moved to the tasks that create gpu resources.
+#[derive(Clone, Default)]
+pub struct RendererErrors(Arc<Mutex<Vec<RendererErrorEvent>>>);
+
+impl RendererErrors {
+    fn report(&self, label: &str, error: wgpu::Error) {
+        log::error!("wgpu error in {label}: {error}");
+        self.0.lock().unwrap().push(RendererErrorEvent {
+            label: label.to_string(),
+            message: error.to_string(),
+        });
+    }
+
+    /// Reports the errors that aren't caught by a [`RendererErrors::scope`].
+    /// By default wgpu panics on those errors.
+    pub fn handle_uncaptured_errors(&self, device: &wgpu::Device) {
+        let errors = self.clone();
+        device.on_uncaptured_error(Box::new(move |error| {
+            errors.report("uncaptured error", error);
+        }));
+    }
+
+    /// Runs `f` inside a validation error scope so any error is reported with the given label.
+    /// The resource created by `f` is still returned but it's invalid and using it will report more errors.
+    ///
+    /// Scopes are per device, a resource created at the same time from another thread
+    /// can be reported with this label.
+    pub fn scope<T>(&self, device: &wgpu::Device, label: &str, f: impl FnOnce() -> T) -> T {
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let result = f();
+        if let Some(error) = future::block_on(device.pop_error_scope()) {
+            self.report(label, error);
+        }
+        result
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// The errors reported after the first `count` ones, they are still sent as events
+    pub(crate) fn since(&self, count: usize) -> Vec<RendererErrorEvent> {
+        self.0.lock().unwrap().iter().skip(count).cloned().collect()
+    }
+
+    fn drain(&self) -> Vec<RendererErrorEvent> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+pub fn send_renderer_errors(
+    renderer: Res<WgpuRenderer>,
+    mut events: EventWriter<RendererErrorEvent>,
+) {
+    events.send_batch(renderer.errors.drain());
+}
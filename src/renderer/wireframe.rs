@@ -3,17 +3,19 @@ use std::borrow::Cow;
 use bevy::{app::prelude::*, ecs::prelude::*, utils::prelude::*};
 
 use crate::{
-    instances::{InstanceBuffer, Instances},
+    camera::{Camera, VisibilityQuery},
+    egui_plugin::viewport::EguiViewport,
+    instances::{CompactInstances, InstanceBuffer, Instances},
     light::Light,
     mesh::Vertex,
     model::Model,
-    texture::Texture,
     transform::TransformRaw,
 };
 
 use super::{
+    base_3d,
     bind_groups::mesh_view::{MeshViewBindGroup, MeshViewBindGroupLayout},
-    DepthTexture, Msaa, WgpuEncoder, WgpuRenderer, WgpuView,
+    outline, DepthTexture, Msaa, WgpuEncoder, WgpuRenderer, WgpuView,
 };
 
 #[derive(Component)]
@@ -24,97 +26,176 @@ pub struct WireframePhase {
     pub render_pipeline: wgpu::RenderPipeline,
 }
 
+/// The faces that get a wireframe, the other ones are culled
+#[allow(unused)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FaceSelection {
+    #[default]
+    Front,
+    Back,
+    Both,
+}
+
+/// Controls the [`Wireframe`] pipeline, it's recreated when this changes.
+/// Drawing only the back faces without the depth test shows the hidden side of the mesh like an x-ray.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WireframeConfig {
+    pub cull: FaceSelection,
+    /// When false the wireframe is drawn on top of everything
+    pub depth_test: bool,
+}
+
+impl Default for WireframeConfig {
+    fn default() -> Self {
+        Self {
+            cull: FaceSelection::Front,
+            depth_test: true,
+        }
+    }
+}
+
 pub struct WireframePlugin;
 impl Plugin for WireframePlugin {
-    fn build(&self, _app: &mut App) {
-        // app.add_startup_system_to_stage(RendererStage::Init, setup)
-        //     .add_system_to_stage(
-        //         RendererStage::Render,
-        //         render
-        //             .label(RenderLabel::Wireframe)
-        //             .after(RenderLabel::Base3d),
-        //     );
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WireframeConfig>()
+            .add_systems(PostStartup, setup.after(base_3d::setup))
+            .add_systems(
+                Update,
+                (update_wireframe_phase, render)
+                    .chain()
+                    .after(base_3d::render)
+                    .before(outline::render),
+            );
+    }
+}
+
+impl WireframePhase {
+    fn new(
+        renderer: &WgpuRenderer,
+        mesh_view_layout: &MeshViewBindGroupLayout,
+        sample_count: u32,
+        config: WireframeConfig,
+    ) -> Self {
+        let shader = renderer
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!(
+                    "shaders/wireframe.wgsl"
+                ))),
+            });
+
+        let pipeline_layout =
+            renderer
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[&mesh_view_layout.0],
+                    push_constant_ranges: &[],
+                });
+
+        let pipeline = renderer
+            .errors
+            .scope(&renderer.device, "Wireframe Pipeline", || {
+                renderer
+                    .device
+                    .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        label: Some("Wireframe Pipeline"),
+                        layout: Some(&pipeline_layout),
+                        vertex: wgpu::VertexState {
+                            module: &shader,
+                            entry_point: "vertex",
+                            buffers: &[Vertex::layout(), TransformRaw::layout()],
+                        },
+                        fragment: Some(wgpu::FragmentState {
+                            module: &shader,
+                            entry_point: "fragment",
+                            targets: &[Some(wgpu::ColorTargetState {
+                                format: renderer.config.format,
+                                blend: None,
+                                write_mask: wgpu::ColorWrites::ALL,
+                            })],
+                        }),
+                        primitive: wgpu::PrimitiveState {
+                            topology: wgpu::PrimitiveTopology::TriangleList,
+                            front_face: wgpu::FrontFace::Ccw,
+                            cull_mode: match config.cull {
+                                FaceSelection::Front => Some(wgpu::Face::Back),
+                                FaceSelection::Back => Some(wgpu::Face::Front),
+                                FaceSelection::Both => None,
+                            },
+                            polygon_mode: wgpu::PolygonMode::Line,
+                            ..default()
+                        },
+                        depth_stencil: Some(wgpu::DepthStencilState {
+                            format: renderer.depth_format,
+                            depth_write_enabled: false,
+                            depth_compare: if config.depth_test {
+                                renderer.depth_compare(wgpu::CompareFunction::Less)
+                            } else {
+                                wgpu::CompareFunction::Always
+                            },
+                            stencil: wgpu::StencilState::default(),
+                            bias: wgpu::DepthBiasState {
+                                // Pulls the lines toward the camera, which is a higher depth with reverse-Z
+                                slope_scale: if renderer.reverse_z { 1.0 } else { -1.0 },
+                                ..default()
+                            },
+                        }),
+                        multisample: wgpu::MultisampleState {
+                            count: sample_count,
+                            ..default()
+                        },
+                        multiview: None,
+                    })
+            });
+
+        Self {
+            render_pipeline: pipeline,
+        }
     }
 }
 
-fn _setup(
+fn setup(
     mut commands: Commands,
     renderer: Res<WgpuRenderer>,
     mesh_view_layout: Res<MeshViewBindGroupLayout>,
     msaa: Res<Msaa>,
+    config: Res<WireframeConfig>,
 ) {
-    let shader = renderer
-        .device
-        .create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/wireframe.wgsl"))),
-        });
-
-    let pipeline_layout = renderer
-        .device
-        .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: None,
-            bind_group_layouts: &[&mesh_view_layout.0],
-            push_constant_ranges: &[],
-        });
-
-    let pipeline = renderer
-        .device
-        .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vertex",
-                buffers: &[Vertex::layout(), TransformRaw::layout()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fragment",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: renderer.config.format,
-                    blend: None,
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                front_face: wgpu::FrontFace::Ccw,
-                polygon_mode: wgpu::PolygonMode::Line,
-                ..default()
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: false,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState {
-                    slope_scale: -1.0,
-                    ..default()
-                },
-            }),
-            multisample: wgpu::MultisampleState {
-                count: msaa.samples,
-                ..default()
-            },
-            multiview: None,
-        });
-
-    commands.insert_resource(WireframePhase {
-        render_pipeline: pipeline,
-    });
+    commands.insert_resource(WireframePhase::new(
+        &renderer,
+        &mesh_view_layout,
+        msaa.sample_count(),
+        *config,
+    ));
+}
+
+fn update_wireframe_phase(
+    mut phase: ResMut<WireframePhase>,
+    renderer: Res<WgpuRenderer>,
+    mesh_view_layout: Res<MeshViewBindGroupLayout>,
+    msaa: Res<Msaa>,
+    config: Res<WireframeConfig>,
+) {
+    if msaa.is_changed() || config.is_changed() {
+        log::info!("updating wireframe pipeline");
+        *phase = WireframePhase::new(&renderer, &mesh_view_layout, msaa.sample_count(), *config);
+    }
 }
 
-fn _render(
+fn render(
     phase: Res<WireframePhase>,
     mesh_view_bind_group: Res<MeshViewBindGroup>,
     depth_texture: Res<DepthTexture>,
     mut encoder: ResMut<WgpuEncoder>,
     view: Res<WgpuView>,
     model_query: Query<
-        (&Model, &InstanceBuffer, Option<&Instances>),
-        (Without<Light>, With<Wireframe>),
+        (&Model, &InstanceBuffer, Option<&Instances>, VisibilityQuery),
+        (Without<Light>, With<Wireframe>, Without<CompactInstances>),
     >,
+    viewport: Option<Res<EguiViewport>>,
+    camera: Res<Camera>,
 ) {
     let encoder = if let Some(encoder) = encoder.0.as_mut() {
         encoder
@@ -122,14 +203,24 @@ fn _render(
         return;
     };
 
+    if model_query.is_empty() {
+        return;
+    }
+
+    // Render to the egui viewport instead of the window when it's used
+    let (view, depth_texture) = match viewport.as_ref().and_then(|v| v.target()) {
+        Some(target) => (&target.view, &target.depth_texture),
+        None => (&*view, &depth_texture.0),
+    };
+
     let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-        label: None,
+        label: Some("Wireframe Pass"),
         color_attachments: &[Some(view.get_color_attachment(wgpu::Operations {
             load: wgpu::LoadOp::Load,
             store: true,
         }))],
         depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-            view: &depth_texture.0.view,
+            view: &depth_texture.view,
             depth_ops: Some(wgpu::Operations {
                 load: wgpu::LoadOp::Load,
                 store: true,
@@ -140,12 +231,20 @@ fn _render(
 
     render_pass.set_pipeline(&phase.render_pipeline);
 
-    for (model, instance_buffer, instances) in &model_query {
+    for (model, instance_buffer, instances, visibility) in &model_query {
+        if !camera.is_visible(visibility) {
+            continue;
+        }
         render_pass.set_vertex_buffer(1, instance_buffer.0.slice(..));
-        for mesh in model.meshes.iter() {
+        // Lines and points are already drawn as lines
+        for mesh in model
+            .meshes
+            .iter()
+            .filter(|mesh| mesh.topology == wgpu::PrimitiveTopology::TriangleList)
+        {
             // mesh.vertex_buffer
             render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-            render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
             render_pass.set_bind_group(0, &mesh_view_bind_group.0, &[]);
             render_pass.draw_indexed(
                 0..mesh.num_elements,
@@ -0,0 +1,84 @@
+use bevy::math::Vec3;
+
+use crate::{mesh::Mesh, model::ModelMesh};
+
+use super::{cone::Cone, cylinder::Cylinder};
+
+/// An arrow starting at the origin and pointing along +Y.
+/// It's made of a cylinder shaft with a cone head merged in a single mesh.
+pub struct Arrow {
+    pub shaft_radius: f32,
+    pub shaft_length: f32,
+    pub head_radius: f32,
+    pub head_length: f32,
+    /// Number of vertices around the shaft and the head
+    pub resolution: u32,
+}
+
+impl Default for Arrow {
+    fn default() -> Self {
+        Self {
+            shaft_radius: 0.05,
+            shaft_length: 0.8,
+            head_radius: 0.1,
+            head_length: 0.2,
+            resolution: 20,
+        }
+    }
+}
+
+impl Arrow {
+    #[allow(unused)]
+    pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("arrow", device, &self.to_mesh())
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
+        let shaft = Cylinder {
+            radius: self.shaft_radius,
+            height: self.shaft_length,
+            resolution: self.resolution,
+            subdivisions: 1,
+        }
+        .to_mesh();
+        let head = Cone {
+            radius: self.head_radius,
+            height: self.head_length,
+            resolution: self.resolution,
+        }
+        .to_mesh();
+
+        let mut mesh = Mesh {
+            vertices: vec![],
+            indices: Some(vec![]),
+            material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+        };
+        // Both shapes are centered on the origin
+        append(&mut mesh, shaft, Vec3::Y * self.shaft_length * 0.5);
+        append(
+            &mut mesh,
+            head,
+            Vec3::Y * (self.shaft_length + self.head_length * 0.5),
+        );
+        mesh
+    }
+}
+
+/// Moves the vertices of `other` by `translation` and adds them to `mesh`.
+/// The indices are offset by the number of vertices already in `mesh`.
+fn append(mesh: &mut Mesh, other: Mesh, translation: Vec3) {
+    let offset = mesh.vertices.len() as u32;
+    mesh.vertices
+        .extend(other.vertices.into_iter().map(|mut vertex| {
+            vertex.position += translation;
+            vertex
+        }));
+    mesh.indices.get_or_insert_with(Vec::new).extend(
+        other
+            .indices
+            .unwrap_or_default()
+            .into_iter()
+            .map(|i| i + offset),
+    );
+}
@@ -374,6 +374,7 @@ impl Capsule {
                 vertices,
                 indices: Some(indices),
                 material_id: None,
+                topology: wgpu::PrimitiveTopology::TriangleList,
             },
         )
     }
@@ -0,0 +1,114 @@
+use bevy::math::{Vec2, Vec3};
+
+use crate::{
+    mesh::{Mesh, Vertex},
+    model::ModelMesh,
+};
+
+/// A cone with its base on the XZ plane pointing along +Y
+pub struct Cone {
+    /// Radius of the base (X&Z axis)
+    pub radius: f32,
+    /// Height of the cone (Y axis)
+    pub height: f32,
+    /// Number of vertices around the base
+    pub resolution: u32,
+}
+
+impl Default for Cone {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            height: 1.0,
+            resolution: 20,
+        }
+    }
+}
+
+impl Cone {
+    #[allow(unused)]
+    pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("cone", device, &self.to_mesh())
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
+        assert!(self.radius > 0.0 && self.height > 0.0 && self.resolution > 0);
+
+        let step = std::f32::consts::PI * 2.0 / self.resolution as f32;
+        let half_height = self.height * 0.5;
+        let side_normal =
+            |theta: f32| Vec3::new(theta.cos(), self.radius / self.height, theta.sin()).normalize();
+
+        let count = (self.resolution * 3 + 1) as usize;
+        let mut vertices = Vec::with_capacity(count);
+
+        // Side vertices
+        for j in 0..self.resolution {
+            let theta = step * j as f32;
+            vertices.push(Vertex::new(
+                Vec3::new(
+                    theta.cos() * self.radius,
+                    -half_height,
+                    theta.sin() * self.radius,
+                ),
+                side_normal(theta),
+                Vec2::new(j as f32 / self.resolution as f32, 1.0),
+            ));
+        }
+
+        // The apex is duplicated for each side triangle so they all get their own normal
+        let apex_offset = self.resolution;
+        for j in 0..self.resolution {
+            let theta = step * (j as f32 + 0.5);
+            vertices.push(Vertex::new(
+                Vec3::new(0.0, half_height, 0.0),
+                side_normal(theta),
+                Vec2::new((j as f32 + 0.5) / self.resolution as f32, 0.0),
+            ));
+        }
+
+        // Bottom vertices
+        let bottom_offset = apex_offset + self.resolution;
+        vertices.push(Vertex::new(
+            Vec3::new(0.0, -half_height, 0.0),
+            Vec3::NEG_Y,
+            Vec2::splat(0.5),
+        ));
+        for j in 0..self.resolution {
+            let theta = step * j as f32;
+            vertices.push(Vertex::new(
+                Vec3::new(
+                    theta.cos() * self.radius,
+                    -half_height,
+                    theta.sin() * self.radius,
+                ),
+                Vec3::NEG_Y,
+                Vec2::new(theta.cos() * 0.5 + 0.5, theta.sin() * 0.5 + 0.5),
+            ));
+        }
+        assert_eq!(vertices.len(), count);
+
+        let index_count = (6 * self.resolution) as usize;
+        let mut indices = Vec::with_capacity(index_count);
+
+        // Side triangles
+        for j in 0..self.resolution {
+            let j1 = (j + 1) % self.resolution;
+            indices.extend([apex_offset + j, j1, j].iter().copied());
+        }
+        // Bottom circle
+        for j in 0..self.resolution {
+            let j1 = (j + 1) % self.resolution;
+            let base = bottom_offset + 1;
+            indices.extend([base + j, base + j1, bottom_offset].iter().copied());
+        }
+        assert_eq!(indices.len(), index_count);
+
+        Mesh {
+            vertices,
+            indices: Some(indices),
+            material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+        }
+    }
+}
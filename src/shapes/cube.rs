@@ -28,6 +28,10 @@ impl Cube {
     }
 
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("cube", device, &self.to_mesh())
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
         #[rustfmt::skip]
         let vertices = vec![
             // Top
@@ -73,14 +77,11 @@ impl Cube {
             20, 21, 22, 22, 23, 20, // back
         ];
 
-        ModelMesh::from_mesh(
-            "cube",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh {
+            vertices,
+            indices: Some(indices),
+            material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+        }
     }
 }
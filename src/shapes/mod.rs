@@ -1,4 +1,6 @@
+pub mod arrow;
 pub mod capsule;
+pub mod cone;
 pub mod cube;
 pub mod cylinder;
 pub mod plane;
@@ -1,3 +1,5 @@
+use bevy::math::Vec2;
+
 use crate::{
     mesh::{Mesh, Vertex},
     model::ModelMesh,
@@ -7,6 +9,14 @@ use crate::{
 pub struct Plane {
     pub resolution: usize,
     pub size: f32,
+    /// Multiplies the uvs, the textures repeat so this is the number of tiles across the plane.
+    /// The material textures have mips and anisotropic filtering so the tiles don't alias in the distance.
+    pub uv_scale: Vec2,
+    /// Uses a single triangle strip with a primitive restart between the rows instead of
+    /// a triangle list. This needs `2 * (resolution + 1) + 1` indices per row instead of
+    /// `6 * resolution`, a 256x256 plane goes from 393216 to 131839 indices, about a third.
+    /// Only the main pass draws strips, see [`Mesh::PRIMITIVE_RESTART`].
+    pub strip: bool,
 }
 
 impl Default for Plane {
@@ -14,6 +24,8 @@ impl Default for Plane {
         Plane {
             resolution: 10,
             size: 1.0,
+            uv_scale: Vec2::ONE,
+            strip: false,
         }
     }
 }
@@ -21,6 +33,10 @@ impl Default for Plane {
 impl Plane {
     #[allow(unused)]
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("plane", device, &self.to_mesh())
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
         let mut vertices = Vec::with_capacity((self.resolution + 1) * (self.resolution + 1));
         let resolution_modifier = self.size / self.resolution as f32;
         for y in 0..=self.resolution {
@@ -33,8 +49,8 @@ impl Plane {
                     ],
                     [0.0, 1.0, 0.0],
                     [
-                        x as f32 / self.resolution as f32,
-                        y as f32 / self.resolution as f32,
+                        x as f32 / self.resolution as f32 * self.uv_scale.x,
+                        y as f32 / self.resolution as f32 * self.uv_scale.y,
                     ],
                 ));
             }
@@ -67,9 +83,79 @@ impl Plane {
             vertices,
             indices: Some(indices),
             material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
         };
         mesh.compute_tangents();
+        // The tangents are computed from the triangle list since they are the same triangles
+        if self.strip {
+            mesh.indices = Some(self.strip_indices());
+            mesh.topology = wgpu::PrimitiveTopology::TriangleStrip;
+        }
+        mesh
+    }
 
-        ModelMesh::from_mesh("plane", device, &mesh)
+    /// Each row zigzags between its two lines of vertices, the first triangle has the same
+    /// winding as the triangle list and the strip alternates it for the other ones
+    fn strip_indices(&self) -> Vec<u32> {
+        let row_size = self.resolution as u32 + 1;
+        let mut indices = Vec::with_capacity(self.resolution * (row_size as usize * 2 + 1));
+        for y in 0..self.resolution as u32 {
+            if y > 0 {
+                indices.push(Mesh::PRIMITIVE_RESTART);
+            }
+            for x in 0..row_size {
+                indices.push(y * row_size + x);
+                indices.push((y + 1) * row_size + x);
+            }
+        }
+        indices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid() {
+        let plane = Plane {
+            resolution: 4,
+            size: 2.0,
+            uv_scale: Vec2::new(2.0, 3.0),
+            strip: false,
+        };
+        let mesh = plane.to_mesh();
+        assert_eq!(mesh.vertices.len(), 5 * 5);
+        assert_eq!(mesh.indices.as_ref().unwrap().len(), 4 * 4 * 6);
+
+        let first = mesh.vertices[0];
+        let last = mesh.vertices[24];
+        assert_eq!(first.position.to_array(), [0.0, 0.0, 0.0]);
+        assert_eq!(last.position.to_array(), [2.0, 0.0, 2.0]);
+        assert_eq!(first.uv, Vec2::ZERO);
+        assert_eq!(mesh.vertices[4].uv, Vec2::new(2.0, 0.0));
+        assert_eq!(mesh.vertices[20].uv, Vec2::new(0.0, 3.0));
+        assert_eq!(last.uv, plane.uv_scale);
+    }
+
+    #[test]
+    fn strip() {
+        let plane = Plane {
+            resolution: 4,
+            strip: true,
+            ..Default::default()
+        };
+        let mesh = plane.to_mesh();
+        assert_eq!(mesh.topology, wgpu::PrimitiveTopology::TriangleStrip);
+        let indices = mesh.indices.unwrap();
+        // 2 indices per column and a restart between the rows
+        assert_eq!(indices.len(), 4 * 5 * 2 + 3);
+        assert_eq!(
+            indices
+                .iter()
+                .filter(|&&i| i == Mesh::PRIMITIVE_RESTART)
+                .count(),
+            3
+        );
     }
 }
@@ -30,6 +30,7 @@ impl Quad {
             vertices,
             indices: Some(indices),
             material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
         };
 
         ModelMesh::from_mesh("quad", device, &mesh)
@@ -64,6 +65,7 @@ impl FullscreenQuad {
             vertices,
             indices: Some(indices),
             material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
         };
 
         ModelMesh::from_mesh("quad", device, &mesh)
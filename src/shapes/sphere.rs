@@ -30,6 +30,10 @@ impl Default for UVSphere {
 impl UVSphere {
     #[allow(unused)]
     pub fn mesh(&self, device: &wgpu::Device) -> ModelMesh {
+        ModelMesh::from_mesh("uv_sphere", device, &self.to_mesh())
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
         // Largely inspired from http://www.songho.ca/opengl/gl_self.html
 
         let sectors = self.sectors as f32;
@@ -65,9 +69,9 @@ impl UVSphere {
         //  | /  |
         //  k2--k2+1
         for i in 0..self.stacks {
-            let mut k1 = i * (self.sectors + 1);
-            let mut k2 = k1 + self.sectors + 1;
-            for _j in 0..self.sectors {
+            for j in 0..self.sectors {
+                let k1 = i * (self.sectors + 1) + j;
+                let k2 = k1 + self.sectors + 1;
                 if i != 0 {
                     indices.push(k1 as u32);
                     indices.push(k2 as u32);
@@ -78,8 +82,6 @@ impl UVSphere {
                     indices.push(k2 as u32);
                     indices.push((k2 + 1) as u32);
                 }
-                k1 += 1;
-                k2 += 1;
             }
         }
 
@@ -88,14 +90,44 @@ impl UVSphere {
             vertices.push(Vertex::from_arrays(*position, normals[i], uvs[i]));
         }
 
-        ModelMesh::from_mesh(
-            "uv_sphere",
-            device,
-            &Mesh {
-                vertices,
-                indices: Some(indices),
-                material_id: None,
-            },
-        )
+        Mesh {
+            vertices,
+            indices: Some(indices),
+            material_id: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vertices_on_the_sphere() {
+        let sphere = UVSphere {
+            radius: 2.0,
+            ..Default::default()
+        };
+        let mesh = sphere.to_mesh();
+        assert_eq!(
+            mesh.vertices.len(),
+            (sphere.stacks + 1) * (sphere.sectors + 1)
+        );
+        for v in &mesh.vertices {
+            assert!((v.position.length() - sphere.radius).abs() < 1e-5);
+            assert!((v.normal.length() - 1.0).abs() < 1e-5);
+            assert!(v.normal.abs_diff_eq(v.position / sphere.radius, 1e-5));
+        }
+    }
+
+    #[test]
+    fn indices() {
+        let sphere = UVSphere::default();
+        let mesh = sphere.to_mesh();
+        let indices = mesh.indices.unwrap();
+        // The first and last stacks only have one triangle per sector
+        assert_eq!(indices.len(), (sphere.stacks - 1) * sphere.sectors * 2 * 3);
+        assert!(indices.iter().all(|&i| (i as usize) < mesh.vertices.len()));
     }
 }
@@ -0,0 +1,13 @@
+use bevy::{ecs::prelude::*, math::prelude::*};
+
+/// The joint matrices used to skin meshes in the vertex shader.
+/// Every skinned mesh indexes in the same list.
+/// The first joint is the identity used by meshes without skinning data, don't modify it.
+#[derive(Resource)]
+pub struct JointMatrices(pub Vec<Mat4>);
+
+impl Default for JointMatrices {
+    fn default() -> Self {
+        Self(vec![Mat4::IDENTITY])
+    }
+}
@@ -9,6 +9,8 @@ pub struct Texture {
 
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    /// The anisotropy of the texture samplers, adapters that don't support it ignore it
+    pub const MAX_ANISOTROPY: u16 = 16;
 
     #[allow(unused)]
     pub fn default_white(device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<Self> {
@@ -64,10 +66,13 @@ impl Texture {
             height: texture_height,
             depth_or_array_layers: 1,
         };
+        // Without mips the tiled textures, like the floor of the examples,
+        // alias at a distance and at grazing angles
+        let mip_level_count = size.max_mips(wgpu::TextureDimension::D2);
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
@@ -75,29 +80,56 @@ impl Texture {
             view_formats: &[],
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * texture_width),
-                rows_per_image: Some(texture_height),
-            },
-            size,
-        );
+        // Averaging srgb values darkens the mips so they are downsampled in linear space.
+        // The linear copy is kept between the levels so the colors are only rounded once.
+        let mut linear = format.is_srgb().then(|| srgb_to_linear(rgba));
+        let mut mip = std::borrow::Cow::Borrowed(rgba);
+        for mip_level in 0..mip_level_count {
+            if mip_level > 0 {
+                let width = (texture_width >> mip_level).max(1);
+                let height = (texture_height >> mip_level).max(1);
+                // Downscaling the previous level is a lot faster than the full image
+                let filter = image::imageops::FilterType::Triangle;
+                mip = std::borrow::Cow::Owned(match &mut linear {
+                    Some(linear) => {
+                        *linear = image::imageops::resize(&*linear, width, height, filter);
+                        linear_to_srgb(linear)
+                    }
+                    None => image::imageops::resize(&*mip, width, height, filter),
+                });
+            }
+            let (width, height) = mip.dimensions();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &mip,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * width),
+                    rows_per_image: Some(height),
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // Anisotropy needs every filter to be linear
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::Repeat,
             address_mode_v: wgpu::AddressMode::Repeat,
             address_mode_w: wgpu::AddressMode::Repeat,
             mag_filter: wgpu::FilterMode::Linear,
             min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy_clamp: Self::MAX_ANISOTROPY,
             ..Default::default()
         });
 
@@ -108,10 +140,15 @@ impl Texture {
         })
     }
 
+    /// The format should be the `depth_format` of the renderer when the texture is used by the 3d passes.
+    /// Filtering depth values is ill-defined and some backends reject it, so the sampler uses
+    /// nearest filtering and must be bound with `wgpu::SamplerBindingType::NonFiltering`.
+    /// The passes reading the depth use `textureLoad` and don't need a sampler at all.
     pub fn create_depth_texture(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
         sample_count: u32,
+        format: wgpu::TextureFormat,
     ) -> Self {
         let size = wgpu::Extent3d {
             width: config.width,
@@ -124,7 +161,7 @@ impl Texture {
             mip_level_count: 1,
             sample_count,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
+            format,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
             view_formats: &[],
         };
@@ -132,6 +169,10 @@ impl Texture {
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("depth_sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
             compare: None,
             lod_min_clamp: 0.0,
             lod_max_clamp: 100.0,
@@ -144,4 +185,111 @@ impl Texture {
             sampler,
         }
     }
+
+    /// A sampler for depth textures sampled with `textureSampleCompare`, like shadow maps.
+    /// It uses linear filtering so the comparison gets hardware PCF,
+    /// it must be bound with `wgpu::SamplerBindingType::Comparison`.
+    ///
+    /// `compare` needs to follow the depth convention used to render the texture,
+    /// use [`WgpuRenderer::depth_compare`](crate::renderer::WgpuRenderer::depth_compare)
+    /// for the textures rendered like the main pass.
+    pub fn create_comparison_sampler(
+        device: &wgpu::Device,
+        label: &str,
+        compare: wgpu::CompareFunction,
+    ) -> wgpu::Sampler {
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some(label),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(compare),
+            ..Default::default()
+        })
+    }
+}
+
+/// Decodes the color channels, the alpha isn't srgb encoded
+fn srgb_to_linear(rgba: &image::RgbaImage) -> image::Rgba32FImage {
+    let decoded: Vec<f32> = (0..=255u8)
+        .map(|c| {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        })
+        .collect();
+    let mut linear = image::Rgba32FImage::new(rgba.width(), rgba.height());
+    for (linear, srgb) in linear.pixels_mut().zip(rgba.pixels()) {
+        let [r, g, b, a] = srgb.0;
+        linear.0 = [
+            decoded[r as usize],
+            decoded[g as usize],
+            decoded[b as usize],
+            a as f32 / 255.0,
+        ];
+    }
+    linear
+}
+
+/// Encodes the color channels back to srgb, the inverse of [`srgb_to_linear`]
+fn linear_to_srgb(linear: &image::Rgba32FImage) -> image::RgbaImage {
+    let encode = |c: f32| {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    };
+    let mut rgba = image::RgbaImage::new(linear.width(), linear.height());
+    for (srgb, linear) in rgba.pixels_mut().zip(linear.pixels()) {
+        let [r, g, b, a] = linear.0;
+        srgb.0 = [
+            encode(r),
+            encode(g),
+            encode(b),
+            (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+        ];
+    }
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_round_trip() {
+        let rgba = image::RgbaImage::from_fn(256, 1, |x, _| {
+            let c = x as u8;
+            image::Rgba([c, c, c, c])
+        });
+        assert_eq!(linear_to_srgb(&srgb_to_linear(&rgba)), rgba);
+    }
+
+    #[test]
+    fn srgb_mip_keeps_the_brightness() {
+        // A black and white checkerboard is middle gray once downsampled,
+        // 188 in srgb instead of the 128 of averaging the srgb values
+        let rgba = image::RgbaImage::from_fn(2, 2, |x, y| {
+            let c = if (x + y) % 2 == 0 { 0 } else { 255 };
+            image::Rgba([c, c, c, 255])
+        });
+        let mip = image::imageops::resize(
+            &srgb_to_linear(&rgba),
+            1,
+            1,
+            image::imageops::FilterType::Triangle,
+        );
+        let [r, g, b, a] = linear_to_srgb(&mip).get_pixel(0, 0).0;
+        assert!((187..=189).contains(&r), "{r}");
+        assert_eq!((r, r, 255), (g, b, a));
+    }
 }
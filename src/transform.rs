@@ -1,5 +1,6 @@
 use bevy::{math::Mat3, transform::prelude::*};
 
+/// The per instance data uploaded to the instance buffer
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct TransformRaw {
@@ -7,6 +8,8 @@ pub struct TransformRaw {
     normal: [[f32; 3]; 3],
 }
 
+/// Computes the model and normal matrices of a bevy [`Transform`].
+/// This is a free function because [`Transform`] is a foreign type, use it with `iter().map(to_raw)`.
 pub fn to_raw(transform: &Transform) -> TransformRaw {
     let model = transform.compute_matrix();
     TransformRaw {
@@ -15,6 +18,39 @@ pub fn to_raw(transform: &Transform) -> TransformRaw {
     }
 }
 
+/// The per instance data of [`CompactInstances`](crate::instances::CompactInstances), 64 bytes instead of 100.
+/// The shader rebuilds the normal matrix by normalizing the axes of the model matrix.
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CompactTransformRaw {
+    model: [[f32; 4]; 4],
+}
+
+/// Like [`to_raw`] but only computes the model matrix
+pub fn to_compact_raw(transform: &Transform) -> CompactTransformRaw {
+    CompactTransformRaw {
+        model: transform.compute_matrix().to_cols_array_2d(),
+    }
+}
+
+impl CompactTransformRaw {
+    pub fn layout<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBUTESS: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+            // Uses the same slots as the model matrix of TransformRaw
+            5 => Float32x4,
+            6 => Float32x4,
+            7 => Float32x4,
+            8 => Float32x4,
+        ];
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<CompactTransformRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &ATTRIBUTESS,
+        }
+    }
+}
+
 impl TransformRaw {
     pub fn layout<'a>() -> wgpu::VertexBufferLayout<'a> {
         const ATTRIBUTESS: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
@@ -43,3 +79,52 @@ impl TransformRaw {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{Mat4, Quat, Vec3};
+
+    use super::*;
+
+    #[test]
+    fn transform_to_raw() {
+        let transform = Transform {
+            translation: Vec3::new(1.0, 2.0, 3.0),
+            rotation: Quat::from_rotation_y(std::f32::consts::FRAC_PI_2),
+            scale: Vec3::splat(2.0),
+        };
+        let raw = to_raw(&transform);
+
+        let model = Mat4::from_cols_array_2d(&raw.model);
+        assert!(model
+            .transform_point3(Vec3::X)
+            .abs_diff_eq(Vec3::new(1.0, 2.0, 1.0), 1e-5));
+        assert!(model
+            .transform_point3(Vec3::ZERO)
+            .abs_diff_eq(transform.translation, 1e-5));
+
+        // The normal matrix only rotates, the scale is uniform
+        let normal = Mat3::from_cols_array_2d(&raw.normal);
+        assert!((normal * Vec3::X).abs_diff_eq(Vec3::NEG_Z, 1e-5));
+        assert!((normal * Vec3::Y).abs_diff_eq(Vec3::Y, 1e-5));
+        assert!((normal * Vec3::Z).abs_diff_eq(Vec3::X, 1e-5));
+    }
+
+    #[test]
+    fn transform_to_compact_raw() {
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0).with_scale(Vec3::new(1.0, 2.0, 3.0));
+        let raw = to_compact_raw(&transform);
+        assert_eq!(raw.model, to_raw(&transform).model);
+        assert_eq!(
+            Mat4::from_cols_array_2d(&raw.model),
+            transform.compute_matrix()
+        );
+    }
+
+    #[test]
+    fn raw_sizes() {
+        // The layouts use one slot per vec4 of the model matrix and one per vec3 of the normal matrix
+        assert_eq!(std::mem::size_of::<TransformRaw>(), 100);
+        assert_eq!(std::mem::size_of::<CompactTransformRaw>(), 64);
+    }
+}
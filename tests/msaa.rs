@@ -0,0 +1,80 @@
+//! Toggles the msaa between frames rendered with `WgpuRenderer::render_once` and checks that
+//! no pass mixes sample counts, which wgpu reports as validation errors.
+//!
+//! The renderer needs a window and a gpu so this is skipped when no display or adapter is available.
+//! It runs without the test harness because winit needs to be on the main thread.
+
+use bevy::{
+    a11y::AccessibilityPlugin, ecs::event::Events, input::InputPlugin, prelude::*,
+    window::WindowPlugin, winit::WinitPlugin,
+};
+use futures_lite::future;
+// bevy's prelude also has an Msaa
+use glace::{model, prelude::*, renderer::Msaa, shapes};
+
+fn has_display() -> bool {
+    !cfg!(target_os = "linux")
+        || std::env::var_os("DISPLAY").is_some()
+        || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+fn has_adapter() -> bool {
+    // The same backend as WgpuRenderer::new
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::VULKAN,
+        ..default()
+    });
+    future::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default())).is_some()
+}
+
+fn spawn_cube(mut commands: Commands, renderer: Res<WgpuRenderer>) {
+    commands.spawn((
+        Model {
+            meshes: vec![shapes::cube::Cube::new(1.0, 1.0, 1.0).mesh(&renderer.device)],
+            materials: vec![model::Material::from_color(Color::WHITE)],
+        },
+        Transform::from_translation(Vec3::NEG_Z * 3.0),
+    ));
+    commands.spawn(Light {
+        position: Vec3::new(2.0, 2.0, 2.0),
+        color: Color::WHITE.as_rgba_f32().into(),
+    });
+}
+
+fn main() {
+    if !has_display() || !has_adapter() {
+        eprintln!("No display or gpu adapter available, skipping the msaa test");
+        return;
+    }
+
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        WindowPlugin::default(),
+        AccessibilityPlugin,
+        WinitPlugin,
+        InputPlugin,
+        WgpuRendererPlugin,
+        EguiPlugin,
+    ))
+    .add_systems(Startup, spawn_cube);
+    app.finish();
+    app.cleanup();
+    // Runs the startup systems and creates the instance buffers of the cube
+    app.update();
+
+    for samples in [4, 1, 4, 0, 1] {
+        app.world.resource_mut::<Msaa>().samples = samples;
+        if let Err(err) = WgpuRenderer::render_once(&mut app.world) {
+            panic!("Rendering with {samples} msaa samples failed: {err}");
+        }
+    }
+
+    // Sends the errors reported outside of render_once, like the ones of the startup systems
+    app.update();
+    let events = app.world.resource::<Events<RendererErrorEvent>>();
+    let mut reader = events.get_reader();
+    let errors: Vec<_> = reader.iter(events).collect();
+    assert!(errors.is_empty(), "{errors:?}");
+    println!("msaa: ok");
+}